@@ -0,0 +1,550 @@
+use std::{
+    hash::Hash,
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use pq_core::{PriorityQueueError, Result};
+use pq_fair::PriorityQueue;
+use tokio::sync::{Notify, Semaphore, TryAcquireError};
+
+#[cfg(feature = "futures")]
+mod stream;
+
+pub use pq_core::IntoPriority;
+#[cfg(feature = "futures")]
+pub use stream::{QueueSink, QueueStream};
+
+struct State<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    pq: PriorityQueue<E, T>,
+    closed: bool,
+}
+
+struct Inner<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    state: Mutex<State<E, T>>,
+    not_empty: Notify,
+    permits: Option<Semaphore>,
+}
+
+pub struct AsyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    inner: Arc<Inner<E, T>>,
+}
+
+// Not derived: cloning the handle must not require `T: Clone`.
+impl<E, T> Clone for AsyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<E, T> AsyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    /// Creates a new asynchronous priority queue with a fixed number of priority levels.
+    ///
+    /// This is the async counterpart of `pq_sync::SyncPriorityQueue`: waiting
+    /// consumers are suspended futures (woken through [`tokio::sync::Notify`])
+    /// rather than parked threads.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` is zero, as at least one level is required.
+    ///
+    pub fn new(n_prio: usize) -> Self {
+        assert!(n_prio > 0, "n_prio must be > 0");
+        Self::from_parts(n_prio, None)
+    }
+
+    /// Creates a new bounded asynchronous priority queue.
+    ///
+    /// The capacity gate is a [`tokio::sync::Semaphore`] holding one permit per
+    /// free slot: [`enqueue()`](Self::enqueue) awaits a permit instead of parking
+    /// the thread, and every dequeue gives one back.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` or `cap` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_async::AsyncPriorityQueue;
+    ///
+    /// # async fn run() {
+    /// let pq = AsyncPriorityQueue::with_capacity(3, 1);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).await.unwrap();
+    ///
+    /// // Full: the next enqueue suspends until a consumer makes room.
+    /// assert!(pq.try_enqueue(0, "A".to_string(), "item2".to_string()).is_err());
+    /// # }
+    /// ```
+    ///
+    pub fn with_capacity(n_prio: usize, cap: usize) -> Self {
+        assert!(n_prio > 0, "n_prio must be > 0");
+        assert!(cap > 0, "cap must be > 0");
+        Self::from_parts(n_prio, Some(Semaphore::new(cap)))
+    }
+
+    fn from_parts(n_prio: usize, permits: Option<Semaphore>) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    pq: PriorityQueue::new(n_prio),
+                    closed: false,
+                }),
+                not_empty: Notify::new(),
+                permits,
+            }),
+        }
+    }
+}
+
+/// ---
+/// ## Queue Operations
+///
+impl<E, T> AsyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    /// Enqueues a new item, awaiting free capacity if the queue is bounded and full.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is, or becomes, closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub async fn enqueue(&self, prio: impl IntoPriority, entity_id: E, item: T) -> Result<()> {
+        self.acquire_slot().await?;
+        self.push("enqueue", prio.as_index(), entity_id, item)
+    }
+
+    /// Attempts to enqueue an item without waiting.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Full`] — if the queue is bounded and full.
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn try_enqueue(&self, prio: impl IntoPriority, entity_id: E, item: T) -> Result<()> {
+        if let Some(permits) = &self.inner.permits {
+            match permits.try_acquire() {
+                Ok(permit) => permit.forget(),
+                Err(TryAcquireError::NoPermits) => return Err(PriorityQueueError::Full),
+                Err(TryAcquireError::Closed) => return Err(PriorityQueueError::Closed),
+            }
+        }
+        self.push("try_enqueue", prio.as_index(), entity_id, item)
+    }
+
+    /// Attempts to dequeue an item without waiting.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn try_dequeue(&self) -> Result<Option<T>> {
+        let mut st = self.lock("try_dequeue")?;
+        let v = st.pq.try_dequeue();
+        drop(st);
+        if v.is_some() {
+            self.release_slot();
+        }
+        Ok(v)
+    }
+
+    /// Dequeues an item, suspending until one becomes available.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed and empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub async fn dequeue(&self) -> Result<T> {
+        self.pop_wait("dequeue").await
+    }
+
+    /// Dequeues an item, suspending at most `timeout` for one to become available.
+    ///
+    /// Returns `Ok(None)` if the timeout expired while the queue was still
+    /// open. An item enqueued right at the deadline is not lost: the queue is
+    /// checked once more after the timer fires.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is, or becomes, closed and empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_async::AsyncPriorityQueue;
+    /// use std::time::Duration;
+    ///
+    /// # async fn run() {
+    /// let pq: AsyncPriorityQueue<String, String> = AsyncPriorityQueue::new(3);
+    ///
+    /// // Nothing queued: gives up after 10ms.
+    /// let v = pq.dequeue_timeout(Duration::from_millis(10)).await.unwrap();
+    /// assert_eq!(v, None);
+    /// # }
+    /// ```
+    ///
+    pub async fn dequeue_timeout(&self, timeout: Duration) -> Result<Option<T>> {
+        match tokio::time::timeout(timeout, self.pop_wait("dequeue_timeout")).await {
+            Ok(res) => res.map(Some),
+            // The wait may have been cancelled just as an item arrived (its
+            // notification then being stored as a permit): trust the queue,
+            // not the timer.
+            Err(_) => self.pop("dequeue_timeout"),
+        }
+    }
+
+    /// Returns the number of items currently queued, across all priority levels.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.lock("len")?.pq.len())
+    }
+
+    /// Returns `true` if no item is currently queued.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.lock("is_empty")?.pq.is_empty())
+    }
+}
+
+/// ---
+/// ## Shutdown Modes
+///
+impl<E, T> AsyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    /// Closes the queue, letting consumers drain the pending items.
+    ///
+    /// New enqueues are rejected with [`PriorityQueueError::Closed`], and so
+    /// are producers awaiting capacity. Consumers keep getting the pending
+    /// items; once the queue is empty, they resolve to
+    /// [`PriorityQueueError::Closed`]. Returns right away, without waiting
+    /// for the drain.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn shutdown_graceful(&self) -> Result<()> {
+        self.lock("shutdown_graceful")?.closed = true;
+        if let Some(permits) = &self.inner.permits {
+            permits.close();
+        }
+        // Consumers waiting on an empty queue must see it closed.
+        self.inner.not_empty.notify_waiters();
+        Ok(())
+    }
+
+    /// Immediately closes the queue, drops pending items, and wakes every waiter.
+    ///
+    /// Suspended consumers resolve to [`PriorityQueueError::Closed`], and so do
+    /// producers awaiting capacity.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn shutdown_immediate(&self) -> Result<()> {
+        let mut st = self.lock("shutdown_immediate")?;
+        st.closed = true;
+        while st.pq.try_dequeue().is_some() {}
+        drop(st);
+        if let Some(permits) = &self.inner.permits {
+            permits.close();
+        }
+        self.inner.not_empty.notify_waiters();
+        Ok(())
+    }
+}
+
+/// ---
+/// ## Internals
+///
+impl<E, T> AsyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    // Locks the state, tagging a poisoned lock with the calling operation.
+    fn lock(&self, op: &'static str) -> Result<MutexGuard<'_, State<E, T>>> {
+        self.inner
+            .state
+            .lock()
+            .map_err(|_| PriorityQueueError::LockError(op))
+    }
+
+    // Takes a capacity permit, if the queue is bounded, waiting for one.
+    async fn acquire_slot(&self) -> Result<()> {
+        if let Some(permits) = &self.inner.permits {
+            permits
+                .acquire()
+                .await
+                .map_err(|_| PriorityQueueError::Closed)?
+                .forget();
+        }
+        Ok(())
+    }
+
+    // Inserts an item once its capacity permit (if any) has been taken.
+    fn push(&self, op: &'static str, prio: usize, entity_id: E, item: T) -> Result<()> {
+        let res = self.lock(op).and_then(|mut st| {
+            if st.closed {
+                return Err(PriorityQueueError::Closed);
+            }
+            st.pq.enqueue(prio, entity_id, item)
+        });
+        if res.is_err() {
+            // The item was not queued: give its slot back.
+            self.release_slot();
+            return res;
+        }
+        self.inner.not_empty.notify_one();
+        Ok(())
+    }
+
+    // Takes the next item, if any; `Closed` once the queue is closed and empty.
+    fn pop(&self, op: &'static str) -> Result<Option<T>> {
+        let (v, closed) = {
+            let mut st = self.lock(op)?;
+            (st.pq.try_dequeue(), st.closed)
+        };
+        if v.is_some() {
+            self.release_slot();
+        } else if closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        Ok(v)
+    }
+
+    // Takes the next item, suspending until one is available or the queue is closed.
+    async fn pop_wait(&self, op: &'static str) -> Result<T> {
+        loop {
+            // Register interest before checking, so a notification sent in
+            // between cannot be missed.
+            let notified = self.inner.not_empty.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if let Some(v) = self.pop(op)? {
+                return Ok(v);
+            }
+            notified.await;
+        }
+    }
+
+    fn release_slot(&self) {
+        if let Some(permits) = &self.inner.permits {
+            permits.add_permits(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use pq_core::PriorityQueueError;
+
+    use crate::AsyncPriorityQueue;
+
+    #[tokio::test]
+    async fn test_dequeue_waits_for_enqueue() {
+        let pq: AsyncPriorityQueue<String, String> = AsyncPriorityQueue::new(3);
+
+        let pq_clone = pq.clone();
+        let consumer = tokio::spawn(async move { pq_clone.dequeue().await });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        pq.enqueue(0, "A".to_string(), "item1".to_string())
+            .await
+            .unwrap();
+
+        assert_eq!(consumer.await.unwrap().unwrap(), "item1");
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_suspends_producer() {
+        let pq: AsyncPriorityQueue<String, String> = AsyncPriorityQueue::with_capacity(3, 1);
+        pq.enqueue(0, "A".to_string(), "item1".to_string())
+            .await
+            .unwrap();
+        assert!(matches!(
+            pq.try_enqueue(0, "A".to_string(), "item2".to_string()),
+            Err(PriorityQueueError::Full)
+        ));
+
+        let pq_clone = pq.clone();
+        let producer = tokio::spawn(async move {
+            pq_clone
+                .enqueue(0, "A".to_string(), "item2".to_string())
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!producer.is_finished());
+
+        assert_eq!(pq.dequeue().await.unwrap(), "item1");
+        producer.await.unwrap().unwrap();
+        assert_eq!(pq.dequeue().await.unwrap(), "item2");
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_wakes_waiters() {
+        let pq: AsyncPriorityQueue<String, String> = AsyncPriorityQueue::with_capacity(3, 1);
+        pq.enqueue(0, "A".to_string(), "item1".to_string())
+            .await
+            .unwrap();
+
+        let pq_clone = pq.clone();
+        let producer = tokio::spawn(async move {
+            pq_clone
+                .enqueue(0, "A".to_string(), "item2".to_string())
+                .await
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        pq.shutdown_immediate().unwrap();
+        assert!(matches!(
+            producer.await.unwrap(),
+            Err(PriorityQueueError::Closed)
+        ));
+        assert!(matches!(
+            pq.dequeue().await,
+            Err(PriorityQueueError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_dequeue_timeout() {
+        let pq: AsyncPriorityQueue<String, String> = AsyncPriorityQueue::new(3);
+
+        // Genuine timeout: nothing arrives.
+        let v = pq.dequeue_timeout(Duration::from_millis(20)).await.unwrap();
+        assert_eq!(v, None);
+
+        // The item arrives before the timeout.
+        let pq_clone = pq.clone();
+        let producer = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            pq_clone
+                .enqueue(0, "A".to_string(), "item1".to_string())
+                .await
+        });
+        let v = pq.dequeue_timeout(Duration::from_secs(5)).await.unwrap();
+        assert_eq!(v.as_deref(), Some("item1"));
+        producer.await.unwrap().unwrap();
+
+        pq.shutdown_immediate().unwrap();
+        assert!(matches!(
+            pq.dequeue_timeout(Duration::from_millis(20)).await,
+            Err(PriorityQueueError::Closed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_graceful_drains() {
+        let pq: AsyncPriorityQueue<String, String> = AsyncPriorityQueue::with_capacity(3, 2);
+        pq.enqueue(0, "A".to_string(), "item1".to_string())
+            .await
+            .unwrap();
+        pq.shutdown_graceful().unwrap();
+        assert!(matches!(
+            pq.try_enqueue(0, "A".to_string(), "item2".to_string()),
+            Err(PriorityQueueError::Closed)
+        ));
+        assert_eq!(pq.dequeue().await.unwrap(), "item1");
+        assert!(matches!(
+            pq.dequeue().await,
+            Err(PriorityQueueError::Closed)
+        ));
+    }
+
+    #[cfg(feature = "futures")]
+    #[tokio::test]
+    async fn test_forward_into_sink_then_drain_stream() {
+        use futures::{SinkExt, StreamExt, TryStreamExt, stream};
+
+        let pq: AsyncPriorityQueue<String, u32> = AsyncPriorityQueue::with_capacity(2, 2);
+        let consumer = {
+            let pq = pq.clone();
+            tokio::spawn(async move { pq.stream().try_collect::<Vec<_>>().await })
+        };
+        // Twice the capacity: the sink has to wait for the consumer.
+        let items = stream::iter((0..4).map(|i| Ok((i % 2, format!("E{i}"), i as u32))));
+        items.forward(pq.sink()).await.unwrap();
+
+        let mut drained = consumer.await.unwrap().unwrap();
+        drained.sort();
+        assert_eq!(drained, vec![0, 1, 2, 3]);
+
+        // Closed by the first sink: the next one errors out.
+        let mut sink = pq.sink();
+        assert!(matches!(
+            sink.send((0, "A".to_string(), 9)).await,
+            Err(PriorityQueueError::Closed)
+        ));
+    }
+
+    #[cfg(feature = "futures")]
+    #[tokio::test]
+    async fn test_stream_yields_lock_error_then_ends() {
+        use futures::StreamExt;
+
+        let pq: AsyncPriorityQueue<String, u32> = AsyncPriorityQueue::new(1);
+        pq.enqueue(0, "A".to_string(), 1).await.unwrap();
+        let mut stream = pq.stream();
+        assert!(matches!(stream.next().await, Some(Ok(1))));
+
+        let poisoner = pq.clone();
+        let _ = std::thread::spawn(move || {
+            let _st = poisoner.inner.state.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(matches!(
+            stream.next().await,
+            Some(Err(PriorityQueueError::LockError(_)))
+        ));
+        assert!(stream.next().await.is_none());
+    }
+}
@@ -0,0 +1,202 @@
+use std::{
+    future::Future,
+    hash::Hash,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use pq_core::{PriorityQueueError, Result};
+
+use crate::AsyncPriorityQueue;
+
+type BoxFuture<R> = Pin<Box<dyn Future<Output = R> + Send>>;
+
+/// A [`Stream`] of the items of an [`AsyncPriorityQueue`], in dequeue order.
+///
+/// Obtained through [`AsyncPriorityQueue::stream()`]. Each item is taken as
+/// by [`dequeue()`](AsyncPriorityQueue::dequeue): the stream waits while the
+/// queue is empty, and ends once it is closed and drained. Any other error
+/// (e.g. [`PriorityQueueError::LockError`] if the lock is poisoned) is
+/// yielded once, then the stream ends. Dropping the stream while it waits
+/// loses no item.
+pub struct QueueStream<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    queue: AsyncPriorityQueue<E, T>,
+    next: Option<BoxFuture<Result<T>>>,
+    done: bool,
+}
+
+impl<E, T> Stream for QueueStream<E, T>
+where
+    E: Eq + Hash + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    type Item = Result<T>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<T>>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+        let this = &mut *self;
+        let next = this.next.get_or_insert_with(|| {
+            let queue = this.queue.clone();
+            Box::pin(async move { queue.dequeue().await })
+        });
+        let res = ready!(next.as_mut().poll(cx));
+        self.next = None;
+        match res {
+            Err(PriorityQueueError::Closed) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Err(e) => {
+                self.done = true;
+                Poll::Ready(Some(Err(e)))
+            }
+            Ok(item) => Poll::Ready(Some(Ok(item))),
+        }
+    }
+}
+
+/// A [`Sink`] of `(priority, entity, item)` into an [`AsyncPriorityQueue`].
+///
+/// Obtained through [`AsyncPriorityQueue::sink()`], to `forward` a stream
+/// into the queue with backpressure:
+///
+/// - `poll_ready` waits for a free slot if the queue is bounded, and
+///   reserves it for the next `start_send`;
+/// - `start_send` enqueues the item;
+/// - `poll_close` shuts the queue down gracefully (see
+///   [`shutdown_graceful()`](AsyncPriorityQueue::shutdown_graceful)):
+///   consumers still get the items already queued.
+///
+/// Errors are those of [`enqueue()`](AsyncPriorityQueue::enqueue), e.g.
+/// [`PriorityQueueError::Closed`] once the queue is closed. A slot reserved
+/// but never used is given back when the sink is dropped.
+pub struct QueueSink<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    queue: AsyncPriorityQueue<E, T>,
+    reserve: Option<BoxFuture<Result<()>>>,
+    reserved: bool,
+}
+
+impl<E, T> Sink<(usize, E, T)> for QueueSink<E, T>
+where
+    E: Eq + Hash + Clone + Send + 'static,
+    T: Send + 'static,
+{
+    type Error = PriorityQueueError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if self.reserved || self.queue.inner.permits.is_none() {
+            return Poll::Ready(Ok(()));
+        }
+        let this = &mut *self;
+        let reserve = this.reserve.get_or_insert_with(|| {
+            let queue = this.queue.clone();
+            Box::pin(async move { queue.acquire_slot().await })
+        });
+        let res = ready!(reserve.as_mut().poll(cx));
+        self.reserve = None;
+        self.reserved = res.is_ok();
+        Poll::Ready(res)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, (prio, entity_id, item): (usize, E, T)) -> Result<()> {
+        if self.queue.inner.permits.is_some() && !std::mem::take(&mut self.reserved) {
+            // Not made ready first: only a free slot will do.
+            return self.queue.try_enqueue(prio, entity_id, item);
+        }
+        self.queue.push("start_send", prio, entity_id, item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        if std::mem::take(&mut self.reserved) {
+            self.queue.release_slot();
+        }
+        Poll::Ready(self.queue.shutdown_graceful())
+    }
+}
+
+impl<E, T> Drop for QueueSink<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        if self.reserved {
+            self.queue.release_slot();
+        }
+    }
+}
+
+/// ---
+/// ## Streams and Sinks
+///
+impl<E, T> AsyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    /// Returns a [`Stream`] dequeuing items from this queue (see [`QueueStream`]).
+    ///
+    /// Several streams (and plain consumers) can share a queue: each item
+    /// goes to exactly one of them.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::TryStreamExt;
+    /// use pq_async::AsyncPriorityQueue;
+    ///
+    /// # async fn run() {
+    /// let pq = AsyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), 1).await.unwrap();
+    /// pq.shutdown_graceful().unwrap();
+    ///
+    /// let items: Vec<u32> = pq.stream().try_collect().await.unwrap();
+    /// assert_eq!(items, vec![1]);
+    /// # }
+    /// ```
+    ///
+    pub fn stream(&self) -> QueueStream<E, T> {
+        QueueStream {
+            queue: self.clone(),
+            next: None,
+            done: false,
+        }
+    }
+
+    /// Returns a [`Sink`] enqueuing `(priority, entity, item)` tuples into
+    /// this queue, with backpressure when bounded (see [`QueueSink`]).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use futures::{StreamExt, stream};
+    /// use pq_async::AsyncPriorityQueue;
+    ///
+    /// # async fn run() {
+    /// let pq = AsyncPriorityQueue::with_capacity(3, 16);
+    /// let items = stream::iter([(0, "A".to_string(), 1), (2, "B".to_string(), 2)]);
+    /// // Closes the queue once the stream is exhausted.
+    /// items.map(Ok).forward(pq.sink()).await.unwrap();
+    /// # }
+    /// ```
+    ///
+    pub fn sink(&self) -> QueueSink<E, T> {
+        QueueSink {
+            queue: self.clone(),
+            reserve: None,
+            reserved: false,
+        }
+    }
+}
@@ -8,32 +8,44 @@
 //!
 //! Implementations compared:
 //! - syncpq : your SyncPriorityQueue wrapped with a bounded layer (1 priority, 1 entity).
+//! - syncflat : same, on SyncFlatPriorityQueue (no entity fairness bookkeeping).
 //! - xbeam  : crossbeam::bounded MPMC.
 //! - mpsc   : std::sync::mpsc::sync_channel bounded (single real consumer).
 //!
 //! Output CSV:
 //! impl,producers,consumers,n_items,capacity,p50_ns,p95_ns,p99_ns,throughput_items_per_s
 //!
+//! With `--sample-depth`, a second CSV block follows with the queue depth sampled
+//! every `--sample-period-us` during the measurement window:
+//! t_ns,depth,capacity
+//!
 //! Run (build release to reduce noise):
 //!   cargo build --release
 //!   target/release/pq-bench --implm syncpq --producers 4 --consumers 4 --n-items 500000 --capacity 1024
+//!   target/release/pq-bench --implm syncflat --producers 4 --consumers 4 --n-items 500000 --capacity 1024
 //!   target/release/pq-bench --implm xbeam  --producers 4 --consumers 4 --n-items 500000 --capacity 1024
 //!   target/release/pq-bench --implm mpsc  --producers 4 --consumers 4 --n-items 500000 --capacity 1024
 
-use anyhow::Result;
+use anyhow::{Result, ensure};
 use clap::Parser;
 use crossbeam_channel as xbeam;
-use pq_sync::SyncPriorityQueue;
+use pq_sync::{
+    BlockingQueue, DEFAULT_SPIN, Queue, Semaphore, SyncFlatPriorityQueue, SyncPriorityQueue,
+};
 use std::{
     fmt::Debug,
-    sync::{Arc, Barrier, Condvar, Mutex, mpsc as stdmpsc},
+    sync::{
+        Arc, Barrier, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc as stdmpsc,
+    },
     thread,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 #[derive(Parser, Debug, Clone)]
 struct Args {
-    /// "syncpq" | "xbeam" | "mpsc"
+    /// "syncpq" | "syncflat" | "xbeam" | "mpsc"
     #[arg(long, default_value = "syncpq")]
     implm: String,
 
@@ -57,9 +69,21 @@ struct Args {
     #[arg(long, default_value = "immediate")]
     shutdown: String,
 
+    /// syncpq only: non-blocking dequeue attempts before parking (default: pq_sync::DEFAULT_SPIN)
+    #[arg(long)]
+    spin: Option<usize>,
+
     /// CPU work per item in nanoseconds (busy-wait), executed by consumers after each message
     #[arg(long, default_value_t = 0u64)]
     work_ns: u64,
+
+    /// sample queue depth during the measurement window (printed as a second CSV block)
+    #[arg(long, default_value_t = false)]
+    sample_depth: bool,
+
+    /// depth sampling period in microseconds
+    #[arg(long, default_value_t = 1000u64)]
+    sample_period_us: u64,
 }
 
 #[derive(Clone)]
@@ -79,6 +103,8 @@ enum Msg {
 trait QueueAdapter: Send + Sync + 'static {
     fn enqueue_data(&self, m: Msg);
     fn dequeue(&self) -> Msg; // blocking
+    fn len(&self) -> usize; // current depth (approximate under contention)
+    fn capacity(&self) -> Option<usize>; // None when unbounded
     fn shutdown_immediate(&self) {} // default no-op
     fn shutdown_graceful(&self) {} // default no-op
 }
@@ -96,6 +122,12 @@ impl QueueAdapter for XBeamAdapter {
     fn dequeue(&self) -> Msg {
         self.rx.recv().unwrap()
     }
+    fn len(&self) -> usize {
+        self.rx.len()
+    }
+    fn capacity(&self) -> Option<usize> {
+        self.tx.capacity()
+    }
 }
 
 // ------------------------ std::mpsc -----------------------------------------
@@ -104,86 +136,100 @@ struct MpscAdapter {
     tx: stdmpsc::SyncSender<Msg>,
     // Receiver<T> is not Sync → protect it; this also matches single-consumer semantics.
     rx: Mutex<stdmpsc::Receiver<Msg>>,
+    // std::mpsc exposes no len(): count sent - received ourselves.
+    depth: AtomicUsize,
+    cap: usize,
 }
 impl QueueAdapter for MpscAdapter {
     fn enqueue_data(&self, m: Msg) {
+        // Counted before the send: a consumer may take the message (and
+        // decrement) before `send` returns, which would wrap the counter.
+        self.depth.fetch_add(1, Ordering::Relaxed);
         self.tx.send(m).unwrap();
     }
     fn dequeue(&self) -> Msg {
         let rx = self.rx.lock().unwrap();
-        rx.recv().unwrap()
+        let m = rx.recv().unwrap();
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+        m
+    }
+    fn len(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+    fn capacity(&self) -> Option<usize> {
+        Some(self.cap)
     }
 }
 
-// ------------------------ SyncPriorityQueue (bounded wrapper) ---------------
+// ------------------------ pq_sync::BlockingQueue (bounded wrapper) ---------
 //
-// SyncPriorityQueue itself is unbounded. To compare apples-to-apples against
-// bounded channels, we add a tiny "capacity gate":
+// Any queue implementing the generic `BlockingQueue` trait through a shared
+// reference (e.g. SyncPriorityQueue), used without its own capacity. To
+// compare apples-to-apples against bounded channels, we add a tiny
+// "capacity gate" (a counting semaphore):
 // - producers block in enqueue when the inflight count reaches 'cap'
 // - consumers release a slot after dequeue(Data)
 
-struct BoundedSyncPQAdapter {
-    pq: SyncPriorityQueue<usize, Msg>,
+struct BoundedQueueAdapter<Q> {
+    pq: Q,
     cap: usize,
-    gate: Gate,
-}
-
-struct Gate {
-    mu: Mutex<usize>, // inflight count (enqueued - dequeued), only for Data
-    cv: Condvar,
+    gate: Semaphore, // one permit per free slot, only for Data
 }
 
-impl BoundedSyncPQAdapter {
-    fn new(cap: usize) -> Self {
+impl<Q> BoundedQueueAdapter<Q> {
+    fn new(pq: Q, cap: usize) -> Self {
         Self {
-            pq: SyncPriorityQueue::<usize, Msg>::new(1),
+            pq,
             cap,
-            gate: Gate {
-                mu: Mutex::new(0),
-                cv: Condvar::new(),
-            },
+            gate: Semaphore::new(cap),
         }
     }
 
     // Acquire one slot; block while capacity is full.
     fn acquire_slot(&self) {
-        let mut n = self.gate.mu.lock().unwrap();
-        while *n >= self.cap {
-            n = self.gate.cv.wait(n).unwrap();
-        }
-        *n += 1;
+        self.gate.acquire().unwrap();
     }
 
     // Release one slot and wake a waiting producer (if any).
     fn release_slot(&self) {
-        let mut n = self.gate.mu.lock().unwrap();
-        *n -= 1;
-        self.gate.cv.notify_one();
+        self.gate.release().unwrap();
     }
 }
 
-impl QueueAdapter for BoundedSyncPQAdapter {
+impl<Q> QueueAdapter for BoundedQueueAdapter<Q>
+where
+    Q: Send + Sync + 'static,
+    for<'a> &'a Q: BlockingQueue<usize, Msg>,
+{
     fn enqueue_data(&self, m: Msg) {
         if matches!(m, Msg::Data(_)) {
             self.acquire_slot();
         }
         // Single priority (0), single entity (0) for apples-to-apples micro-bench.
-        self.pq.enqueue(0, 0, m).unwrap();
+        (&self.pq).enqueue(0, 0, m).unwrap();
     }
 
     fn dequeue(&self) -> Msg {
-        let msg = self.pq.dequeue().unwrap();
+        let msg = (&self.pq).dequeue().unwrap();
         if matches!(msg, Msg::Data(_)) {
             self.release_slot();
         }
         msg
     }
 
+    fn len(&self) -> usize {
+        Queue::len(&&self.pq).unwrap_or(0)
+    }
+
+    fn capacity(&self) -> Option<usize> {
+        Some(self.cap)
+    }
+
     fn shutdown_immediate(&self) {
-        let _ = self.pq.shutdown_immediate();
+        let _ = (&self.pq).shutdown_immediate();
     }
     fn shutdown_graceful(&self) {
-        let _ = self.pq.shutdown_graceful();
+        let _ = (&self.pq).shutdown_graceful();
     }
 }
 
@@ -202,6 +248,7 @@ fn busy_work_ns(ns: u64) {
 
 fn main() -> Result<()> {
     let args = Args::parse();
+    ensure!(args.n_items > 0, "--n-items must be > 0");
 
     // Build adapter
     let adapter: Box<dyn QueueAdapter> = match args.implm.as_str() {
@@ -214,11 +261,22 @@ fn main() -> Result<()> {
             Box::new(MpscAdapter {
                 tx,
                 rx: Mutex::new(rx),
+                depth: AtomicUsize::new(0),
+                cap: args.capacity,
             })
         }
-        "syncpq" => Box::new(BoundedSyncPQAdapter::new(args.capacity)),
+        "syncpq" => Box::new(BoundedQueueAdapter::new(
+            SyncPriorityQueue::<usize, Msg>::builder(1)
+                .spin(args.spin.unwrap_or(DEFAULT_SPIN))
+                .build(),
+            args.capacity,
+        )),
+        "syncflat" => Box::new(BoundedQueueAdapter::new(
+            SyncFlatPriorityQueue::<Msg>::new(1),
+            args.capacity,
+        )),
         other => {
-            eprintln!("Unknown --implm={other}. Use 'syncpq' | 'xbeam' | 'mpsc'.");
+            eprintln!("Unknown --implm={other}. Use 'syncpq' | 'syncflat' | 'xbeam' | 'mpsc'.");
             std::process::exit(2);
         }
     };
@@ -244,6 +302,8 @@ fn main() -> Result<()> {
             }
         }));
     }
+    // Only consumers hold a sender now: if they all die, `recv` fails instead of hanging.
+    drop(lat_tx);
 
     // Split Data items across producers
     let base = args.n_items / args.producers;
@@ -264,17 +324,45 @@ fn main() -> Result<()> {
         }));
     }
 
+    // Optional depth sampler: runs alongside the measurement window only.
+    let sampling = Arc::new(AtomicBool::new(args.sample_depth));
+    let sampler = args.sample_depth.then(|| {
+        let q = Arc::clone(&q);
+        let sampling = Arc::clone(&sampling);
+        let period = Duration::from_micros(args.sample_period_us);
+        thread::spawn(move || {
+            let t0 = Instant::now();
+            let mut samples = Vec::new();
+            while sampling.load(Ordering::Relaxed) {
+                samples.push((t0.elapsed().as_nanos() as u64, q.len()));
+                thread::sleep(period);
+            }
+            samples
+        })
+    });
+
     // Start the measurement window: when we release producers.
     let t0 = Instant::now();
     start_barrier.wait();
 
     // Collect exactly N Data latencies → end of measurement window.
     let mut lats = Vec::with_capacity(args.n_items);
-    for _ in 0..args.n_items {
-        let ns = lat_rx.recv().unwrap();
+    while lats.len() < args.n_items {
+        let Ok(ns) = lat_rx.recv() else {
+            break;
+        };
         lats.push(ns);
     }
     let elapsed = t0.elapsed();
+    // An undercount would silently skew every number below: refuse to report it.
+    ensure!(
+        lats.len() == args.n_items,
+        "collected {} latencies, expected {}",
+        lats.len(),
+        args.n_items
+    );
+    sampling.store(false, Ordering::Relaxed);
+    let samples = sampler.map(|h| h.join().unwrap()).unwrap_or_default();
 
     // After measurement: clean shutdown (excluded from metrics)
     // 1) Ask consumers to stop (poison pills): send exactly `consumers` Stop messages.
@@ -292,8 +380,8 @@ fn main() -> Result<()> {
         h.join().unwrap();
     }
 
-    // 4) For SyncPriorityQueue, call the chosen shutdown after everything (no effect on metrics)
-    if args.implm.as_str() == "syncpq" {
+    // 4) For the pq_sync queues, call the chosen shutdown after everything (no effect on metrics)
+    if matches!(args.implm.as_str(), "syncpq" | "syncflat") {
         match args.shutdown.as_str() {
             "immediate" => q.shutdown_immediate(),
             "graceful" => q.shutdown_graceful(),
@@ -324,6 +412,15 @@ fn main() -> Result<()> {
         tps as u64
     );
 
+    if args.sample_depth {
+        let cap = q.capacity().map(|c| c.to_string()).unwrap_or_default();
+        println!();
+        println!("t_ns,depth,capacity");
+        for (t_ns, depth) in samples {
+            println!("{},{},{}", t_ns, depth, cap);
+        }
+    }
+
     Ok(())
 }
 
@@ -334,5 +431,5 @@ fn percentile(sorted_ns: &[u64], p: f64) -> f64 {
     }
     let n = sorted_ns.len();
     let rank = ((p / 100.0) * (n as f64 - 1.0)).round() as usize;
-    sorted_ns[rank] as f64
+    sorted_ns[rank.min(n - 1)] as f64
 }
@@ -5,20 +5,135 @@ pub type Result<T> = result::Result<T, PriorityQueueError>;
 #[derive(Debug)]
 pub enum PriorityQueueError {
     BadPriority(usize),
-    LockError,
+    LockError(&'static str),
     Closed,
     Timeout,
+    Full,
+    UnknownLease(u64),
+    AllocFailed,
     NotImplemented,
+    TooManyEntities,
+    LevelNotEmpty(usize),
+    NoIndex,
 }
 
 impl fmt::Display for PriorityQueueError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             PriorityQueueError::BadPriority(prio) => write!(f, "bad priority {}", prio),
-            PriorityQueueError::LockError => write!(f, "lock failed"),
+            PriorityQueueError::LockError(op) => write!(f, "lock failed in {}", op),
             PriorityQueueError::Closed => write!(f, "closed"),
             PriorityQueueError::Timeout => write!(f, "timeout"),
+            PriorityQueueError::Full => write!(f, "full"),
+            PriorityQueueError::UnknownLease(id) => write!(f, "unknown lease {}", id),
+            PriorityQueueError::AllocFailed => write!(f, "allocation failed"),
             PriorityQueueError::NotImplemented => write!(f, "not implemented"),
+            PriorityQueueError::TooManyEntities => write!(f, "too many entities"),
+            PriorityQueueError::LevelNotEmpty(prio) => write!(f, "level {} is not empty", prio),
+            PriorityQueueError::NoIndex => write!(f, "no index for this key type"),
         }
     }
 }
+
+/// Result of a dequeue bounded in time.
+///
+/// Keeps the three possible endings apart, so that a consumer loop can decide
+/// what to do next from the outcome alone, without a separate (and racy)
+/// check of the queue state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DequeueOutcome<T> {
+    /// An item was dequeued in time.
+    Item(T),
+    /// No item arrived in time, but the queue is still open: retry later.
+    Timeout,
+    /// The queue is closed and drained: no item will ever come, stop.
+    Closed,
+}
+
+/// Conversion of a caller-side priority into a level index (0 = highest priority).
+///
+/// Implemented for `usize`; implement it for your own priority enum to use it
+/// directly at the API boundary:
+///
+/// ```
+/// use pq_core::IntoPriority;
+///
+/// #[derive(Clone, Copy)]
+/// #[repr(usize)]
+/// enum Priority {
+///     High,
+///     Normal,
+///     Low,
+/// }
+///
+/// impl IntoPriority for Priority {
+///     fn as_index(self) -> usize {
+///         self as usize
+///     }
+/// }
+///
+/// assert_eq!(Priority::Low.as_index(), 2);
+/// ```
+pub trait IntoPriority: Copy {
+    fn as_index(self) -> usize;
+}
+
+impl IntoPriority for usize {
+    fn as_index(self) -> usize {
+        self
+    }
+}
+
+/// The core operations of a fair priority queue, whatever its implementation:
+/// `E` is the entity an item belongs to, `T` the item.
+///
+/// Implemented by `pq_fair::PriorityQueue` (single-threaded) and
+/// `pq_sync::SyncPriorityQueue` (shared between threads, also through a
+/// `&SyncPriorityQueue`), so that code written against this trait can swap
+/// one for the other. Their fairness-free variants, `pq_fair::FlatPriorityQueue`
+/// and `pq_sync::SyncFlatPriorityQueue`, implement it too and ignore the
+/// entity. Blocking operations live in [`BlockingQueue`].
+///
+/// ```
+/// use pq_core::{Queue, Result};
+///
+/// // Takes whatever is queued right now, most urgent first.
+/// fn take_all<E, T>(queue: &mut impl Queue<E, T>) -> Result<Vec<T>> {
+///     let mut items = Vec::new();
+///     while let Some(item) = queue.try_dequeue()? {
+///         items.push(item);
+///     }
+///     Ok(items)
+/// }
+/// ```
+pub trait Queue<E, T> {
+    /// Queues `item` for `entity_id` at level `prio` (0 = highest priority).
+    fn enqueue(&mut self, prio: usize, entity_id: E, item: T) -> Result<()>;
+
+    /// Takes the next item, or `None` if the queue is empty; never blocks.
+    fn try_dequeue(&mut self) -> Result<Option<T>>;
+
+    /// Number of queued items.
+    fn len(&self) -> Result<usize>;
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
+
+/// A [`Queue`] whose consumers can wait for items, and that can be shut down.
+pub trait BlockingQueue<E, T>: Queue<E, T> {
+    /// Takes the next item, blocking while the queue is empty.
+    ///
+    /// Fails with [`PriorityQueueError::Closed`] once the queue is shut down
+    /// and has nothing left to give.
+    fn dequeue(&mut self) -> Result<T>;
+
+    /// Closes the queue and drops the pending items: blocked consumers are
+    /// woken up and fail.
+    fn shutdown_immediate(&mut self) -> Result<()>;
+
+    /// Closes the queue to producers, and lets consumers drain the pending
+    /// items.
+    fn shutdown_graceful(&mut self) -> Result<()>;
+}
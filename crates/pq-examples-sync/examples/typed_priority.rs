@@ -0,0 +1,35 @@
+use pq_sync::{IntoPriority, SyncPriorityQueue};
+
+/// Application-level priorities, mapped onto queue levels (0 = highest).
+#[derive(Debug, Clone, Copy)]
+#[repr(usize)]
+enum Priority {
+    High,
+    Normal,
+    Low,
+}
+
+impl IntoPriority for Priority {
+    fn as_index(self) -> usize {
+        self as usize
+    }
+}
+
+fn main() {
+    let pq = SyncPriorityQueue::new(3);
+
+    pq.enqueue(Priority::Low, "A".to_string(), "cleanup".to_string())
+        .unwrap();
+    pq.enqueue(Priority::Normal, "A".to_string(), "report".to_string())
+        .unwrap();
+    pq.enqueue(Priority::High, "B".to_string(), "alert".to_string())
+        .unwrap();
+
+    // Raw indices keep working.
+    pq.enqueue(1, "B".to_string(), "sync".to_string()).unwrap();
+
+    // alert, report, sync, cleanup
+    while let Some(item) = pq.try_dequeue().unwrap() {
+        println!("{}", item);
+    }
+}
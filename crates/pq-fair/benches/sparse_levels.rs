@@ -0,0 +1,40 @@
+//! Micro-benchmark: enqueue→try_dequeue cost with many priority levels, of
+//! which only a couple hold items.
+//!
+//! The most urgent non-empty level is found through a bitmask of the
+//! non-empty levels, so the cost per dequeue should stay flat as the number of
+//! (empty) levels grows, instead of growing with it.
+//!
+//! Output CSV:
+//! n_prio,active_levels,ns_per_op
+//!
+//! Run:
+//!   cargo bench -p pq-fair --bench sparse_levels
+
+use std::{hint::black_box, time::Instant};
+
+use pq_fair::PriorityQueue;
+
+const OPS: usize = 1_000_000;
+
+// The two active levels sit at the bottom: the worst case for a scan.
+fn run(n_prio: usize) -> f64 {
+    let active = [n_prio - 2, n_prio - 1];
+    let mut pq = PriorityQueue::new(n_prio);
+    for (i, &prio) in active.iter().enumerate() {
+        pq.enqueue(prio, i, 0usize).unwrap();
+    }
+    let start = Instant::now();
+    for i in 0..OPS {
+        let item = pq.try_dequeue().unwrap();
+        pq.enqueue(active[i % 2], i % 2, black_box(item)).unwrap();
+    }
+    start.elapsed().as_nanos() as f64 / OPS as f64
+}
+
+fn main() {
+    println!("n_prio,active_levels,ns_per_op");
+    for n_prio in [2, 8, 64, 256, 1024] {
+        println!("{n_prio},2,{:.1}", run(n_prio));
+    }
+}
@@ -0,0 +1,50 @@
+// Allocator of the item storage, i.e. the per-entity deques.
+//
+// With the `allocator-api` feature (nightly only), a PriorityQueue can be
+// given any `std::alloc::Allocator` for them (see `PriorityQueue::new_in`).
+// Without it, the same code compiles on stable: `Allocator` and `Global` are
+// private stand-ins, and `Deque<U, A>` is a plain `VecDeque<U>`.
+
+use std::collections::VecDeque;
+
+#[cfg(feature = "allocator-api")]
+pub use std::alloc::{Allocator, Global};
+
+#[cfg(not(feature = "allocator-api"))]
+pub use stable::{Allocator, Global};
+
+#[cfg(feature = "allocator-api")]
+pub(crate) type Deque<U, A> = VecDeque<U, A>;
+
+// `A` must appear in the alias; the projection always resolves to `X`.
+#[cfg(not(feature = "allocator-api"))]
+pub(crate) type Deque<U, A> = <A as stable::Ignore<VecDeque<U>>>::Out;
+
+#[cfg(feature = "allocator-api")]
+pub(crate) fn new_deque<U, A: Allocator + Clone>(alloc: &A) -> Deque<U, A> {
+    VecDeque::new_in(alloc.clone())
+}
+
+#[cfg(not(feature = "allocator-api"))]
+pub(crate) fn new_deque<U, A: Allocator + Clone>(_alloc: &A) -> Deque<U, A> {
+    VecDeque::new()
+}
+
+#[cfg(not(feature = "allocator-api"))]
+mod stable {
+    // Not nameable from outside the crate: on stable, `A` is always `Global`.
+    pub trait Allocator {}
+
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct Global;
+
+    impl Allocator for Global {}
+
+    pub trait Ignore<X> {
+        type Out;
+    }
+
+    impl<A: ?Sized, X> Ignore<X> for A {
+        type Out = X;
+    }
+}
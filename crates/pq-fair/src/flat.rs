@@ -0,0 +1,108 @@
+use std::collections::VecDeque;
+
+use pq_core::{PriorityQueueError, Queue, Result};
+
+// Strict priority queue without entity fairness: one plain FIFO per level.
+//
+// For configurations that don't need per-entity round-robin (e.g. a single
+// producer entity), this skips the HashMap/HashSet/ring bookkeeping of
+// `PriorityQueue` entirely, while reporting the same errors.
+pub struct FlatPriorityQueue<T> {
+    queues: Vec<VecDeque<T>>,
+    len: usize,
+}
+
+impl<T> FlatPriorityQueue<T> {
+    // fn new
+    //
+    // # Panics
+    //
+    // Panics if `n_prio` is zero, as at least one level is required.
+    pub fn new(n_prio: usize) -> Self {
+        assert!(n_prio > 0, "n_prio must be > 0");
+        let mut queues = Vec::with_capacity(n_prio);
+        queues.resize_with(n_prio, VecDeque::new);
+        Self { queues, len: 0 }
+    }
+
+    // fn n_prio
+    pub fn n_prio(&self) -> usize {
+        self.queues.len()
+    }
+
+    // fn is_empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // fn is_level_empty
+    pub fn is_level_empty(&self, prio: usize) -> Result<bool> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level.is_empty())
+    }
+
+    // fn len
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // fn len_at_priority
+    pub fn len_at_priority(&self, prio: usize) -> Result<usize> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level.len())
+    }
+
+    // fn enqueue
+    pub fn enqueue(&mut self, prio: usize, item: T) -> Result<()> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        level.push_back(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    // fn try_dequeue
+    pub fn try_dequeue(&mut self) -> Option<T> {
+        self.try_dequeue_tagged().map(|(_, item)| item)
+    }
+
+    // fn try_dequeue_tagged
+    //
+    // Same as try_dequeue, but also tells the priority level of the item.
+    pub fn try_dequeue_tagged(&mut self) -> Option<(usize, T)> {
+        let (prio, item) = self
+            .queues
+            .iter_mut()
+            .enumerate()
+            .find_map(|(prio, level)| level.pop_front().map(|item| (prio, item)))?;
+        self.len -= 1;
+        Some((prio, item))
+    }
+}
+
+// The entity is ignored: items of a level come out in arrival order.
+impl<E, T> Queue<E, T> for FlatPriorityQueue<T> {
+    fn enqueue(&mut self, prio: usize, _entity_id: E, item: T) -> Result<()> {
+        FlatPriorityQueue::enqueue(self, prio, item)
+    }
+
+    fn try_dequeue(&mut self) -> Result<Option<T>> {
+        Ok(FlatPriorityQueue::try_dequeue(self))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(FlatPriorityQueue::len(self))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(FlatPriorityQueue::is_empty(self))
+    }
+}
@@ -1,36 +1,493 @@
+#![cfg_attr(feature = "allocator-api", feature(allocator_api))]
+
 use std::{
+    cmp::Reverse,
     collections::{HashMap, HashSet, VecDeque},
+    fmt,
     hash::Hash,
+    ops::Range,
+    sync::Arc,
 };
 
-use pq_core::{PriorityQueueError, Result};
+use pq_core::{PriorityQueueError, Queue, Result};
+
+mod alloc;
+mod flat;
+mod occupancy;
+mod ord;
+
+use alloc::{Allocator, Deque, Global, new_deque};
+use occupancy::Occupancy;
+
+pub use flat::FlatPriorityQueue;
+pub use ord::OrdPriorityQueue;
+
+// Scheduling policy of a PriorityQueue.
+//
+// StrictPriority (the default): levels are served in priority order, each with
+// its own round-robin of entities (tempered by level quotas, if any).
+//
+// GlobalEntityFair: fairness is per entity across the whole queue. A single
+// ring holds every entity with pending items, whatever their levels; on its
+// turn, an entity gives its most urgent item (lowest priority number, oldest
+// first). Priorities only order an entity's own items: a busy entity with
+// urgent items cannot starve another one with background work. Level quotas
+// are ignored.
+//
+// DecayingPriority: levels are served in priority order, except that each
+// dequeue adds `decay_permille` thousandths to the served level's penalty,
+// and the next level is the non-empty one with the lowest index plus penalty
+// (the most urgent one on ties). A constantly busy level thus yields a turn
+// to the next waiting one after about `1000 / decay_permille` consecutive
+// dequeues, and further levels get rarer turns. A level's penalty is cleared
+// when it yields or runs empty. Level quotas are ignored, unless
+// `decay_permille` is 0: that is StrictPriority. The decay is an integer so
+// that policies compare exactly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Policy {
+    #[default]
+    StrictPriority,
+    GlobalEntityFair,
+    DecayingPriority {
+        decay_permille: u32,
+    },
+}
+
+// Cross-level entity index, only maintained under Policy::GlobalEntityFair.
+#[derive(Clone)]
+struct GlobalRing<E> {
+    rr: VecDeque<E>,
+    // pending items per entity, across all levels
+    pending: HashMap<E, usize>,
+}
+
+// Deficit round robin, opt-in: within a level, an entity's turn is measured
+// in item cost rather than item count. Each entity has a deficit, topped up
+// by its quantum every time round-robin reaches it; it gives items while the
+// deficit covers the cost of its front item, and the turn passes otherwise.
+// An entity arrives with one quantum of deficit and loses what is left when
+// it runs out of items. With every cost and quantum at 1, this is the plain
+// round-robin.
+#[derive(Clone)]
+struct Drr<T> {
+    // the quantum of entities not given one with set_quantum
+    quantum: u64,
+    cost: Arc<dyn Fn(&T) -> u64 + Send + Sync>,
+}
+
+// Pending items per entity across all levels, opt-in (see
+// PriorityQueue::set_entity_counts).
+#[derive(Clone)]
+struct EntityCounts<E> {
+    pending: HashMap<E, usize>,
+}
+
+// An item, tagged with its enqueue sequence number (a logical timestamp).
+#[derive(Clone)]
+struct Entry<T> {
+    seq: u64,
+    item: T,
+}
 
-struct PriorityLevel<E, T>
+#[derive(Clone)]
+struct PriorityLevel<E, T, A>
 where
     E: Eq + Hash + Clone,
+    A: Allocator,
 {
-    by_entities: HashMap<E, VecDeque<T>>,
+    by_entities: HashMap<E, Deque<Entry<T>, A>>,
     rr: VecDeque<E>,
     actives: HashSet<E>,
+    len: usize,
+    // consecutive dequeues allowed before yielding to a lower level
+    quota: usize,
+    // most items the level may hold, all entities together
+    cap: usize,
+    served: usize,
+    // added to the level's index to rank it, under Policy::DecayingPriority,
+    // in thousandths
+    penalty: u64,
+    // dequeues served by more urgent levels while this one waited, with aging
+    skipped: usize,
+    // consecutive turns given to an entity before rotation moves on, when
+    // not the default of 1
+    weights: HashMap<E, u32>,
+    // turns left in the current burst of weighted entities being served
+    credits: HashMap<E, u32>,
+    // with deficit round robin: quanta not the queue's default, and the
+    // deficits of active entities, when not a full quantum
+    quanta: HashMap<E, u64>,
+    deficits: HashMap<E, u64>,
+    // in fifo mode: (seq, entity) of the level's items in arrival order,
+    // plus stale entries of items that left out of turn
+    arrivals: VecDeque<(u64, E)>,
+}
+
+impl<E, T, A> PriorityLevel<E, T, A>
+where
+    E: Eq + Hash + Clone,
+    A: Allocator + Clone,
+{
+    // fn push
+    //
+    // Appends `entry` to the deque of `entity_id`, which joins the back of
+    // `rr` if it was not active, with a deque from `pool` if there is one.
+    fn push(
+        &mut self,
+        entity_id: E,
+        entry: Entry<T>,
+        pool: &mut Option<Vec<Deque<Entry<T>, A>>>,
+        alloc: &A,
+    ) {
+        if self.actives.insert(entity_id.clone()) {
+            // each active entity is in rr once: a newcomer must not be there
+            debug_assert_eq!(
+                self.rr.len() + 1,
+                self.actives.len(),
+                "inactive entity found in rr"
+            );
+            self.rr.push_back(entity_id.clone());
+        }
+        self.by_entities
+            .entry(entity_id)
+            .or_insert_with(|| {
+                pool.as_mut()
+                    .and_then(Vec::pop)
+                    .unwrap_or_else(|| new_deque(alloc))
+            })
+            .push_back(entry);
+        self.len += 1;
+    }
+
+    // fn pop_turn
+    //
+    // One round-robin step: the entity in front of `rr` (once deficit round
+    // robin has picked it) gives its oldest item to `f`, then goes back in
+    // line, at the front if its turn goes on (a weight burst, or a deficit
+    // covering its next item), at the back otherwise. An entity left without
+    // items leaves the level, its deque going to `pool`. In fifo mode,
+    // fifo_turn serves instead.
+    fn pop_turn<R>(
+        &mut self,
+        drr: Option<&Drr<T>>,
+        fifo: bool,
+        pool: &mut Option<Vec<Deque<Entry<T>, A>>>,
+        f: impl FnOnce(&E, Entry<T>) -> R,
+    ) -> Option<R> {
+        if fifo {
+            return self.fifo_turn(pool, f);
+        }
+        if let Some(drr) = drr {
+            self.drr_rotate(drr);
+        }
+        // take the entity in front of the round-robin deque
+        let entity_id = self.rr.pop_front()?;
+        // and its oldest task/item
+        let items = self.by_entities.get_mut(&entity_id)?;
+        let entry = items.pop_front()?;
+        let costs = drr.map(|drr| {
+            let next = items.front().map(|next| (drr.cost)(&next.item));
+            ((drr.cost)(&entry.item), next)
+        });
+        let exhausted = items.is_empty();
+        self.len -= 1;
+        let ret = f(&entity_id, entry);
+        let keep_front = match (drr, costs) {
+            (Some(drr), Some((cost, next))) => self.drr_charge(drr, &entity_id, cost, next),
+            // a weighted entity starts a burst of `weight` turns, sized by its
+            // weight at that time, and keeps the front until the burst is over
+            _ if self.weights.is_empty() && self.credits.is_empty() => false,
+            _ => {
+                let left = match self.credits.remove(&entity_id) {
+                    Some(left) => left - 1,
+                    None => self.weights.get(&entity_id).map_or(0, |w| w - 1),
+                };
+                if left > 0 && !exhausted {
+                    self.credits.insert(entity_id.clone(), left);
+                }
+                left > 0
+            }
+        };
+        if !exhausted {
+            debug_assert_eq!(
+                self.rr.len() + 1,
+                self.actives.len(),
+                "served entity still in rr"
+            );
+            if keep_front {
+                self.rr.push_front(entity_id);
+            } else {
+                self.rr.push_back(entity_id);
+            }
+        } else {
+            PriorityQueue::<E, T, A>::recycle(pool, self.by_entities.remove(&entity_id));
+            self.actives.remove(&entity_id);
+        }
+        // println!("{}", items.len()); // <- not allowed by the compiler
+        Some(ret)
+    }
+
+    // fn fifo_turn
+    //
+    // pop_turn in fifo mode: the entity holding the level's oldest item,
+    // found through `arrivals` past the stale entries, gives it to `f`. The
+    // order of `rr` does not matter here and is left alone, so that serving
+    // costs the same however many entities wait; only an entity left without
+    // items is looked up there, to leave the level.
+    fn fifo_turn<R>(
+        &mut self,
+        pool: &mut Option<Vec<Deque<Entry<T>, A>>>,
+        f: impl FnOnce(&E, Entry<T>) -> R,
+    ) -> Option<R> {
+        let entity_id = loop {
+            let (seq, entity_id) = self.arrivals.pop_front()?;
+            if self.is_front(seq, &entity_id) {
+                break entity_id;
+            }
+        };
+        let items = self.by_entities.get_mut(&entity_id)?;
+        let entry = items.pop_front()?;
+        let exhausted = items.is_empty();
+        self.len -= 1;
+        let ret = f(&entity_id, entry);
+        if exhausted {
+            self.leave_rr(&entity_id);
+            PriorityQueue::<E, T, A>::recycle(pool, self.by_entities.remove(&entity_id));
+            self.actives.remove(&entity_id);
+        }
+        Some(ret)
+    }
+
+    // fn leave_rr
+    //
+    // Removes `entity_id` from `rr`, scanning from both ends at once: in fifo
+    // mode, entities stay where they joined, and the one leaving is usually
+    // among the oldest or the newest.
+    fn leave_rr(&mut self, entity_id: &E) {
+        let n = self.rr.len();
+        let pos = (0..n.div_ceil(2)).find_map(|i| {
+            if self.rr[i] == *entity_id {
+                Some(i)
+            } else if self.rr[n - 1 - i] == *entity_id {
+                Some(n - 1 - i)
+            } else {
+                None
+            }
+        });
+        if let Some(pos) = pos {
+            self.rr.remove(pos);
+        }
+    }
 }
 
-pub struct PriorityQueue<E, T>
+// A clone is an independent queue in the exact same state: rings, quotas,
+// credits and sequence numbers included, so it dequeues in the same order.
+#[derive(Clone)]
+pub struct PriorityQueue<E, T, A = Global>
 where
     E: Eq + Hash + Clone,
+    A: Allocator,
 {
-    queues: Vec<PriorityLevel<E, T>>,
+    queues: Vec<PriorityLevel<E, T, A>>,
+    next_seq: u64,
+    // empty deques kept for reuse by reactivating entities (opt-in)
+    pool: Option<Vec<Deque<Entry<T>, A>>>,
+    // Some under Policy::GlobalEntityFair
+    global: Option<GlobalRing<E>>,
+    // Some with entity counts
+    counts: Option<EntityCounts<E>>,
+    // Some under Policy::DecayingPriority: the penalty of a served level, in
+    // thousandths
+    decay: Option<u32>,
+    // Some with aging: the skips after which a waiting level gets a turn
+    aging: Option<usize>,
+    // Some with deficit round robin
+    drr: Option<Drr<T>>,
+    // levels serve their items in enqueue order, entities notwithstanding
+    fifo: bool,
+    // which levels hold items
+    occupancy: Occupancy,
+    // allocator of the per-entity deques
+    alloc: A,
 }
 
-impl<E, T> PriorityLevel<E, T>
+impl<E, T, A> PriorityLevel<E, T, A>
 where
     E: Eq + Hash + Clone,
+    A: Allocator,
 {
     pub fn new() -> Self {
         Self {
             by_entities: HashMap::new(),
             rr: VecDeque::new(),
             actives: HashSet::new(),
+            len: 0,
+            quota: usize::MAX,
+            cap: usize::MAX,
+            served: 0,
+            penalty: 0,
+            skipped: 0,
+            weights: HashMap::new(),
+            credits: HashMap::new(),
+            quanta: HashMap::new(),
+            deficits: HashMap::new(),
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    fn quantum(&self, drr: &Drr<T>, entity_id: &E) -> u64 {
+        self.quanta.get(entity_id).copied().unwrap_or(drr.quantum)
+    }
+
+    fn deficit(&self, drr: &Drr<T>, entity_id: &E) -> u64 {
+        self.deficits
+            .get(entity_id)
+            .copied()
+            .unwrap_or_else(|| self.quantum(drr, entity_id))
+    }
+
+    fn set_deficit(&mut self, drr: &Drr<T>, entity_id: &E, deficit: u64) {
+        if deficit == self.quantum(drr, entity_id) {
+            self.deficits.remove(entity_id);
+        } else {
+            self.deficits.insert(entity_id.clone(), deficit);
+        }
+    }
+
+    // fn is_front
+    //
+    // Whether the item `seq` is the front item of `entity_id`: an entry of
+    // `arrivals` for which this does not hold is stale. An entity's entries
+    // are in the order of its deque, so the front item always has the
+    // first live entry.
+    fn is_front(&self, seq: u64, entity_id: &E) -> bool {
+        self.by_entities
+            .get(entity_id)
+            .and_then(|items| items.front())
+            .is_some_and(|entry| entry.seq == seq)
+    }
+
+    // fn fifo_next
+    //
+    // The entity holding the level's oldest item, past the stale entries.
+    fn fifo_next(&self) -> Option<&E> {
+        self.arrivals
+            .iter()
+            .find(|(seq, entity_id)| self.is_front(*seq, entity_id))
+            .map(|(_, entity_id)| entity_id)
+    }
+
+    // fn fifo_arrive
+    //
+    // Records the arrival of item `seq` of `entity_id`, rebuilding `arrivals`
+    // once stale entries outnumber the live ones, so that it stays O(items).
+    fn fifo_arrive(&mut self, seq: u64, entity_id: E) {
+        self.arrivals.push_back((seq, entity_id));
+        if self.arrivals.len() > 2 * self.len + 16 {
+            self.fifo_rebuild();
+        }
+    }
+
+    // fn fifo_rebuild
+    //
+    // Rebuilds `arrivals` from the deques, by sequence number, but keeping
+    // each entity's entries in the order of its deque (which items moved
+    // with reassign_head may have left out of sequence order).
+    fn fifo_rebuild(&mut self) {
+        let mut arrivals: Vec<(u64, u64, E)> = Vec::with_capacity(self.len);
+        for (entity_id, items) in &self.by_entities {
+            let mut key = 0;
+            for entry in items.iter() {
+                key = key.max(entry.seq);
+                arrivals.push((key, entry.seq, entity_id.clone()));
+            }
+        }
+        arrivals.sort_by_key(|&(key, seq, _)| (key, seq));
+        self.arrivals.clear();
+        self.arrivals.extend(
+            arrivals
+                .into_iter()
+                .map(|(_, seq, entity_id)| (seq, entity_id)),
+        );
+    }
+
+    // fn drr_next
+    //
+    // The entity deficit round robin serves next, as its position in `rr`,
+    // with the number of full rotations it takes before its deficit covers
+    // its front item: the fewest rotations win, then the earliest position.
+    // Stops at the first entity that can be served right away.
+    fn drr_next(&self, drr: &Drr<T>) -> Option<(usize, u64)> {
+        let mut best: Option<(usize, u64)> = None;
+        for (pos, entity_id) in self.rr.iter().enumerate() {
+            let Some(entry) = self
+                .by_entities
+                .get(entity_id)
+                .and_then(|items| items.front())
+            else {
+                continue;
+            };
+            let short = (drr.cost)(&entry.item).saturating_sub(self.deficit(drr, entity_id));
+            let rounds = short.div_ceil(self.quantum(drr, entity_id));
+            if best.is_none_or(|(_, best_rounds)| rounds < best_rounds) {
+                best = Some((pos, rounds));
+                if rounds == 0 {
+                    break;
+                }
+            }
+        }
+        best
+    }
+
+    // fn drr_rotate
+    //
+    // Brings the entity drr_next picks to the front of `rr`, in one go: the
+    // entities it passes over get the top-ups of the turns they let go.
+    fn drr_rotate(&mut self, drr: &Drr<T>) {
+        let Some((pos, rounds)) = self.drr_next(drr) else {
+            return;
+        };
+        if pos == 0 && rounds == 0 {
+            return;
+        }
+        let topped: Vec<(E, u64)> = self
+            .rr
+            .iter()
+            .enumerate()
+            .filter_map(|(i, entity_id)| {
+                let turns = rounds + u64::from(i < pos);
+                let top_up = turns.saturating_mul(self.quantum(drr, entity_id));
+                (turns > 0).then(|| {
+                    let deficit = self.deficit(drr, entity_id).saturating_add(top_up);
+                    (entity_id.clone(), deficit)
+                })
+            })
+            .collect();
+        for (entity_id, deficit) in topped {
+            self.set_deficit(drr, &entity_id, deficit);
+        }
+        self.rr.rotate_left(pos);
+    }
+
+    // fn drr_charge
+    //
+    // Charges `cost` to the deficit of `entity_id`, just served, and tells
+    // whether it keeps the front for its next item, of cost `next` (None if
+    // it has no items left, and then no deficit either). Passing the turn on
+    // tops the deficit up by a quantum.
+    fn drr_charge(&mut self, drr: &Drr<T>, entity_id: &E, cost: u64, next: Option<u64>) -> bool {
+        let deficit = self.deficit(drr, entity_id).saturating_sub(cost);
+        let Some(next) = next else {
+            self.deficits.remove(entity_id);
+            return false;
+        };
+        if next <= deficit {
+            self.set_deficit(drr, entity_id, deficit);
+            return true;
         }
+        let quantum = self.quantum(drr, entity_id);
+        self.set_deficit(drr, entity_id, deficit.saturating_add(quantum));
+        false
     }
 }
 
@@ -40,106 +497,2949 @@ where
 {
     // fn new
     pub fn new(n_prio: usize) -> Self {
+        Self::with_alloc(n_prio, Global)
+    }
+
+    // fn try_new
+    //
+    // Same as new, but a failure to allocate the levels (e.g. an absurd
+    // `n_prio` coming from untrusted config) is reported as AllocFailed
+    // instead of aborting the process.
+    pub fn try_new(n_prio: usize) -> Result<Self> {
+        let mut queues = Vec::new();
+        queues
+            .try_reserve_exact(n_prio)
+            .map_err(|_| PriorityQueueError::AllocFailed)?;
+        queues.resize_with(n_prio, PriorityLevel::new);
+        Ok(Self {
+            queues,
+            ..Self::new(0)
+        })
+    }
+
+    // fn with_policy
+    pub fn with_policy(n_prio: usize, policy: Policy) -> Self {
+        let mut pq = Self::new(n_prio);
+        pq.set_policy(policy);
+        pq
+    }
+
+    // fn with_level_quotas
+    //
+    // One level per quota: level `p` may serve `quotas[p]` consecutive items
+    // before it must let the next non-empty level serve one. `usize::MAX`
+    // everywhere is strict priority, the same as `new`.
+    pub fn with_level_quotas(quotas: Vec<usize>) -> Self {
+        let mut pq = Self::new(quotas.len());
+        for (level, quota) in pq.queues.iter_mut().zip(quotas) {
+            level.quota = quota;
+        }
+        pq
+    }
+
+    // fn with_aging
+    //
+    // Same as new, with anti-starvation aging: each time a dequeue is served
+    // by a more urgent level, every waiting level counts a skip, and a level
+    // that has waited `threshold` skips is served next, once, whatever more
+    // urgent items are pending (the most urgent such level first). Serving a
+    // level, or its running empty, clears its count. A flooded level 0 thus
+    // lets a waiting level 2 through every `threshold + 1` dequeues or so.
+    // Aging applies on top of level quotas, and is ignored under
+    // GlobalEntityFair and DecayingPriority.
+    pub fn with_aging(n_prio: usize, threshold: usize) -> Self {
+        assert!(threshold > 0, "aging threshold must be > 0");
+        let mut pq = Self::new(n_prio);
+        pq.aging = Some(threshold);
+        pq
+    }
+
+    // fn with_level_caps
+    //
+    // One level per cap: level `p` holds at most `caps[p]` items, all
+    // entities together, and enqueues beyond that fail with Full until an
+    // item leaves. `usize::MAX` everywhere is unbounded, the same as `new`.
+    pub fn with_level_caps(caps: Vec<usize>) -> Self {
+        let mut pq = Self::new(caps.len());
+        for (level, cap) in pq.queues.iter_mut().zip(caps) {
+            level.cap = cap;
+        }
+        pq
+    }
+
+    // fn with_deque_pool
+    //
+    // Same as new, but the per-entity deques are recycled: when an entity
+    // runs out of items, its (cleared) deque goes to a free list, and the next
+    // entity to activate takes it from there instead of allocating. This cuts
+    // allocator churn when entities come and go all the time. The pool never
+    // holds more deques than the peak number of active entities.
+    pub fn with_deque_pool(n_prio: usize) -> Self {
+        let mut pq = Self::new(n_prio);
+        pq.set_deque_pool(true);
+        pq
+    }
+
+    // fn with_deficit_round_robin
+    //
+    // Same as new, but each level runs deficit round robin (see Drr): an
+    // entity's turn lets it give items up to `quantum` in total cost, as
+    // measured by `cost_fn`, deficits carrying over from one turn to the
+    // next. Entities can be given their own quantum with set_quantum.
+    // Weights are ignored, and so is DRR under GlobalEntityFair.
+    pub fn with_deficit_round_robin(
+        n_prio: usize,
+        quantum: u64,
+        cost_fn: impl Fn(&T) -> u64 + Send + Sync + 'static,
+    ) -> Self {
+        assert!(quantum > 0, "quantum must be > 0");
+        let mut pq = Self::new(n_prio);
+        pq.drr = Some(Drr {
+            quantum,
+            cost: Arc::new(cost_fn),
+        });
+        pq
+    }
+
+    // fn fifo
+    //
+    // Same as new, without fairness: each level gives its items in enqueue
+    // order, as a single FIFO would, whatever their entities. Entities are
+    // still recorded (entity_len, remove_entity and co. work as usual), but
+    // do not take turns: weights are ignored. Each level keeps its items'
+    // arrival order on the side, so a dequeue is O(1) amortized however many
+    // entities wait; only taking an entity's last item also looks it up among
+    // the level's active entities. Levels are still chosen by the policy,
+    // save GlobalEntityFair, which keeps its own entity ring.
+    pub fn fifo(n_prio: usize) -> Self {
+        let mut pq = Self::new(n_prio);
+        pq.fifo = true;
+        pq
+    }
+}
+
+impl<E, T, A> PriorityQueue<E, T, A>
+where
+    E: Eq + Hash + Clone,
+    A: Allocator + Clone,
+{
+    // fn new_in
+    //
+    // Same as new, with the items stored in deques allocated by `alloc`
+    // (nightly, `allocator-api` feature). The bookkeeping (entity maps,
+    // round-robin rings) stays on the global allocator.
+    #[cfg(feature = "allocator-api")]
+    pub fn new_in(n_prio: usize, alloc: A) -> Self {
+        Self::with_alloc(n_prio, alloc)
+    }
+
+    fn with_alloc(n_prio: usize, alloc: A) -> Self {
         let mut queues = Vec::with_capacity(n_prio);
         queues.resize_with(n_prio, PriorityLevel::new);
-        Self { queues }
+        Self {
+            queues,
+            next_seq: 0,
+            pool: None,
+            global: None,
+            counts: None,
+            decay: None,
+            aging: None,
+            drr: None,
+            fifo: false,
+            occupancy: Occupancy::new(n_prio),
+            alloc,
+        }
+    }
+
+    // fn set_policy
+    //
+    // Switching to GlobalEntityFair indexes the pending entities, in level
+    // order then in the round-robin order of each level. Switching to
+    // DecayingPriority starts with no penalties.
+    pub fn set_policy(&mut self, policy: Policy) {
+        self.decay = None;
+        for level in &mut self.queues {
+            level.penalty = 0;
+        }
+        match (policy, &self.global) {
+            (Policy::StrictPriority, _) => self.global = None,
+            (Policy::DecayingPriority { decay_permille }, _) => {
+                self.global = None;
+                self.decay = Some(decay_permille);
+            }
+            (Policy::GlobalEntityFair, Some(_)) => {}
+            (Policy::GlobalEntityFair, None) => {
+                let mut global = GlobalRing::new();
+                for level in &self.queues {
+                    for entity_id in &level.rr {
+                        global.track(entity_id, level.by_entities[entity_id].len());
+                    }
+                }
+                self.global = Some(global);
+            }
+        }
+    }
+
+    // fn policy
+    pub fn policy(&self) -> Policy {
+        match (&self.global, self.decay) {
+            (Some(_), _) => Policy::GlobalEntityFair,
+            (None, Some(decay_permille)) => Policy::DecayingPriority { decay_permille },
+            (None, None) => Policy::StrictPriority,
+        }
+    }
+
+    // fn set_level_quotas
+    //
+    // Same as with_level_quotas, on an existing queue: `quotas[p]` replaces
+    // the quota of level `p`; levels past the end of `quotas` keep theirs.
+    pub fn set_level_quotas(&mut self, quotas: Vec<usize>) -> Result<()> {
+        if quotas.len() > self.queues.len() {
+            return Err(PriorityQueueError::BadPriority(self.queues.len()));
+        }
+        for (level, quota) in self.queues.iter_mut().zip(quotas) {
+            level.quota = quota;
+        }
+        Ok(())
+    }
+
+    // fn set_deque_pool
+    //
+    // Turns deque recycling on or off; turning it off frees the pooled deques.
+    pub fn set_deque_pool(&mut self, enabled: bool) {
+        match (enabled, &self.pool) {
+            (true, None) => self.pool = Some(Vec::new()),
+            (false, _) => self.pool = None,
+            (true, Some(_)) => {}
+        }
+    }
+
+    // fn resize
+    //
+    // Grows to `n_prio` levels by appending empty ones, with the default
+    // configuration; queued items stay where they are. Shrinking goes
+    // through shrink, and fails the same way.
+    pub fn resize(&mut self, n_prio: usize) -> Result<()> {
+        if n_prio == 0 || n_prio < self.queues.len() {
+            return self.shrink(n_prio);
+        }
+        self.queues.resize_with(n_prio, PriorityLevel::new);
+        Ok(())
+    }
+
+    // fn shrink
+    //
+    // Drops the levels from `n_prio` on, provided they are all empty: items
+    // are never dropped. Otherwise fails with LevelNotEmpty, naming the
+    // first non-empty one, and the queue is left as it was. The settings of
+    // the dropped levels (quotas, caps, weights) go with them. A queue keeps
+    // at least one level: shrinking to 0 fails with BadPriority(0).
+    pub fn shrink(&mut self, n_prio: usize) -> Result<()> {
+        if n_prio == 0 {
+            return Err(PriorityQueueError::BadPriority(0));
+        }
+        if let Some(prio) = self.occupancy.next_from(n_prio) {
+            return Err(PriorityQueueError::LevelNotEmpty(prio));
+        }
+        self.queues.truncate(n_prio);
+        self.occupancy.truncate(n_prio);
+        Ok(())
+    }
+
+    // fn n_prio
+    pub fn n_prio(&self) -> usize {
+        self.queues.len()
     }
 
     // fn is_empty
+    //
+    // O(1): answered by the occupancy bitmask, without scanning levels.
     pub fn is_empty(&self) -> bool {
-        self.queues.iter().all(|l| l.by_entities.is_empty())
+        self.occupancy.is_empty()
+    }
+
+    // fn is_level_empty
+    pub fn is_level_empty(&self, prio: usize) -> Result<bool> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level.actives.is_empty())
+    }
+
+    // fn len
+    //
+    // Number of queued items (not entities), across all levels. Each level
+    // keeps its own count up to date, so this is O(levels), whatever the
+    // number of entities or items.
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|l| l.len).sum()
+    }
+
+    // fn len_at_priority
+    //
+    // Number of items at level `prio`, all entities included. O(1), from the
+    // level's own count.
+    pub fn len_at_priority(&self, prio: usize) -> Result<usize> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level.len)
+    }
+
+    // fn back_of_entity
+    //
+    // The most recently enqueued item of `entity_id` at level `prio`, if any.
+    pub fn back_of_entity(&self, prio: usize, entity_id: &E) -> Result<Option<&T>> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level
+            .by_entities
+            .get(entity_id)
+            .and_then(|items| items.back())
+            .map(|entry| &entry.item))
     }
 
     // fn enqueue
     pub fn enqueue(&mut self, prio: usize, entity_id: E, item: T) -> Result<()> {
-        if prio >= self.queues.len() {
-            return Err(PriorityQueueError::BadPriority(prio));
+        self.enqueue_seq(prio, entity_id, item).map(|_| ())
+    }
+
+    // fn enqueue_seq
+    //
+    // Same as enqueue, but returns the sequence number given to the item: it
+    // identifies the item until it leaves (see try_dequeue_seq and seqs).
+    pub fn enqueue_seq(&mut self, prio: usize, entity_id: E, item: T) -> Result<u64> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        if level.len >= level.cap {
+            return Err(PriorityQueueError::Full);
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.push_entry(prio, entity_id, Entry { seq, item });
+        Ok(seq)
+    }
+
+    // fn enqueue_many
+    //
+    // Same as calling enqueue for each of `items` in turn, with the same
+    // fairness, but the priority is checked and the entity registered once
+    // for the whole batch. Returns how many items were added; an empty batch
+    // leaves the queue untouched. A batch that does not fit entirely under
+    // the level's cap fails with Full, and none of it is enqueued.
+    pub fn enqueue_many<I: IntoIterator<Item = T>>(
+        &mut self,
+        prio: usize,
+        entity_id: E,
+        items: I,
+    ) -> Result<usize> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        if level.cap < usize::MAX {
+            // the batch must be counted before anything is enqueued
+            let items: Vec<T> = items.into_iter().collect();
+            if items.len() > level.cap.saturating_sub(level.len) {
+                return Err(PriorityQueueError::Full);
+            }
+            return Ok(self.extend_entity(prio, entity_id, items));
+        }
+        Ok(self.extend_entity(prio, entity_id, items))
+    }
+
+    // fn extend_entity
+    //
+    // Appends `items` to the deque of `entity_id` at level `prio`, which is
+    // registered in the round-robin if it was not active there and `items`
+    // is not empty; returns how many were added.
+    fn extend_entity(
+        &mut self,
+        prio: usize,
+        entity_id: E,
+        items: impl IntoIterator<Item = T>,
+    ) -> usize {
+        let mut items = items.into_iter().peekable();
+        if items.peek().is_none() {
+            return 0;
         }
         let level = &mut self.queues[prio];
         if level.actives.insert(entity_id.clone()) {
             level.rr.push_back(entity_id.clone());
         }
-        level
+        let deque = level
             .by_entities
-            .entry(entity_id)
-            .or_default()
-            .push_back(item);
+            .entry(entity_id.clone())
+            .or_insert_with(|| {
+                self.pool
+                    .as_mut()
+                    .and_then(Vec::pop)
+                    .unwrap_or_else(|| new_deque(&self.alloc))
+            });
+        let before = deque.len();
+        let first_seq = self.next_seq;
+        let next_seq = &mut self.next_seq;
+        deque.extend(items.map(|item| {
+            let seq = *next_seq;
+            *next_seq += 1;
+            Entry { seq, item }
+        }));
+        let added = deque.len() - before;
+        level.len += added;
+        if self.fifo {
+            for seq in first_seq..self.next_seq {
+                level.fifo_arrive(seq, entity_id.clone());
+            }
+        }
+        self.occupancy.update(prio, level.len);
+        if let Some(global) = &mut self.global {
+            global.track(&entity_id, added);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.add(&entity_id, added);
+        }
+        added
+    }
 
-        Ok(())
+    // fn push_entry
+    //
+    // Appends `entry` to the deque of `entity_id` at level `prio`, which is
+    // registered in the round-robin if it was not active there.
+    fn push_entry(&mut self, prio: usize, entity_id: E, entry: Entry<T>) {
+        if let Some(global) = &mut self.global {
+            global.track(&entity_id, 1);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.add(&entity_id, 1);
+        }
+        let arrival = self.fifo.then(|| (entry.seq, entity_id.clone()));
+        let level = &mut self.queues[prio];
+        level.push(entity_id, entry, &mut self.pool, &self.alloc);
+        if let Some((seq, entity_id)) = arrival {
+            level.fifo_arrive(seq, entity_id);
+        }
+        self.occupancy.update(prio, level.len);
+    }
+
+    // fn enqueue_pos
+    //
+    // Same as enqueue, but returns how many items of the same entity are
+    // ahead of the new one at that level: its 0-based position in the
+    // entity's own line. Only a snapshot at insertion time.
+    pub fn enqueue_pos(&mut self, prio: usize, entity_id: E, item: T) -> Result<usize> {
+        let ahead = self.entity_len_at(prio, &entity_id)?;
+        self.enqueue_seq(prio, entity_id, item)?;
+        Ok(ahead)
     }
 
     // fn try_dequeue
     pub fn try_dequeue(&mut self) -> Option<T> {
-        // for each level
-        for level in self.queues.iter_mut() {
-            // if there is an entity in round-robin deque
-            if let Some(entity_id) = level.rr.pop_front() {
-                // look if there is a task/item available
-                if let Some(items) = level.by_entities.get_mut(&entity_id) {
-                    if let Some(item) = items.pop_front() {
-                        if !items.is_empty() {
-                            level.rr.push_back(entity_id);
-                        } else {
-                            level.by_entities.remove(&entity_id);
-                            level.actives.remove(&entity_id);
-                        }
-                        // println!("{}", items.len()); // <- not allowed by the compiler
-                        return Some(item);
-                    }
-                }
+        self.pop_next(|_, _, entry| entry.item)
+    }
+
+    // fn peek
+    //
+    // The item try_dequeue would return next, left in place: the round-robin
+    // order and the turn counters (quotas, weights, penalties) are untouched,
+    // so peeking twice gives the same item.
+    pub fn peek(&self) -> Option<&T> {
+        let (prio, entity_id) = match &self.global {
+            Some(global) => {
+                let entity_id = global.rr.front()?;
+                let prio = self
+                    .queues
+                    .iter()
+                    .position(|l| l.by_entities.contains_key(entity_id))?;
+                (prio, entity_id)
+            }
+            None => {
+                let prio = self.peek_level()?;
+                let level = &self.queues[prio];
+                let entity_id = match &self.drr {
+                    _ if self.fifo => level.fifo_next()?,
+                    Some(drr) => level.rr.get(level.drr_next(drr)?.0)?,
+                    None => level.rr.front()?,
+                };
+                (prio, entity_id)
             }
+        };
+        self.queues[prio]
+            .by_entities
+            .get(entity_id)?
+            .front()
+            .map(|entry| &entry.item)
+    }
+
+    // fn try_dequeue_tagged
+    //
+    // Same as try_dequeue, but also tells where the item came from:
+    // (priority level, entity, item).
+    pub fn try_dequeue_tagged(&mut self) -> Option<(usize, E, T)> {
+        self.pop_next(|prio, entity_id, entry| (prio, entity_id.clone(), entry.item))
+    }
+
+    // fn try_dequeue_at
+    //
+    // Takes the next item of level `prio` only, following its round-robin;
+    // the other levels and the level quotas are ignored.
+    pub fn try_dequeue_at(&mut self, prio: usize) -> Result<Option<(E, T)>> {
+        if prio >= self.queues.len() {
+            return Err(PriorityQueueError::BadPriority(prio));
         }
-        None
+        Ok(self.pop_at(prio, |_, entity_id, entry| (entity_id.clone(), entry.item)))
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    // fn try_dequeue_at_seq
+    //
+    // Same as try_dequeue_at, with the item's sequence number in front:
+    // (seq, entity, item).
+    pub fn try_dequeue_at_seq(&mut self, prio: usize) -> Result<Option<(u64, E, T)>> {
+        if prio >= self.queues.len() {
+            return Err(PriorityQueueError::BadPriority(prio));
+        }
+        Ok(self.pop_at(prio, |_, entity_id, Entry { seq, item }| {
+            (seq, entity_id.clone(), item)
+        }))
+    }
 
-    #[test]
-    fn it_works() {
-        let mut pq = PriorityQueue::new(3);
+    // fn drain
+    //
+    // Takes every item, in the order successive try_dequeue calls would
+    // return them: priorities, quotas and round-robins all apply.
+    pub fn drain(&mut self) -> Vec<T> {
+        let mut items = Vec::with_capacity(self.len());
+        while let Some(item) = self.try_dequeue() {
+            items.push(item);
+        }
+        items
+    }
 
-        assert!(pq.try_dequeue().is_none());
+    // fn try_dequeue_priority
+    //
+    // Same as try_dequeue_at, without the entity: the next item of level
+    // `prio` in its round-robin order, or None if the level is empty.
+    pub fn try_dequeue_priority(&mut self, prio: usize) -> Result<Option<T>> {
+        if prio >= self.queues.len() {
+            return Err(PriorityQueueError::BadPriority(prio));
+        }
+        Ok(self.pop_at(prio, |_, _, entry| entry.item))
+    }
 
-        let a = "A".to_string();
-        let b = "B".to_string();
-        let c = "C".to_string();
+    // fn try_dequeue_seq
+    //
+    // Same as try_dequeue_tagged, with the item's sequence number in front:
+    // (seq, priority level, entity, item).
+    pub fn try_dequeue_seq(&mut self) -> Option<(u64, usize, E, T)> {
+        self.pop_next(|prio, entity_id, Entry { seq, item }| (seq, prio, entity_id.clone(), item))
+    }
 
-        for i in 1..=4 {
-            let res = pq.enqueue(1, a.clone(), format!("{}{}", a, i));
-            assert!(res.is_ok());
+    // fn try_dequeue_with
+    //
+    // Same as try_dequeue_seq, but hands (seq, priority level, entity, item)
+    // to `f` instead of returning them: the entity is only borrowed, so it
+    // is never cloned.
+    pub fn try_dequeue_with<R>(&mut self, f: impl FnOnce(u64, usize, &E, T) -> R) -> Option<R> {
+        self.pop_next(|prio, entity_id, Entry { seq, item }| f(seq, prio, entity_id, item))
+    }
+
+    // fn iter
+    //
+    // Every queued item as (priority level, entity, item), without taking
+    // anything: level by level from the most urgent, entities in round-robin
+    // order, then each entity's items oldest first. This is not dequeue order
+    // (an entity's items are not interleaved with the others').
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &E, &T)> + '_ {
+        self.queues.iter().enumerate().flat_map(|(prio, level)| {
+            level.rr.iter().flat_map(move |entity_id| {
+                level
+                    .by_entities
+                    .get(entity_id)
+                    .into_iter()
+                    .flatten()
+                    .map(move |entry| (prio, entity_id, &entry.item))
+            })
+        })
+    }
+
+    // fn seqs
+    //
+    // Sequence numbers of all the queued items, in no particular order.
+    pub fn seqs(&self) -> impl Iterator<Item = u64> + '_ {
+        self.queues
+            .iter()
+            .flat_map(|level| level.by_entities.values())
+            .flat_map(|items| items.iter().map(|entry| entry.seq))
+    }
+
+    // fn pop_next
+    //
+    // Takes the next item and hands it to `f` along with its origin; the
+    // entity is only borrowed, so callers that don't need it never clone it.
+    fn pop_next<R>(&mut self, f: impl FnOnce(usize, &E, Entry<T>) -> R) -> Option<R> {
+        if self.global.is_some() {
+            return self.pop_global(f);
         }
+        let prio = self.next_level()?;
+        self.pop_at(prio, f)
+    }
 
-        for i in 1..=2 {
-            let res = pq.enqueue(1, b.clone(), format!("{}{}", b, i));
-            assert!(res.is_ok());
+    // fn pop_at
+    //
+    // pop_next at a given level, whatever the policy: the entity in front of
+    // the level's round-robin deque gives its oldest item.
+    fn pop_at<R>(&mut self, prio: usize, f: impl FnOnce(usize, &E, Entry<T>) -> R) -> Option<R> {
+        let level = &mut self.queues[prio];
+        let global = &mut self.global;
+        let counts = &mut self.counts;
+        let ret = level.pop_turn(
+            self.drr.as_ref(),
+            self.fifo,
+            &mut self.pool,
+            |entity_id, entry| {
+                if let Some(global) = global {
+                    global.untrack(entity_id, 1);
+                }
+                if let Some(counts) = counts {
+                    counts.sub(entity_id, 1);
+                }
+                f(prio, entity_id, entry)
+            },
+        )?;
+        self.occupancy.update(prio, level.len);
+        Some(ret)
+    }
+
+    // fn pop_global
+    //
+    // pop_next under GlobalEntityFair: the entity in front of the global ring
+    // gives the oldest item of its most urgent level. An entity running out of
+    // items at a level leaves that level's ring, in O(active entities there).
+    fn pop_global<R>(&mut self, f: impl FnOnce(usize, &E, Entry<T>) -> R) -> Option<R> {
+        let global = self.global.as_mut()?;
+        let entity_id = global.rr.pop_front()?;
+        let prio = self
+            .queues
+            .iter()
+            .position(|l| l.by_entities.contains_key(&entity_id))?;
+        let level = &mut self.queues[prio];
+        let items = level.by_entities.get_mut(&entity_id)?;
+        let entry = items.pop_front()?;
+        level.len -= 1;
+        self.occupancy.update(prio, level.len);
+        let ret = f(prio, &entity_id, entry);
+        if items.is_empty() {
+            Self::recycle(&mut self.pool, level.by_entities.remove(&entity_id));
+            level.actives.remove(&entity_id);
+            level.rr.retain(|e| *e != entity_id);
+            level.credits.remove(&entity_id);
+            level.deficits.remove(&entity_id);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.sub(&entity_id, 1);
         }
+        let pending = global.pending.get_mut(&entity_id)?;
+        *pending -= 1;
+        if *pending > 0 {
+            global.rr.push_back(entity_id);
+        } else {
+            global.pending.remove(&entity_id);
+        }
+        Some(ret)
+    }
 
-        let res = pq.enqueue(0, c.clone(), format!("{}1", c));
-        assert!(res.is_ok());
+    // fn next_level
+    //
+    // The level to serve next (see peek_level), counting the turn: the more
+    // urgent levels passed over have yielded and start afresh, and the
+    // chosen one uses up one dequeue of its quota, or adds `decay` to its
+    // penalty under DecayingPriority.
+    fn next_level(&mut self) -> Option<usize> {
+        let next = self.peek_level();
+        let decay = self.decay.filter(|&decay| decay > 0);
+        // empty levels passed over start afresh too
+        let end = match decay {
+            Some(_) => self.queues.len(),
+            None => next.unwrap_or(self.queues.len()),
+        };
+        self.reset_emptied(end);
+        let prio = next?;
+        let mut passed = self.occupancy.next_from(0);
+        while let Some(skipped) = passed.filter(|&skipped| skipped < prio) {
+            let level = &mut self.queues[skipped];
+            level.served = 0;
+            level.penalty = 0;
+            passed = self.occupancy.next_from(skipped + 1);
+        }
+        if decay.is_none() && self.aging.is_some() {
+            let mut waiting = self.occupancy.next_from(prio + 1);
+            while let Some(lower) = waiting {
+                let level = &mut self.queues[lower];
+                level.skipped = level.skipped.saturating_add(1);
+                waiting = self.occupancy.next_from(lower + 1);
+            }
+        }
+        let level = &mut self.queues[prio];
+        level.skipped = 0;
+        match decay {
+            Some(decay) => level.penalty = level.penalty.saturating_add(decay.into()),
+            None => level.served = level.served.saturating_add(1),
+        }
+        Some(prio)
+    }
 
-        // 1. expected C1
-        let ret = pq.try_dequeue();
-        assert!(ret.is_some() && ret.unwrap() == "C1");
+    // fn peek_level
+    //
+    // The most urgent non-empty level, unless it has used up its quota of
+    // consecutive dequeues while a lower level is waiting: it then yields
+    // one turn. With aging, a level that waited long enough comes first.
+    // Nothing is updated, see next_level.
+    fn peek_level(&self) -> Option<usize> {
+        if self.decay.is_some_and(|decay| decay > 0) {
+            return self.peek_level_decaying();
+        }
+        if let Some(threshold) = self.aging {
+            let mut next = self.occupancy.next_from(0);
+            while let Some(prio) = next {
+                if self.queues[prio].skipped >= threshold {
+                    return Some(prio);
+                }
+                next = self.occupancy.next_from(prio + 1);
+            }
+        }
+        let mut next = self.occupancy.next_from(0);
+        while let Some(prio) = next {
+            let level = &self.queues[prio];
+            let lower = self.occupancy.next_from(prio + 1);
+            if level.served < level.quota || lower.is_none() {
+                break;
+            }
+            next = lower;
+        }
+        next
+    }
 
-        // 2. expected A1, B1, A2, B2, A3, A4
-        let ret = pq.try_dequeue();
-        assert!(ret.is_some() && ret.unwrap() == "A1");
-        let ret = pq.try_dequeue();
-        assert!(ret.is_some() && ret.unwrap() == "B1");
-        let ret = pq.try_dequeue();
-        assert!(ret.is_some() && ret.unwrap() == "A2");
-        let ret = pq.try_dequeue();
-        assert!(ret.is_some() && ret.unwrap() == "B2");
-        let ret = pq.try_dequeue();
-        assert!(ret.is_some() && ret.unwrap() == "A3");
-        let ret = pq.try_dequeue();
-        assert!(ret.is_some() && ret.unwrap() == "A4");
+    // fn reset_emptied
+    //
+    // Clears the turn counters (quota, penalty and skips) of the levels
+    // below `end` that ran empty since they were last passed over.
+    fn reset_emptied(&mut self, end: usize) {
+        for prio in self.occupancy.take_emptied(end) {
+            let level = &mut self.queues[prio];
+            level.served = 0;
+            level.penalty = 0;
+            level.skipped = 0;
+        }
+    }
 
-        // pq must be empty
+    // fn peek_level_decaying
+    //
+    // peek_level under DecayingPriority: the non-empty level with the lowest
+    // index plus penalty, the most urgent one on ties. Levels that ran empty
+    // are not candidates, so their stale penalties do not matter here.
+    fn peek_level_decaying(&self) -> Option<usize> {
+        // ranks are in thousandths, like penalties
+        let base = |prio: usize| (prio as u64).saturating_mul(1000);
+        let mut best: Option<(usize, u64)> = None;
+        let mut next = self.occupancy.next_from(0);
+        while let Some(prio) = next {
+            // penalties are never negative: no later level can rank better
+            if best.is_some_and(|(_, best_rank)| base(prio) >= best_rank) {
+                break;
+            }
+            let rank = base(prio).saturating_add(self.queues[prio].penalty);
+            if best.is_none_or(|(_, best_rank)| rank < best_rank) {
+                best = Some((prio, rank));
+            }
+            next = self.occupancy.next_from(prio + 1);
+        }
+        best.map(|(prio, _)| prio)
+    }
 
+    // fn dequeue_one_per_entity
+    //
+    // One full turn of the round-robin at level `prio`: every entity active at
+    // the start of the call gives exactly one item, in ring order.
+    pub fn dequeue_one_per_entity(&mut self, prio: usize) -> Result<Vec<(E, T)>> {
+        self.take_one_per_entity(prio, |_, entity_id, item| (entity_id, item))
+    }
+
+    // fn dequeue_one_per_entity_seq
+    //
+    // Same as dequeue_one_per_entity, with each item's sequence number in
+    // front: (seq, entity, item).
+    pub fn dequeue_one_per_entity_seq(&mut self, prio: usize) -> Result<Vec<(u64, E, T)>> {
+        self.take_one_per_entity(prio, |seq, entity_id, item| (seq, entity_id, item))
+    }
+
+    // fn take_one_per_entity
+    //
+    // dequeue_one_per_entity, handing each (seq, entity, item) to `f`.
+    fn take_one_per_entity<R>(
+        &mut self,
+        prio: usize,
+        mut f: impl FnMut(u64, E, T) -> R,
+    ) -> Result<Vec<R>> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        let mut batch = Vec::with_capacity(level.rr.len());
+        for _ in 0..level.rr.len() {
+            let Some(entity_id) = level.rr.pop_front() else {
+                break;
+            };
+            level.credits.remove(&entity_id);
+            level.deficits.remove(&entity_id);
+            if let Some(items) = level.by_entities.get_mut(&entity_id)
+                && let Some(Entry { seq, item }) = items.pop_front()
+            {
+                if !items.is_empty() {
+                    level.rr.push_back(entity_id.clone());
+                } else {
+                    Self::recycle(&mut self.pool, level.by_entities.remove(&entity_id));
+                    level.actives.remove(&entity_id);
+                }
+                if let Some(global) = &mut self.global {
+                    global.untrack(&entity_id, 1);
+                }
+                if let Some(counts) = &mut self.counts {
+                    counts.sub(&entity_id, 1);
+                }
+                batch.push(f(seq, entity_id, item));
+            }
+        }
+        level.len -= batch.len();
+        self.occupancy.update(prio, level.len);
+        Ok(batch)
+    }
+
+    // fn heads_at
+    //
+    // The front item of every active entity of level `prio`, in round-robin
+    // order, i.e. what each entity would give on its next turn.
+    pub fn heads_at(&self, prio: usize) -> Result<Vec<(E, &T)>> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level
+            .rr
+            .iter()
+            .filter_map(|entity_id| {
+                let entry = level.by_entities.get(entity_id)?.front()?;
+                Some((entity_id.clone(), &entry.item))
+            })
+            .collect())
+    }
+
+    // fn try_dequeue_entity
+    //
+    // try_dequeue_entity_at at the most urgent level where `entity_id` has
+    // items; None if it has none anywhere.
+    pub fn try_dequeue_entity(&mut self, entity_id: &E) -> Option<T> {
+        let prio = self
+            .queues
+            .iter()
+            .position(|level| level.actives.contains(entity_id))?;
+        self.try_dequeue_entity_at(prio, entity_id).ok().flatten()
+    }
+
+    // fn try_dequeue_entity_at
+    //
+    // Takes the front item of `entity_id` at level `prio`, out of round-robin
+    // order. This counts as the entity's turn: if it has items left, it moves
+    // to the back of the ring.
+    pub fn try_dequeue_entity_at(&mut self, prio: usize, entity_id: &E) -> Result<Option<T>> {
+        self.try_dequeue_entity_at_seq(prio, entity_id)
+            .map(|entry| entry.map(|(_, item)| item))
+    }
+
+    // fn try_dequeue_entity_at_seq
+    //
+    // Same as try_dequeue_entity_at, with the item's sequence number in
+    // front: (seq, item).
+    pub fn try_dequeue_entity_at_seq(
+        &mut self,
+        prio: usize,
+        entity_id: &E,
+    ) -> Result<Option<(u64, T)>> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        let Some(items) = level.by_entities.get_mut(entity_id) else {
+            return Ok(None);
+        };
+        let Some(Entry { seq, item }) = items.pop_front() else {
+            return Ok(None);
+        };
+        level.len -= 1;
+        self.occupancy.update(prio, level.len);
+        let exhausted = items.is_empty();
+        level.rr.retain(|e| e != entity_id);
+        level.credits.remove(entity_id);
+        level.deficits.remove(entity_id);
+        if exhausted {
+            Self::recycle(&mut self.pool, level.by_entities.remove(entity_id));
+            level.actives.remove(entity_id);
+        } else {
+            level.rr.push_back(entity_id.clone());
+        }
+        if let Some(global) = &mut self.global {
+            global.untrack(entity_id, 1);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.sub(entity_id, 1);
+        }
+        Ok(Some((seq, item)))
+    }
+
+    // fn reassign_head
+    //
+    // Moves the front item of `from` at level `prio` to the back of `to`'s
+    // deque at the same level, as if `to` had just enqueued it (but with its
+    // original sequence number). Unlike a dequeue, this is not `from`'s turn:
+    // it keeps its place in the ring. Returns whether an item was moved.
+    pub fn reassign_head(&mut self, prio: usize, from: &E, to: E) -> Result<bool> {
+        self.reassign_head_seq(prio, from, to)
+            .map(|seq| seq.is_some())
+    }
+
+    // fn reassign_head_seq
+    //
+    // Same as reassign_head, but returns the sequence number of the moved
+    // item, if any.
+    pub fn reassign_head_seq(&mut self, prio: usize, from: &E, to: E) -> Result<Option<u64>> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        let Some(items) = level.by_entities.get_mut(from) else {
+            return Ok(None);
+        };
+        let Some(entry) = items.pop_front() else {
+            return Ok(None);
+        };
+        let seq = entry.seq;
+        level.len -= 1;
+        self.occupancy.update(prio, level.len);
+        if items.is_empty() {
+            Self::recycle(&mut self.pool, level.by_entities.remove(from));
+            level.actives.remove(from);
+            level.rr.retain(|e| e != from);
+            level.credits.remove(from);
+            level.deficits.remove(from);
+        }
+        if let Some(global) = &mut self.global {
+            global.untrack(from, 1);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.sub(from, 1);
+        }
+        self.push_entry(prio, to, entry);
+        Ok(Some(seq))
+    }
+
+    // fn entity_len_at
+    //
+    // Number of items of `entity_id` at level `prio` (0 if it has none).
+    pub fn entity_len_at(&self, prio: usize, entity_id: &E) -> Result<usize> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level
+            .by_entities
+            .get(entity_id)
+            .map_or(0, |items| items.len()))
+    }
+
+    // fn entity_len
+    //
+    // Number of items of `entity_id` across all levels (0 if it has none).
+    // O(1) with entity counts, O(levels) otherwise.
+    pub fn entity_len(&self, entity_id: &E) -> usize {
+        if let Some(counts) = &self.counts {
+            return counts.pending.get(entity_id).copied().unwrap_or(0);
+        }
+        self.queues
+            .iter()
+            .filter_map(|level| level.by_entities.get(entity_id))
+            .map(|items| items.len())
+            .sum()
+    }
+
+    // fn contains_entity
+    //
+    // Whether `entity_id` has pending items at any level; stops at the first
+    // level where it does, or asks the entity counts.
+    pub fn contains_entity(&self, entity_id: &E) -> bool {
+        if let Some(counts) = &self.counts {
+            return counts.pending.contains_key(entity_id);
+        }
+        self.queues
+            .iter()
+            .any(|level| level.actives.contains(entity_id))
+    }
+
+    // fn set_entity_counts
+    //
+    // Turns on or off a count of the pending items of each entity across
+    // levels, kept up to date by every operation: entity_len,
+    // contains_entity and n_entities then answer in O(1), and entity_lens
+    // lists them, for a hash map update per enqueue and dequeue.
+    pub fn set_entity_counts(&mut self, enabled: bool) {
+        if !enabled {
+            self.counts = None;
+            return;
+        }
+        if self.counts.is_some() {
+            return;
+        }
+        let mut counts = EntityCounts {
+            pending: HashMap::new(),
+        };
+        for level in &self.queues {
+            for (entity_id, items) in &level.by_entities {
+                counts.add(entity_id, items.len());
+            }
+        }
+        self.counts = Some(counts);
+    }
+
+    // fn n_entities
+    //
+    // Number of entities with pending items at any level: O(1) with entity
+    // counts, O(active entities) otherwise.
+    pub fn n_entities(&self) -> usize {
+        match &self.counts {
+            Some(counts) => counts.pending.len(),
+            None => self.active_entities().len(),
+        }
+    }
+
+    // fn entity_lens
+    //
+    // Each entity with pending items, with its number of items across
+    // levels, in no particular order; None without entity counts.
+    pub fn entity_lens(&self) -> Option<impl Iterator<Item = (&E, usize)> + '_> {
+        let counts = self.counts.as_ref()?;
+        Some(counts.pending.iter().map(|(entity_id, &n)| (entity_id, n)))
+    }
+
+    // fn set_weight
+    //
+    // Gives `entity_id` `weight` consecutive turns at level `prio` each time
+    // round-robin reaches it, instead of one (0 counts as 1). The weight
+    // outlives the entity's items: it applies whenever the entity is active
+    // at that level. A burst already started keeps the length it started
+    // with; the new weight applies from the entity's next burst.
+    // Under GlobalEntityFair, weights are ignored.
+    pub fn set_weight(&mut self, prio: usize, entity_id: E, weight: u32) -> Result<()> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        if weight > 1 {
+            level.weights.insert(entity_id, weight);
+        } else {
+            level.weights.remove(&entity_id);
+        }
+        Ok(())
+    }
+
+    // fn enqueue_weighted
+    //
+    // Enqueues `item` and sets the weight of `entity_id` at level `prio` in
+    // one call (see set_weight): the entity gets `weight` consecutive turns
+    // per rotation. Entities never given a weight keep the default of 1.
+    // If the enqueue fails, the weight is left unchanged.
+    pub fn enqueue_weighted(
+        &mut self,
+        prio: usize,
+        entity_id: E,
+        item: T,
+        weight: u32,
+    ) -> Result<()> {
+        self.enqueue(prio, entity_id.clone(), item)?;
+        self.set_weight(prio, entity_id, weight)
+    }
+
+    // fn weight
+    //
+    // The weight of `entity_id` at level `prio`, or None if it has the
+    // default weight of 1.
+    pub fn weight(&self, prio: usize, entity_id: &E) -> Result<Option<u32>> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level.weights.get(entity_id).copied())
+    }
+
+    // fn set_quantum
+    //
+    // Gives `entity_id` its own quantum at level `prio` under deficit round
+    // robin (0 counts as 1); like a weight, it outlives the entity's items.
+    // The deficit in hand is kept: the new quantum applies from the next
+    // top-up. Without deficit round robin, the quantum is stored but unused.
+    pub fn set_quantum(&mut self, prio: usize, entity_id: E, quantum: u64) -> Result<()> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        level.quanta.insert(entity_id, quantum.max(1));
+        Ok(())
+    }
+
+    // fn entities_at
+    //
+    // Active entities of level `prio`, in round-robin order.
+    pub fn entities_at(&self, prio: usize) -> Result<Vec<E>> {
+        let level = self
+            .queues
+            .get(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        Ok(level.rr.iter().cloned().collect())
+    }
+
+    // fn active_entities
+    //
+    // Entities with pending items at any level, each listed once: level by
+    // level from the most urgent, in round-robin order within a level.
+    pub fn active_entities(&self) -> Vec<E> {
+        let mut seen = HashSet::new();
+        self.queues
+            .iter()
+            .flat_map(|level| level.rr.iter())
+            .filter(|entity_id| seen.insert(*entity_id))
+            .cloned()
+            .collect()
+    }
+
+    // fn remove_entity_at
+    //
+    // Drops every item of `entity_id` at level `prio` only; returns how many.
+    pub fn remove_entity_at(&mut self, prio: usize, entity_id: &E) -> Result<usize> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        let Some(items) = level.by_entities.remove(entity_id) else {
+            return Ok(0);
+        };
+        level.actives.remove(entity_id);
+        level.rr.retain(|e| e != entity_id);
+        level.credits.remove(entity_id);
+        level.deficits.remove(entity_id);
+        let removed = items.len();
+        level.len -= removed;
+        self.occupancy.update(prio, level.len);
+        Self::recycle(&mut self.pool, Some(items));
+        if let Some(global) = &mut self.global {
+            global.untrack(entity_id, removed);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.sub(entity_id, removed);
+        }
+        Ok(removed)
+    }
+
+    // fn remove_entity
+    //
+    // Drops every item of `entity_id`, at all levels; returns how many. The
+    // other entities keep their round-robin order.
+    pub fn remove_entity(&mut self, entity_id: &E) -> usize {
+        (0..self.queues.len())
+            .map(|prio| self.remove_entity_at(prio, entity_id).unwrap_or(0))
+            .sum()
+    }
+
+    // fn reprioritize
+    //
+    // Moves every item of `entity_id` from level `from` to level `to`;
+    // returns how many. The entity joins the back of the round-robin at `to`
+    // if it was not active there; if it was, both lines are merged in
+    // enqueue order. Fails with Full, moving nothing, if the items do not fit
+    // under the cap of `to`. Moving to the same level is a no-op.
+    pub fn reprioritize(&mut self, entity_id: &E, from: usize, to: usize) -> Result<usize> {
+        for prio in [from, to] {
+            if prio >= self.queues.len() {
+                return Err(PriorityQueueError::BadPriority(prio));
+            }
+        }
+        if from == to {
+            return Ok(0);
+        }
+        let moved = self.entity_len_at(from, entity_id)?;
+        if moved == 0 {
+            return Ok(0);
+        }
+        let target = &self.queues[to];
+        if moved > target.cap.saturating_sub(target.len) {
+            return Err(PriorityQueueError::Full);
+        }
+
+        let level = &mut self.queues[from];
+        let Some(mut items) = level.by_entities.remove(entity_id) else {
+            return Ok(0);
+        };
+        level.actives.remove(entity_id);
+        level.rr.retain(|e| e != entity_id);
+        level.credits.remove(entity_id);
+        level.deficits.remove(entity_id);
+        level.len -= moved;
+        self.occupancy.update(from, level.len);
+
+        let level = &mut self.queues[to];
+        match level.by_entities.get_mut(entity_id) {
+            Some(line) => {
+                line.append(&mut items);
+                line.make_contiguous().sort_by_key(|entry| entry.seq);
+                Self::recycle(&mut self.pool, Some(items));
+            }
+            None => {
+                level.actives.insert(entity_id.clone());
+                level.rr.push_back(entity_id.clone());
+                level.by_entities.insert(entity_id.clone(), items);
+            }
+        }
+        level.len += moved;
+        if self.fifo {
+            level.fifo_rebuild();
+        }
+        self.occupancy.update(to, level.len);
+        Ok(moved)
+    }
+
+    // fn remove_lowest_of_entity
+    //
+    // Removes, among the items of `entity_id` at the levels in `levels`, the
+    // one with the lowest `key`, provided it is strictly below `below`. Ties go
+    // to the least urgent level, then to the most recently enqueued item.
+    // Returns the item with its level.
+    pub fn remove_lowest_of_entity<K: Ord>(
+        &mut self,
+        levels: Range<usize>,
+        entity_id: &E,
+        below: K,
+        key: impl Fn(&T) -> K,
+    ) -> Option<(usize, T)> {
+        self.remove_lowest_of_entity_seq(levels, entity_id, below, key)
+            .map(|(prio, _, item)| (prio, item))
+    }
+
+    // fn remove_lowest_of_entity_seq
+    //
+    // Same as remove_lowest_of_entity, but also returns the item's sequence
+    // number: (priority level, seq, item).
+    pub fn remove_lowest_of_entity_seq<K: Ord>(
+        &mut self,
+        levels: Range<usize>,
+        entity_id: &E,
+        below: K,
+        key: impl Fn(&T) -> K,
+    ) -> Option<(usize, u64, T)> {
+        let key = &key;
+        let levels = levels.start..levels.end.min(self.queues.len());
+        let (_, Reverse(prio), Reverse(idx)) = levels
+            .flat_map(|prio| {
+                let items = self.queues[prio].by_entities.get(entity_id);
+                items
+                    .into_iter()
+                    .flatten()
+                    .enumerate()
+                    .map(move |(idx, entry)| (key(&entry.item), Reverse(prio), Reverse(idx)))
+            })
+            .filter(|(k, _, _)| *k < below)
+            .min()?;
+
+        let level = &mut self.queues[prio];
+        let items = level.by_entities.get_mut(entity_id)?;
+        let Entry { seq, item } = items.remove(idx)?;
+        level.len -= 1;
+        self.occupancy.update(prio, level.len);
+        if items.is_empty() {
+            Self::recycle(&mut self.pool, level.by_entities.remove(entity_id));
+            level.actives.remove(entity_id);
+            level.rr.retain(|e| e != entity_id);
+            level.credits.remove(entity_id);
+            level.deficits.remove(entity_id);
+        }
+        if let Some(global) = &mut self.global {
+            global.untrack(entity_id, 1);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.sub(entity_id, 1);
+        }
+        Some((prio, seq, item))
+    }
+
+    // fn remove_seq
+    //
+    // Removes the item with sequence number `seq`, provided it is one of
+    // `entity_id`'s at level `prio`; None if there is no such item. Costs a
+    // scan of that entity's items at that level only, whatever the queue size.
+    pub fn remove_seq(&mut self, prio: usize, entity_id: &E, seq: u64) -> Result<Option<T>> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        let Some(items) = level.by_entities.get_mut(entity_id) else {
+            return Ok(None);
+        };
+        let Some(idx) = items.iter().position(|entry| entry.seq == seq) else {
+            return Ok(None);
+        };
+        let Some(Entry { item, .. }) = items.remove(idx) else {
+            return Ok(None);
+        };
+        level.len -= 1;
+        self.occupancy.update(prio, level.len);
+        if items.is_empty() {
+            Self::recycle(&mut self.pool, level.by_entities.remove(entity_id));
+            level.actives.remove(entity_id);
+            level.rr.retain(|e| e != entity_id);
+            level.credits.remove(entity_id);
+            level.deficits.remove(entity_id);
+        }
+        if let Some(global) = &mut self.global {
+            global.untrack(entity_id, 1);
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.sub(entity_id, 1);
+        }
+        Ok(Some(item))
+    }
+
+    // fn rebalance
+    //
+    // Explicit, occasional maintenance operation (not meant for the hot path):
+    // reorders the round-robin ring of level `prio` so that entities whose head
+    // item has been waiting the longest get the next turns.
+    pub fn rebalance(&mut self, prio: usize) -> Result<()> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        let by_entities = &level.by_entities;
+        level.rr.make_contiguous().sort_by_key(|entity_id| {
+            by_entities
+                .get(entity_id)
+                .and_then(|items| items.front())
+                .map_or(u64::MAX, |entry| entry.seq)
+        });
+        Ok(())
+    }
+
+    // fn reset
+    //
+    // Empties the queue back to its freshly built state, sequence numbers
+    // included, but keeps its configuration (policy, quotas, deque pool) and
+    // the capacity of its rings and maps: a pooled queue is reused without
+    // reallocating. The per-entity deques go to the deque pool, if enabled.
+    pub fn reset(&mut self) {
+        for level in &mut self.queues {
+            for (_, items) in level.by_entities.drain() {
+                Self::recycle(&mut self.pool, Some(items));
+            }
+            level.rr.clear();
+            level.actives.clear();
+            level.credits.clear();
+            level.deficits.clear();
+            level.arrivals.clear();
+            level.len = 0;
+            level.served = 0;
+            level.penalty = 0;
+            level.skipped = 0;
+        }
+        self.occupancy.clear();
+        if let Some(global) = &mut self.global {
+            global.rr.clear();
+            global.pending.clear();
+        }
+        if let Some(counts) = &mut self.counts {
+            counts.pending.clear();
+        }
+        self.next_seq = 0;
+    }
+
+    // fn clear
+    //
+    // Same as reset, under the name collections use: the queue is emptied
+    // for the next batch, its levels and allocations kept.
+    pub fn clear(&mut self) {
+        self.reset();
+    }
+
+    // fn validate_rr
+    //
+    // Checks the invariant the round-robin relies on, at every level: each
+    // entity with pending items appears exactly once in `rr`, and `rr`,
+    // `actives` and `by_entities` agree on who those entities are. A
+    // duplicate would give an entity extra turns, and a stale entry would
+    // point at no items. Returns the first violation found. O(total entities).
+    pub fn validate_rr(&self) -> std::result::Result<(), String> {
+        for (prio, level) in self.queues.iter().enumerate() {
+            let mut seen = HashSet::with_capacity(level.rr.len());
+            for entity_id in &level.rr {
+                if !seen.insert(entity_id) {
+                    return Err(format!("level {prio}: duplicate entity in rr"));
+                }
+                if !level.actives.contains(entity_id) {
+                    return Err(format!("level {prio}: entity in rr but not active"));
+                }
+                if level
+                    .by_entities
+                    .get(entity_id)
+                    .is_none_or(|items| items.is_empty())
+                {
+                    return Err(format!("level {prio}: entity in rr has no items"));
+                }
+            }
+            if seen.len() != level.actives.len() {
+                return Err(format!(
+                    "level {prio}: {} entities in rr, {} active",
+                    seen.len(),
+                    level.actives.len()
+                ));
+            }
+            if level.by_entities.len() != level.actives.len() {
+                return Err(format!(
+                    "level {prio}: {} entities with a deque, {} active",
+                    level.by_entities.len(),
+                    level.actives.len()
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    // fn rr_mut
+    //
+    // Test-only access to the ring of level `prio`, to corrupt it on purpose.
+    #[cfg(test)]
+    fn rr_mut(&mut self, prio: usize) -> &mut VecDeque<E> {
+        &mut self.queues[prio].rr
+    }
+
+    // fn recycle
+    //
+    // Hands a deque that left `by_entities` back to the pool, if pooling is on.
+    // It is always cleared first: a reused deque must never leak stale items.
+    fn recycle(pool: &mut Option<Vec<Deque<Entry<T>, A>>>, items: Option<Deque<Entry<T>, A>>) {
+        if let (Some(pool), Some(mut items)) = (pool, items) {
+            items.clear();
+            pool.push(items);
+        }
+    }
+}
+
+impl<E, T, A> PriorityQueue<E, T, A>
+where
+    E: Eq + Hash + Clone + Ord,
+    A: Allocator + Clone,
+{
+    // fn new_round
+    //
+    // Starts a new round at level `prio`: the round-robin ring is put back in
+    // canonical (ascending entity) order, whatever rotation the previous round
+    // left it in. Only the ring is reordered; each entity keeps its items, in
+    // their order.
+    pub fn new_round(&mut self, prio: usize) -> Result<()> {
+        let level = self
+            .queues
+            .get_mut(prio)
+            .ok_or(PriorityQueueError::BadPriority(prio))?;
+        level.rr.make_contiguous().sort_unstable();
+        Ok(())
+    }
+}
+
+// Queue
+//
+// The generic interface, for code that can also run on a SyncPriorityQueue.
+impl<E, T, A> Queue<E, T> for PriorityQueue<E, T, A>
+where
+    E: Eq + Hash + Clone,
+    A: Allocator + Clone,
+{
+    fn enqueue(&mut self, prio: usize, entity_id: E, item: T) -> Result<()> {
+        PriorityQueue::enqueue(self, prio, entity_id, item)
+    }
+
+    fn try_dequeue(&mut self) -> Result<Option<T>> {
+        Ok(PriorityQueue::try_dequeue(self))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(PriorityQueue::len(self))
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Ok(PriorityQueue::is_empty(self))
+    }
+}
+
+// Debug
+//
+// Item counts per entity, level by level, in round-robin order; the items
+// themselves are not printed, so T needs no Debug. Busy levels only list
+// their first DEBUG_ENTITIES entities, to keep the output bounded.
+const DEBUG_ENTITIES: usize = 16;
+
+impl<E, T, A> fmt::Debug for PriorityQueue<E, T, A>
+where
+    E: Eq + Hash + Clone + fmt::Debug,
+    A: Allocator,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let levels: Vec<LevelCounts<'_, E, T, A>> = self.queues.iter().map(LevelCounts).collect();
+        f.debug_struct("PriorityQueue")
+            .field("len", &self.queues.iter().map(|l| l.len).sum::<usize>())
+            .field("levels", &levels)
+            .finish_non_exhaustive()
+    }
+}
+
+struct LevelCounts<'a, E, T, A>(&'a PriorityLevel<E, T, A>)
+where
+    E: Eq + Hash + Clone,
+    A: Allocator;
+
+impl<E, T, A> fmt::Debug for LevelCounts<'_, E, T, A>
+where
+    E: Eq + Hash + Clone + fmt::Debug,
+    A: Allocator,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let level = self.0;
+        let mut map = f.debug_map();
+        for entity_id in level.rr.iter().take(DEBUG_ENTITIES) {
+            let n = level
+                .by_entities
+                .get(entity_id)
+                .map_or(0, |items| items.len());
+            map.entry(entity_id, &n);
+        }
+        if level.rr.len() > DEBUG_ENTITIES {
+            map.finish_non_exhaustive()
+        } else {
+            map.finish()
+        }
+    }
+}
+
+impl<E> EntityCounts<E>
+where
+    E: Eq + Hash + Clone,
+{
+    fn add(&mut self, entity_id: &E, n: usize) {
+        match self.pending.get_mut(entity_id) {
+            Some(pending) => *pending += n,
+            None => {
+                self.pending.insert(entity_id.clone(), n);
+            }
+        }
+    }
+
+    // entities left without items are forgotten, so that `pending` only
+    // holds the active ones
+    fn sub(&mut self, entity_id: &E, n: usize) {
+        if let Some(pending) = self.pending.get_mut(entity_id) {
+            *pending -= n;
+            if *pending == 0 {
+                self.pending.remove(entity_id);
+            }
+        }
+    }
+}
+
+impl<E> GlobalRing<E>
+where
+    E: Eq + Hash + Clone,
+{
+    fn new() -> Self {
+        Self {
+            rr: VecDeque::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    // `n` more pending items for `entity_id`; newcomers join the back of the ring.
+    fn track(&mut self, entity_id: &E, n: usize) {
+        let pending = self.pending.entry(entity_id.clone()).or_insert(0);
+        if *pending == 0 {
+            self.rr.push_back(entity_id.clone());
+        }
+        *pending += n;
+    }
+
+    // `n` items of `entity_id` left outside of its turn; it leaves the ring
+    // once it has none left.
+    fn untrack(&mut self, entity_id: &E, n: usize) {
+        let Some(pending) = self.pending.get_mut(entity_id) else {
+            return;
+        };
+        *pending -= n;
+        if *pending == 0 {
+            self.pending.remove(entity_id);
+            self.rr.retain(|e| e != entity_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use proptest::prelude::*;
+
+    use super::*;
+
+    #[test]
+    fn it_works() {
+        let mut pq = PriorityQueue::new(3);
+
+        assert!(pq.try_dequeue().is_none());
+
+        let a = "A".to_string();
+        let b = "B".to_string();
+        let c = "C".to_string();
+
+        for i in 1..=4 {
+            let res = pq.enqueue(1, a.clone(), format!("{}{}", a, i));
+            assert!(res.is_ok());
+        }
+
+        for i in 1..=2 {
+            let res = pq.enqueue(1, b.clone(), format!("{}{}", b, i));
+            assert!(res.is_ok());
+        }
+
+        let res = pq.enqueue(0, c.clone(), format!("{}1", c));
+        assert!(res.is_ok());
+
+        // 1. expected C1
+        let ret = pq.try_dequeue();
+        assert!(ret.is_some() && ret.unwrap() == "C1");
+
+        // 2. expected A1, B1, A2, B2, A3, A4
+        let ret = pq.try_dequeue();
+        assert!(ret.is_some() && ret.unwrap() == "A1");
+        let ret = pq.try_dequeue();
+        assert!(ret.is_some() && ret.unwrap() == "B1");
+        let ret = pq.try_dequeue();
+        assert!(ret.is_some() && ret.unwrap() == "A2");
+        let ret = pq.try_dequeue();
+        assert!(ret.is_some() && ret.unwrap() == "B2");
+        let ret = pq.try_dequeue();
+        assert!(ret.is_some() && ret.unwrap() == "A3");
+        let ret = pq.try_dequeue();
+        assert!(ret.is_some() && ret.unwrap() == "A4");
+
+        // pq must be empty
+
+        assert!(pq.try_dequeue().is_none());
+    }
+
+    #[test]
+    fn rebalance_advances_oldest_head() {
+        let mut pq = PriorityQueue::new(1);
+
+        pq.enqueue(0, "X", "X1").unwrap();
+        pq.enqueue(0, "X", "X2").unwrap();
+        pq.enqueue(0, "Y", "Y1").unwrap();
+        pq.enqueue(0, "Z", "Z1").unwrap();
+
+        // X is served and goes to the back of the ring, although X2 is older
+        // than both Y1 and Z1.
+        assert_eq!(pq.try_dequeue(), Some("X1"));
+
+        assert!(pq.rebalance(0).is_ok());
+        assert_eq!(pq.try_dequeue(), Some("X2"));
+        assert_eq!(pq.try_dequeue(), Some("Y1"));
+        assert_eq!(pq.try_dequeue(), Some("Z1"));
+
+        assert!(pq.rebalance(1).is_err());
+    }
+
+    #[test]
+    fn dequeue_one_per_entity_serves_everyone_once() {
+        let mut pq = PriorityQueue::new(2);
+
+        pq.enqueue(1, "A", "A1").unwrap();
+        pq.enqueue(1, "A", "A2").unwrap();
+        pq.enqueue(1, "B", "B1").unwrap();
+        pq.enqueue(1, "C", "C1").unwrap();
+        pq.enqueue(0, "D", "D1").unwrap();
+
+        let batch = pq.dequeue_one_per_entity(1).unwrap();
+        assert_eq!(batch, vec![("A", "A1"), ("B", "B1"), ("C", "C1")]);
+
+        let batch = pq.dequeue_one_per_entity(1).unwrap();
+        assert_eq!(batch, vec![("A", "A2")]);
+
+        assert!(pq.dequeue_one_per_entity(1).unwrap().is_empty());
+        assert!(pq.dequeue_one_per_entity(2).is_err());
+        assert_eq!(pq.try_dequeue(), Some("D1"));
+    }
+
+    #[test]
+    fn entities_are_scoped_per_level() {
+        let mut pq = PriorityQueue::new(2);
+
+        pq.enqueue(0, "A", "A1").unwrap();
+        pq.enqueue(0, "B", "B1").unwrap();
+        pq.enqueue(0, "A", "A2").unwrap();
+        pq.enqueue(1, "A", "A3").unwrap();
+
+        assert_eq!(pq.entities_at(0).unwrap(), vec!["A", "B"]);
+        assert_eq!(pq.entities_at(1).unwrap(), vec!["A"]);
+
+        assert_eq!(pq.remove_entity_at(0, &"A").unwrap(), 2);
+        assert_eq!(pq.remove_entity_at(0, &"A").unwrap(), 0);
+        assert_eq!(pq.entities_at(0).unwrap(), vec!["B"]);
+        assert!(pq.remove_entity_at(2, &"A").is_err());
+
+        assert_eq!(pq.try_dequeue(), Some("B1"));
+        assert_eq!(pq.try_dequeue(), Some("A3"));
+        assert_eq!(pq.try_dequeue(), None);
+    }
+
+    #[test]
+    fn level_quotas_interleave_levels() {
+        let mut pq = PriorityQueue::with_level_quotas(vec![2, usize::MAX]);
+        for i in 0..6 {
+            pq.enqueue(0, "A", i).unwrap();
+        }
+        for i in 0..3 {
+            pq.enqueue(1, "B", 10 + i).unwrap();
+        }
+
+        // Level 0 serves 2 items, then yields one turn to level 1.
+        let mut levels = Vec::new();
+        while let Some((prio, _, _)) = pq.try_dequeue_tagged() {
+            levels.push(prio);
+        }
+        assert_eq!(levels, vec![0, 0, 1, 0, 0, 1, 0, 0, 1]);
+
+        // Default quotas are strict priority.
+        let mut pq = PriorityQueue::new(2);
+        pq.enqueue(1, "B", 10).unwrap();
+        pq.enqueue(0, "A", 0).unwrap();
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.enqueue(0, "A", 2).unwrap();
+        assert_eq!(pq.try_dequeue(), Some(0));
+        assert_eq!(pq.try_dequeue(), Some(1));
+        assert_eq!(pq.try_dequeue(), Some(2));
+        assert_eq!(pq.try_dequeue(), Some(10));
+    }
+
+    #[derive(Debug, Clone)]
+    enum FifoOp {
+        Enqueue(u8),
+        Dequeue,
+        DequeueOnePerEntity,
+        Rebalance,
+    }
+
+    fn fifo_op() -> impl Strategy<Value = FifoOp> {
+        prop_oneof![
+            4 => (0u8..5).prop_map(FifoOp::Enqueue),
+            3 => Just(FifoOp::Dequeue),
+            1 => Just(FifoOp::DequeueOnePerEntity),
+            1 => Just(FifoOp::Rebalance),
+        ]
+    }
+
+    proptest! {
+        // Items of one entity at one level always come out in enqueue order,
+        // whatever the interleaving with other entities and with entities
+        // leaving and re-joining the round-robin.
+        #[test]
+        fn per_entity_fifo(ops in prop::collection::vec(fifo_op(), 0..200)) {
+            let mut pq = PriorityQueue::new(1);
+            let mut next_seq: HashMap<u8, u32> = HashMap::new();
+            let mut last_out: HashMap<u8, u32> = HashMap::new();
+            let mut out = Vec::new();
+
+            for op in ops {
+                match op {
+                    FifoOp::Enqueue(entity) => {
+                        let seq = next_seq.entry(entity).or_default();
+                        pq.enqueue(0, entity, (entity, *seq)).unwrap();
+                        *seq += 1;
+                    }
+                    FifoOp::Dequeue => out.extend(pq.try_dequeue()),
+                    FifoOp::DequeueOnePerEntity => out.extend(
+                        pq.dequeue_one_per_entity(0).unwrap().into_iter().map(|(_, item)| item),
+                    ),
+                    FifoOp::Rebalance => pq.rebalance(0).unwrap(),
+                }
+            }
+            out.extend(std::iter::from_fn(|| pq.try_dequeue()));
+
+            for (entity, seq) in out {
+                if let Some(&last) = last_out.get(&entity) {
+                    prop_assert!(seq > last, "entity {} gave {} after {}", entity, seq, last);
+                }
+                last_out.insert(entity, seq);
+            }
+            // Nothing lost either: every entity's last item came out.
+            for (entity, n) in next_seq {
+                prop_assert_eq!(last_out.get(&entity), Some(&(n - 1)));
+            }
+        }
+    }
+
+    #[test]
+    fn new_round_restores_canonical_order() {
+        let mut pq = PriorityQueue::new(1);
+        for entity in ["C", "A", "B"] {
+            pq.enqueue(0, entity, format!("{entity}1")).unwrap();
+            pq.enqueue(0, entity, format!("{entity}2")).unwrap();
+        }
+        assert_eq!(pq.try_dequeue().unwrap(), "C1");
+        assert_eq!(pq.entities_at(0).unwrap(), vec!["A", "B", "C"]);
+        assert_eq!(pq.try_dequeue().unwrap(), "A1");
+
+        pq.new_round(0).unwrap();
+        assert_eq!(pq.entities_at(0).unwrap(), vec!["A", "B", "C"]);
+        let order: Vec<_> = std::iter::from_fn(|| pq.try_dequeue()).collect();
+        assert_eq!(order, vec!["A2", "B1", "C2", "B2"]);
+        assert!(matches!(
+            pq.new_round(1),
+            Err(PriorityQueueError::BadPriority(1))
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "n_prio must be > 0")]
+    fn flat_queue_needs_a_level() {
+        FlatPriorityQueue::<u32>::new(0);
+    }
+
+    #[test]
+    fn flat_queue_is_strict_priority_fifo() {
+        let mut pq = FlatPriorityQueue::new(2);
+        pq.enqueue(1, "low1").unwrap();
+        pq.enqueue(0, "high1").unwrap();
+        pq.enqueue(1, "low2").unwrap();
+        pq.enqueue(0, "high2").unwrap();
+        assert_eq!(pq.len(), 4);
+        assert_eq!(pq.len_at_priority(1).unwrap(), 2);
+        assert!(matches!(
+            pq.enqueue(2, "bad"),
+            Err(PriorityQueueError::BadPriority(2))
+        ));
+
+        let order: Vec<_> = std::iter::from_fn(|| pq.try_dequeue()).collect();
+        assert_eq!(order, vec!["high1", "high2", "low1", "low2"]);
+        assert!(pq.is_empty());
+    }
+
+    #[test]
+    fn deque_pool_reuses_cleared_deques() {
+        let mut pq = PriorityQueue::with_deque_pool(2);
+        pq.enqueue(0, "A", "A1").unwrap();
+        pq.enqueue(0, "B", "B1").unwrap();
+        pq.enqueue(0, "B", "B2").unwrap();
+        assert_eq!(pq.try_dequeue(), Some("A1"));
+        assert_eq!(pq.pool.as_ref().unwrap().len(), 1);
+
+        // B leaves with items still queued: its deque must come back empty.
+        assert_eq!(pq.remove_entity_at(0, &"B").unwrap(), 2);
+        let pool = pq.pool.as_ref().unwrap();
+        assert_eq!(pool.len(), 2);
+        assert!(pool.iter().all(|items| items.is_empty()));
+
+        // New entities take pooled deques and only ever see their own items.
+        pq.enqueue(1, "C", "C1").unwrap();
+        pq.enqueue(0, "D", "D1").unwrap();
+        assert!(pq.pool.as_ref().unwrap().is_empty());
+        assert_eq!(pq.try_dequeue(), Some("D1"));
+        assert_eq!(pq.try_dequeue(), Some("C1"));
+        assert_eq!(pq.try_dequeue(), None);
+
+        pq.set_deque_pool(false);
+        assert!(pq.pool.is_none());
+    }
+
+    #[test]
+    fn remove_lowest_of_entity_picks_least_important() {
+        let mut pq = PriorityQueue::new(3);
+        pq.enqueue(0, "A", 5).unwrap();
+        pq.enqueue(2, "A", 1).unwrap();
+        pq.enqueue(1, "A", 1).unwrap();
+        pq.enqueue(1, "B", 0).unwrap();
+
+        // Lowest key is 1, at levels 1 and 2: the least urgent level wins.
+        assert_eq!(
+            pq.remove_lowest_of_entity(0..3, &"A", 10, |&i| i),
+            Some((2, 1))
+        );
+        assert_eq!(
+            pq.remove_lowest_of_entity(0..3, &"A", 10, |&i| i),
+            Some((1, 1))
+        );
+        // Nothing strictly below the threshold, or outside the levels searched.
+        assert_eq!(pq.remove_lowest_of_entity(0..3, &"A", 5, |&i| i), None);
+        assert_eq!(pq.remove_lowest_of_entity(1..3, &"A", 10, |&i| i), None);
+        assert_eq!(pq.entities_at(1).unwrap(), vec!["B"]);
+        assert_eq!(pq.len(), 2);
+    }
+
+    #[test]
+    fn global_entity_fair_vs_strict_priority() {
+        fn fill(pq: &mut PriorityQueue<&str, &str>) {
+            pq.enqueue(0, "A", "A0a").unwrap();
+            pq.enqueue(0, "A", "A0b").unwrap();
+            pq.enqueue(0, "A", "A0c").unwrap();
+            pq.enqueue(2, "B", "B2").unwrap();
+            pq.enqueue(1, "C", "C1").unwrap();
+            pq.enqueue(2, "C", "C2").unwrap();
+        }
+        fn drain<'a>(pq: &mut PriorityQueue<&str, &'a str>) -> Vec<&'a str> {
+            std::iter::from_fn(|| pq.try_dequeue()).collect()
+        }
+
+        let mut strict = PriorityQueue::new(3);
+        fill(&mut strict);
+        assert_eq!(strict.policy(), Policy::StrictPriority);
+        assert_eq!(drain(&mut strict), ["A0a", "A0b", "A0c", "C1", "B2", "C2"]);
+
+        // One turn per entity; each turn serves the entity's most urgent item.
+        let mut global = PriorityQueue::with_policy(3, Policy::GlobalEntityFair);
+        fill(&mut global);
+        assert_eq!(drain(&mut global), ["A0a", "B2", "C1", "A0b", "C2", "A0c"]);
+        assert!(global.is_empty());
+
+        // Switching on a filled queue, with removals outside of the turns.
+        let mut switched = PriorityQueue::new(3);
+        fill(&mut switched);
+        switched.set_policy(Policy::GlobalEntityFair);
+        assert_eq!(switched.remove_entity_at(2, &"B").unwrap(), 1);
+        assert_eq!(switched.try_dequeue_tagged(), Some((0, "A", "A0a")));
+        assert_eq!(switched.try_dequeue_tagged(), Some((1, "C", "C1")));
+        assert_eq!(switched.len(), 3);
+        assert_eq!(drain(&mut switched), ["A0b", "C2", "A0c"]);
+    }
+
+    #[cfg(feature = "allocator-api")]
+    #[test]
+    fn items_live_in_the_given_allocator() {
+        use std::{
+            alloc::{AllocError, Allocator, Global, Layout},
+            cell::Cell,
+            ptr::NonNull,
+        };
+
+        #[derive(Clone, Copy)]
+        struct Counting<'a>(&'a Cell<usize>);
+
+        unsafe impl Allocator for Counting<'_> {
+            fn allocate(&self, layout: Layout) -> std::result::Result<NonNull<[u8]>, AllocError> {
+                self.0.set(self.0.get() + 1);
+                Global.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                unsafe { Global.deallocate(ptr, layout) }
+            }
+        }
+
+        let allocs = Cell::new(0);
+        let mut pq = PriorityQueue::new_in(2, Counting(&allocs));
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.enqueue(1, "B", 2).unwrap();
+        assert_eq!(allocs.get(), 2);
+        assert_eq!(pq.try_dequeue(), Some(1));
+        assert_eq!(pq.try_dequeue(), Some(2));
+    }
+
+    #[test]
+    fn heads_and_out_of_order_dequeue() {
+        let mut pq = PriorityQueue::new(2);
+        pq.enqueue(0, "A", "A1").unwrap();
+        pq.enqueue(0, "A", "A2").unwrap();
+        pq.enqueue(0, "B", "B1").unwrap();
+        pq.enqueue(0, "C", "C1").unwrap();
+        assert_eq!(
+            pq.heads_at(0).unwrap(),
+            vec![("A", &"A1"), ("B", &"B1"), ("C", &"C1")]
+        );
+        assert!(pq.heads_at(1).unwrap().is_empty());
+
+        // B is served out of turn and leaves; A is served and goes to the back.
+        assert_eq!(pq.try_dequeue_entity_at(0, &"B").unwrap(), Some("B1"));
+        assert_eq!(pq.try_dequeue_entity_at(0, &"A").unwrap(), Some("A1"));
+        assert_eq!(pq.try_dequeue_entity_at(0, &"B").unwrap(), None);
+        assert_eq!(pq.entities_at(0).unwrap(), vec!["C", "A"]);
+        assert_eq!(pq.len(), 2);
+        assert!(pq.try_dequeue_entity_at(2, &"A").is_err());
+    }
+
+    #[test]
+    fn try_new_reports_alloc_failure() {
+        let pq = PriorityQueue::<&str, u32>::try_new(10_000).unwrap();
+        assert_eq!(pq.n_prio(), 10_000);
+        assert!(matches!(
+            PriorityQueue::<&str, u32>::try_new(usize::MAX / 2),
+            Err(PriorityQueueError::AllocFailed)
+        ));
+    }
+
+    #[test]
+    fn seqs_follow_items() {
+        let mut pq = PriorityQueue::new(2);
+        let a = pq.enqueue_seq(1, "A", "A1").unwrap();
+        let b = pq.enqueue_seq(0, "B", "B1").unwrap();
+        assert_ne!(a, b);
+        let mut seqs: Vec<_> = pq.seqs().collect();
+        seqs.sort_unstable();
+        assert_eq!(seqs, vec![a, b]);
+        assert_eq!(pq.try_dequeue_seq(), Some((b, 0, "B", "B1")));
+        assert_eq!(pq.seqs().collect::<Vec<_>>(), vec![a]);
+    }
+
+    #[test]
+    fn seq_variants_report_the_items_taken() {
+        let mut pq = PriorityQueue::new(2);
+        let a1 = pq.enqueue_seq(1, "A", "A1").unwrap();
+        let a2 = pq.enqueue_seq(1, "A", "A2").unwrap();
+        let b1 = pq.enqueue_seq(1, "B", "B1").unwrap();
+        let b2 = pq.enqueue_seq(0, "B", "B2").unwrap();
+        let c1 = pq.enqueue_seq(1, "C", "C1").unwrap();
+
+        assert_eq!(pq.try_dequeue_at_seq(1).unwrap(), Some((a1, "A", "A1")));
+        assert_eq!(
+            pq.try_dequeue_entity_at_seq(1, &"C").unwrap(),
+            Some((c1, "C1"))
+        );
+        assert_eq!(
+            pq.remove_lowest_of_entity_seq(0..2, &"B", 1, |_| 0),
+            Some((1, b1, "B1"))
+        );
+        assert_eq!(
+            pq.dequeue_one_per_entity_seq(1).unwrap(),
+            vec![(a2, "A", "A2")]
+        );
+        assert_eq!(pq.seqs().collect::<Vec<_>>(), vec![b2]);
+    }
+
+    #[test]
+    fn try_dequeue_at_serves_one_level() {
+        let mut pq = PriorityQueue::new(2);
+        pq.enqueue(0, "A", "A0").unwrap();
+        pq.enqueue(1, "A", "A1").unwrap();
+        pq.enqueue(1, "A", "A2").unwrap();
+        pq.enqueue(1, "B", "B1").unwrap();
+
+        assert_eq!(pq.try_dequeue_at(1).unwrap(), Some(("A", "A1")));
+        assert_eq!(pq.try_dequeue_at(1).unwrap(), Some(("B", "B1")));
+        assert_eq!(pq.try_dequeue_at(1).unwrap(), Some(("A", "A2")));
+        assert_eq!(pq.try_dequeue_at(1).unwrap(), None);
+        assert!(pq.try_dequeue_at(2).is_err());
+        assert_eq!(pq.len(), 1);
+    }
+
+    #[test]
+    fn reset_matches_a_fresh_queue() {
+        fn fill(pq: &mut PriorityQueue<&'static str, u32>) {
+            pq.enqueue(1, "A", 1).unwrap();
+            pq.enqueue(0, "B", 2).unwrap();
+            pq.enqueue(1, "C", 3).unwrap();
+            pq.enqueue(1, "A", 4).unwrap();
+        }
+
+        let mut pq = PriorityQueue::with_level_quotas(vec![2, usize::MAX]);
+        pq.set_deque_pool(true);
+        fill(&mut pq);
+        pq.enqueue(0, "D", 5).unwrap();
+        pq.try_dequeue();
+        pq.reset();
+        assert!(pq.is_empty());
+        assert_eq!(pq.len(), 0);
+        assert!(pq.try_dequeue().is_none());
+        assert!(pq.entities_at(1).unwrap().is_empty());
+        // All the levels are still there.
+        assert_eq!(pq.n_prio(), 2);
+        pq.enqueue(1, "E", 6).unwrap();
+        assert_eq!(pq.try_dequeue(), Some(6));
+        pq.reset();
+
+        let mut fresh = PriorityQueue::with_level_quotas(vec![2, usize::MAX]);
+        fill(&mut pq);
+        fill(&mut fresh);
+        let mut seqs: Vec<u64> = pq.seqs().collect();
+        seqs.sort_unstable();
+        assert_eq!(seqs, vec![0, 1, 2, 3]);
+        while let Some(item) = fresh.try_dequeue() {
+            assert_eq!(pq.try_dequeue(), Some(item));
+        }
+        assert!(pq.is_empty());
+    }
+
+    #[test]
+    fn clear_keeps_every_level() {
+        let mut pq = PriorityQueue::new(3);
+        for (prio, entity, item) in [(0, "A", 1), (2, "B", 2), (2, "A", 3)] {
+            pq.enqueue(prio, entity, item).unwrap();
+        }
+        pq.clear();
+        assert!(pq.try_dequeue().is_none());
+        assert_eq!(pq.n_prio(), 3);
+        assert!(!pq.contains_entity(&"A"));
+        pq.validate_rr().unwrap();
+
+        pq.enqueue(2, "C", 4).unwrap();
+        assert_eq!(pq.try_dequeue_tagged(), Some((2, "C", 4)));
+    }
+
+    // Starvation harness: a single entity keeps level 0 busy, refilling it
+    // before every dequeue, while one item waits at level 2 from the start.
+    // Returns after how many dequeues the level-2 item was served, if it was
+    // within `budget`.
+    fn dequeues_until_background_served(
+        mut pq: PriorityQueue<&'static str, u32>,
+        budget: usize,
+    ) -> Option<usize> {
+        pq.enqueue(2, "background", 0).unwrap();
+        for n in 1..=budget {
+            pq.enqueue(0, "hot", 1).unwrap();
+            if pq.try_dequeue()? == 0 {
+                return Some(n);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn level_quotas_prevent_starvation() {
+        // Strict priority: level 2 is never served while level 0 is refilled.
+        assert_eq!(
+            dequeues_until_background_served(PriorityQueue::new(3), 10_000),
+            None
+        );
+
+        // Level quotas: level 0 yields a turn after `quota` dequeues in a row;
+        // the empty level 1 is skipped, so level 2 gets it.
+        for quota in [1, 4, 100] {
+            let pq = PriorityQueue::with_level_quotas(vec![quota, usize::MAX, usize::MAX]);
+            assert_eq!(
+                dequeues_until_background_served(pq, 10_000),
+                Some(quota + 1)
+            );
+        }
+    }
+
+    #[test]
+    fn reassign_head_moves_to_the_back_of_the_target() {
+        let mut pq = PriorityQueue::new(2);
+        pq.enqueue(1, "A", "A1").unwrap();
+        pq.enqueue(1, "A", "A2").unwrap();
+        pq.enqueue(1, "B", "B1").unwrap();
+        pq.enqueue(1, "C", "C1").unwrap();
+
+        assert!(pq.reassign_head(1, &"A", "C").unwrap());
+        assert_eq!(pq.entities_at(1).unwrap(), vec!["A", "B", "C"]);
+        assert!(pq.reassign_head(1, &"A", "D").unwrap());
+        assert_eq!(pq.entities_at(1).unwrap(), vec!["B", "C", "D"]);
+        assert!(!pq.reassign_head(1, &"A", "D").unwrap());
+        assert!(!pq.reassign_head(0, &"B", "D").unwrap());
+        assert!(pq.reassign_head(2, &"B", "D").is_err());
+        assert_eq!(pq.len(), 4);
+
+        let mut order = Vec::new();
+        while let Some(item) = pq.try_dequeue() {
+            order.push(item);
+        }
+        assert_eq!(order, vec!["B1", "C1", "A2", "A1"]);
+
+        // Under the global policy, the moved item follows its new entity.
+        let mut pq = PriorityQueue::with_policy(2, Policy::GlobalEntityFair);
+        pq.enqueue(0, "A", "A1").unwrap();
+        pq.enqueue(1, "B", "B1").unwrap();
+        assert!(pq.reassign_head(0, &"A", "B").unwrap());
+        assert_eq!(pq.try_dequeue(), Some("A1"));
+        assert_eq!(pq.try_dequeue(), Some("B1"));
+        assert_eq!(pq.try_dequeue(), None);
+        assert!(pq.is_empty());
+    }
+
+    #[test]
+    fn enqueue_pos_counts_items_ahead_for_the_entity() {
+        let mut pq = PriorityQueue::new(2);
+        assert_eq!(pq.enqueue_pos(1, "A", "A1").unwrap(), 0);
+        assert_eq!(pq.enqueue_pos(1, "B", "B1").unwrap(), 0);
+        assert_eq!(pq.enqueue_pos(1, "A", "A2").unwrap(), 1);
+        assert_eq!(pq.enqueue_pos(0, "A", "A0").unwrap(), 0);
+        assert_eq!(pq.enqueue_pos(1, "A", "A3").unwrap(), 2);
+        assert!(pq.enqueue_pos(2, "A", "A4").is_err());
+        assert_eq!(pq.entity_len_at(1, &"A").unwrap(), 3);
+
+        assert_eq!(pq.try_dequeue(), Some("A0"));
+        assert_eq!(pq.try_dequeue(), Some("A1"));
+        assert_eq!(pq.enqueue_pos(1, "A", "A5").unwrap(), 2);
+    }
+
+    #[test]
+    fn weights_apply_from_the_next_burst() {
+        let mut pq = PriorityQueue::new(1);
+        for i in 0..8 {
+            pq.enqueue(0, "heavy", i).unwrap();
+            pq.enqueue(0, "light", 100 + i).unwrap();
+        }
+        pq.set_weight(0, "heavy", 3).unwrap();
+        assert_eq!(pq.weight(0, &"heavy").unwrap(), Some(3));
+        assert_eq!(pq.weight(0, &"light").unwrap(), None);
+
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            order.push(pq.try_dequeue().unwrap());
+        }
+        assert_eq!(order, vec![0, 1, 2, 100, 3]);
+
+        // Mid-burst: heavy finishes the 3 turns it started with.
+        pq.set_weight(0, "heavy", 1).unwrap();
+        assert_eq!(pq.weight(0, &"heavy").unwrap(), None);
+        let mut order = Vec::new();
+        for _ in 0..5 {
+            order.push(pq.try_dequeue().unwrap());
+        }
+        assert_eq!(order, vec![4, 5, 101, 6, 102]);
+        assert!(matches!(
+            pq.set_weight(1, "heavy", 2),
+            Err(PriorityQueueError::BadPriority(1))
+        ));
+    }
+
+    #[test]
+    fn validate_rr_catches_corruption() {
+        let mut pq = PriorityQueue::new(2);
+        assert_eq!(pq.validate_rr(), Ok(()));
+        pq.enqueue(1, "A", 1).unwrap();
+        pq.enqueue(1, "A", 2).unwrap();
+        pq.enqueue(1, "B", 3).unwrap();
+        pq.try_dequeue().unwrap();
+        assert_eq!(pq.validate_rr(), Ok(()));
+
+        pq.rr_mut(1).push_back("B");
+        assert_eq!(
+            pq.validate_rr(),
+            Err("level 1: duplicate entity in rr".to_string())
+        );
+        pq.rr_mut(1).pop_back();
+        pq.rr_mut(1).push_back("C");
+        assert_eq!(
+            pq.validate_rr(),
+            Err("level 1: entity in rr but not active".to_string())
+        );
+        pq.rr_mut(1).pop_back();
+        pq.rr_mut(1).retain(|e| *e != "A");
+        assert_eq!(
+            pq.validate_rr(),
+            Err("level 1: 1 entities in rr, 2 active".to_string())
+        );
+    }
+
+    #[test]
+    fn clone_dequeues_in_the_same_order() {
+        let mut pq = PriorityQueue::with_level_quotas(vec![2, usize::MAX]);
+        pq.set_weight(1, "B", 2).unwrap();
+        for i in 0..6 {
+            pq.enqueue(i % 2, ["A", "B", "C"][i % 3], i).unwrap();
+        }
+        pq.try_dequeue().unwrap();
+        let mut copy = pq.clone();
+        let mut drained = Vec::new();
+        while let Some(v) = pq.try_dequeue_tagged() {
+            drained.push(v);
+        }
+        let mut replayed = Vec::new();
+        while let Some(v) = copy.try_dequeue_tagged() {
+            replayed.push(v);
+        }
+        assert_eq!(replayed, drained);
+        assert_eq!(replayed.len(), 5);
+    }
+
+    #[test]
+    fn decaying_priority_gives_lower_levels_turns() {
+        fn serve(pq: &mut PriorityQueue<&str, usize>, n: usize) -> Vec<usize> {
+            // Every level stays busy: each served item is replaced.
+            (0..n)
+                .map(|_| {
+                    let (prio, entity, _) = pq.try_dequeue_tagged().unwrap();
+                    pq.enqueue(prio, entity, prio).unwrap();
+                    prio
+                })
+                .collect()
+        }
+        fn fill(pq: &mut PriorityQueue<&str, usize>) {
+            for prio in 0..3 {
+                pq.enqueue(prio, "A", prio).unwrap();
+            }
+        }
+
+        let mut strict =
+            PriorityQueue::with_policy(3, Policy::DecayingPriority { decay_permille: 0 });
+        fill(&mut strict);
+        assert!(serve(&mut strict, 100).iter().all(|&prio| prio == 0));
+
+        let mut decaying = PriorityQueue::with_policy(
+            3,
+            Policy::DecayingPriority {
+                decay_permille: 250,
+            },
+        );
+        assert_eq!(
+            decaying.policy(),
+            Policy::DecayingPriority {
+                decay_permille: 250
+            }
+        );
+        fill(&mut decaying);
+        // Level 0 yields once its penalty exceeds the gap to level 1.
+        assert_eq!(serve(&mut decaying, 6), [0, 0, 0, 0, 0, 1]);
+
+        let served = serve(&mut decaying, 1000);
+        let count = |prio| served.iter().filter(|&&p| p == prio).count();
+        assert!(count(0) > count(1) && count(1) > count(2));
+        assert!(count(2) > 0);
+    }
+
+    #[test]
+    fn len_counts_items_not_entities() {
+        let mut pq = PriorityQueue::new(3);
+        assert!(pq.is_empty());
+        assert_eq!(pq.len(), 0);
+
+        for i in 0..3 {
+            pq.enqueue(0, "A", i).unwrap();
+        }
+        pq.enqueue(2, "A", 3).unwrap();
+        pq.enqueue(2, "B", 4).unwrap();
+        assert!(!pq.is_empty());
+        assert_eq!(pq.len(), 5);
+
+        // Checking is not destructive.
+        assert_eq!(pq.len(), 5);
+        assert_eq!(pq.try_dequeue(), Some(0));
+        assert_eq!(pq.len(), 4);
+        while pq.try_dequeue().is_some() {}
+        assert!(pq.is_empty());
+        assert_eq!(pq.len(), 0);
+    }
+
+    #[test]
+    fn peek_does_not_dequeue() {
+        let mut pq = PriorityQueue::new(3);
+        assert_eq!(pq.peek(), None);
+        for (prio, entity, item) in [
+            (1, "A", "A1"),
+            (1, "A", "A2"),
+            (1, "B", "B1"),
+            (0, "C", "C1"),
+        ] {
+            pq.enqueue(prio, entity, item).unwrap();
+        }
+        pq.set_weight(1, "A", 2).unwrap();
+
+        // Every item is peeked right before being taken, whatever the policy.
+        let mut order = Vec::new();
+        while let Some(&next) = pq.peek() {
+            assert_eq!(pq.peek(), Some(&next));
+            assert_eq!(pq.try_dequeue(), Some(next));
+            order.push(next);
+        }
+        assert_eq!(order, ["C1", "A1", "A2", "B1"]);
+
+        let mut quotas = PriorityQueue::with_level_quotas(vec![1, 1]);
+        let mut decaying = PriorityQueue::with_policy(
+            2,
+            Policy::DecayingPriority {
+                decay_permille: 500,
+            },
+        );
+        let mut global = PriorityQueue::with_policy(2, Policy::GlobalEntityFair);
+        for pq in [&mut quotas, &mut decaying, &mut global] {
+            for i in 0..4 {
+                pq.enqueue(0, "A", i).unwrap();
+                pq.enqueue(1, "B", 10 + i).unwrap();
+            }
+            while let Some(&next) = pq.peek() {
+                assert_eq!(pq.peek(), Some(&next));
+                assert_eq!(pq.try_dequeue(), Some(next));
+            }
+            assert!(pq.is_empty());
+        }
+    }
+
+    #[test]
+    fn drain_follows_dequeue_order() {
+        let mut pq = PriorityQueue::new(3);
+        assert!(pq.drain().is_empty());
+
+        for i in 1..=4 {
+            pq.enqueue(1, "A", format!("A{i}")).unwrap();
+        }
+        for i in 1..=2 {
+            pq.enqueue(1, "B", format!("B{i}")).unwrap();
+        }
+        pq.enqueue(0, "C", "C1".to_string()).unwrap();
+
+        assert_eq!(pq.drain(), ["C1", "A1", "B1", "A2", "B2", "A3", "A4"]);
+        assert!(pq.is_empty());
         assert!(pq.try_dequeue().is_none());
     }
+
+    #[test]
+    fn remove_entity_purges_every_level() {
+        let mut pq = PriorityQueue::new(3);
+        for (prio, entity, item) in [
+            (0, "A", 1),
+            (0, "B", 2),
+            (0, "C", 3),
+            (1, "A", 4),
+            (1, "A", 5),
+            (2, "B", 6),
+        ] {
+            pq.enqueue(prio, entity, item).unwrap();
+        }
+        assert_eq!(pq.remove_entity(&"A"), 3);
+        assert_eq!(pq.remove_entity(&"A"), 0);
+        assert_eq!(pq.len(), 3);
+        assert!(pq.is_level_empty(1).unwrap());
+        assert_eq!(pq.entities_at(0).unwrap(), ["B", "C"]);
+        assert_eq!(pq.drain(), [2, 3, 6]);
+    }
+
+    #[test]
+    fn len_at_priority_sums_entities() {
+        let mut pq = PriorityQueue::new(3);
+        for (prio, entity) in [(0, "A"), (0, "A"), (0, "B"), (2, "A")] {
+            pq.enqueue(prio, entity, ()).unwrap();
+        }
+        assert_eq!(pq.len_at_priority(0).unwrap(), 3);
+        assert_eq!(pq.len_at_priority(1).unwrap(), 0);
+        assert_eq!(pq.len_at_priority(2).unwrap(), 1);
+        assert!(matches!(
+            pq.len_at_priority(3),
+            Err(PriorityQueueError::BadPriority(3))
+        ));
+
+        pq.try_dequeue();
+        assert_eq!(pq.len_at_priority(0).unwrap(), 2);
+    }
+
+    #[test]
+    fn entity_len_sums_levels() {
+        let mut pq = PriorityQueue::new(3);
+        assert_eq!(pq.entity_len(&"A"), 0);
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.enqueue(2, "A", 2).unwrap();
+        pq.enqueue(2, "A", 3).unwrap();
+        pq.enqueue(2, "B", 4).unwrap();
+        assert_eq!(pq.entity_len(&"A"), 3);
+        assert_eq!(pq.entity_len(&"B"), 1);
+
+        pq.try_dequeue();
+        assert_eq!(pq.entity_len(&"A"), 2);
+    }
+
+    #[test]
+    fn active_entities_are_listed_once() {
+        let mut pq = PriorityQueue::new(2);
+        assert!(pq.active_entities().is_empty());
+        for (prio, entity) in [(1, "C"), (1, "A"), (0, "B"), (0, "A")] {
+            pq.enqueue(prio, entity, ()).unwrap();
+        }
+        assert_eq!(pq.active_entities(), ["B", "A", "C"]);
+
+        pq.remove_entity(&"A");
+        assert_eq!(pq.active_entities(), ["B", "C"]);
+    }
+
+    #[test]
+    fn contains_entity_checks_every_level() {
+        let mut pq = PriorityQueue::new(3);
+        assert!(!pq.contains_entity(&"A"));
+        pq.enqueue(2, "A", 1).unwrap();
+        assert!(pq.contains_entity(&"A"));
+        assert!(!pq.contains_entity(&"B"));
+
+        pq.try_dequeue();
+        assert!(!pq.contains_entity(&"A"));
+    }
+
+    #[test]
+    fn enqueue_many_matches_one_by_one() {
+        let mut batched = PriorityQueue::new(2);
+        let mut single = PriorityQueue::new(2);
+        assert!(matches!(
+            batched.enqueue_many(2, "A", [1]),
+            Err(PriorityQueueError::BadPriority(2))
+        ));
+        assert_eq!(batched.enqueue_many(1, "A", []).unwrap(), 0);
+        assert!(batched.is_empty());
+
+        assert_eq!(batched.enqueue_many(1, "A", 0..3).unwrap(), 3);
+        batched.enqueue(1, "B", 10).unwrap();
+        assert_eq!(batched.enqueue_many(1, "A", 3..5).unwrap(), 2);
+        for i in 0..3 {
+            single.enqueue(1, "A", i).unwrap();
+        }
+        single.enqueue(1, "B", 10).unwrap();
+        for i in 3..5 {
+            single.enqueue(1, "A", i).unwrap();
+        }
+
+        assert_eq!(batched.len(), 6);
+        let mut seqs: Vec<u64> = batched.seqs().collect();
+        seqs.sort_unstable();
+        assert_eq!(seqs, (0..6).collect::<Vec<_>>());
+        assert_eq!(batched.drain(), single.drain());
+    }
+
+    #[test]
+    fn enqueue_weighted_serves_three_to_one() {
+        let mut pq = PriorityQueue::new(1);
+        for i in 0..6 {
+            pq.enqueue_weighted(0, "premium", format!("P{i}"), 3)
+                .unwrap();
+            pq.enqueue(0, "basic", format!("B{i}")).unwrap();
+        }
+        assert_eq!(pq.weight(0, &"premium").unwrap(), Some(3));
+        assert_eq!(pq.weight(0, &"basic").unwrap(), None);
+        assert_eq!(
+            pq.drain(),
+            [
+                "P0", "P1", "P2", "B0", "P3", "P4", "P5", "B1", "B2", "B3", "B4", "B5"
+            ]
+        );
+    }
+
+    #[test]
+    fn entity_counts_follow_every_operation() {
+        fn check(pq: &PriorityQueue<u8, u32>) {
+            let mut expected: HashMap<u8, usize> = HashMap::new();
+            for (_, &entity_id, _) in pq.iter() {
+                *expected.entry(entity_id).or_default() += 1;
+            }
+            let counts: HashMap<u8, usize> =
+                pq.entity_lens().unwrap().map(|(&e, n)| (e, n)).collect();
+            assert_eq!(counts, expected);
+            assert_eq!(pq.n_entities(), expected.len());
+        }
+
+        let mut pq = PriorityQueue::new(3);
+        pq.enqueue(1, 9, 0).unwrap();
+        pq.set_entity_counts(true);
+        check(&pq);
+        let mut rng = 7u32;
+        for i in 0..2000 {
+            rng = rng.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            let (prio, entity) = ((rng >> 8) as usize % 3, (rng >> 12) as u8 % 5);
+            match (rng >> 16) % 10 {
+                0..=3 => pq.enqueue(prio, entity, i).unwrap(),
+                4 => {
+                    pq.enqueue_many(prio, entity, [i, i + 1]).unwrap();
+                }
+                5 => {
+                    pq.try_dequeue();
+                }
+                6 => {
+                    pq.remove_entity_at(prio, &entity).unwrap();
+                }
+                7 => {
+                    pq.reassign_head(prio, &entity, (entity + 1) % 5).unwrap();
+                }
+                8 => {
+                    pq.dequeue_one_per_entity(prio).unwrap();
+                }
+                _ => {
+                    pq.try_dequeue_entity(&entity);
+                }
+            }
+            if i == 1000 {
+                pq.set_policy(Policy::GlobalEntityFair);
+            }
+            check(&pq);
+        }
+        assert_eq!(
+            pq.entity_len(&3),
+            pq.iter().filter(|(_, e, _)| **e == 3).count()
+        );
+        pq.reset();
+        check(&pq);
+        pq.set_entity_counts(false);
+        assert!(pq.entity_lens().is_none());
+    }
+
+    #[test]
+    fn enqueue_weighted_failing_keeps_the_weight() {
+        let mut pq = PriorityQueue::with_level_caps(vec![1]);
+        pq.enqueue(0, "A", 1).unwrap();
+        assert!(matches!(
+            pq.enqueue_weighted(0, "A", 2, 5),
+            Err(PriorityQueueError::Full)
+        ));
+        assert_eq!(pq.weight(0, &"A").unwrap(), None);
+    }
+
+    #[test]
+    fn aging_lets_waiting_levels_through() {
+        // Strict priority: level 2 is never served while level 0 is refilled.
+        assert_eq!(
+            dequeues_until_background_served(PriorityQueue::new(3), 1000),
+            None
+        );
+        // Four skips, then its turn.
+        let aging = PriorityQueue::with_aging(3, 4);
+        assert_eq!(dequeues_until_background_served(aging, 1000), Some(5));
+
+        // Under a constant flood, levels 1 and 2 both get regular turns.
+        let mut pq = PriorityQueue::with_aging(3, 4);
+        for prio in 1..3 {
+            for i in 0..100 {
+                pq.enqueue(prio, "background", i).unwrap();
+            }
+        }
+        let mut served = [0; 3];
+        for _ in 0..100 {
+            pq.enqueue(0, "hot", 0).unwrap();
+            let (prio, _, _) = pq.try_dequeue_tagged().unwrap();
+            served[prio] += 1;
+        }
+        assert!(served[0] > served[1] && served[1] > 0);
+        assert!(served[2] > 0);
+    }
+
+    #[test]
+    fn try_dequeue_priority_rotates_like_try_dequeue() {
+        let mut pq = PriorityQueue::new(2);
+        let mut reference = PriorityQueue::new(2);
+        for (entity, item) in [("A", 1), ("A", 2), ("B", 3), ("C", 4), ("B", 5)] {
+            pq.enqueue(1, entity, item).unwrap();
+            reference.enqueue(1, entity, item).unwrap();
+        }
+        pq.enqueue(0, "D", 0).unwrap();
+
+        assert_eq!(pq.try_dequeue_priority(0).unwrap(), Some(0));
+        assert_eq!(pq.try_dequeue_priority(0).unwrap(), None);
+        assert!(matches!(
+            pq.try_dequeue_priority(2),
+            Err(PriorityQueueError::BadPriority(2))
+        ));
+        while let Some(item) = reference.try_dequeue() {
+            assert_eq!(pq.try_dequeue_priority(1).unwrap(), Some(item));
+        }
+        assert!(pq.is_empty());
+    }
+
+    #[test]
+    fn level_caps_reject_overflow() {
+        let mut pq = PriorityQueue::with_level_caps(vec![2, usize::MAX]);
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.enqueue(0, "B", 2).unwrap();
+        // The cap is per level, whatever the entity.
+        assert!(matches!(
+            pq.enqueue(0, "C", 3),
+            Err(PriorityQueueError::Full)
+        ));
+        assert!(matches!(
+            pq.enqueue_many(0, "C", [3]),
+            Err(PriorityQueueError::Full)
+        ));
+        assert_eq!(pq.len(), 2);
+        // Other levels are not affected.
+        assert_eq!(pq.enqueue_many(1, "C", 0..100).unwrap(), 100);
+
+        assert_eq!(pq.try_dequeue(), Some(1));
+        pq.enqueue(0, "C", 3).unwrap();
+        assert_eq!(pq.len_at_priority(0).unwrap(), 2);
+    }
+
+    #[test]
+    fn resize_never_drops_items() {
+        let mut pq = PriorityQueue::new(2);
+        pq.enqueue(1, "A", 1).unwrap();
+        pq.enqueue(0, "B", 0).unwrap();
+
+        pq.resize(4).unwrap();
+        assert_eq!(pq.n_prio(), 4);
+        pq.enqueue(3, "A", 3).unwrap();
+        pq.enqueue(2, "C", 2).unwrap();
+        assert_eq!(pq.len(), 4);
+
+        assert!(matches!(
+            pq.shrink(3),
+            Err(PriorityQueueError::LevelNotEmpty(3))
+        ));
+        assert!(matches!(
+            pq.resize(1),
+            Err(PriorityQueueError::LevelNotEmpty(1))
+        ));
+        assert_eq!(pq.n_prio(), 4);
+        assert_eq!(pq.drain(), [0, 1, 2, 3]);
+
+        pq.enqueue(0, "A", 0).unwrap();
+        pq.shrink(1).unwrap();
+        assert_eq!(pq.n_prio(), 1);
+        assert!(matches!(
+            pq.enqueue(1, "A", 1),
+            Err(PriorityQueueError::BadPriority(1))
+        ));
+        assert_eq!(pq.drain(), [0]);
+
+        // Not even empty levels go below one.
+        for res in [pq.shrink(0), pq.resize(0)] {
+            assert!(matches!(res, Err(PriorityQueueError::BadPriority(0))));
+        }
+        assert_eq!(pq.n_prio(), 1);
+    }
+
+    #[test]
+    fn reprioritize_moves_and_merges() {
+        let mut pq = PriorityQueue::new(3);
+        for (prio, entity, item) in [
+            (2, "A", 1),
+            (0, "A", 2),
+            (2, "A", 3),
+            (2, "B", 4),
+            (0, "C", 5),
+        ] {
+            pq.enqueue(prio, entity, item).unwrap();
+        }
+        assert!(matches!(
+            pq.reprioritize(&"A", 3, 0),
+            Err(PriorityQueueError::BadPriority(3))
+        ));
+        assert_eq!(pq.reprioritize(&"A", 2, 2).unwrap(), 0);
+        assert_eq!(pq.reprioritize(&"D", 2, 0).unwrap(), 0);
+
+        // A is already active at level 0: its lines merge in enqueue order.
+        assert_eq!(pq.reprioritize(&"A", 2, 0).unwrap(), 2);
+        assert_eq!(pq.len_at_priority(0).unwrap(), 4);
+        assert_eq!(pq.entities_at(2).unwrap(), ["B"]);
+        pq.validate_rr().unwrap();
+        // B joins the back of an empty level.
+        assert_eq!(pq.reprioritize(&"B", 2, 1).unwrap(), 1);
+        assert!(pq.is_level_empty(2).unwrap());
+        assert_eq!(pq.drain(), [1, 5, 2, 3, 4]);
+
+        let mut capped = PriorityQueue::with_level_caps(vec![1, usize::MAX]);
+        capped.enqueue(1, "A", 1).unwrap();
+        capped.enqueue(1, "A", 2).unwrap();
+        assert!(matches!(
+            capped.reprioritize(&"A", 1, 0),
+            Err(PriorityQueueError::Full)
+        ));
+        assert_eq!(capped.len_at_priority(1).unwrap(), 2);
+    }
+
+    #[test]
+    fn try_dequeue_entity_takes_most_urgent_first() {
+        let mut pq = PriorityQueue::new(3);
+        for (prio, entity, item) in [(2, "A", 1), (1, "A", 2), (1, "B", 3), (1, "A", 4)] {
+            pq.enqueue(prio, entity, item).unwrap();
+        }
+        assert_eq!(pq.try_dequeue_entity(&"C"), None);
+        assert_eq!(pq.try_dequeue_entity(&"A"), Some(2));
+        assert_eq!(pq.try_dequeue_entity(&"A"), Some(4));
+        assert_eq!(pq.entities_at(1).unwrap(), ["B"]);
+        assert_eq!(pq.try_dequeue_entity(&"A"), Some(1));
+        assert_eq!(pq.try_dequeue_entity(&"A"), None);
+        pq.validate_rr().unwrap();
+        assert_eq!(pq.drain(), [3]);
+    }
+
+    #[test]
+    fn iter_visits_every_item_in_place() {
+        let mut pq = PriorityQueue::new(3);
+        for (prio, entity, item) in [(2, "A", 1), (0, "B", 2), (0, "A", 3), (0, "B", 4)] {
+            pq.enqueue(prio, entity, item).unwrap();
+        }
+        let items: Vec<(usize, &str, i32)> = pq.iter().map(|(p, &e, &i)| (p, e, i)).collect();
+        assert_eq!(items, [(0, "B", 2), (0, "B", 4), (0, "A", 3), (2, "A", 1)]);
+        assert_eq!(pq.iter().count(), pq.len());
+
+        // Nothing moved.
+        assert_eq!(pq.try_dequeue(), Some(2));
+        assert_eq!(pq.try_dequeue(), Some(3));
+    }
+
+    #[test]
+    fn debug_prints_counts_not_items() {
+        struct Opaque;
+
+        let mut pq = PriorityQueue::new(2);
+        for entity in ["A", "A", "B"] {
+            pq.enqueue(0, entity, Opaque).unwrap();
+        }
+        assert_eq!(
+            format!("{pq:?}"),
+            r#"PriorityQueue { len: 3, levels: [{"A": 2, "B": 1}, {}], .. }"#
+        );
+
+        let mut busy = PriorityQueue::new(1);
+        for i in 0..20 {
+            busy.enqueue(0, i.to_string(), Opaque).unwrap();
+        }
+        let out = format!("{busy:?}");
+        assert!(out.contains(r#""15": 1, .."#));
+        assert!(!out.contains(r#""16""#));
+    }
+
+    #[test]
+    fn deficit_round_robin_shares_by_cost() {
+        // Items are (entity, cost): A sends big items, B small ones.
+        let mut pq = PriorityQueue::with_deficit_round_robin(1, 4, |&(_, cost): &(&str, u64)| cost);
+        for item in [("A", 4), ("A", 4), ("A", 4)] {
+            pq.enqueue(0, "A", item).unwrap();
+        }
+        for _ in 0..8 {
+            pq.enqueue(0, "B", ("B", 1)).unwrap();
+        }
+        // C's item is too big for one quantum: it waits for a second one.
+        pq.enqueue(0, "C", ("C", 6)).unwrap();
+
+        let mut order = Vec::new();
+        while let Some(peeked) = pq.peek().copied() {
+            let item = pq.try_dequeue().unwrap();
+            assert_eq!(peeked, item);
+            order.push(item.0);
+        }
+        assert_eq!(
+            order,
+            ["A", "B", "B", "B", "B", "A", "B", "B", "B", "B", "C", "A"]
+        );
+        pq.validate_rr().unwrap();
+
+        // A quantum of its own doubles A's share.
+        let mut pq = PriorityQueue::with_deficit_round_robin(1, 2, |&cost: &u64| cost);
+        pq.set_quantum(0, "A", 4).unwrap();
+        for _ in 0..4 {
+            pq.enqueue(0, "A", 2).unwrap();
+            pq.enqueue(0, "B", 2).unwrap();
+        }
+        let mut order = Vec::new();
+        while let Some((_, entity, _)) = pq.try_dequeue_tagged() {
+            order.push(entity);
+        }
+        assert_eq!(order, ["A", "A", "B", "A", "A", "B", "B", "B"]);
+
+        // Unit costs and quanta are the plain round-robin.
+        let mut drr = PriorityQueue::with_deficit_round_robin(2, 1, |_: &u32| 1);
+        let mut plain = PriorityQueue::new(2);
+        for (i, (prio, entity)) in [(1, "A"), (1, "A"), (1, "B"), (0, "C"), (1, "A"), (1, "B")]
+            .into_iter()
+            .enumerate()
+        {
+            drr.enqueue(prio, entity, i as u32).unwrap();
+            plain.enqueue(prio, entity, i as u32).unwrap();
+        }
+        assert_eq!(drr.drain(), plain.drain());
+    }
+
+    #[test]
+    fn fifo_ignores_entities() {
+        let mut pq = PriorityQueue::fifo(2);
+        for (i, (prio, entity)) in [(1, "A"), (1, "A"), (1, "B"), (0, "C"), (1, "A"), (1, "B")]
+            .into_iter()
+            .enumerate()
+        {
+            pq.enqueue(prio, entity, i).unwrap();
+        }
+        pq.set_weight(1, "B", 3).unwrap();
+        assert_eq!(pq.entity_len(&"A"), 3);
+        assert_eq!(pq.entities_at(1).unwrap(), ["A", "B"]);
+        assert_eq!(pq.peek(), Some(&3));
+        assert_eq!(pq.try_dequeue(), Some(3));
+        // Pure insertion order at level 1, B's weight notwithstanding.
+        assert_eq!(pq.peek(), Some(&0));
+        assert_eq!(pq.drain(), [0, 1, 2, 4, 5]);
+        pq.validate_rr().unwrap();
+
+        // Items leaving out of turn, or moving in from another level, keep
+        // the order of the rest.
+        let mut pq = PriorityQueue::fifo(2);
+        let mut seqs = Vec::new();
+        for (i, (prio, entity)) in [(0, "A"), (0, "B"), (1, "B"), (0, "A"), (0, "C"), (1, "C")]
+            .into_iter()
+            .enumerate()
+        {
+            seqs.push(pq.enqueue_seq(prio, entity, i).unwrap());
+        }
+        assert_eq!(pq.remove_seq(0, &"A", seqs[0]).unwrap(), Some(0));
+        assert_eq!(pq.remove_entity_at(0, &"C").unwrap(), 1);
+        assert_eq!(pq.reprioritize(&"B", 1, 0).unwrap(), 1);
+        assert_eq!(pq.drain(), [1, 2, 3, 5]);
+        pq.validate_rr().unwrap();
+
+        // One item per entity stays cheap to drain.
+        let mut pq = PriorityQueue::fifo(1);
+        for i in 0..50_000 {
+            pq.enqueue(0, i, i).unwrap();
+        }
+        assert!(pq.drain().into_iter().eq(0..50_000));
+
+        // new keeps the round-robin.
+        let mut fair = PriorityQueue::new(1);
+        for (i, entity) in ["A", "A", "B"].into_iter().enumerate() {
+            fair.enqueue(0, entity, i).unwrap();
+        }
+        assert_eq!(fair.drain(), [0, 2, 1]);
+    }
+
+    #[test]
+    fn fifo_serving_ignores_waiting_entities() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // Counts entity comparisons, the cost of looking entities up.
+        static EQS: AtomicUsize = AtomicUsize::new(0);
+        #[derive(Clone)]
+        struct Id(u32);
+        impl Hash for Id {
+            fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+                self.0.hash(state);
+            }
+        }
+        impl PartialEq for Id {
+            fn eq(&self, other: &Self) -> bool {
+                EQS.fetch_add(1, Ordering::Relaxed);
+                self.0 == other.0
+            }
+        }
+        impl Eq for Id {}
+
+        // One entity holds the oldest items, 10,000 others wait behind it.
+        let mut pq = PriorityQueue::fifo(1);
+        for i in 0..1_000 {
+            pq.enqueue(0, Id(0), i).unwrap();
+        }
+        for i in 1..=10_000 {
+            pq.enqueue(0, Id(i), 1_000 + i).unwrap();
+        }
+        EQS.store(0, Ordering::Relaxed);
+        for i in 0..999 {
+            assert_eq!(pq.try_dequeue(), Some(i));
+        }
+        // A few comparisons per dequeue (hash lookups), not one per entity.
+        assert!(EQS.load(Ordering::Relaxed) < 10 * 999);
+
+        // Its last item takes it out of the ring, from the front.
+        assert_eq!(pq.try_dequeue(), Some(999));
+        assert_eq!(pq.entities_at(0).unwrap().len(), 10_000);
+        assert!(pq.drain().into_iter().eq(1_001..=11_000));
+        pq.validate_rr().unwrap();
+    }
 }
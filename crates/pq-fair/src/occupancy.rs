@@ -0,0 +1,121 @@
+// Which levels of a PriorityQueue hold items, one bit per level, so that
+// finding the most urgent non-empty level skips empty ones 64 at a time
+// instead of visiting each.
+//
+// It also remembers the levels that ran empty since the level scan last
+// passed them over: the scan used to visit every level and reset the turn
+// counters of the empty ones it went through, and still does so for those,
+// without visiting the levels that stayed empty all along.
+#[derive(Clone)]
+pub(crate) struct Occupancy {
+    nonempty: BitSet,
+    emptied: BitSet,
+}
+
+impl Occupancy {
+    pub(crate) fn new(n_prio: usize) -> Self {
+        Self {
+            nonempty: BitSet::new(n_prio),
+            emptied: BitSet::new(n_prio),
+        }
+    }
+
+    // Level `prio` now holds `len` items.
+    pub(crate) fn update(&mut self, prio: usize, len: usize) {
+        if len > 0 {
+            self.nonempty.insert(prio);
+            self.emptied.remove(prio);
+        } else if self.nonempty.remove(prio) {
+            self.emptied.insert(prio);
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.nonempty.first_from(0).is_none()
+    }
+
+    // The most urgent non-empty level from `prio` on.
+    pub(crate) fn next_from(&self, prio: usize) -> Option<usize> {
+        self.nonempty.first_from(prio)
+    }
+
+    // Takes the levels below `end` that ran empty since last taken.
+    pub(crate) fn take_emptied(&mut self, end: usize) -> Vec<usize> {
+        let mut taken = Vec::new();
+        while let Some(prio) = self.emptied.first_from(0).filter(|&prio| prio < end) {
+            self.emptied.remove(prio);
+            taken.push(prio);
+        }
+        taken
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.nonempty.clear();
+        self.emptied.clear();
+    }
+
+    // Forgets the levels from `n_prio` on.
+    pub(crate) fn truncate(&mut self, n_prio: usize) {
+        self.nonempty.truncate(n_prio);
+        self.emptied.truncate(n_prio);
+    }
+}
+
+#[derive(Clone)]
+struct BitSet {
+    words: Vec<u64>,
+}
+
+impl BitSet {
+    fn new(n_bits: usize) -> Self {
+        Self {
+            words: vec![0; n_bits.div_ceil(64)],
+        }
+    }
+
+    fn insert(&mut self, bit: usize) {
+        let word = bit / 64;
+        if word >= self.words.len() {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (bit % 64);
+    }
+
+    // Returns whether the bit was set.
+    fn remove(&mut self, bit: usize) -> bool {
+        let Some(word) = self.words.get_mut(bit / 64) else {
+            return false;
+        };
+        let mask = 1 << (bit % 64);
+        let was_set = *word & mask != 0;
+        *word &= !mask;
+        was_set
+    }
+
+    // The lowest set bit from `bit` on.
+    fn first_from(&self, bit: usize) -> Option<usize> {
+        let mut idx = bit / 64;
+        let mut word = *self.words.get(idx)? & (u64::MAX << (bit % 64));
+        loop {
+            if word != 0 {
+                return Some(idx * 64 + word.trailing_zeros() as usize);
+            }
+            idx += 1;
+            word = *self.words.get(idx)?;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.words.fill(0);
+    }
+
+    // Clears the bits from `n_bits` on.
+    fn truncate(&mut self, n_bits: usize) {
+        self.words.truncate(n_bits.div_ceil(64));
+        if let Some(last) = self.words.last_mut()
+            && !n_bits.is_multiple_of(64)
+        {
+            *last &= (1 << (n_bits % 64)) - 1;
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{collections::BTreeMap, hash::Hash};
+
+use crate::{Entry, PriorityLevel, alloc::Global};
+
+// Fair priority queue keyed by any ordered priority, instead of a fixed
+// number of levels indexed by `usize`.
+//
+// Levels are created on first use and leave the map as soon as they run
+// empty, so sparse priorities (e.g. 10, 500, 10_000) cost nothing in
+// between, and every priority is valid: there is no BadPriority. Emptied
+// levels are kept aside and reused, maps and rings included, so a busy level
+// that drains and refills does not reallocate. Lower keys are served first;
+// within a level, entities take turns with the same code as in
+// `PriorityQueue`. Quotas, caps, weights and policies are not supported.
+pub struct OrdPriorityQueue<P, E, T>
+where
+    P: Ord,
+    E: Eq + Hash + Clone,
+{
+    levels: BTreeMap<P, PriorityLevel<E, T, Global>>,
+    // emptied levels, for reuse; never more than the peak number of levels
+    spare: Vec<PriorityLevel<E, T, Global>>,
+    len: usize,
+    next_seq: u64,
+}
+
+impl<P, E, T> OrdPriorityQueue<P, E, T>
+where
+    P: Ord,
+    E: Eq + Hash + Clone,
+{
+    // fn new
+    pub fn new() -> Self {
+        Self {
+            levels: BTreeMap::new(),
+            spare: Vec::new(),
+            len: 0,
+            next_seq: 0,
+        }
+    }
+
+    // fn n_levels
+    //
+    // Number of levels in use, i.e. of distinct priorities with items.
+    pub fn n_levels(&self) -> usize {
+        self.levels.len()
+    }
+
+    // fn is_empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // fn len
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // fn len_at_priority
+    pub fn len_at_priority(&self, prio: &P) -> usize {
+        self.levels.get(prio).map_or(0, |level| level.len)
+    }
+
+    // fn enqueue
+    pub fn enqueue(&mut self, prio: P, entity_id: E, item: T) {
+        let spare = &mut self.spare;
+        let level = self
+            .levels
+            .entry(prio)
+            .or_insert_with(|| spare.pop().unwrap_or_else(PriorityLevel::new));
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        level.push(entity_id, Entry { seq, item }, &mut None, &Global);
+        self.len += 1;
+    }
+
+    // fn try_dequeue
+    //
+    // Takes the next item of the lowest priority key, following the
+    // round-robin of its entities.
+    pub fn try_dequeue(&mut self) -> Option<T> {
+        let mut first = self.levels.first_entry()?;
+        let item = first
+            .get_mut()
+            .pop_turn(None, false, &mut None, |_, entry| entry.item)?;
+        if first.get().len == 0 {
+            self.spare.push(first.remove());
+        }
+        self.len -= 1;
+        Some(item)
+    }
+}
+
+impl<P, E, T> Default for OrdPriorityQueue<P, E, T>
+where
+    P: Ord,
+    E: Eq + Hash + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ord_queue_takes_any_ordered_priority() {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+        enum Urgency {
+            Now,
+            Later,
+        }
+
+        let mut pq = OrdPriorityQueue::new();
+        assert_eq!(pq.try_dequeue(), None);
+        pq.enqueue(Urgency::Later, "A", 1);
+        pq.enqueue(Urgency::Now, "A", 2);
+        pq.enqueue(Urgency::Now, "A", 3);
+        pq.enqueue(Urgency::Now, "B", 4);
+        assert_eq!(pq.len(), 4);
+        assert_eq!(pq.len_at_priority(&Urgency::Now), 3);
+        assert_eq!(pq.try_dequeue(), Some(2));
+        assert_eq!(pq.try_dequeue(), Some(4));
+        assert_eq!(pq.try_dequeue(), Some(3));
+        assert_eq!(pq.try_dequeue(), Some(1));
+        assert!(pq.is_empty());
+
+        // Sparse keys: only the levels in use exist.
+        let mut sparse = OrdPriorityQueue::default();
+        for prio in [10_000u32, 10, 500] {
+            sparse.enqueue(prio, (), prio);
+        }
+        assert_eq!(sparse.n_levels(), 3);
+        assert_eq!(sparse.try_dequeue(), Some(10));
+        assert_eq!(sparse.n_levels(), 2);
+        assert_eq!(sparse.try_dequeue(), Some(500));
+        assert_eq!(sparse.try_dequeue(), Some(10_000));
+        assert_eq!(sparse.n_levels(), 0);
+        // Emptied levels are kept for the next keys.
+        assert_eq!(sparse.spare.len(), 3);
+        sparse.enqueue(7, (), 7);
+        assert_eq!(sparse.spare.len(), 2);
+        assert_eq!(sparse.try_dequeue(), Some(7));
+    }
+}
@@ -0,0 +1,85 @@
+use std::time::{Duration, Instant};
+
+/// Default period between two adjustments of an adaptive capacity (see
+/// [`SyncPriorityQueueBuilder::adapt_every()`](crate::SyncPriorityQueueBuilder::adapt_every)).
+pub const DEFAULT_ADAPT_PERIOD: Duration = Duration::from_millis(100);
+
+// AIMD controller of the capacity of a bounded queue (see
+// `SyncPriorityQueue::with_adaptive_capacity`).
+//
+// There is no background thread: the controller is ticked by the queue
+// operations themselves, under the lock, and acts at most once per period.
+// Each period it looks at how full the queue is and at how fast it was
+// drained since the previous adjustment:
+//
+// - below the target fill, consumers keep up: the capacity grows by a fixed
+//   step (a sixteenth of the min..max range), up to `max`;
+// - above the target fill with a dequeue rate that did not improve, items
+//   wait longer and longer: the capacity is halved, down to `min`;
+// - above the target fill while consumers speed up, it is left alone.
+pub(crate) struct AdaptiveCapacity {
+    min: usize,
+    max: usize,
+    target_fill: f64,
+    period: Duration,
+    window_start: Instant,
+    // `total_dequeued` when the current window started
+    dequeued_at_start: u64,
+    // dequeues per second over the previous window
+    last_rate: f64,
+}
+
+impl AdaptiveCapacity {
+    pub(crate) fn new(min: usize, max: usize, target_fill: f64) -> Self {
+        Self {
+            min,
+            max,
+            target_fill,
+            period: DEFAULT_ADAPT_PERIOD,
+            window_start: Instant::now(),
+            dequeued_at_start: 0,
+            last_rate: 0.0,
+        }
+    }
+
+    pub(crate) fn min(&self) -> usize {
+        self.min
+    }
+
+    pub(crate) fn set_period(&mut self, period: Duration) {
+        self.period = period;
+    }
+
+    // Starts over, as on a fresh queue whose dequeue count is back to 0.
+    pub(crate) fn restart(&mut self) {
+        self.window_start = Instant::now();
+        self.dequeued_at_start = 0;
+        self.last_rate = 0.0;
+    }
+
+    // Returns the new capacity once a period has elapsed, given the current
+    // one, the number of items it is charged with, and the lifetime dequeue count.
+    pub(crate) fn tick(&mut self, cap: usize, len: usize, total_dequeued: u64) -> Option<usize> {
+        let elapsed = self.window_start.elapsed();
+        if elapsed < self.period {
+            return None;
+        }
+        // the count only goes down through a reset, which restarts the window
+        // too; saturate anyway rather than panic under the lock
+        let dequeued = total_dequeued.saturating_sub(self.dequeued_at_start);
+        let rate = dequeued as f64 / elapsed.as_secs_f64();
+        let fill = len as f64 / cap as f64;
+        let step = ((self.max - self.min) / 16).max(1);
+        let next = if fill < self.target_fill {
+            (cap + step).min(self.max)
+        } else if fill > self.target_fill && rate <= self.last_rate {
+            (cap / 2).max(self.min)
+        } else {
+            cap
+        };
+        self.window_start = Instant::now();
+        self.dequeued_at_start = total_dequeued;
+        self.last_rate = rate;
+        Some(next)
+    }
+}
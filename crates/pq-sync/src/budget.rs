@@ -0,0 +1,49 @@
+// Byte budget of a queue bounded in bytes rather than in items (see
+// `SyncPriorityQueue::with_byte_capacity`). Sizes come from a user function,
+// evaluated once when an item enters the queue and once when it leaves: it
+// must return the same size for the same item.
+pub(crate) struct ByteBudget<T> {
+    max: usize,
+    used: usize,
+    size_of: Box<dyn Fn(&T) -> usize + Send + Sync>,
+}
+
+impl<T> ByteBudget<T> {
+    pub(crate) fn new(max: usize, size_of: impl Fn(&T) -> usize + Send + Sync + 'static) -> Self {
+        Self {
+            max,
+            used: 0,
+            size_of: Box::new(size_of),
+        }
+    }
+
+    pub(crate) fn size(&self, item: &T) -> usize {
+        (self.size_of)(item)
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used
+    }
+
+    // Whether `item` can be added without exceeding the budget.
+    pub(crate) fn fits(&self, item: &T) -> bool {
+        self.used.saturating_add(self.size(item)) <= self.max
+    }
+
+    // Whether `item` can never fit, even in an empty queue.
+    pub(crate) fn exceeds(&self, item: &T) -> bool {
+        self.size(item) > self.max
+    }
+
+    pub(crate) fn add(&mut self, size: usize) {
+        self.used = self.used.saturating_add(size);
+    }
+
+    pub(crate) fn remove(&mut self, item: &T) {
+        self.used = self.used.saturating_sub(self.size(item));
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.used = 0;
+    }
+}
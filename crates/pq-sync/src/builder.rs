@@ -0,0 +1,300 @@
+use std::{collections::HashMap, hash::Hash, sync::Arc, time::Duration};
+
+use crate::{
+    Inner, PoisonPolicy, Policy, State, SyncPriorityQueue, adaptive::AdaptiveCapacity,
+    budget::ByteBudget, index::KeyIndex, oplog::OpLog, wakeup::WaiterQueue,
+};
+
+/// Default number of non-blocking attempts made by `dequeue()` before parking.
+pub const DEFAULT_SPIN: usize = 8;
+
+/// Builder for a [`SyncPriorityQueue`] with non-default options.
+///
+/// Obtained through [`SyncPriorityQueue::builder()`]. Each option is
+/// independent, so they can be freely combined:
+///
+/// ```no_run
+/// use pq_sync::SyncPriorityQueue;
+///
+/// let pq = SyncPriorityQueue::<String, String>::builder(3)
+///     .capacity(1024)
+///     .spin(32)
+///     .build();
+/// ```
+pub struct SyncPriorityQueueBuilder<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    st: State<E, T>,
+    spin: usize,
+    contention_probe: bool,
+    poison: PoisonPolicy,
+}
+
+impl<E, T> SyncPriorityQueueBuilder<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    pub(crate) fn new(n_prio: usize) -> Self {
+        assert!(n_prio > 0, "n_prio must be > 0");
+        Self {
+            st: State::new(n_prio),
+            spin: DEFAULT_SPIN,
+            contention_probe: false,
+            poison: PoisonPolicy::Error,
+        }
+    }
+
+    /// Bounds the queue to at most `cap` items (see [`SyncPriorityQueue::with_capacity()`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cap` is zero.
+    ///
+    pub fn capacity(mut self, cap: usize) -> Self {
+        assert!(cap > 0, "cap must be > 0");
+        self.st.cap = Some(cap);
+        self
+    }
+
+    /// Bounds the number of entities with pending items to `max` (see
+    /// [`SyncPriorityQueue::with_max_entities()`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max` is zero.
+    ///
+    pub fn max_entities(mut self, max: usize) -> Self {
+        assert!(max > 0, "max must be > 0");
+        self.st.max_entities = Some(max);
+        self.st.pq.set_entity_counts(true);
+        self
+    }
+
+    /// Reserves `min_per_entity` slots of the capacity to each entity (see
+    /// [`SyncPriorityQueue::with_fair_capacity()`]). Only meaningful with a
+    /// [`capacity()`](Self::capacity).
+    ///
+    pub fn reserved_per_entity(mut self, min_per_entity: usize) -> Self {
+        self.st.reserved = Some(min_per_entity);
+        self.st.pq.set_entity_counts(true);
+        self
+    }
+
+    /// Bounds the queue with a capacity tuned at runtime between `min` and
+    /// `max` items (see [`SyncPriorityQueue::with_adaptive_capacity()`]).
+    /// Replaces a fixed [`capacity()`](Self::capacity).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `min` is zero, if `max` is less than `min`, or if
+    /// `target_fill` is not in `(0, 1]`.
+    ///
+    pub fn adaptive_capacity(mut self, min: usize, max: usize, target_fill: f64) -> Self {
+        assert!(min > 0, "min must be > 0");
+        assert!(max >= min, "max must be >= min");
+        assert!(
+            target_fill > 0.0 && target_fill <= 1.0,
+            "target_fill must be in (0, 1]"
+        );
+        self.st.cap = Some(min);
+        self.st.adaptive = Some(AdaptiveCapacity::new(min, max, target_fill));
+        self
+    }
+
+    /// Sets the period between two adjustments of an adaptive capacity
+    /// (default: [`DEFAULT_ADAPT_PERIOD`](crate::DEFAULT_ADAPT_PERIOD)).
+    ///
+    /// # Panics
+    ///
+    /// Panics if no [`adaptive_capacity()`](Self::adaptive_capacity) was set before.
+    ///
+    pub fn adapt_every(mut self, period: Duration) -> Self {
+        self.st
+            .adaptive
+            .as_mut()
+            .expect("adapt_every requires an adaptive capacity")
+            .set_period(period);
+        self
+    }
+
+    /// Bounds the summed size of the queued items to `max_bytes`, each item
+    /// being sized by `size_fn` (see [`SyncPriorityQueue::with_byte_capacity()`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_bytes` is zero.
+    ///
+    pub fn byte_capacity(
+        mut self,
+        max_bytes: usize,
+        size_fn: impl Fn(&T) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        assert!(max_bytes > 0, "max_bytes must be > 0");
+        self.st.bytes = Some(ByteBudget::new(max_bytes, size_fn));
+        self
+    }
+
+    /// Indexes the items by the key `key_fn` gives them, for
+    /// [`SyncPriorityQueue::cancel_by_key()`] (see
+    /// [`SyncPriorityQueue::with_index()`]).
+    ///
+    pub fn index<K>(mut self, key_fn: impl Fn(&T) -> K + Send + Sync + 'static) -> Self
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        E: Send + Sync + 'static,
+        T: 'static,
+    {
+        self.st.index = Some(Box::new(KeyIndex::new(key_fn)));
+        self
+    }
+
+    /// Bounds priority level `prio` to at most `cap` items, independently of
+    /// the other levels (see [`SyncPriorityQueue::enqueue_blocking()`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `cap` is zero or `prio` is not a valid level.
+    ///
+    pub fn level_capacity(mut self, prio: usize, cap: usize) -> Self {
+        assert!(cap > 0, "cap must be > 0");
+        let n_prio = self.st.level_caps.len();
+        let Some(slot) = self.st.level_caps.get_mut(prio) else {
+            panic!("prio must be < {n_prio}");
+        };
+        *slot = Some(cap);
+        self
+    }
+
+    /// Lets each level serve a bounded number of consecutive items before
+    /// yielding one turn to the next non-empty level (see
+    /// [`SyncPriorityQueue::with_level_quotas()`]).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `quotas` does not hold exactly one non-zero quota per level.
+    ///
+    pub fn level_quotas(mut self, quotas: Vec<usize>) -> Self {
+        assert_eq!(quotas.len(), self.st.pq.n_prio(), "one quota per level");
+        assert!(quotas.iter().all(|&q| q > 0), "quotas must be > 0");
+        self.st
+            .pq
+            .set_level_quotas(quotas)
+            .expect("one quota per level");
+        self
+    }
+
+    /// Sets the scheduling policy of the underlying fair queue (default:
+    /// [`Policy::StrictPriority`]).
+    ///
+    /// With [`Policy::GlobalEntityFair`], entities take turns across the whole
+    /// queue rather than per level, each turn serving the entity's most urgent
+    /// item; level quotas are then ignored.
+    ///
+    /// With [`Policy::DecayingPriority`], levels are served in priority order,
+    /// but each dequeue penalizes the served level by `decay_permille`
+    /// thousandths of a level, so that a constantly busy level periodically
+    /// yields a turn to less urgent ones.
+    ///
+    pub fn policy(mut self, policy: Policy) -> Self {
+        self.st.pq.set_policy(policy);
+        self
+    }
+
+    /// Recycles the per-entity deques of the underlying fair queue instead of
+    /// reallocating them each time an entity becomes active again.
+    ///
+    /// Worth enabling when many entities keep coming and going: it trades a
+    /// little retained memory (at most one empty deque per entity that was
+    /// active at the peak) for less allocator churn on the hot path.
+    ///
+    pub fn deque_pool(mut self) -> Self {
+        self.st.pq.set_deque_pool(true);
+        self
+    }
+
+    /// Sets how many non-blocking attempts `dequeue()` makes before parking
+    /// the thread (default: [`DEFAULT_SPIN`]).
+    ///
+    /// Under load, an item often arrives within microseconds: spinning briefly
+    /// avoids the cost of a park/unpark round-trip. Spinning threads poll a
+    /// lock-free depth hint, and take the lock only once it shows an item.
+    /// `0` disables spinning.
+    ///
+    pub fn spin(mut self, spins: usize) -> Self {
+        self.spin = spins;
+        self
+    }
+
+    /// Wakes parked consumers in FIFO order: when an item arrives, the
+    /// consumer that has been waiting the longest gets it.
+    ///
+    /// By default, all parked consumers share one condvar and the OS picks
+    /// which one wakes up, which often is the same few threads: work ends up
+    /// unevenly spread across a worker pool. Fair wakeup keeps one wait slot
+    /// per parked consumer, at the cost of a small allocation per park.
+    /// Only parked consumers are ordered: one that finds an item during its
+    /// spin phase (see [`spin()`](Self::spin)) takes it without queueing.
+    ///
+    pub fn fair_wakeup(mut self) -> Self {
+        self.st.waiters = Some(WaiterQueue::new());
+        self
+    }
+
+    /// Counts the lock acquisitions that had to wait (see
+    /// [`SyncPriorityQueue::contention_count()`]). Disabled by default.
+    ///
+    /// Each acquisition then tries the lock once before blocking on it, a
+    /// small overhead on every operation.
+    ///
+    pub fn contention_probe(mut self) -> Self {
+        self.contention_probe = true;
+        self
+    }
+
+    /// Enables stats mode: counts dequeued items per entity, for
+    /// [`SyncPriorityQueue::fairness_report()`]. Disabled by default.
+    ///
+    /// Dequeues then look up the entity of every item they hand out, and the
+    /// counts grow with the number of distinct entities ever served.
+    ///
+    pub fn entity_stats(mut self) -> Self {
+        self.st.served = Some(HashMap::new());
+        self
+    }
+
+    /// Sets what every operation does once the internal lock is poisoned by
+    /// a thread that panicked while holding it (default:
+    /// [`PoisonPolicy::Error`]). See [`PoisonPolicy`] for the consistency
+    /// caveats of recovering.
+    ///
+    pub fn poison_policy(mut self, policy: PoisonPolicy) -> Self {
+        self.poison = policy;
+        self
+    }
+
+    /// Records the last `max_len` operations in an op log (see
+    /// [`SyncPriorityQueue::op_log()`]). Disabled by default.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_len` is zero.
+    ///
+    pub fn op_log(mut self, max_len: usize) -> Self {
+        assert!(max_len > 0, "max_len must be > 0");
+        self.st.op_log = Some(OpLog::new(max_len));
+        self
+    }
+
+    /// Creates the queue.
+    pub fn build(self) -> SyncPriorityQueue<E, T> {
+        SyncPriorityQueue {
+            inner: Arc::new(Inner::new(
+                self.st,
+                self.spin,
+                self.contention_probe,
+                self.poison,
+            )),
+        }
+    }
+}
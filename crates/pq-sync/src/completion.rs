@@ -0,0 +1,122 @@
+use std::{
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use pq_core::{PriorityQueueError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Pending,
+    Dequeued,
+    Dropped,
+}
+
+struct Signal {
+    outcome: Mutex<Outcome>,
+    cv: Condvar,
+}
+
+impl Signal {
+    // The outcome is a plain value: a panic elsewhere cannot leave it torn.
+    fn lock(&self) -> MutexGuard<'_, Outcome> {
+        self.outcome.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Resolves when the item enqueued by
+/// [`SyncPriorityQueue::enqueue_notified()`](crate::SyncPriorityQueue::enqueue_notified)
+/// is pulled by a consumer.
+///
+/// The handle is independent from the queue: it can be moved to another
+/// thread, and waiting on it never blocks producers or consumers.
+pub struct CompletionHandle {
+    signal: Arc<Signal>,
+}
+
+impl CompletionHandle {
+    /// Blocks until the item is dequeued.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the item left the queue without
+    ///   being dequeued (removed, evicted, or dropped at shutdown).
+    ///
+    pub fn wait(&self) -> Result<()> {
+        let outcome = self
+            .signal
+            .cv
+            .wait_while(self.signal.lock(), |o| *o == Outcome::Pending)
+            .unwrap_or_else(PoisonError::into_inner);
+        Self::result(*outcome)
+    }
+
+    /// Same as [`wait()`](Self::wait), but gives up after `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Timeout`] — if the item is still pending after `timeout`.
+    /// * [`PriorityQueueError::Closed`] — if the item left the queue without being dequeued.
+    ///
+    pub fn wait_timeout(&self, timeout: Duration) -> Result<()> {
+        let (outcome, _) = self
+            .signal
+            .cv
+            .wait_timeout_while(self.signal.lock(), timeout, |o| *o == Outcome::Pending)
+            .unwrap_or_else(PoisonError::into_inner);
+        Self::result(*outcome)
+    }
+
+    /// Returns `true` once the item has been dequeued.
+    pub fn is_dequeued(&self) -> bool {
+        *self.signal.lock() == Outcome::Dequeued
+    }
+
+    fn result(outcome: Outcome) -> Result<()> {
+        match outcome {
+            Outcome::Pending => Err(PriorityQueueError::Timeout),
+            Outcome::Dequeued => Ok(()),
+            Outcome::Dropped => Err(PriorityQueueError::Closed),
+        }
+    }
+}
+
+// Queue side of a CompletionHandle, kept in the state next to the item.
+// Dropping it without firing resolves the handle as dropped, so that a
+// waiter never hangs on an item that is gone.
+pub(crate) struct Completion {
+    signal: Arc<Signal>,
+}
+
+impl Completion {
+    pub(crate) fn new() -> (Self, CompletionHandle) {
+        let signal = Arc::new(Signal {
+            outcome: Mutex::new(Outcome::Pending),
+            cv: Condvar::new(),
+        });
+        let handle = CompletionHandle {
+            signal: Arc::clone(&signal),
+        };
+        (Self { signal }, handle)
+    }
+
+    pub(crate) fn fire(self) {
+        self.resolve(Outcome::Dequeued);
+    }
+
+    fn resolve(&self, outcome: Outcome) {
+        let mut current = self.signal.lock();
+        if *current == Outcome::Pending {
+            *current = outcome;
+            self.signal.cv.notify_all();
+        }
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        self.resolve(Outcome::Dropped);
+    }
+}
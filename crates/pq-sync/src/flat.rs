@@ -0,0 +1,204 @@
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+use pq_core::{IntoPriority, PriorityQueueError, Result};
+use pq_fair::FlatPriorityQueue;
+
+/// A thread-safe strict priority queue without entity fairness.
+///
+/// Shares a [`FlatPriorityQueue`] between threads, the way
+/// [`SyncPriorityQueue`](crate::SyncPriorityQueue) shares a fair
+/// `PriorityQueue`: items of a level come out in arrival order, whoever
+/// produced them. For configurations that don't need per-entity round-robin
+/// (e.g. a single producer entity), this skips its bookkeeping entirely.
+///
+/// It implements the generic [`Queue`](crate::Queue) and
+/// [`BlockingQueue`](crate::BlockingQueue) traits (the entity argument is
+/// ignored), so that code written against them runs on either queue. The
+/// extras of `SyncPriorityQueue` (capacities, leases, op log...) are not
+/// available.
+///
+/// # Examples
+///
+/// ```no_run
+/// use pq_sync::SyncFlatPriorityQueue;
+///
+/// let pq = SyncFlatPriorityQueue::new(2);
+/// pq.enqueue(1, "later").unwrap();
+/// pq.enqueue(0, "first").unwrap();
+///
+/// assert_eq!(pq.dequeue().unwrap(), "first");
+/// assert_eq!(pq.try_dequeue().unwrap(), Some("later"));
+/// ```
+pub struct SyncFlatPriorityQueue<T> {
+    inner: Arc<Inner<T>>,
+}
+
+struct Inner<T> {
+    state: Mutex<State<T>>,
+    cv: Condvar,
+}
+
+struct State<T> {
+    pq: FlatPriorityQueue<T>,
+    closed: bool,
+}
+
+impl<T> Clone for SyncFlatPriorityQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<T> SyncFlatPriorityQueue<T> {
+    /// Creates a queue with `n_prio` priority levels.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` is zero, as at least one level is required.
+    ///
+    pub fn new(n_prio: usize) -> Self {
+        assert!(n_prio > 0, "n_prio must be > 0");
+        Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    pq: FlatPriorityQueue::new(n_prio),
+                    closed: false,
+                }),
+                cv: Condvar::new(),
+            }),
+        }
+    }
+
+    fn lock(&self, op: &'static str) -> Result<MutexGuard<'_, State<T>>> {
+        self.inner
+            .state
+            .lock()
+            .map_err(|_| PriorityQueueError::LockError(op))
+    }
+
+    /// Enqueues an item at level `prio` and wakes one waiting consumer.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn enqueue(&self, prio: impl IntoPriority, item: T) -> Result<()> {
+        let mut st = self.lock("enqueue")?;
+        if st.closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        st.pq.enqueue(prio.as_index(), item)?;
+        drop(st);
+        self.inner.cv.notify_one();
+        Ok(())
+    }
+
+    /// Takes the next item without blocking, or `Ok(None)` if the queue is empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn try_dequeue(&self) -> Result<Option<T>> {
+        let mut st = self.lock("try_dequeue")?;
+        let item = st.pq.try_dequeue();
+        self.unlock_dequeued(st);
+        Ok(item)
+    }
+
+    /// Takes the next item, blocking while the queue is empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — once the queue is closed and drained.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn dequeue(&self) -> Result<T> {
+        let st = self.lock("dequeue")?;
+        let mut st = self
+            .inner
+            .cv
+            .wait_while(st, |s| s.pq.is_empty() && !s.closed)
+            .map_err(|_| PriorityQueueError::LockError("dequeue"))?;
+        let item = st.pq.try_dequeue().ok_or(PriorityQueueError::Closed)?;
+        self.unlock_dequeued(st);
+        Ok(item)
+    }
+
+    // Releases the lock; once the queue is empty, wakes everyone up so that a
+    // graceful shutdown sees the drain.
+    fn unlock_dequeued(&self, st: MutexGuard<'_, State<T>>) {
+        let empty = st.pq.is_empty();
+        drop(st);
+        if empty {
+            self.inner.cv.notify_all();
+        }
+    }
+
+    /// Number of queued items.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn len(&self) -> Result<usize> {
+        Ok(self.lock("len")?.pq.len())
+    }
+
+    /// Whether no item is queued.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn is_empty(&self) -> Result<bool> {
+        Ok(self.lock("is_empty")?.pq.is_empty())
+    }
+
+    /// Closes the queue and drops the pending items; blocked consumers fail
+    /// with [`PriorityQueueError::Closed`].
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn shutdown_immediate(&self) -> Result<()> {
+        let mut st = self.lock("shutdown_immediate")?;
+        st.closed = true;
+        while st.pq.try_dequeue().is_some() {}
+        drop(st);
+        self.inner.cv.notify_all();
+        Ok(())
+    }
+
+    /// Closes the queue to producers and blocks until consumers have drained it.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn shutdown_graceful(&self) -> Result<()> {
+        let mut st = self.lock("shutdown_graceful")?;
+        st.closed = true;
+        self.inner.cv.notify_all();
+        st = self
+            .inner
+            .cv
+            .wait_while(st, |s| !s.pq.is_empty())
+            .map_err(|_| PriorityQueueError::LockError("shutdown_graceful"))?;
+        drop(st);
+        self.inner.cv.notify_all();
+        Ok(())
+    }
+}
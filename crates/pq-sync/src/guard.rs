@@ -0,0 +1,83 @@
+use std::{
+    fmt,
+    hash::Hash,
+    ops::{Deref, DerefMut},
+    sync::PoisonError,
+};
+
+use crate::{Freed, SyncPriorityQueue};
+
+/// An item dequeued by
+/// [`SyncPriorityQueue::dequeue_guarded()`](crate::SyncPriorityQueue::dequeue_guarded),
+/// still holding its capacity slot.
+///
+/// The guard derefs to the item. The slot is given back to producers when
+/// the guard is dropped, including while unwinding from a panic in the code
+/// processing the item, so that a failing consumer cannot leak capacity.
+pub struct ItemGuard<'a, T> {
+    item: T,
+    // None when the queue is unbounded: there is no slot to give back.
+    // Otherwise, the queue and its held epoch when the slot was taken.
+    slot: Option<(&'a dyn Slot, u64)>,
+}
+
+impl<'a, T> ItemGuard<'a, T> {
+    pub(crate) fn new(item: T, slot: Option<(&'a dyn Slot, u64)>) -> Self {
+        Self { item, slot }
+    }
+}
+
+impl<T> Deref for ItemGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.item
+    }
+}
+
+impl<T> DerefMut for ItemGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.item
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for ItemGuard<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("ItemGuard").field(&self.item).finish()
+    }
+}
+
+impl<T> Drop for ItemGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some((slot, epoch)) = self.slot {
+            slot.release(epoch);
+        }
+    }
+}
+
+// Capacity slot held by an ItemGuard, erasing the entity type of the queue.
+pub(crate) trait Slot {
+    // Gives the slot back, unless the queue was reset since `epoch`.
+    fn release(&self, epoch: u64);
+}
+
+impl<E, T> Slot for SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    // Runs from Drop, possibly while unwinding: a poisoned lock must not
+    // keep the slot from being freed. The freed slot wakes threads up the
+    // same way a dequeue does.
+    fn release(&self, epoch: u64) {
+        let mut st = self
+            .inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if st.held_epoch != epoch {
+            return;
+        }
+        st.held -= 1;
+        self.unlock_dequeued(st, 1, Freed::Slot);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{
+    any::Any,
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+
+// Secondary index of the queued items by a user key (see
+// `SyncPriorityQueue::with_index`), type-erased so that the key type does not
+// show in the queue's own type: `cancel_by_key` gets it back by downcasting.
+pub(crate) trait ItemIndex<E, T>: Send + Sync {
+    // A new item was queued as `seq`, by `entity_id` at level `prio`.
+    fn insert(&mut self, prio: usize, entity_id: &E, seq: u64, item: &T);
+
+    // `item` left the queue, by whatever path.
+    fn remove(&mut self, item: &T);
+
+    // Item `seq` (`item`) now belongs to `to`, at the same level.
+    fn reassign(&mut self, seq: u64, to: &E, item: &T);
+
+    // Takes every location recorded for `key`, stale ones included; None if
+    // `key` is not of the index's key type.
+    fn take(&mut self, key: &dyn Any) -> Option<Vec<(usize, E, u64)>>;
+
+    // Whether enough locations went stale to be worth a `compact`.
+    fn wants_compaction(&self) -> bool;
+
+    // Drops the locations whose sequence number is not in `live`.
+    fn compact(&mut self, live: &HashSet<u64>);
+
+    fn clear(&mut self);
+}
+
+// The locations of the items sharing a key.
+//
+// An item leaving the queue (dequeued, dropped, evicted...) only decrements
+// `live`: most paths do not report which item left, so its location stays
+// behind, stale, until `live` drops to 0 (the key goes away) or the index is
+// compacted. A cancel tries every location: stale ones find nothing.
+struct Slots<E> {
+    live: usize,
+    at: Vec<(usize, E, u64)>,
+}
+
+pub(crate) struct KeyIndex<T, K, E> {
+    key_of: Box<dyn Fn(&T) -> K + Send + Sync>,
+    keys: HashMap<K, Slots<E>>,
+    // queued items, i.e. sum of `live`
+    live: usize,
+    // stale locations, i.e. sum of `at.len() - live`
+    stale: usize,
+}
+
+impl<T, K, E> KeyIndex<T, K, E> {
+    pub(crate) fn new(key_of: impl Fn(&T) -> K + Send + Sync + 'static) -> Self {
+        Self {
+            key_of: Box::new(key_of),
+            keys: HashMap::new(),
+            live: 0,
+            stale: 0,
+        }
+    }
+}
+
+impl<T, K, E> ItemIndex<E, T> for KeyIndex<T, K, E>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    E: Clone + Send + Sync,
+{
+    fn insert(&mut self, prio: usize, entity_id: &E, seq: u64, item: &T) {
+        let slots = self.keys.entry((self.key_of)(item)).or_insert(Slots {
+            live: 0,
+            at: Vec::new(),
+        });
+        slots.live += 1;
+        slots.at.push((prio, entity_id.clone(), seq));
+        self.live += 1;
+    }
+
+    fn remove(&mut self, item: &T) {
+        let key = (self.key_of)(item);
+        let Some(slots) = self.keys.get_mut(&key) else {
+            return;
+        };
+        slots.live -= 1;
+        self.live -= 1;
+        if slots.live == 0 {
+            self.stale -= slots.at.len() - 1;
+            self.keys.remove(&key);
+        } else {
+            self.stale += 1;
+        }
+    }
+
+    fn reassign(&mut self, seq: u64, to: &E, item: &T) {
+        let key = (self.key_of)(item);
+        if let Some(slots) = self.keys.get_mut(&key)
+            && let Some(slot) = slots.at.iter_mut().find(|(_, _, s)| *s == seq)
+        {
+            slot.1 = to.clone();
+        }
+    }
+
+    fn take(&mut self, key: &dyn Any) -> Option<Vec<(usize, E, u64)>> {
+        let key = key.downcast_ref::<K>()?;
+        let Some(slots) = self.keys.remove(key) else {
+            return Some(Vec::new());
+        };
+        self.live -= slots.live;
+        self.stale -= slots.at.len() - slots.live;
+        Some(slots.at)
+    }
+
+    // Compacting costs a pass over the queue: only once stale locations
+    // outnumber the live ones, so that it is paid for by the removals.
+    fn wants_compaction(&self) -> bool {
+        self.stale > self.live.max(64)
+    }
+
+    fn compact(&mut self, live: &HashSet<u64>) {
+        for slots in self.keys.values_mut() {
+            slots.at.retain(|(_, _, seq)| live.contains(seq));
+        }
+        self.stale = 0;
+    }
+
+    fn clear(&mut self) {
+        self.keys.clear();
+        self.live = 0;
+        self.stale = 0;
+    }
+}
@@ -0,0 +1,28 @@
+use std::{fmt, time::Instant};
+
+/// Identifies an item handed out by
+/// [`SyncPriorityQueue::dequeue_lease()`](crate::SyncPriorityQueue::dequeue_lease)
+/// until it is acked or nacked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct LeaseId(pub(crate) u64);
+
+impl LeaseId {
+    /// Returns the raw id, as reported by [`PriorityQueueError::UnknownLease`](pq_core::PriorityQueueError::UnknownLease).
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+impl fmt::Display for LeaseId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// An in-flight item, with what is needed to put it back where it came from.
+pub(crate) struct Lease<E, T> {
+    pub(crate) prio: usize,
+    pub(crate) entity_id: E,
+    pub(crate) item: T,
+    pub(crate) since: Instant,
+}
@@ -1,18 +1,124 @@
 use std::{
-    hash::Hash,
-    sync::{Arc, Condvar, Mutex},
-    time::Duration,
+    collections::{HashMap, hash_map::RandomState},
+    hash::{BuildHasher, Hash, Hasher},
+    hint,
+    ops::{Bound, RangeBounds},
+    sync::{
+        Arc, Condvar, LockResult, Mutex, MutexGuard, PoisonError, TryLockError,
+        atomic::{AtomicU64, Ordering},
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
 use pq_core::{PriorityQueueError, Result};
 use pq_fair::PriorityQueue;
 
+mod adaptive;
+mod budget;
+mod builder;
+mod completion;
+mod flat;
+mod guard;
+mod index;
+mod lease;
+mod metrics;
+mod mpsc;
+mod oplog;
+#[cfg(feature = "bytes")]
+mod payload;
+mod poison;
+mod queue;
+mod rate;
+mod removal;
+mod semaphore;
+mod wakeup;
+mod watch;
+
+use adaptive::AdaptiveCapacity;
+use budget::ByteBudget;
+use completion::Completion;
+use index::ItemIndex;
+use lease::Lease;
+use metrics::Gauges;
+use oplog::OpLog;
+use rate::DequeueRate;
+use removal::{RemoveHook, Removed};
+use wakeup::WaiterQueue;
+
+pub use adaptive::DEFAULT_ADAPT_PERIOD;
+pub use builder::{DEFAULT_SPIN, SyncPriorityQueueBuilder};
+pub use completion::CompletionHandle;
+pub use flat::SyncFlatPriorityQueue;
+pub use guard::ItemGuard;
+pub use lease::LeaseId;
+pub use metrics::QueueMetrics;
+pub use mpsc::MpscCompat;
+pub use oplog::{DEFAULT_OP_LOG_LEN, Op, OpRecord};
+#[cfg(feature = "bytes")]
+pub use payload::BytesPriorityQueue;
+pub use poison::PoisonPolicy;
+pub use pq_core::{BlockingQueue, DequeueOutcome, IntoPriority, Queue};
+pub use pq_fair::Policy;
+pub use removal::RemovalReason;
+pub use semaphore::Semaphore;
+pub use watch::{DepthEvent, DepthWatcher};
+
 struct State<E, T>
 where
     E: Eq + Hash + Clone,
 {
     pq: PriorityQueue<E, T>,
     closed: bool,
+    // set once `shutdown_graceful_then_fence` saw the queue drained: no item
+    // can be dequeued anymore
+    fenced: bool,
+    cap: Option<usize>,
+    // Some when `cap` is tuned at runtime
+    adaptive: Option<AdaptiveCapacity>,
+    // Some when each entity has a reserved number of slots within `cap`
+    reserved: Option<usize>,
+    // Some when the number of entities with pending items is bounded
+    max_entities: Option<usize>,
+    // items handed out by `dequeue_guarded` whose guard is still alive: they
+    // keep their capacity slot
+    held: usize,
+    // bumped by `reset`, which forgets held slots: guards from before it
+    // have nothing left to give back
+    held_epoch: u64,
+    depth_watchers: usize,
+    floor_waiters: usize,
+    // consumers blocked in `dequeue_from`, waiting on a single level
+    level_consumers: usize,
+    level_caps: Vec<Option<usize>>,
+    level_waiters: Vec<usize>,
+    // sum of `level_waiters`
+    level_waiting: usize,
+    // level of the last item taken by `pop` and its variants
+    popped_at: usize,
+    leases: HashMap<LeaseId, Lease<E, T>>,
+    next_lease: u64,
+    last_dequeue: Option<Instant>,
+    op_log: Option<OpLog<E>>,
+    total_enqueued: u64,
+    total_dequeued: u64,
+    total_removed: u64,
+    // Some when parked consumers are woken in FIFO order
+    waiters: Option<WaiterQueue>,
+    // completions of items enqueued with `enqueue_notified`, by sequence number
+    notifiers: HashMap<u64, Completion>,
+    // Some in stats mode: items dequeued so far, per entity
+    served: Option<HashMap<E, u64>>,
+    // Some when the capacity is measured in bytes
+    bytes: Option<ByteBudget<T>>,
+    // moving average of the dequeue rate, for `estimated_wait`
+    rate: DequeueRate,
+    // Some when items are indexed by a secondary key
+    index: Option<Box<dyn ItemIndex<E, T>>>,
+    // Some when items removed without being dequeued are reported
+    on_remove: Option<RemoveHook<E, T>>,
+    // items removed under the lock, to hand to `on_remove` once it is released
+    removed: Vec<(RemovalReason, usize, E, T)>,
 }
 
 impl<E, T> State<E, T>
@@ -23,26 +129,531 @@ where
         Self {
             pq: PriorityQueue::new(n_prio),
             closed: false,
+            fenced: false,
+            cap: None,
+            adaptive: None,
+            reserved: None,
+            max_entities: None,
+            held: 0,
+            held_epoch: 0,
+            depth_watchers: 0,
+            floor_waiters: 0,
+            level_consumers: 0,
+            level_caps: vec![None; n_prio],
+            level_waiters: vec![0; n_prio],
+            level_waiting: 0,
+            popped_at: 0,
+            leases: HashMap::new(),
+            next_lease: 0,
+            last_dequeue: None,
+            op_log: None,
+            total_enqueued: 0,
+            total_dequeued: 0,
+            total_removed: 0,
+            waiters: None,
+            notifiers: HashMap::new(),
+            served: None,
+            bytes: None,
+            rate: DequeueRate::new(),
+            index: None,
+            on_remove: None,
+            removed: Vec::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.cap.is_some_and(|cap| self.pq.len() + self.held >= cap)
+    }
+
+    // Lets an adaptive capacity adjust itself, once per period.
+    fn adapt(&mut self) {
+        let Some(adaptive) = &mut self.adaptive else {
+            return;
+        };
+        let cap = self.cap.unwrap_or(adaptive.min());
+        let len = self.pq.len() + self.held;
+        if let Some(cap) = adaptive.tick(cap, len, self.total_dequeued) {
+            self.cap = Some(cap);
+        }
+    }
+
+    // Fair capacity: makes room in a full queue for an entity below its
+    // reserved slots, by dropping the most recent item of the least urgent
+    // level of the entity with the most queued items, if that one is above
+    // its reservation. Returns whether a slot was freed. The caller must
+    // only call this once the enqueue is otherwise sure to succeed. The fair
+    // queue keeps per-entity counts under a reservation, so this costs a
+    // scan of the active entities, without allocating.
+    fn evict_for(&mut self, entity_id: &E) -> bool {
+        let Some(reserved) = self.reserved else {
+            return false;
+        };
+        if !self.is_full() || self.pq.entity_len(entity_id) >= reserved {
+            return false;
+        }
+        let Some(victim) = self.pq.entity_lens().and_then(|lens| {
+            lens.filter(|&(entity, n)| entity != entity_id && n > reserved)
+                .max_by_key(|&(_, n)| n)
+                .map(|(entity, _)| entity.clone())
+        }) else {
+            return false;
+        };
+        let n_prio = self.pq.n_prio();
+        let Some((prio, seq, old)) =
+            self.pq
+                .remove_lowest_of_entity_seq(0..n_prio, &victim, 1, |_| 0)
+        else {
+            return false;
+        };
+        self.settle_notifier(seq, false);
+        self.total_removed += 1;
+        self.release(&old);
+        self.discard(RemovalReason::Overflow, prio, victim, old);
+        true
+    }
+
+    // Entity cap: an entity with no pending item at any level is only
+    // admitted while fewer than `max_entities` entities have some. The fair
+    // queue keeps per-entity counts under a cap, so this is O(1).
+    fn admit(&self, entity_id: &E) -> Result<()> {
+        let Some(max) = self.max_entities else {
+            return Ok(());
+        };
+        if self.pq.contains_entity(entity_id) {
+            return Ok(());
+        }
+        if self.pq.n_entities() >= max {
+            return Err(PriorityQueueError::TooManyEntities);
+        }
+        Ok(())
+    }
+
+    // Whether the byte budget, if any, has room for `item`.
+    fn fits(&self, item: &T) -> bool {
+        self.bytes.as_ref().is_none_or(|bytes| bytes.fits(item))
+    }
+
+    // Accounts for an item that left the queue, if bytes are budgeted or
+    // items indexed.
+    fn release(&mut self, item: &T) {
+        if let Some(bytes) = &mut self.bytes {
+            bytes.remove(item);
+        }
+        if let Some(index) = &mut self.index {
+            index.remove(item);
+        }
+        self.compact_index();
+    }
+
+    // Drops the index's stale locations once they outnumber the live ones.
+    // A pass over the queue, paid for by the removals that left them behind:
+    // enqueues never pay for it.
+    fn compact_index(&mut self) {
+        if let Some(index) = &mut self.index
+            && index.wants_compaction()
+        {
+            index.compact(&self.pq.seqs().collect());
+        }
+    }
+
+    // Drops an item removed without being dequeued, or keeps it for the
+    // removal hook, if any.
+    fn discard(&mut self, reason: RemovalReason, prio: usize, entity_id: E, item: T) {
+        if self.on_remove.is_some() {
+            self.removed.push((reason, prio, entity_id, item));
+        }
+    }
+
+    // The items kept for the removal hook so far. The caller must drop the
+    // result after releasing the lock: that is when the hook runs.
+    fn take_removed(&mut self) -> Removed<E, T> {
+        if self.removed.is_empty() {
+            return Removed::none();
+        }
+        Removed::new(self.on_remove.clone(), std::mem::take(&mut self.removed))
+    }
+
+    fn is_level_full(&self, prio: usize) -> bool {
+        self.level_caps
+            .get(prio)
+            .copied()
+            .flatten()
+            .is_some_and(|cap| self.pq.len_at_priority(prio).unwrap_or(0) >= cap)
+    }
+
+    // True once every level more urgent than `min_prio` is empty.
+    fn is_drained_above(&self, min_prio: usize) -> bool {
+        (0..min_prio).all(|prio| self.pq.is_level_empty(prio).unwrap_or(true))
+    }
+
+    // Enqueues, recording the operation if the op log is enabled.
+    // Returns the item's sequence number.
+    fn push(&mut self, prio: usize, entity_id: E, item: T) -> Result<u64> {
+        self.adapt();
+        if self.pq.is_empty() {
+            self.rate.restart(Instant::now());
+        }
+        let size = self.bytes.as_ref().map(|bytes| bytes.size(&item));
+        let indexed = self.index.as_ref().map(|_| entity_id.clone());
+        let seq = match &mut self.op_log {
+            None => self.pq.enqueue_seq(prio, entity_id, item)?,
+            Some(log) => {
+                let entity = entity_id.clone();
+                let seq = self.pq.enqueue_seq(prio, entity_id, item)?;
+                log.push(Op::Enqueue { prio, entity });
+                seq
+            }
+        };
+        if let (Some(bytes), Some(size)) = (&mut self.bytes, size) {
+            bytes.add(size);
+        }
+        if let (Some(index), Some(entity_id)) = (&mut self.index, indexed)
+            && let Some(item) = self.pq.back_of_entity(prio, &entity_id)?
+        {
+            index.insert(prio, &entity_id, seq, item);
+        }
+        self.total_enqueued += 1;
+        Ok(seq)
+    }
+
+    // Dequeues, recording the operation if the op log is enabled.
+    fn pop(&mut self) -> Option<T> {
+        if self.fenced {
+            return None;
+        }
+        if !self.notifiers.is_empty() || self.served.is_some() {
+            return self.pop_tagged().map(|(_, _, item)| item);
+        }
+        let (prio, item) = match &mut self.op_log {
+            None => self.pq.try_dequeue_with(|_, prio, _, item| (prio, item))?,
+            Some(log) => {
+                let (prio, entity, item) = self.pq.try_dequeue_tagged()?;
+                log.push(Op::Dequeue { prio, entity });
+                (prio, item)
+            }
+        };
+        self.popped_at = prio;
+        self.note_dequeued(1);
+        self.release(&item);
+        Some(item)
+    }
+
+    // Same as `pop`, but also tells where the item came from.
+    fn pop_tagged(&mut self) -> Option<(usize, E, T)> {
+        if self.fenced {
+            return None;
+        }
+        let (prio, entity_id, item) = if self.notifiers.is_empty() {
+            self.pq.try_dequeue_tagged()?
+        } else {
+            let (seq, prio, entity_id, item) = self.pq.try_dequeue_seq()?;
+            if let Some(completion) = self.notifiers.remove(&seq) {
+                completion.fire();
+            }
+            (prio, entity_id, item)
+        };
+        if let Some(log) = &mut self.op_log {
+            log.push(Op::Dequeue {
+                prio,
+                entity: entity_id.clone(),
+            });
+        }
+        self.popped_at = prio;
+        self.note_dequeued(1);
+        self.note_served(&entity_id, 1);
+        self.release(&item);
+        Some((prio, entity_id, item))
+    }
+
+    // Same as `pop_tagged`, but hands the entity to `f` by reference instead
+    // of cloning it. `f` runs under the lock.
+    fn pop_with<R>(&mut self, f: impl FnOnce(&E, T) -> R) -> Option<R> {
+        if self.fenced {
+            return None;
+        }
+        let Self {
+            pq,
+            notifiers,
+            op_log,
+            served,
+            bytes,
+            index,
+            popped_at,
+            ..
+        } = self;
+        let ret = pq.try_dequeue_with(|seq, prio, entity_id, item| {
+            *popped_at = prio;
+            if let Some(completion) = notifiers.remove(&seq) {
+                completion.fire();
+            }
+            if let Some(log) = op_log {
+                log.push(Op::Dequeue {
+                    prio,
+                    entity: entity_id.clone(),
+                });
+            }
+            Self::count_served(served, entity_id, 1);
+            if let Some(bytes) = bytes {
+                bytes.remove(&item);
+            }
+            if let Some(index) = index {
+                index.remove(&item);
+            }
+            f(entity_id, item)
+        })?;
+        self.compact_index();
+        self.note_dequeued(1);
+        Some(ret)
+    }
+
+    // Dequeues from level `prio` only, recording the operation if the op log
+    // is enabled.
+    fn pop_at(&mut self, prio: usize) -> Result<Option<T>> {
+        if self.fenced {
+            return self.pq.is_level_empty(prio).map(|_| None);
+        }
+        let Some((seq, entity_id, item)) = self.pq.try_dequeue_at_seq(prio)? else {
+            return Ok(None);
+        };
+        self.popped_at = prio;
+        self.settle_notifier(seq, true);
+        self.note_dequeued(1);
+        self.note_served(&entity_id, 1);
+        self.release(&item);
+        if let Some(log) = &mut self.op_log {
+            log.push(Op::Dequeue {
+                prio,
+                entity: entity_id,
+            });
+        }
+        Ok(Some(item))
+    }
+
+    // Takes the head of `entity_id` at level `prio`, out of round-robin order,
+    // recording the operation if the op log is enabled.
+    fn pop_entity_at(&mut self, prio: usize, entity_id: &E) -> Result<Option<T>> {
+        if self.fenced {
+            return self.pq.is_level_empty(prio).map(|_| None);
+        }
+        let item = self
+            .pq
+            .try_dequeue_entity_at_seq(prio, entity_id)?
+            .map(|(seq, item)| {
+                self.settle_notifier(seq, true);
+                item
+            });
+        if item.is_some() {
+            self.popped_at = prio;
+            if let Some(log) = &mut self.op_log {
+                log.push(Op::Dequeue {
+                    prio,
+                    entity: entity_id.clone(),
+                });
+            }
+            self.note_dequeued(1);
+            self.note_served(entity_id, 1);
+        }
+        if let Some(item) = &item {
+            self.release(item);
+        }
+        Ok(item)
+    }
+
+    // Accounts for `n` items handed out to consumers.
+    fn note_dequeued(&mut self, n: usize) {
+        if n > 0 {
+            let now = Instant::now();
+            self.last_dequeue = Some(now);
+            self.rate.record(now, n);
+            self.total_dequeued += n as u64;
+            self.adapt();
+        }
+    }
+
+    // Stats mode: accounts for `n` items of `entity_id` handed out to consumers.
+    fn note_served(&mut self, entity_id: &E, n: u64) {
+        Self::count_served(&mut self.served, entity_id, n);
+    }
+
+    // `note_served` on the field alone, for callers borrowing other fields.
+    fn count_served(served: &mut Option<HashMap<E, u64>>, entity_id: &E, n: u64) {
+        if let Some(served) = served {
+            match served.get_mut(entity_id) {
+                Some(count) => *count += n,
+                None => {
+                    served.insert(entity_id.clone(), n);
+                }
+            }
+        }
+    }
+
+    // Drops every pending item, counting them as removed.
+    fn discard_all(&mut self) {
+        self.total_removed += self.pq.len() as u64;
+        if self.on_remove.is_some() {
+            while let Some((prio, entity_id, item)) = self.pq.try_dequeue_tagged() {
+                self.discard(RemovalReason::Cleared, prio, entity_id, item);
+            }
+        } else {
+            while self.pq.try_dequeue().is_some() {}
+        }
+        self.notifiers.clear();
+        if let Some(bytes) = &mut self.bytes {
+            bytes.clear();
+        }
+        if let Some(index) = &mut self.index {
+            index.clear();
+        }
+    }
+
+    // Resolves the completion of item `seq`, if it has one, once the item
+    // left the queue: as dequeued if `dequeued`, as dropped otherwise.
+    fn settle_notifier(&mut self, seq: u64, dequeued: bool) {
+        if let Some(completion) = self.notifiers.remove(&seq)
+            && dequeued
+        {
+            completion.fire();
+        }
+    }
+
+    // Puts the items of `leases` back into the queue; returns how many.
+    fn requeue_leases(&mut self, leases: impl IntoIterator<Item = LeaseId>) -> usize {
+        let mut requeued = 0;
+        for id in leases {
+            if let Some(lease) = self.leases.remove(&id)
+                && self.push(lease.prio, lease.entity_id, lease.item).is_ok()
+            {
+                requeued += 1;
+            }
+        }
+        requeued
+    }
+
+    // Marks the queue closed, recording it if the op log is enabled.
+    fn close(&mut self) {
+        self.closed = true;
+        if let Some(waiters) = &self.waiters {
+            waiters.wake_all();
+        }
+        if let Some(log) = &mut self.op_log {
+            log.push(Op::Shutdown);
         }
     }
 }
 
+// What a blocking enqueue keeps track of, besides queueing the item.
+enum Track {
+    Nothing,
+    // how many items of the same entity are ahead at its level
+    Ahead,
+    // the item's dequeue, resolving this completion
+    Completion(Completion),
+}
+
+// Where the items handed to `unlock_dequeued` came from: producers gated on
+// other levels have nothing to wake up for.
+#[derive(Clone, Copy)]
+enum Freed {
+    // items of level `prio`
+    Level(usize),
+    // items of several levels, or of unknown ones
+    Levels,
+    // no queued item: a queue-wide slot held by an `ItemGuard`
+    Slot,
+}
+
 struct Inner<E, T>
 where
     E: Eq + Hash + Clone,
 {
     state: Mutex<State<E, T>>,
     cv: Condvar,
+    not_full: Condvar,
+    level_not_full: Box<[Condvar]>,
+    depth_cv: Condvar,
+    gauges: Gauges,
+    spin: usize,
+    // Some when lock acquisitions that had to wait are counted
+    contended: Option<AtomicU64>,
+    poison: PoisonPolicy,
+    #[cfg(any(test, feature = "test-hooks"))]
+    notify_hook: Mutex<Option<NotifyHook>>,
 }
 
+#[cfg(any(test, feature = "test-hooks"))]
+type NotifyHook = Arc<dyn Fn() + Send + Sync>;
+
 impl<E, T> Inner<E, T>
 where
     E: Eq + Hash + Clone,
 {
-    fn new(n_prio: usize) -> Self {
+    fn new(st: State<E, T>, spin: usize, contention_probe: bool, poison: PoisonPolicy) -> Self {
         Self {
-            state: Mutex::new(State::new(n_prio)),
+            gauges: Gauges::new(st.pq.n_prio()),
+            level_not_full: (0..st.pq.n_prio()).map(|_| Condvar::new()).collect(),
+            state: Mutex::new(st),
             cv: Condvar::new(),
+            not_full: Condvar::new(),
+            depth_cv: Condvar::new(),
+            spin,
+            contended: contention_probe.then(|| AtomicU64::new(0)),
+            poison,
+            #[cfg(any(test, feature = "test-hooks"))]
+            notify_hook: Mutex::new(None),
+        }
+    }
+
+    // Runs the test hook, if any, before waking threads up. Compiled out of
+    // regular builds.
+    fn before_notify(&self) {
+        #[cfg(any(test, feature = "test-hooks"))]
+        {
+            let hook = self
+                .notify_hook
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clone();
+            if let Some(hook) = hook {
+                hook();
+            }
+        }
+    }
+
+    // Applies the poison policy to a poisoned lock acquisition (or condvar
+    // wait) made by `op`: the one place deciding what poisoning means.
+    fn recover<G>(&self, poisoned: PoisonError<G>, op: &'static str) -> Result<G> {
+        match self.poison {
+            PoisonPolicy::Error => Err(PriorityQueueError::LockError(op)),
+            PoisonPolicy::Recover => Ok(poisoned.into_inner()),
+            PoisonPolicy::Panic => panic!("queue lock poisoned (in {op})"),
+        }
+    }
+
+    // Same as `recover`, on the result of a lock acquisition or condvar wait.
+    fn unpoison<G>(&self, res: LockResult<G>, op: &'static str) -> Result<G> {
+        res.or_else(|poisoned| self.recover(poisoned, op))
+    }
+
+    // Same as `unpoison`, on the result of a wait that a waiter count was
+    // raised for: `leave` lowers it, even when the wait ends in an error.
+    fn unpoison_leaving<G>(
+        &self,
+        mut res: LockResult<G>,
+        op: &'static str,
+        leave: impl FnOnce(&mut G),
+    ) -> Result<G> {
+        match &mut res {
+            Ok(guard) => leave(guard),
+            Err(poisoned) => leave(poisoned.get_mut()),
+        }
+        self.unpoison(res, op)
+    }
+
+    // Wakes every producer blocked on a capacity gate, global or per level.
+    fn wake_producers(&self) {
+        self.not_full.notify_all();
+        for gate in &self.level_not_full {
+            gate.notify_all();
         }
     }
 }
@@ -96,240 +707,477 @@ where
     /// * [`dequeue()`] — Remove an item, blocking if necessary.
     ///
     pub fn new(n_prio: usize) -> Self {
-        assert!(n_prio > 0, "n_prio must be > 0");
-        Self {
-            inner: Arc::new(Inner::new(n_prio)),
-        }
+        Self::builder(n_prio).build()
     }
-}
 
-/// ---
-/// ## Queue Operations
-///
-/// Core blocking operations for producing and consuming items.
-///
-impl<E, T> SyncPriorityQueue<E, T>
-where
-    E: Eq + Hash + Clone,
-{
-    /// Enqueues a new item into the priority queue.
+    /// Creates a new bounded synchronized priority queue.
     ///
-    /// This method inserts an element into the queue at the given priority level.
-    /// Each priority level manages its own set of entities, ensuring fair
-    /// scheduling across producers.
+    /// Behaves like [`new()`], except that at most `cap` items (across all
+    /// priority levels and entities) may be queued at once.
     ///
     /// # Arguments
     ///
-    /// * `prio` — Priority level of the item (0 = highest priority).
-    /// * `entity_id` — Identifier for the logical producer or entity.
-    /// * `item` — The data to enqueue.
+    /// * `n_prio` — The number of priority levels in the queue (must be greater than 0).
+    /// * `cap` — The maximum number of queued items (must be greater than 0).
     ///
     /// # Behavior
     ///
-    /// - If the queue is closed (via [`shutdown_immediate()`], [`shutdown_graceful()`], or
-    ///   [`shutdown_timeout()`]), this method immediately returns [`PriorityQueueError::Closed`].
-    /// - If the provided priority is out of bounds, it returns [`PriorityQueueError::BadPriority`].
-    /// - Otherwise, the item is added and all waiting threads are notified with `notify_one()`.
+    /// - [`enqueue()`] blocks while the queue is full, until a consumer makes
+    ///   room or the queue is closed.
+    /// - [`try_enqueue()`] never blocks and returns [`PriorityQueueError::Full`].
     ///
-    /// # Errors
+    /// # Panics
     ///
-    /// Returns:
-    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
-    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
-    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    /// This function will **panic** if `n_prio` or `cap` is zero.
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use pq_sync::SyncPriorityQueue;
     ///
-    /// let pq = SyncPriorityQueue::new(3);
-    ///
-    /// pq.enqueue(0, "client_A".to_string(), "task_1".to_string()).unwrap();
-    /// pq.enqueue(1, "client_B".to_string(), "task_2".to_string()).unwrap();
-    ///
-    /// // Trying to enqueue after shutdown fails:
-    /// pq.shutdown_immediate().unwrap();
-    /// assert!(pq.enqueue(0, "client_C".to_string(), "task_3".to_string()).is_err());
+    /// let pq = SyncPriorityQueue::with_capacity(3, 1);
+    /// pq.try_enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// assert!(pq.try_enqueue(0, "A".to_string(), "item2".to_string()).is_err());
     /// ```
     ///
-    /// # See also
-    /// * [`dequeue()`] — Removes an item, blocking if the queue is empty.
-    /// * [`try_dequeue()`] — Attempts to remove an item without blocking.
-    ///
-    pub fn enqueue(&self, prio: usize, entity_id: E, item: T) -> Result<()> {
-        let mut st = self
-            .inner
-            .state
-            .lock()
-            .map_err(|_e| PriorityQueueError::LockError)?;
-        if st.closed {
-            return Err(PriorityQueueError::Closed);
-        }
-        st.pq.enqueue(prio, entity_id, item)?;
-        drop(st); // unlock
-        self.inner.cv.notify_one();
-        Ok(())
+    pub fn with_capacity(n_prio: usize, cap: usize) -> Self {
+        Self::builder(n_prio).capacity(cap).build()
     }
 
-    /// Attempts to dequeue an item without blocking.
+    /// Creates a new synchronized priority queue bounding the number of
+    /// distinct entities with pending items.
     ///
-    /// This non-blocking variant tries to remove and return the next available
-    /// element from the queue. If the queue is empty, it returns `Ok(None)`
-    /// immediately.
+    /// Each entity costs a deque and some bookkeeping, whatever its number
+    /// of items: a flood of distinct entity ids (e.g. spoofed client keys)
+    /// grows memory even with a bounded item count. Here, once `max` entities
+    /// have pending items, enqueues for any other entity are rejected, while
+    /// the entities already in the queue keep enqueuing freely.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_prio` — The number of priority levels in the queue (must be greater than 0).
+    /// * `max` — The maximum number of entities with pending items (must be greater than 0).
     ///
     /// # Behavior
     ///
-    /// - If the queue contains an item, it is dequeued and returned as `Ok(Some(item))`.
-    /// - If the queue becomes empty after removal, all waiting threads are
-    ///   notified via `notify_all()`.
-    /// - If the queue is empty from the start, the function returns `Ok(None)`
-    ///   without blocking.
+    /// - An entity counts once, whatever the number of levels it has items
+    ///   at, and stops counting once its last item is dequeued or removed.
+    /// - Enqueues for a new entity at the limit fail with
+    ///   [`PriorityQueueError::TooManyEntities`] — including blocking ones,
+    ///   which do not wait for an entity to leave.
+    /// - Items put back by [`nack()`] are not checked: they were admitted once.
+    /// - The check is O(1): the queue keeps per-entity counts of pending items.
     ///
-    /// # Errors
+    /// # Panics
     ///
-    /// Returns:
-    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    /// This function will **panic** if `n_prio` or `max` is zero.
     ///
     /// # Examples
     ///
     /// ```no_run
+    /// use pq_core::PriorityQueueError;
     /// use pq_sync::SyncPriorityQueue;
     ///
-    /// let pq = SyncPriorityQueue::new(3);
+    /// let pq = SyncPriorityQueue::with_max_entities(3, 1);
+    /// pq.try_enqueue(0, "A".to_string(), 1).unwrap();
+    /// assert!(matches!(
+    ///     pq.try_enqueue(0, "B".to_string(), 2),
+    ///     Err(PriorityQueueError::TooManyEntities)
+    /// ));
+    /// ```
     ///
-    /// pq.enqueue(0, "client_A".to_string(), "task_1".to_string()).unwrap();
+    pub fn with_max_entities(n_prio: usize, max: usize) -> Self {
+        Self::builder(n_prio).max_entities(max).build()
+    }
+
+    /// Creates a new synchronized priority queue whose items are indexed by
+    /// a secondary key, so that they can be cancelled by key (see
+    /// [`cancel_by_key()`]).
     ///
-    /// assert_eq!(pq.try_dequeue().unwrap(), Some("task_1".to_string()));
+    /// Entities and priorities tell where an item waits; the key tells what
+    /// it belongs to, e.g. a job id shared by items of several entities at
+    /// several levels.
     ///
-    /// // Nothing left — returns None immediately
-    /// assert_eq!(pq.try_dequeue().unwrap(), None);
-    /// ```
+    /// # Arguments
     ///
-    /// # See also
-    /// * [`dequeue()`] — Blocking variant that waits for an item.
-    /// * [`enqueue()`] — Adds a new item to the queue.
+    /// * `n_prio` — The number of priority levels in the queue (must be greater than 0).
+    /// * `key_fn` — The key of an item. It is called when the item enters the
+    ///   queue and again when it leaves, so it must be cheap and always
+    ///   return the same key for the same item.
     ///
-    pub fn try_dequeue(&self) -> Result<Option<T>> {
-        let mut st = self
-            .inner
-            .state
-            .lock()
-            .map_err(|_e| PriorityQueueError::LockError)?;
-        Ok(st.pq.try_dequeue())
-    }
-
-    /// Dequeues an item from the queue, blocking until one becomes available.
+    /// # Memory
     ///
-    /// This method waits for an item to be enqueued if the queue is empty,
-    /// and returns it once available.
-    /// It uses a condition variable (`Condvar`) to sleep efficiently until
-    /// new items arrive or the queue is closed.
+    /// The index holds one entry per distinct key, plus one location (level,
+    /// entity clone and sequence number) per queued item. Locations of the
+    /// items that left the queue are dropped lazily: with all of a key's
+    /// items, or by a compaction pass once they outnumber the queued items.
+    /// Count about twice the size of a location per queued item, at worst.
     ///
-    /// # Behavior
+    /// # Panics
     ///
-    /// - If the queue has items, the next available one is returned immediately.
-    /// - If the queue is empty but **not closed**, the thread blocks until:
-    ///   * a producer enqueues a new item, or
-    ///   * the queue is closed.
-    /// - If the queue is closed **and** empty, it returns [`PriorityQueueError::Closed`].
-    /// - If the queue becomes empty after dequeueing, it notifies all waiting threads.
+    /// This function will **panic** if `n_prio` is zero.
     ///
-    /// Internally, this method uses:
+    /// # Examples
     ///
-    /// ```ignore
-    /// st = self.inner.cv.wait_while(st, |s| s.pq.is_empty() && !s.closed)?;
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::with_index(3, |task: &(u64, String)| task.0);
+    /// pq.enqueue(0, "A".to_string(), (7, "fetch".to_string())).unwrap();
+    /// assert_eq!(pq.cancel_by_key(&7u64).unwrap(), 1);
     /// ```
     ///
-    /// This ensures safe handling of **spurious wakeups**, as the condition is
-    /// always rechecked upon every wake.
+    pub fn with_index<K>(n_prio: usize, key_fn: impl Fn(&T) -> K + Send + Sync + 'static) -> Self
+    where
+        K: Eq + Hash + Send + Sync + 'static,
+        E: Send + Sync + 'static,
+        T: 'static,
+    {
+        Self::builder(n_prio).index(key_fn).build()
+    }
+
+    /// Creates a new bounded synchronized priority queue where each entity
+    /// has a minimum number of reserved slots.
     ///
-    /// # Errors
+    /// With a plain capacity (see [`with_capacity()`]), a greedy entity can
+    /// take every slot and leave the others nothing to enqueue into. Here,
+    /// each entity is guaranteed `min_per_entity` slots within the shared
+    /// `total`: when the queue is full, an entity holding fewer items than
+    /// that still gets in, by evicting an item of the entity holding the most.
     ///
-    /// Returns:
-    /// * [`PriorityQueueError::Closed`] — if the queue is closed and empty.
-    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    /// # Arguments
+    ///
+    /// * `n_prio` — The number of priority levels in the queue (must be greater than 0).
+    /// * `total` — The maximum number of queued items (must be greater than 0).
+    /// * `min_per_entity` — The number of slots reserved to each entity.
+    ///
+    /// # Behavior
+    ///
+    /// - While the queue is not full, any entity may enqueue, up to `total`.
+    /// - Once it is full, an enqueue for an entity holding fewer than
+    ///   `min_per_entity` items (across all levels) evicts one item of the
+    ///   entity holding the most, provided that one holds more than
+    ///   `min_per_entity`. The evicted item is the most recently enqueued of
+    ///   its least urgent level; it is dropped, and its
+    ///   [`CompletionHandle`], if any, resolves as [`PriorityQueueError::Closed`].
+    /// - Otherwise (the entity is at or above its reservation, or nobody is
+    ///   above theirs), [`try_enqueue()`] returns [`PriorityQueueError::Full`]
+    ///   and [`enqueue()`] blocks as with a plain capacity.
+    /// - Reservations are only honored if `total` covers them: with more than
+    ///   `total / min_per_entity` entities, latecomers may find no slot to take.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` or `total` is zero.
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use pq_sync::SyncPriorityQueue;
-    /// use std::thread;
     ///
-    /// let pq = SyncPriorityQueue::new(3);
-    /// let pq_clone = pq.clone();
+    /// let pq = SyncPriorityQueue::with_fair_capacity(1, 2, 1);
+    /// pq.try_enqueue(0, "greedy".to_string(), 1).unwrap();
+    /// pq.try_enqueue(0, "greedy".to_string(), 2).unwrap();
+    /// // Full, but "polite" has a reserved slot: greedy's 2 is evicted.
+    /// pq.try_enqueue(0, "polite".to_string(), 3).unwrap();
+    /// assert_eq!(pq.len().unwrap(), 2);
+    /// ```
     ///
-    /// // Consumer
-    /// thread::spawn(move || {
-    ///     let item = pq_clone.dequeue().unwrap();
-    ///     assert_eq!(item, "task_1".to_string());
-    /// });
+    pub fn with_fair_capacity(n_prio: usize, total: usize, min_per_entity: usize) -> Self {
+        Self::builder(n_prio)
+            .capacity(total)
+            .reserved_per_entity(min_per_entity)
+            .build()
+    }
+
+    /// Creates a new bounded synchronized priority queue whose capacity
+    /// adjusts itself to the consumers' throughput.
     ///
-    /// // Producer
-    /// pq.enqueue(0, "client_A".to_string(), "task_1".to_string()).unwrap();
-    /// ```
+    /// The capacity starts at `min` and is tuned AIMD-style (additive
+    /// increase, multiplicative decrease) once per period (see
+    /// [`SyncPriorityQueueBuilder::adapt_every()`], default
+    /// [`DEFAULT_ADAPT_PERIOD`]):
     ///
-    /// # See also
-    /// * [`try_dequeue()`] — Non-blocking version of this method.
-    /// * [`shutdown_graceful()`] — Waits for all items to be consumed before closing.
-    /// * [`shutdown_timeout()`] — Same, but with a maximum timeout.
+    /// - if the queue is less than `target_fill` full, consumers keep up:
+    ///   the capacity grows by a sixteenth of the `min..=max` range (at
+    ///   least one slot), up to `max`;
+    /// - if it is more than `target_fill` full and the dequeue rate did not
+    ///   improve over the previous period, items wait longer and longer: the
+    ///   capacity is halved, down to `min`;
+    /// - otherwise (full, but consumers are speeding up), it is left alone.
     ///
-    pub fn dequeue(&self) -> Result<T> {
-        let mut st = self
-            .inner
-            .state
-            .lock()
-            .map_err(|_| PriorityQueueError::LockError)?;
-        st = self
-            .inner
-            .cv
-            .wait_while(st, |s| s.pq.is_empty() && !s.closed)
-            .map_err(|_| PriorityQueueError::LockError)?;
-        let Some(v) = st.pq.try_dequeue() else {
-            return Err(PriorityQueueError::Closed);
-        };
-        let became_empty = st.pq.is_empty();
-        drop(st);
-        if became_empty {
-            self.inner.cv.notify_all();
-        }
-        Ok(v)
+    /// # Arguments
+    ///
+    /// * `n_prio` — The number of priority levels in the queue (must be greater than 0).
+    /// * `min` — The smallest capacity (must be greater than 0).
+    /// * `max` — The largest capacity (must be at least `min`).
+    /// * `target_fill` — The fill ratio the controller aims for, in `(0, 1]`.
+    ///
+    /// # Behavior
+    ///
+    /// - There is no background thread: adjustments happen during enqueues
+    ///   and dequeues, so an idle queue keeps its capacity.
+    /// - Shrinking below the current depth evicts nothing: producers block
+    ///   (or get [`PriorityQueueError::Full`]) until consumers catch up.
+    /// - Otherwise the queue behaves as one built with [`with_capacity()`],
+    ///   and [`capacity()`] returns the current value.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` or `min` is zero, if `max` is
+    /// less than `min`, or if `target_fill` is not in `(0, 1]`.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::with_adaptive_capacity(3, 16, 4096, 0.5);
+    /// assert_eq!(pq.capacity().unwrap(), 16);
+    /// ```
+    ///
+    pub fn with_adaptive_capacity(n_prio: usize, min: usize, max: usize, target_fill: f64) -> Self {
+        Self::builder(n_prio)
+            .adaptive_capacity(min, max, target_fill)
+            .build()
+    }
+
+    /// Creates a new synchronized priority queue with levels `0..=max_index`.
+    ///
+    /// Meant for priorities expressed as a type (see [`IntoPriority`]): pass
+    /// the index of the least urgent priority, and the queue gets exactly
+    /// `max_index + 1` levels. This avoids keeping a hardcoded level count in
+    /// sync with the priority type, and the off-by-one
+    /// [`PriorityQueueError::BadPriority`] that comes with it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `max_index` is `usize::MAX`: the level count would overflow.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::{IntoPriority, SyncPriorityQueue};
+    ///
+    /// #[derive(Clone, Copy)]
+    /// #[repr(usize)]
+    /// enum Priority {
+    ///     High,
+    ///     Normal,
+    ///     Low,
+    /// }
+    ///
+    /// impl IntoPriority for Priority {
+    ///     fn as_index(self) -> usize {
+    ///         self as usize
+    ///     }
+    /// }
+    ///
+    /// let pq = SyncPriorityQueue::with_levels_for(Priority::Low.as_index());
+    /// pq.enqueue(Priority::Low, "A".to_string(), "item1".to_string()).unwrap();
+    /// ```
+    ///
+    pub fn with_levels_for(max_index: usize) -> Self {
+        let n_prio = max_index
+            .checked_add(1)
+            .expect("with_levels_for: max_index + 1 overflows usize");
+        Self::new(n_prio)
+    }
+
+    /// Creates a new synchronized priority queue with one level per variant of `P`.
+    ///
+    /// Same as [`with_levels_for()`], with the level count taken from
+    /// [`strum::EnumCount`], so adding a variant to the priority enum is
+    /// enough to grow the queue. Requires the `strum` feature.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::{IntoPriority, SyncPriorityQueue};
+    /// use strum::EnumCount;
+    ///
+    /// #[derive(Clone, Copy, EnumCount)]
+    /// enum Priority {
+    ///     High,
+    ///     Normal,
+    ///     Low,
+    /// }
+    ///
+    /// impl IntoPriority for Priority {
+    ///     fn as_index(self) -> usize {
+    ///         self as usize
+    ///     }
+    /// }
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::for_enum::<Priority>();
+    /// ```
+    ///
+    #[cfg(feature = "strum")]
+    pub fn for_enum<P>() -> Self
+    where
+        P: IntoPriority + strum::EnumCount,
+    {
+        Self::new(P::COUNT)
+    }
+
+    /// Creates a new synchronized priority queue bounded in bytes rather than in items.
+    ///
+    /// The size of each item is given by `size_fn` (e.g. the length of a
+    /// buffer plus some overhead): the queue accepts items as long as the sum
+    /// of their sizes stays within `max_bytes`. This is a much tighter memory
+    /// bound than an item count when payloads vary wildly in size.
+    ///
+    /// # Arguments
+    ///
+    /// * `n_prio` — The number of priority levels in the queue (must be greater than 0).
+    /// * `max_bytes` — The maximum summed size of the queued items (must be greater than 0).
+    /// * `size_fn` — The approximate size of an item, in bytes. It is called when
+    ///   the item enters the queue and again when it leaves, so it must be cheap
+    ///   and always return the same size for the same item.
+    ///
+    /// # Behavior
+    ///
+    /// - An item fits if the queued sizes plus its own do not exceed
+    ///   `max_bytes`: reaching exactly `max_bytes` is allowed.
+    /// - [`enqueue()`] blocks until the item fits; [`try_enqueue()`] returns
+    ///   [`PriorityQueueError::Full`] instead.
+    /// - An item larger than `max_bytes` can never fit: it is rejected with
+    ///   [`PriorityQueueError::Full`] right away, even by blocking enqueues.
+    /// - [`enqueue_or_replace_own()`] does not evict to make room for bytes:
+    ///   it fails with [`PriorityQueueError::Full`] if the item does not fit.
+    /// - Items put back by [`nack()`] were admitted once and are not checked again.
+    /// - The byte capacity combines with an item capacity (see
+    ///   [`SyncPriorityQueueBuilder::byte_capacity()`]): an item must fit both.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` or `max_bytes` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::with_byte_capacity(3, 1 << 20, |buf: &Vec<u8>| buf.len());
+    /// pq.enqueue(0, "A".to_string(), vec![0u8; 1024]).unwrap();
+    /// assert_eq!(pq.byte_len().unwrap(), 1024);
+    /// ```
+    ///
+    pub fn with_byte_capacity(
+        n_prio: usize,
+        max_bytes: usize,
+        size_fn: impl Fn(&T) -> usize + Send + Sync + 'static,
+    ) -> Self {
+        Self::builder(n_prio)
+            .byte_capacity(max_bytes, size_fn)
+            .build()
+    }
+
+    /// Creates a new synchronized priority queue where each level has a quota of consecutive dequeues.
+    ///
+    /// There is one level per quota. Level `p` may serve `quotas[p]` items in
+    /// a row; once its quota is used up, if a lower-priority level has items,
+    /// that level serves one item before level `p` starts a new quota. This
+    /// sits between strict priority (the default, equivalent to all quotas
+    /// being `usize::MAX`) and weighted fair queueing: urgent work is still
+    /// generally favored, but lower levels can no longer starve.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `quotas` is empty or holds a zero quota.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// // Level 0 gets 3 turns for every turn of level 1.
+    /// let pq = SyncPriorityQueue::<String, String>::with_level_quotas(vec![3, usize::MAX]);
+    /// ```
+    ///
+    pub fn with_level_quotas(quotas: Vec<usize>) -> Self {
+        Self::builder(quotas.len()).level_quotas(quotas).build()
+    }
+
+    /// Creates a new synchronized priority queue that records its operations.
+    ///
+    /// Behaves like [`new()`], except that the last [`DEFAULT_OP_LOG_LEN`]
+    /// enqueues, dequeues and shutdowns are kept in a ring buffer, readable
+    /// through [`op_log()`]. Use [`SyncPriorityQueueBuilder::op_log()`] to pick
+    /// another length.
+    ///
+    /// This is a debugging aid for reproducing scheduling anomalies: each
+    /// record costs a timestamp and an entity clone, so leave it off in
+    /// production.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` is zero.
+    ///
+    pub fn with_op_log(n_prio: usize) -> Self {
+        Self::builder(n_prio).op_log(DEFAULT_OP_LOG_LEN).build()
+    }
+
+    /// Returns a [`SyncPriorityQueueBuilder`] to combine construction options.
+    ///
+    /// # Panics
+    ///
+    /// This function will **panic** if `n_prio` is zero.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::builder(3)
+    ///     .capacity(128)
+    ///     .spin(0)
+    ///     .build();
+    /// ```
+    ///
+    pub fn builder(n_prio: usize) -> SyncPriorityQueueBuilder<E, T> {
+        SyncPriorityQueueBuilder::new(n_prio)
     }
 }
 
 /// ---
-/// ## Shutdown Modes
+/// ## Queue Operations
 ///
-/// Control how and when the queue stops processing items.
+/// Core blocking operations for producing and consuming items.
 ///
 impl<E, T> SyncPriorityQueue<E, T>
 where
     E: Eq + Hash + Clone,
 {
-    /// Immediately closes the queue and wakes all waiting threads.
+    /// Enqueues a new item into the priority queue.
     ///
-    /// This method sets the internal `closed` flag to `true`, clears all pending
-    /// elements in the queue, and notifies all waiting producers and consumers.
+    /// This method inserts an element into the queue at the given priority level.
+    /// Each priority level manages its own set of entities, ensuring fair
+    /// scheduling across producers.
     ///
-    /// Unlike [`shutdown_graceful()`] or [`shutdown_timeout()`], this variant
-    /// does **not wait** for items to be consumed — it simply closes everything
-    /// at once.
+    /// # Arguments
     ///
-    /// # Behavior
+    /// * `prio` — Priority level of the item (0 = highest priority), as a `usize`
+    ///   or any type implementing [`IntoPriority`] (e.g. a priority enum).
+    /// * `entity_id` — Identifier for the logical producer or entity.
+    /// * `item` — The data to enqueue.
     ///
-    /// - Any pending calls to [`dequeue()`] will return [`PriorityQueueError::Closed`].
-    /// - Any subsequent calls to [`enqueue()`] will also return [`PriorityQueueError::Closed`].
-    /// - The internal condition variable is signaled with `notify_all()`,
-    ///   ensuring that no thread remains blocked on the queue.
+    /// # Behavior
     ///
-    /// This operation is **idempotent** — calling it multiple times has no effect
-    /// after the first invocation.
+    /// - If the queue is closed (via [`shutdown_immediate()`], [`shutdown_graceful()`], or
+    ///   [`shutdown_timeout()`]), this method immediately returns [`PriorityQueueError::Closed`].
+    /// - If the provided priority is out of bounds, it returns [`PriorityQueueError::BadPriority`].
+    /// - If the queue is bounded (see [`with_capacity()`]) and full, it blocks until
+    ///   a consumer makes room or the queue is closed. The same applies to a
+    ///   level bounded on its own (see [`enqueue_blocking()`]).
+    /// - Otherwise, the item is added and all waiting threads are notified with `notify_one()`.
     ///
     /// # Errors
     ///
     /// Returns:
-    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the internal state
-    ///   has been poisoned (for example, due to a panic in another thread).
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
     ///
     /// # Examples
     ///
@@ -338,250 +1186,5270 @@ where
     ///
     /// let pq = SyncPriorityQueue::new(3);
     ///
-    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
-    /// pq.enqueue(1, "B".to_string(), "item2".to_string()).unwrap();
+    /// pq.enqueue(0, "client_A".to_string(), "task_1".to_string()).unwrap();
+    /// pq.enqueue(1, "client_B".to_string(), "task_2".to_string()).unwrap();
     ///
+    /// // Trying to enqueue after shutdown fails:
     /// pq.shutdown_immediate().unwrap();
-    ///
-    /// assert!(pq.enqueue(2, "C".to_string(), "item3".to_string()).is_err());
-    /// assert!(pq.dequeue().is_err());
+    /// assert!(pq.enqueue(0, "client_C".to_string(), "task_3".to_string()).is_err());
     /// ```
     ///
     /// # See also
-    /// * [`shutdown_graceful()`] — Waits for the queue to empty before closing.
-    /// * [`shutdown_timeout()`] — Like graceful shutdown, but with a maximum wait duration.
+    /// * [`dequeue()`] — Removes an item, blocking if the queue is empty.
+    /// * [`try_dequeue()`] — Attempts to remove an item without blocking.
     ///
-    pub fn shutdown_immediate(&self) -> Result<()> {
-        let mut st = self
-            .inner
-            .state
-            .lock()
-            .map_err(|_| PriorityQueueError::LockError)?;
-        st.closed = true;
-        while let Some(_) = st.pq.try_dequeue() {}
-        drop(st);
-        self.inner.cv.notify_all();
-        Ok(())
+    pub fn enqueue(&self, prio: impl IntoPriority, entity_id: E, item: T) -> Result<()> {
+        self.push_blocking("enqueue", prio.as_index(), entity_id, item)
     }
 
-    /// Closes the queue and waits until all elements have been consumed.
-    ///
-    /// This method sets the internal `closed` flag to `true` and blocks
-    /// until the queue becomes empty. Once all items are processed,
-    /// it wakes all waiting threads with `notify_all()`.
-    ///
-    /// Unlike [`shutdown_immediate()`], this method gives active consumers
-    /// time to finish processing pending elements.
+    /// Same as [`enqueue()`], but returns the position of the new item in its
+    /// entity's line at that level.
     ///
-    /// # Behavior
+    /// The position is the number of items of the same entity, at the same
+    /// level, that were queued ahead of the new one (`0` means it is next for
+    /// its entity). Together with the number of active entities, it gives a
+    /// producer a rough "your position in line" estimate of its delay.
     ///
-    /// - The queue is marked as closed immediately.
-    /// - Producers can no longer enqueue new items.
-    /// - Consumers may continue dequeuing until the queue is empty.
-    /// - Once empty, all waiting threads are notified and the function returns.
+    /// It is an instantaneous snapshot taken under the lock at insertion
+    /// time, not a guarantee: items of other entities and other levels are
+    /// interleaved by the scheduler, and the count changes as soon as
+    /// consumers make progress.
     ///
     /// # Errors
     ///
-    /// Returns:
-    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the internal state
-    ///   has been poisoned (for example, due to a panic in another thread).
+    /// Same as [`enqueue()`].
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use pq_sync::SyncPriorityQueue;
-    /// use std::thread;
-    /// use std::time::Duration;
     ///
     /// let pq = SyncPriorityQueue::new(3);
-    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
-    ///
-    /// // A separate thread will dequeue after a short delay.
-    /// let pq_clone = pq.clone();
-    /// thread::spawn(move || {
-    ///     thread::sleep(Duration::from_millis(50));
-    ///     pq_clone.dequeue().unwrap();
-    /// });
-    ///
-    /// pq.shutdown_graceful().unwrap();
+    /// let a = "A".to_string();
+    /// assert_eq!(pq.enqueue_pos(1, a.clone(), "job1".to_string()).unwrap(), 0);
+    /// assert_eq!(pq.enqueue_pos(1, a.clone(), "job2".to_string()).unwrap(), 1);
     /// ```
     ///
-    /// # See also
-    /// * [`shutdown_immediate()`] — Closes the queue immediately without waiting.
-    /// * [`shutdown_timeout()`] — Like graceful shutdown, but with a maximum wait duration.
-    ///
-    pub fn shutdown_graceful(&self) -> Result<()> {
-        let mut st = self
-            .inner
-            .state
-            .lock()
-            .map_err(|_| PriorityQueueError::LockError)?;
-        st.closed = true;
-        if st.pq.is_empty() {
-            drop(st);
-            self.inner.cv.notify_all();
-            return Ok(());
-        }
-        st = self
-            .inner
-            .cv
-            .wait_while(st, |s| !s.pq.is_empty())
-            .map_err(|_| PriorityQueueError::LockError)?;
-        drop(st);
-        self.inner.cv.notify_all();
-        Ok(())
+    pub fn enqueue_pos(&self, prio: impl IntoPriority, entity_id: E, item: T) -> Result<usize> {
+        self.push_blocking_pos("enqueue_pos", prio.as_index(), entity_id, item)
     }
 
-    /// Closes the queue and waits for it to empty, up to a maximum duration.
-    ///
-    /// This method behaves like [`shutdown_graceful()`],
-    /// but limits the total waiting time.
-    /// If the queue is not empty after the specified duration,
-    /// it returns [`PriorityQueueError::Timeout`].
+    /// Enqueues an item, blocking only while its own priority level is full.
     ///
-    /// # Arguments
-    ///
-    /// * `timeout` — Maximum duration to wait before aborting the shutdown.
+    /// This is an alias of [`enqueue()`], which behaves the same way; the
+    /// name spells out the blocking for code that wants it explicit.
     ///
-    /// # Behavior
+    /// With per-level capacities (see [`SyncPriorityQueueBuilder::level_capacity()`]),
+    /// each level has its own gate: a producer blocked on a full prio-2 band
+    /// does not hold back a prio-0 producer whose level still has room. The
+    /// parked thread sleeps on a condition variable dedicated to its level and
+    /// is only woken once an item leaves that same level, instead of joining a
+    /// thundering herd on a single global "not full" signal.
     ///
-    /// - The queue is marked as closed immediately.
-    /// - Consumers may continue processing items until the timeout expires.
-    /// - If the timeout expires before the queue becomes empty,
-    ///   the function returns a `Timeout` error.
-    /// - Otherwise, the shutdown completes successfully.
+    /// If the queue also has a global capacity (see [`with_capacity()`]), the
+    /// call additionally waits for global room.
     ///
     /// # Errors
     ///
     /// Returns:
-    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the state is poisoned.
-    /// * [`PriorityQueueError::Timeout`] — if the queue did not empty within the timeout period.
+    /// * [`PriorityQueueError::Closed`] — if the queue is, or becomes, closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
     ///
     /// # Examples
     ///
     /// ```no_run
     /// use pq_sync::SyncPriorityQueue;
-    /// use std::thread;
-    /// use std::time::Duration;
     ///
-    /// let pq = SyncPriorityQueue::new(3);
-    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// let pq = SyncPriorityQueue::builder(3)
+    ///     .level_capacity(2, 1)
+    ///     .build();
+    /// pq.enqueue_blocking(2, "A".to_string(), "bulk1".to_string()).unwrap();
     ///
-    /// // Another thread consumes after a short delay
-    /// let pq_clone = pq.clone();
-    /// thread::spawn(move || {
-    ///     thread::sleep(Duration::from_millis(50));
-    ///     pq_clone.dequeue().unwrap();
-    /// });
+    /// // Level 2 is full, level 0 is not: this does not block.
+    /// pq.enqueue_blocking(0, "B".to_string(), "urgent".to_string()).unwrap();
+    /// ```
     ///
-    /// // This will succeed: queue empties before timeout
-    /// pq.shutdown_timeout(Duration::from_millis(100)).unwrap();
+    pub fn enqueue_blocking(&self, prio: impl IntoPriority, entity_id: E, item: T) -> Result<()> {
+        self.push_blocking("enqueue_blocking", prio.as_index(), entity_id, item)
+    }
+
+    /// Attempts to enqueue an item without blocking.
     ///
-    /// // But if we reduce the timeout drastically:
-    /// let pq = SyncPriorityQueue::new(3);
-    /// pq.enqueue(0, "B".to_string(), "item2".to_string()).unwrap();
-    /// let result = pq.shutdown_timeout(Duration::from_millis(5));
-    /// assert!(result.is_err()); // -> Timeout
-    /// ```
+    /// Same as [`enqueue()`], except that on a full bounded queue it fails
+    /// immediately instead of waiting for room. On an unbounded queue, it
+    /// behaves exactly like [`enqueue()`].
     ///
-    /// # See also
-    /// * [`shutdown_graceful()`] — Waits indefinitely for the queue to empty.
-    /// * [`shutdown_immediate()`] — Closes immediately without waiting.
+    /// # Errors
     ///
-    pub fn shutdown_timeout(&self, timeout: Duration) -> Result<()> {
-        let mut st = self
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
+    /// * [`PriorityQueueError::Full`] — if the queue, or the item's level, is bounded and full.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # See also
+    /// * [`enqueue()`] — Blocking variant.
+    /// * [`enqueue_backoff()`] — Retries with exponential backoff while full.
+    ///
+    pub fn try_enqueue(&self, prio: impl IntoPriority, entity_id: E, item: T) -> Result<()> {
+        match self.try_push("try_enqueue", prio.as_index(), entity_id, item)? {
+            None => Ok(()),
+            Some(_) => Err(PriorityQueueError::Full),
+        }
+    }
+
+    /// Enqueues an item, retrying with exponential backoff while the queue is full.
+    ///
+    /// This sits between [`try_enqueue()`] (never waits) and [`enqueue()`]
+    /// (waits as long as needed): after each [`PriorityQueueError::Full`]
+    /// rejection, the calling thread sleeps for `base * 2^attempt`, scaled by a
+    /// random jitter in `[0.5, 1.0]` so that competing producers spread out.
+    ///
+    /// # Arguments
+    ///
+    /// * `prio` — Priority level of the item (0 = highest priority).
+    /// * `entity_id` — Identifier for the logical producer or entity.
+    /// * `item` — The data to enqueue.
+    /// * `max_retries` — Number of retries after the first attempt.
+    /// * `base` — Delay before the first retry.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Full`] — if the queue was still full after `max_retries` retries.
+    /// * [`PriorityQueueError::Closed`] — if the queue is, or becomes, closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # See also
+    /// * [`enqueue_backoff_with()`] — Same, with an injected sleep function and no jitter.
+    ///
+    pub fn enqueue_backoff(
+        &self,
+        prio: impl IntoPriority,
+        entity_id: E,
+        item: T,
+        max_retries: usize,
+        base: Duration,
+    ) -> Result<()> {
+        self.enqueue_backoff_with(prio, entity_id, item, max_retries, base, |d| {
+            thread::sleep(d.mul_f64(0.5 + 0.5 * jitter()))
+        })
+    }
+
+    /// Same as [`enqueue_backoff()`], but delegates waiting to `sleep`.
+    ///
+    /// `sleep` receives the nominal, jitter-free delay `base * 2^attempt`,
+    /// which makes the retry schedule deterministic and testable.
+    ///
+    pub fn enqueue_backoff_with(
+        &self,
+        prio: impl IntoPriority,
+        mut entity_id: E,
+        mut item: T,
+        max_retries: usize,
+        base: Duration,
+        mut sleep: impl FnMut(Duration),
+    ) -> Result<()> {
+        let prio = prio.as_index();
+        for attempt in 0..=max_retries {
+            let Some(rejected) = self.try_push("enqueue_backoff", prio, entity_id, item)? else {
+                return Ok(());
+            };
+            if attempt == max_retries {
+                break;
+            }
+            (entity_id, item) = rejected;
+            let factor = 1u32.checked_shl(attempt as u32).unwrap_or(u32::MAX);
+            sleep(base.saturating_mul(factor));
+        }
+        Err(PriorityQueueError::Full)
+    }
+
+    /// Enqueues an item and returns a handle that resolves when a consumer pulls it.
+    ///
+    /// Lets a producer know that its item started being processed, without a
+    /// separate tracking system. Otherwise behaves exactly like [`enqueue()`],
+    /// including blocking while the queue or the item's level is full.
+    ///
+    /// The handle resolves as soon as the item is dequeued, by any consumer
+    /// path ([`dequeue()`], [`try_dequeue()`], [`dequeue_lease()`],
+    /// [`dequeue_one_per_entity()`], ...). If the item leaves the queue
+    /// otherwise (removed by [`remove_entity_at()`], evicted by
+    /// [`enqueue_or_replace_own()`], dropped or taken back at shutdown), or if
+    /// the queue itself is dropped, waiting on the handle returns
+    /// [`PriorityQueueError::Closed`].
+    ///
+    /// Tagging is opt-in: as long as no notified item is pending, the other
+    /// operations pay nothing for this feature.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use std::thread;
+    ///
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// let done = pq
+    ///     .enqueue_notified(0, "A".to_string(), "task_1".to_string())
+    ///     .unwrap();
+    ///
+    /// let consumer = pq.clone();
+    /// thread::spawn(move || consumer.dequeue());
+    ///
+    /// done.wait().unwrap(); // task_1 was picked up
+    /// ```
+    ///
+    pub fn enqueue_notified(
+        &self,
+        prio: impl IntoPriority,
+        entity_id: E,
+        item: T,
+    ) -> Result<CompletionHandle> {
+        let (completion, handle) = Completion::new();
+        self.push_blocking_unless(
+            "enqueue_notified",
+            prio.as_index(),
+            entity_id,
+            item,
+            |_, _, _| false,
+            Track::Completion(completion),
+        )?;
+        Ok(handle)
+    }
+
+    /// Enqueues an item, making room if needed by evicting a less important item of the same entity.
+    ///
+    /// Lets a producer curate its own backlog on a bounded queue: when the
+    /// queue (or the item's level, see [`enqueue_blocking()`]) is full, the
+    /// pending item of `entity_id` with the lowest `importance` is evicted to
+    /// make room for the new one, provided it is strictly less important than
+    /// the new item. Items of other entities are never touched.
+    ///
+    /// Among equally unimportant candidates, the one at the least urgent level
+    /// is evicted, then the most recently enqueued one. When the item's level
+    /// is full, candidates are looked for at that level only. An eviction is
+    /// recorded in the op log as a dequeue.
+    ///
+    /// Returns `Ok(None)` if there was room, or `Ok(Some(evicted))`. This
+    /// method never blocks.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Full`] — if the queue is full and the entity has
+    ///   nothing less important than the new item.
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::with_capacity(3, 1);
+    /// pq.enqueue(2, "A".to_string(), "nice to have".to_string()).unwrap();
+    ///
+    /// let importance = |item: &String| if item.starts_with("must") { 10 } else { 1 };
+    /// let evicted = pq
+    ///     .enqueue_or_replace_own(0, "A".to_string(), "must do".to_string(), importance)
+    ///     .unwrap();
+    /// assert_eq!(evicted, Some("nice to have".to_string()));
+    /// ```
+    ///
+    pub fn enqueue_or_replace_own(
+        &self,
+        prio: impl IntoPriority,
+        entity_id: E,
+        item: T,
+        importance: impl Fn(&T) -> u32,
+    ) -> Result<Option<T>> {
+        let prio = prio.as_index();
+        let mut st = self.lock("enqueue_or_replace_own")?;
+        if st.closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        if prio >= st.pq.n_prio() {
+            return Err(PriorityQueueError::BadPriority(prio));
+        }
+        st.admit(&entity_id)?;
+        // Evicting one item is not guaranteed to free enough bytes.
+        if !st.fits(&item) {
+            return Err(PriorityQueueError::Full);
+        }
+        let mut evicted = None;
+        if st.is_full() || st.is_level_full(prio) {
+            // Only an item of level `prio` frees a slot of a full level.
+            let levels = if st.is_level_full(prio) {
+                prio..prio + 1
+            } else {
+                0..st.pq.n_prio()
+            };
+            let below = importance(&item);
+            let Some((at, seq, old)) =
+                st.pq
+                    .remove_lowest_of_entity_seq(levels, &entity_id, below, &importance)
+            else {
+                return Err(PriorityQueueError::Full);
+            };
+            st.settle_notifier(seq, false);
+            st.total_removed += 1;
+            if let Some(log) = &mut st.op_log {
+                log.push(Op::Dequeue {
+                    prio: at,
+                    entity: entity_id.clone(),
+                });
+            }
+            st.release(&old);
+            evicted = Some(old);
+        }
+        st.push(prio, entity_id, item)?;
+        self.unlock_enqueued(st, 1);
+        Ok(evicted)
+    }
+
+    /// Attempts to dequeue an item without blocking.
+    ///
+    /// This non-blocking variant tries to remove and return the next available
+    /// element from the queue. If the queue is empty, it returns `Ok(None)`
+    /// immediately.
+    ///
+    /// # Behavior
+    ///
+    /// - If the queue contains an item, it is dequeued and returned as `Ok(Some(item))`.
+    /// - If the queue becomes empty after removal, all waiting threads are
+    ///   notified via `notify_all()`.
+    /// - If the queue is empty from the start, the function returns `Ok(None)`
+    ///   without blocking.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    ///
+    /// pq.enqueue(0, "client_A".to_string(), "task_1".to_string()).unwrap();
+    ///
+    /// assert_eq!(pq.try_dequeue().unwrap(), Some("task_1".to_string()));
+    ///
+    /// // Nothing left — returns None immediately
+    /// assert_eq!(pq.try_dequeue().unwrap(), None);
+    /// ```
+    ///
+    /// # See also
+    /// * [`dequeue()`] — Blocking variant that waits for an item.
+    /// * [`enqueue()`] — Adds a new item to the queue.
+    ///
+    pub fn try_dequeue(&self) -> Result<Option<T>> {
+        let mut st = self.lock("try_dequeue")?;
+        let v = st.pop();
+        let from = Freed::Level(st.popped_at);
+        self.unlock_dequeued(st, usize::from(v.is_some()), from);
+        Ok(v)
+    }
+
+    /// Dequeues an item from the queue, blocking until one becomes available.
+    ///
+    /// This method waits for an item to be enqueued if the queue is empty,
+    /// and returns it once available.
+    /// It uses a condition variable (`Condvar`) to sleep efficiently until
+    /// new items arrive or the queue is closed.
+    ///
+    /// # Behavior
+    ///
+    /// - If the queue has items, the next available one is returned immediately.
+    /// - If the queue is empty but **not closed**, the thread blocks until:
+    ///   * a producer enqueues a new item, or
+    ///   * the queue is closed.
+    /// - If the queue is closed **and** empty, it returns [`PriorityQueueError::Closed`].
+    /// - If the queue becomes empty after dequeueing, it notifies all waiting threads.
+    ///
+    /// Before parking, the thread first makes a few non-blocking attempts (see
+    /// [`SyncPriorityQueueBuilder::spin()`]). This is purely an optimization: the
+    /// blocking path below is unchanged.
+    ///
+    /// Internally, this method uses:
+    ///
+    /// ```ignore
+    /// st = self.inner.cv.wait_while(st, |s| s.pq.is_empty() && !s.closed)?;
+    /// ```
+    ///
+    /// This ensures safe handling of **spurious wakeups**, as the condition is
+    /// always rechecked upon every wake.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed and empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::thread;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// let pq_clone = pq.clone();
+    ///
+    /// // Consumer
+    /// thread::spawn(move || {
+    ///     let item = pq_clone.dequeue().unwrap();
+    ///     assert_eq!(item, "task_1".to_string());
+    /// });
+    ///
+    /// // Producer
+    /// pq.enqueue(0, "client_A".to_string(), "task_1".to_string()).unwrap();
+    /// ```
+    ///
+    /// # See also
+    /// * [`try_dequeue()`] — Non-blocking version of this method.
+    /// * [`shutdown_graceful()`] — Waits for all items to be consumed before closing.
+    /// * [`shutdown_timeout()`] — Same, but with a maximum timeout.
+    ///
+    pub fn dequeue(&self) -> Result<T> {
+        self.pop_blocking("dequeue", State::pop)
+    }
+
+    /// Dequeues an item, blocking at most `timeout` for one to become available.
+    ///
+    /// Unlike an `Option`, the returned [`DequeueOutcome`] tells the two
+    /// "no item" cases apart:
+    ///
+    /// - [`DequeueOutcome::Item`] — an item was dequeued;
+    /// - [`DequeueOutcome::Timeout`] — the timeout expired while the queue was
+    ///   still open: more items may come, retry later;
+    /// - [`DequeueOutcome::Closed`] — the queue is closed and drained: stop.
+    ///
+    /// The decision is made under the lock, so it cannot race with a
+    /// concurrent shutdown the way a separate closed-check would.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::{DequeueOutcome, SyncPriorityQueue};
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    ///
+    /// loop {
+    ///     match pq.dequeue_timeout(Duration::from_millis(100)).unwrap() {
+    ///         DequeueOutcome::Item(task) => println!("{task}"),
+    ///         DequeueOutcome::Timeout => { /* idle housekeeping, then retry */ }
+    ///         DequeueOutcome::Closed => break,
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn dequeue_timeout(&self, timeout: Duration) -> Result<DequeueOutcome<T>> {
+        match self.pop_timeout("dequeue_timeout", timeout, State::pop) {
+            Ok(Some(v)) => Ok(DequeueOutcome::Item(v)),
+            Ok(None) => Ok(DequeueOutcome::Timeout),
+            Err(PriorityQueueError::Closed) => Ok(DequeueOutcome::Closed),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Dequeues a batch of `n` items, waiting at most `max_wait` for the
+    /// backlog to reach that size.
+    ///
+    /// Meant for batch consumers that would rather not wake up for every
+    /// single item: the call returns as soon as `n` items are queued, or once
+    /// `max_wait` has elapsed with whatever is there by then (possibly
+    /// nothing). Items are taken in the usual fair order, under a single
+    /// lock acquisition.
+    ///
+    /// # Behavior
+    ///
+    /// - Returns at most `n` items; fewer only if the wait timed out or the
+    ///   queue was closed.
+    /// - `n == 0` returns an empty `Vec` right away.
+    /// - Closing the queue ends the wait immediately, with the items still
+    ///   queued (at most `n`).
+    /// - Other consumers are not held back while this one waits: they may
+    ///   take items from the backlog it is waiting on.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed and empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    ///
+    /// while let Ok(batch) = pq.dequeue_at_least(64, Duration::from_millis(50)) {
+    ///     // one round-trip to the database for up to 64 rows
+    /// }
+    /// ```
+    ///
+    pub fn dequeue_at_least(&self, n: usize, max_wait: Duration) -> Result<Vec<T>> {
+        if n == 0 {
+            return Ok(Vec::new());
+        }
+        let mut st = self.lock("dequeue_at_least")?;
+        // Enqueues wake depth waiters on `depth_cv`, and closes always do.
+        st.depth_watchers += 1;
+        let res = self
             .inner
-            .state
-            .lock()
-            .map_err(|_| PriorityQueueError::LockError)?;
-        st.closed = true;
-        if st.pq.is_empty() {
-            drop(st);
-            self.inner.cv.notify_all();
-            return Ok(());
+            .depth_cv
+            .wait_timeout_while(st, max_wait, |s| s.pq.len() < n && !s.closed);
+        let (mut st, _) = self
+            .inner
+            .unpoison_leaving(res, "dequeue_at_least", |(st, _)| st.depth_watchers -= 1)?;
+        let mut batch = Vec::with_capacity(n.min(st.pq.len()));
+        while batch.len() < n {
+            let Some(item) = st.pop() else {
+                break;
+            };
+            batch.push(item);
+        }
+        if batch.is_empty() && st.closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        let taken = batch.len();
+        self.unlock_dequeued(st, taken, Freed::Levels);
+        Ok(batch)
+    }
+
+    /// Dequeues an item, blocking until one is available, along with the remaining depth.
+    ///
+    /// Behaves exactly like [`dequeue()`], but also returns the total number of
+    /// items left in the queue right after this one was taken, measured under
+    /// the same lock acquisition. Unlike a follow-up call to [`len()`], the
+    /// depth is guaranteed consistent with the item: an adaptive consumer can
+    /// rely on it to decide whether to grab more work or back off.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed and empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "task_1".to_string()).unwrap();
+    /// pq.enqueue(1, "B".to_string(), "task_2".to_string()).unwrap();
+    ///
+    /// let (task, remaining) = pq.dequeue_with_depth().unwrap();
+    /// assert_eq!(task, "task_1");
+    /// assert_eq!(remaining, 1);
+    /// ```
+    ///
+    pub fn dequeue_with_depth(&self) -> Result<(T, usize)> {
+        self.pop_blocking("dequeue_with_depth", |st| {
+            let v = st.pop()?;
+            Some((v, st.pq.len()))
+        })
+    }
+
+    /// Dequeues an item, blocking until one is available, and hands it to `f`
+    /// along with a borrow of its entity.
+    ///
+    /// Getting the entity back by value means cloning it, since the queue
+    /// usually keeps it in its round-robin for the entity's next items. For
+    /// callers that only need a transient look at the entity (logging,
+    /// routing, a metrics label), this method lends it instead, and returns
+    /// whatever `f` returns.
+    ///
+    /// # Behavior
+    ///
+    /// - Blocks exactly like [`dequeue()`].
+    /// - `f` runs **under the queue lock**: the entity borrow points into the
+    ///   queue's own structures, and cannot outlive the lock. Keep `f` short —
+    ///   pick out what is needed from the entity, and do the actual processing
+    ///   of the item after this method returns. A panic in `f` poisons the queue.
+    /// - `f` must not call back into this queue, or it deadlocks.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed and empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "tenant-with-a-long-id".to_string(), "task_1".to_string()).unwrap();
+    ///
+    /// let (shard, task) = pq.dequeue_with(|tenant, task| (tenant.len() % 4, task)).unwrap();
+    /// assert_eq!((shard, task.as_str()), (1, "task_1"));
+    /// ```
+    ///
+    pub fn dequeue_with<R>(&self, f: impl FnOnce(&E, T) -> R) -> Result<R> {
+        let mut f = Some(f);
+        self.pop_blocking("dequeue_with", |st| {
+            // Only called back once an item is out: `f` is still there.
+            st.pop_with(|entity_id, item| f.take().map(|f| f(entity_id, item)))
+                .flatten()
+        })
+    }
+
+    /// Dequeues an item, blocking until one is available, and keeps its
+    /// capacity slot until the returned guard is dropped.
+    ///
+    /// With a plain [`dequeue()`], the slot is freed as soon as the item
+    /// leaves the queue, so the capacity bounds queued items only. Here the
+    /// capacity bounds queued *and in-process* items: producers get the slot
+    /// back once the consumer is done with the item, i.e. drops the
+    /// [`ItemGuard`]. Being released on drop, the slot is freed even if the
+    /// processing panics.
+    ///
+    /// # Behavior
+    ///
+    /// - Blocks exactly like [`dequeue()`].
+    /// - Only the queue-wide capacity (see [`with_capacity()`]) is held:
+    ///   per-level capacities are released on dequeue, as usual.
+    /// - On an unbounded queue, the guard holds nothing and dropping it is free.
+    /// - A held item no longer counts in [`len()`], but still counts in
+    ///   [`is_full()`] and [`fill_ratio()`].
+    ///
+    /// # Errors
+    ///
+    /// Same as [`dequeue()`].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::with_capacity(3, 1);
+    /// pq.enqueue(0, "A".to_string(), "task_1".to_string()).unwrap();
+    ///
+    /// let task = pq.dequeue_guarded().unwrap();
+    /// assert_eq!(*task, "task_1");
+    /// // Still in process: no room for another item yet.
+    /// assert!(pq.is_full().unwrap());
+    ///
+    /// drop(task);
+    /// assert!(!pq.is_full().unwrap());
+    /// ```
+    ///
+    pub fn dequeue_guarded(&self) -> Result<ItemGuard<'_, T>> {
+        let (v, held) = self.pop_blocking("dequeue_guarded", |st| {
+            let v = st.pop()?;
+            let held = st.cap.is_some();
+            st.held += usize::from(held);
+            Some((v, held.then_some(st.held_epoch)))
+        })?;
+        let slot = held.map(|epoch| (self as &dyn guard::Slot, epoch));
+        Ok(ItemGuard::new(v, slot))
+    }
+
+    /// Dequeues exactly one item from each entity active at level `prio`.
+    ///
+    /// This performs one full turn of the level's round-robin in a single lock
+    /// acquisition: entities are visited in ring order, each gives its head
+    /// item, and the ring advances exactly as if [`try_dequeue()`] had been
+    /// called once per entity. It supports "serve everyone a little" fan-out
+    /// scheduling, unlike a plain batch which may take many items from the
+    /// same entity.
+    ///
+    /// An empty level yields an empty `Vec`, not an error. This method never blocks.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "A1".to_string()).unwrap();
+    /// pq.enqueue(0, "A".to_string(), "A2".to_string()).unwrap();
+    /// pq.enqueue(0, "B".to_string(), "B1".to_string()).unwrap();
+    ///
+    /// let batch = pq.dequeue_one_per_entity(0).unwrap();
+    /// assert_eq!(batch.len(), 2); // A1 and B1
+    /// ```
+    ///
+    pub fn dequeue_one_per_entity(&self, prio: usize) -> Result<Vec<(E, T)>> {
+        let mut st = self.lock("dequeue_one_per_entity")?;
+        if st.fenced {
+            return st.pq.is_level_empty(prio).map(|_| Vec::new());
+        }
+        let taken = st.pq.dequeue_one_per_entity_seq(prio)?;
+        st.note_dequeued(taken.len());
+        let mut batch = Vec::with_capacity(taken.len());
+        for (seq, entity_id, item) in taken {
+            st.settle_notifier(seq, true);
+            st.note_served(&entity_id, 1);
+            st.release(&item);
+            batch.push((entity_id, item));
+        }
+        self.unlock_dequeued(st, batch.len(), Freed::Level(prio));
+        Ok(batch)
+    }
+
+    /// Lets `f` pick which entity of level `prio` to serve, then dequeues that entity's head.
+    ///
+    /// This is an escape hatch for custom selection policies: `f` is shown the
+    /// head item of every active entity of the level, in round-robin order,
+    /// and returns the entity to serve (or `None` to serve nobody). The chosen
+    /// entity's head is then dequeued, and the entity moves to the back of the
+    /// ring as if it had had its regular turn. Items are only lent to `f`, so
+    /// `T` does not need to be `Clone`.
+    ///
+    /// Returns the dequeued item, or `None` if the level is empty, `f` returned
+    /// `None`, or `f` returned an entity that has no item at that level.
+    /// This method never blocks waiting for items.
+    ///
+    /// # Behavior
+    ///
+    /// - `f` runs while the internal lock is held: keep it short, and do not
+    ///   call back into the queue from it (that would deadlock).
+    /// - `f` is not called when the level is empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "a long task".to_string()).unwrap();
+    /// pq.enqueue(0, "B".to_string(), "short".to_string()).unwrap();
+    ///
+    /// // Shortest job first, among the heads of level 0.
+    /// let item = pq
+    ///     .with_heads(0, |heads| {
+    ///         heads.iter().min_by_key(|(_, item)| item.len()).map(|(e, _)| e.clone())
+    ///     })
+    ///     .unwrap();
+    /// assert_eq!(item, Some("short".to_string()));
+    /// ```
+    ///
+    pub fn with_heads(
+        &self,
+        prio: usize,
+        f: impl FnOnce(&[(E, &T)]) -> Option<E>,
+    ) -> Result<Option<T>> {
+        let mut st = self.lock("with_heads")?;
+        let chosen = {
+            let heads = st.pq.heads_at(prio)?;
+            if heads.is_empty() {
+                return Ok(None);
+            }
+            f(&heads)
+        };
+        let Some(entity_id) = chosen else {
+            return Ok(None);
+        };
+        let item = st.pop_entity_at(prio, &entity_id)?;
+        if item.is_some() {
+            self.unlock_dequeued(st, 1, Freed::Level(prio));
+        }
+        Ok(item)
+    }
+
+    /// Attempts to dequeue an item from level `prio` only, without blocking.
+    ///
+    /// The other levels are ignored entirely, whatever their priority, and so
+    /// are the level quotas and the queue policy: the item is the next one of
+    /// the level's per-entity round-robin.
+    ///
+    /// Returns `Ok(None)` if level `prio` is empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "urgent".to_string()).unwrap();
+    /// pq.enqueue(2, "B".to_string(), "background".to_string()).unwrap();
+    ///
+    /// // A background worker only ever serves level 2.
+    /// assert_eq!(pq.try_dequeue_from(2).unwrap(), Some("background".to_string()));
+    /// assert_eq!(pq.try_dequeue_from(2).unwrap(), None);
+    /// ```
+    ///
+    /// # See also
+    /// * [`dequeue_from()`] — Blocking variant that waits for an item at that level.
+    /// * [`try_dequeue()`] — Serves all levels.
+    ///
+    pub fn try_dequeue_from(&self, prio: usize) -> Result<Option<T>> {
+        let mut st = self.lock("try_dequeue_from")?;
+        let v = st.pop_at(prio)?;
+        self.unlock_dequeued(st, usize::from(v.is_some()), Freed::Level(prio));
+        Ok(v)
+    }
+
+    /// Dequeues an item from level `prio` only, blocking until one is available.
+    ///
+    /// Same selection as [`try_dequeue_from()`]: items at other levels are
+    /// ignored, and never wake this consumer up.
+    ///
+    /// # Behavior
+    ///
+    /// - If level `prio` has items, the next one is returned immediately.
+    /// - Otherwise the thread blocks until an item is enqueued at that level,
+    ///   or the queue is closed.
+    /// - Once the queue is closed, remaining items of the level are still
+    ///   served; [`PriorityQueueError::Closed`] is returned when the level is
+    ///   empty, even if other levels still hold items.
+    /// - With [`SyncPriorityQueueBuilder::fair_wakeup()`], this consumer does
+    ///   not join the line of parked consumers: it is woken by every enqueue
+    ///   while it waits.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed and level `prio` is empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::thread;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// let background = pq.clone();
+    ///
+    /// let worker = thread::spawn(move || {
+    ///     while let Ok(item) = background.dequeue_from(2) {
+    ///         println!("background: {item}");
+    ///     }
+    /// });
+    ///
+    /// pq.enqueue(2, "B".to_string(), "reindex".to_string()).unwrap();
+    /// pq.shutdown_immediate().unwrap();
+    /// worker.join().unwrap();
+    /// ```
+    ///
+    /// # See also
+    /// * [`try_dequeue_from()`] — Non-blocking version of this method.
+    /// * [`dequeue()`] — Serves all levels.
+    ///
+    pub fn dequeue_from(&self, prio: usize) -> Result<T> {
+        let mut st = self.lock("dequeue_from")?;
+        if prio >= st.pq.n_prio() {
+            return Err(PriorityQueueError::BadPriority(prio));
+        }
+        // Enqueues only notify one consumer, which may not be waiting on this
+        // level: ask them to wake everyone while we wait.
+        st.level_consumers += 1;
+        let res = self.inner.cv.wait_while(st, |s| {
+            s.pq.is_level_empty(prio).unwrap_or(false) && !s.closed
+        });
+        let mut st = self
+            .inner
+            .unpoison_leaving(res, "dequeue_from", |st| st.level_consumers -= 1)?;
+        match st.pop_at(prio)? {
+            Some(v) => {
+                self.unlock_dequeued(st, 1, Freed::Level(prio));
+                Ok(v)
+            }
+            None => Err(PriorityQueueError::Closed),
+        }
+    }
+
+    /// Dequeues an item, blocking until one is available, then transforms it with `f`.
+    ///
+    /// The item is pulled under the lock exactly like [`dequeue()`], but `f` is
+    /// invoked only once the lock has been released, so it may run arbitrary
+    /// (even slow or blocking) user code without stalling producers or other
+    /// consumers.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`dequeue()`]; `f` is not called on error.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "task_1".to_string()).unwrap();
+    ///
+    /// let len = pq.dequeue_map(|item| item.len()).unwrap();
+    /// assert_eq!(len, 6);
+    /// ```
+    ///
+    pub fn dequeue_map<U>(&self, f: impl FnOnce(T) -> U) -> Result<U> {
+        let v = self.dequeue()?;
+        Ok(f(v))
+    }
+
+    /// Processes queued items with `f` until the queue is empty or `budget` has elapsed.
+    ///
+    /// Meant for frame-bounded loops (games, simulations, cooperative
+    /// schedulers): each tick drains what it can within its time slice, then
+    /// returns control. Items are pulled one at a time, and `f` runs with the
+    /// lock released, so long processing never blocks producers.
+    ///
+    /// The budget is checked before each item is pulled: the call never starts
+    /// a new item past the deadline, but it does not interrupt `f`, so a slow
+    /// last item can overrun the budget by its own processing time.
+    ///
+    /// Returns the number of items processed. This method never blocks waiting
+    /// for items.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "event_1".to_string()).unwrap();
+    ///
+    /// // Once per frame:
+    /// let handled = pq
+    ///     .drain_for(Duration::from_millis(4), |event| println!("{event}"))
+    ///     .unwrap();
+    /// ```
+    ///
+    pub fn drain_for(&self, budget: Duration, mut f: impl FnMut(T)) -> Result<usize> {
+        let start = Instant::now();
+        let mut processed = 0;
+        while start.elapsed() < budget {
+            let Some(v) = self.try_dequeue()? else {
+                break;
+            };
+            f(v);
+            processed += 1;
+        }
+        Ok(processed)
+    }
+
+    /// Returns the number of items currently queued, across all priority levels.
+    ///
+    /// The value is a snapshot taken under the lock; it may be stale as soon as
+    /// this method returns if other threads are producing or consuming.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn len(&self) -> Result<usize> {
+        let st = self.lock("len")?;
+        Ok(st.pq.len())
+    }
+
+    /// Returns `true` if no item is currently queued.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn is_empty(&self) -> Result<bool> {
+        let st = self.lock("is_empty")?;
+        Ok(st.pq.is_empty())
+    }
+
+    /// Returns the summed size of the queued items, in bytes.
+    ///
+    /// Sizes are those given by the `size_fn` of a queue bounded in bytes
+    /// (see [`with_byte_capacity()`]); other queues do not size their items
+    /// and always return `0`.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn byte_len(&self) -> Result<usize> {
+        let st = self.lock("byte_len")?;
+        Ok(st.bytes.as_ref().map_or(0, ByteBudget::used))
+    }
+
+    /// Returns `true` if the queue is bounded and has reached its capacity.
+    ///
+    /// Items held by an [`ItemGuard`] (see [`dequeue_guarded()`]) still use
+    /// their slot. Always `false` for unbounded queues. A producer can use it to skip
+    /// generating an expensive item that [`try_enqueue()`] would reject anyway;
+    /// like any snapshot, the answer may be stale by the time it is acted upon.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::with_capacity(3, 1);
+    /// assert!(!pq.is_full().unwrap());
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// assert!(pq.is_full().unwrap());
+    /// ```
+    ///
+    pub fn is_full(&self) -> Result<bool> {
+        let st = self.lock("is_full")?;
+        Ok(st.is_full())
+    }
+
+    /// Returns the current capacity of the queue, in items.
+    ///
+    /// This is the value given at construction, or the latest one chosen by
+    /// an adaptive capacity (see [`with_adaptive_capacity()`]). Unbounded
+    /// queues return `usize::MAX`.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn capacity(&self) -> Result<usize> {
+        let st = self.lock("capacity")?;
+        Ok(st.cap.unwrap_or(usize::MAX))
+    }
+
+    /// Returns how full a bounded queue is, as `len / capacity`.
+    ///
+    /// Items held by an [`ItemGuard`] still count in `len` here, as they do
+    /// against the capacity. Returns `None` for unbounded queues.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn fill_ratio(&self) -> Result<Option<f64>> {
+        let st = self.lock("fill_ratio")?;
+        Ok(st
+            .cap
+            .map(|cap| (st.pq.len() + st.held) as f64 / cap as f64))
+    }
+
+    /// Returns the queue depth, total and per priority level, read under the lock.
+    ///
+    /// The snapshot is exact and self-consistent (`depth` is the sum of
+    /// `depth_by_priority`), but taking it contends with producers and
+    /// consumers for the queue mutex. Monitoring code polling at a high rate
+    /// should prefer [`metrics_relaxed()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn metrics(&self) -> Result<QueueMetrics> {
+        let st = self.lock("metrics")?;
+        Ok(QueueMetrics {
+            depth: st.pq.len(),
+            depth_by_priority: (0..st.pq.n_prio())
+                .map(|prio| st.pq.len_at_priority(prio))
+                .collect::<Result<_>>()?,
+            total_enqueued: st.total_enqueued,
+            total_dequeued: st.total_dequeued,
+            total_removed: st.total_removed,
+        })
+    }
+
+    /// Returns the queue depth, total and per priority level, without locking.
+    ///
+    /// The values come from atomic gauges that every mutating operation
+    /// republishes while it still holds the lock, so reading them never
+    /// blocks nor slows down producers and consumers. This makes it suitable
+    /// for metrics exporters and dashboards polling at a high rate.
+    ///
+    /// # Staleness
+    ///
+    /// - Each counter holds a value it actually had at some recent point; it
+    ///   may lag behind operations completing concurrently with the read.
+    /// - Counters are read one by one, not atomically as a group: while the
+    ///   queue is being mutated, `depth` may differ from the sum of
+    ///   `depth_by_priority`, and two levels may reflect different moments.
+    /// - Once the queue is quiescent, the snapshot is exact.
+    ///
+    /// Use [`metrics()`] when a consistent snapshot is required.
+    ///
+    /// This method never fails, even if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(1, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// let m = pq.metrics_relaxed();
+    /// assert_eq!(m.depth, 1);
+    /// assert_eq!(m.depth_by_priority, vec![0, 1, 0]);
+    /// ```
+    ///
+    pub fn metrics_relaxed(&self) -> QueueMetrics {
+        self.inner.gauges.snapshot()
+    }
+
+    /// Returns how many times an operation found the internal mutex already
+    /// held and had to wait for it.
+    ///
+    /// This is a cheap probe to tell whether the single lock is the
+    /// bottleneck, without an external profiler: compare its rate with the
+    /// rate of operations (e.g. [`total_enqueued()`]). It is only counted
+    /// when the queue was built with
+    /// [`SyncPriorityQueueBuilder::contention_probe()`]; otherwise this
+    /// always returns `0`.
+    ///
+    /// # Behavior
+    ///
+    /// - Each acquisition counts at most once, however long it waited.
+    /// - Re-acquisitions of the lock by a thread waking up from a blocking
+    ///   wait (e.g. in [`dequeue()`]) are not counted.
+    /// - Like [`metrics_relaxed()`], this never takes the lock and never fails.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::builder(3)
+    ///     .contention_probe()
+    ///     .build();
+    /// // ... run the workload ...
+    /// println!("contended acquisitions: {}", pq.contention_count());
+    /// ```
+    ///
+    pub fn contention_count(&self) -> u64 {
+        self.inner
+            .contended
+            .as_ref()
+            .map_or(0, |contended| contended.load(Ordering::Relaxed))
+    }
+
+    /// Returns the number of items enqueued since the queue was created.
+    ///
+    /// The counter never decreases, which makes it suitable for a Prometheus
+    /// `counter` (rates are computed by the monitoring side), unlike the depth
+    /// returned by [`len()`], which is a gauge. Items put back by [`nack()`] or
+    /// [`recover_expired_leases()`] count again.
+    ///
+    /// Under the lock, `total_enqueued() - total_dequeued() - total_removed()`
+    /// always equals [`len()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # See also
+    ///
+    /// * [`total_dequeued()`] — The matching counter for items handed out.
+    /// * [`total_removed()`] — The matching counter for items dropped instead.
+    /// * [`metrics()`] — All three counters along with the depth, in one snapshot.
+    ///
+    pub fn total_enqueued(&self) -> Result<u64> {
+        let st = self.lock("total_enqueued")?;
+        Ok(st.total_enqueued)
+    }
+
+    /// Returns the number of items dequeued since the queue was created.
+    ///
+    /// Counts every dequeue, including batch ones such as
+    /// [`dequeue_one_per_entity()`] and [`drain_for()`], and leased items.
+    /// Items that left without being dequeued are counted by
+    /// [`total_removed()`] instead, so that this counter measures the
+    /// throughput of consumers.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// pq.enqueue(1, "A".to_string(), "item2".to_string()).unwrap();
+    /// pq.dequeue().unwrap();
+    ///
+    /// assert_eq!(pq.total_dequeued().unwrap(), 1);
+    /// ```
+    ///
+    pub fn total_dequeued(&self) -> Result<u64> {
+        let st = self.lock("total_dequeued")?;
+        Ok(st.total_dequeued)
+    }
+
+    /// Returns the number of items that left the queue without being
+    /// dequeued since it was created.
+    ///
+    /// That is, items removed by [`remove_entity_at()`] or
+    /// [`cancel_by_key()`], evicted by [`enqueue_or_replace_own()`] or to
+    /// honor another entity's reserved slots, and items dropped or taken back
+    /// at shutdown. With [`total_dequeued()`], it accounts for every item that
+    /// left: `total_enqueued() - total_dequeued() - total_removed()` always
+    /// equals the depth.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// pq.enqueue(1, "B".to_string(), "item2".to_string()).unwrap();
+    /// pq.dequeue().unwrap();
+    /// pq.remove_entity_at(1, &"B".to_string()).unwrap();
+    ///
+    /// assert_eq!(pq.total_dequeued().unwrap(), 1);
+    /// assert_eq!(pq.total_removed().unwrap(), 1);
+    /// ```
+    ///
+    pub fn total_removed(&self) -> Result<u64> {
+        let st = self.lock("total_removed")?;
+        Ok(st.total_removed)
+    }
+
+    /// Estimates how long an item enqueued now at level `prio` for
+    /// `entity_id` would wait before being dequeued, e.g. to show clients an
+    /// ETA.
+    ///
+    /// This is a **heuristic**: the number of items expected to leave the
+    /// queue first, times the average time between two dequeues measured so
+    /// far. It assumes strict priorities and steady consumers, and cannot
+    /// foresee items enqueued later at more urgent levels, so treat it as a
+    /// rough order of magnitude rather than a deadline.
+    ///
+    /// # Behavior
+    ///
+    /// - Items ahead are every item of the more urgent levels, plus, at
+    ///   `prio`, the entity's own pending items and, round-robin oblige, up
+    ///   to one more item than that from each other entity of the level.
+    /// - The dequeue rate is an exponentially weighted moving average over
+    ///   the recent dequeues, so it follows consumers that speed up or slow
+    ///   down within a few dozen items. Time spent with an empty queue is not
+    ///   counted as slowness.
+    /// - Returns `None` until at least two dequeues have been observed (or
+    ///   one since an item arrived in the empty queue).
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    /// // ... consumers at work ...
+    /// match pq.estimated_wait(1, &"A".to_string()).unwrap() {
+    ///     Some(eta) => println!("about {:.0?}", eta),
+    ///     None => println!("no estimate yet"),
+    /// }
+    /// ```
+    ///
+    /// # See also
+    ///
+    /// * [`enqueue_pos()`] — The entity's own position in line, at insertion time.
+    ///
+    pub fn estimated_wait(
+        &self,
+        prio: impl IntoPriority,
+        entity_id: &E,
+    ) -> Result<Option<Duration>> {
+        let prio = prio.as_index();
+        let st = self.lock("estimated_wait")?;
+        let own = st.pq.entity_len_at(prio, entity_id)?;
+        let mut ahead = own;
+        for entity in st.pq.entities_at(prio)? {
+            if entity != *entity_id {
+                ahead += st.pq.entity_len_at(prio, &entity)?.min(own + 1);
+            }
+        }
+        for level in 0..prio {
+            ahead += st.pq.len_at_priority(level)?;
+        }
+        // The new item leaves with the dequeue after the last one ahead.
+        Ok(st.rate.time_for(ahead + 1))
+    }
+
+    /// Audits fairness: returns each entity's share of the items dequeued so
+    /// far, and whether it is within `tolerance` of its fair share.
+    ///
+    /// Requires stats mode (see [`SyncPriorityQueueBuilder::entity_stats()`]),
+    /// which counts dequeued items per entity; without it, nothing has been
+    /// observed and the report is empty.
+    ///
+    /// # Behavior
+    ///
+    /// - Each tuple holds an entity, its observed share in `0.0..=1.0`, and
+    ///   `true` if the share deviates from the fair share by at most
+    ///   `tolerance` (an absolute difference of shares, e.g. `0.05` for five
+    ///   percentage points).
+    /// - The fair share is proportional to the entity's weight (see
+    ///   [`set_weight()`]), taken as its highest weight across levels, among
+    ///   the entities served so far: without weights, `1/n` for `n` entities.
+    ///   Entities that never had an item dequeued are not reported.
+    /// - Entities are sorted by decreasing share, so the most favoured one
+    ///   comes first.
+    /// - Shares reflect what was dequeued, not what was enqueued: an entity
+    ///   that submits little work naturally gets a small share.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::builder(3)
+    ///     .entity_stats()
+    ///     .build();
+    /// // ... run the workload ...
+    /// for (entity, share, fair) in pq.fairness_report(0.05).unwrap() {
+    ///     if !fair {
+    ///         println!("{entity}: {:.1}% of the items", share * 100.0);
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn fairness_report(&self, tolerance: f64) -> Result<Vec<(E, f64, bool)>> {
+        let st = self.lock("fairness_report")?;
+        let Some(served) = &st.served else {
+            return Ok(Vec::new());
+        };
+        let total: u64 = served.values().sum();
+        if total == 0 {
+            return Ok(Vec::new());
+        }
+        let weight_of = |entity_id: &E| {
+            (0..st.pq.n_prio())
+                .filter_map(|prio| st.pq.weight(prio, entity_id).ok().flatten())
+                .max()
+                .unwrap_or(1) as f64
+        };
+        let weights: Vec<f64> = served.keys().map(weight_of).collect();
+        let total_weight: f64 = weights.iter().sum();
+        let mut report: Vec<(E, f64, bool)> = served
+            .iter()
+            .zip(weights)
+            .map(|((entity_id, &count), weight)| {
+                let share = count as f64 / total as f64;
+                let fair = weight / total_weight;
+                (entity_id.clone(), share, (share - fair).abs() <= tolerance)
+            })
+            .collect();
+        report.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Ok(report)
+    }
+
+    /// Sets the weight of `entity_id` at level `prio`: the number of items it
+    /// may give in a row each time the round-robin reaches it.
+    ///
+    /// Every entity has a weight of 1 by default, i.e. plain round-robin. An
+    /// entity with weight 3 is served three times before the rotation moves
+    /// on, so among busy entities it gets three times the throughput. This
+    /// lets operators promote (or demote back) a tenant without restarting.
+    ///
+    /// # Behavior
+    ///
+    /// - The weight applies at level `prio` only, whenever `entity_id` has
+    ///   items there; it is kept while the entity has none.
+    /// - A burst of turns already started keeps the length it started with:
+    ///   the new weight takes effect from the entity's next turn in the
+    ///   rotation, never cutting short or stretching one in progress.
+    /// - A weight of 0 or 1 restores the default.
+    /// - Weights are ignored under [`Policy::GlobalEntityFair`].
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    /// pq.set_weight(1, &"premium".to_string(), 3).unwrap();
+    /// assert_eq!(pq.weight(1, &"premium".to_string()).unwrap(), Some(3));
+    /// ```
+    ///
+    /// # See also
+    /// * [`weight()`] — Read it back.
+    /// * [`fairness_report()`] — Check the resulting shares.
+    ///
+    pub fn set_weight(&self, prio: impl IntoPriority, entity_id: &E, weight: u32) -> Result<()> {
+        let mut st = self.lock("set_weight")?;
+        st.pq.set_weight(prio.as_index(), entity_id.clone(), weight)
+    }
+
+    /// Returns the weight of `entity_id` at level `prio`, or `None` if it has
+    /// the default weight of 1 (see [`set_weight()`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn weight(&self, prio: impl IntoPriority, entity_id: &E) -> Result<Option<u32>> {
+        let st = self.lock("weight")?;
+        st.pq.weight(prio.as_index(), entity_id)
+    }
+
+    /// Returns the recorded operations, oldest first.
+    ///
+    /// Only enqueues, dequeues ([`dequeue()`] and [`try_dequeue()`]) and
+    /// shutdowns are recorded, with their priority and entity but never their
+    /// payload. Once the log is full, the oldest records are discarded.
+    ///
+    /// Returns an empty `Vec` if the queue was not created with an op log
+    /// (see [`with_op_log()`]).
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::with_op_log(3);
+    /// pq.enqueue(1, "A".to_string(), "item1".to_string()).unwrap();
+    /// pq.dequeue().unwrap();
+    ///
+    /// for record in pq.op_log().unwrap() {
+    ///     println!("{:?} {:?}", record.at, record.op);
+    /// }
+    /// ```
+    ///
+    pub fn op_log(&self) -> Result<Vec<OpRecord<E>>> {
+        let st = self.lock("op_log")?;
+        Ok(st.op_log.as_ref().map_or_else(Vec::new, OpLog::to_vec))
+    }
+
+    /// Returns how long ago an item was last dequeued, or `None` if none ever was.
+    ///
+    /// Every successful dequeue records a timestamp, whatever the method used
+    /// (removals such as [`remove_entity_at()`] do not count). A supervisor can
+    /// combine it with [`is_empty()`]: a queue that holds items while nothing
+    /// has been dequeued for a long time points to wedged consumers.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    ///
+    /// let idle = pq.time_since_last_dequeue().unwrap();
+    /// if !pq.is_empty().unwrap() && idle.is_some_and(|d| d > Duration::from_secs(30)) {
+    ///     eprintln!("consumers look stalled");
+    /// }
+    /// ```
+    ///
+    pub fn time_since_last_dequeue(&self) -> Result<Option<Duration>> {
+        let st = self.lock("time_since_last_dequeue")?;
+        Ok(st.last_dequeue.map(|at| at.elapsed()))
+    }
+
+    /// Returns `true` if no item is queued at priority level `prio`.
+    ///
+    /// Only the requested level is inspected, which makes this cheaper than
+    /// computing a full length when a scheduler just needs to know whether
+    /// urgent work is pending.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(2, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// assert!(pq.is_level_empty(0).unwrap());
+    /// assert!(!pq.is_level_empty(2).unwrap());
+    /// ```
+    ///
+    pub fn is_level_empty(&self, prio: usize) -> Result<bool> {
+        let st = self.lock("is_level_empty")?;
+        st.pq.is_level_empty(prio)
+    }
+
+    /// Returns `true` if any level within `range` has pending items.
+    ///
+    /// This generalizes [`is_level_empty()`] to a band of priorities, for
+    /// tiered workers or admission control: e.g. `pq.has_work_in_range(..2)`
+    /// tells whether anything urgent is pending, without consuming it.
+    /// Only the levels of the range are inspected.
+    ///
+    /// # Behavior
+    ///
+    /// - The range must lie within the levels of the queue: it is not clamped.
+    ///   `..` covers every level.
+    /// - An empty range (e.g. `2..2`) holds no work.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the range reaches past the last
+    ///   level; the first invalid bound is reported.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(4);
+    /// pq.enqueue(2, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// assert!(!pq.has_work_in_range(..2).unwrap());
+    /// assert!(pq.has_work_in_range(1..=2).unwrap());
+    /// assert!(pq.has_work_in_range(2..).unwrap());
+    /// assert!(pq.has_work_in_range(0..5).is_err());
+    /// ```
+    ///
+    pub fn has_work_in_range(&self, range: impl RangeBounds<usize>) -> Result<bool> {
+        let st = self.lock("has_work_in_range")?;
+        let n_prio = st.pq.n_prio();
+        let start = match range.start_bound() {
+            Bound::Included(&prio) => prio,
+            Bound::Excluded(&prio) => prio.saturating_add(1),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&prio) if prio >= n_prio => {
+                return Err(PriorityQueueError::BadPriority(prio));
+            }
+            Bound::Included(&prio) => prio + 1,
+            Bound::Excluded(&prio) => prio,
+            Bound::Unbounded => n_prio,
+        };
+        if start > n_prio {
+            return Err(PriorityQueueError::BadPriority(start));
+        }
+        if end > n_prio {
+            return Err(PriorityQueueError::BadPriority(end - 1));
+        }
+        Ok((start..end).any(|prio| !st.pq.is_level_empty(prio).unwrap_or(true)))
+    }
+
+    /// Returns the entities with pending items at level `prio`, in round-robin order.
+    ///
+    /// Entities are tracked per priority level: the same id at two levels is two
+    /// independent streams, and this method only reports the requested level.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn entities_at(&self, prio: usize) -> Result<Vec<E>> {
+        let st = self.lock("entities_at")?;
+        st.pq.entities_at(prio)
+    }
+
+    /// Removes every item of `entity_id` at level `prio`, leaving its other levels untouched.
+    ///
+    /// Returns the number of items dropped (0 if the entity had nothing queued
+    /// at that level). The relative round-robin order of the remaining entities
+    /// is preserved.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn remove_entity_at(&self, prio: usize, entity_id: &E) -> Result<usize> {
+        let mut st = self.lock("remove_entity_at")?;
+        let removed = if st.bytes.is_some()
+            || st.index.is_some()
+            || st.on_remove.is_some()
+            || !st.notifiers.is_empty()
+        {
+            // Each item must be sized, unindexed, settled and reported as it
+            // leaves.
+            let mut removed = 0;
+            while let Some((seq, item)) = st.pq.try_dequeue_entity_at_seq(prio, entity_id)? {
+                st.settle_notifier(seq, false);
+                st.release(&item);
+                st.discard(RemovalReason::EntityRemoved, prio, entity_id.clone(), item);
+                removed += 1;
+            }
+            removed
+        } else {
+            st.pq.remove_entity_at(prio, entity_id)?
+        };
+        st.total_removed += removed as u64;
+        self.unlock_dequeued(st, removed, Freed::Level(prio));
+        Ok(removed)
+    }
+
+    /// Removes every queued item whose key is `key`, across entities and
+    /// levels, and returns how many were removed.
+    ///
+    /// Requires a queue indexed by that key type (see [`with_index()`]),
+    /// e.g. to cancel all the pending work of a job fanned out to several
+    /// entities and priorities.
+    ///
+    /// # Behavior
+    ///
+    /// - The cost is proportional to the items recorded under `key`, not to
+    ///   the size of the queue (plus, for each, a scan of its entity's items
+    ///   at its level).
+    /// - Removed items are dropped, and counted by [`total_removed()`].
+    ///   Their [`CompletionHandle`]s resolve as not dequeued, and blocked
+    ///   producers get the freed room.
+    /// - Items held by a consumer (e.g. leased) are no longer queued, and are
+    ///   left alone.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::NoIndex`] — if the queue has no index, or if
+    ///   `K` is not the key type of its index (mind integer literals: `&7`
+    ///   is an `&i32`).
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// // (job id, step)
+    /// let pq = SyncPriorityQueue::with_index(3, |task: &(u64, String)| task.0);
+    /// pq.enqueue(0, "A".to_string(), (7, "fetch".to_string())).unwrap();
+    /// pq.enqueue(2, "B".to_string(), (7, "report".to_string())).unwrap();
+    /// pq.enqueue(1, "B".to_string(), (8, "fetch".to_string())).unwrap();
+    ///
+    /// assert_eq!(pq.cancel_by_key(&7u64).unwrap(), 2);
+    /// assert_eq!(pq.len().unwrap(), 1);
+    /// ```
+    ///
+    pub fn cancel_by_key<K: 'static>(&self, key: &K) -> Result<usize> {
+        let mut st = self.lock("cancel_by_key")?;
+        let slots = st
+            .index
+            .as_mut()
+            .and_then(|index| index.take(key))
+            .ok_or(PriorityQueueError::NoIndex)?;
+        let mut removed = 0;
+        for (prio, entity_id, seq) in slots {
+            // Stale locations (items gone since) find nothing.
+            if let Some(item) = st.pq.remove_seq(prio, &entity_id, seq)? {
+                st.settle_notifier(seq, false);
+                if let Some(bytes) = &mut st.bytes {
+                    bytes.remove(&item);
+                }
+                st.discard(RemovalReason::Cancelled, prio, entity_id, item);
+                removed += 1;
+            }
+        }
+        st.total_removed += removed as u64;
+        self.unlock_dequeued(st, removed, Freed::Levels);
+        Ok(removed)
+    }
+
+    /// Registers `f` to receive every item removed from the queue without
+    /// being dequeued, replacing any previous hook.
+    ///
+    /// Items dropped by administrative operations are otherwise gone for
+    /// good: the hook gets them as `f(reason, priority, entity, item)`, e.g.
+    /// to audit them or move them to a dead-letter store.
+    ///
+    /// # Behavior
+    ///
+    /// - Covers every path that drops items: [`remove_entity_at()`]
+    ///   ([`RemovalReason::EntityRemoved`]), [`cancel_by_key()`]
+    ///   ([`RemovalReason::Cancelled`]), evictions for reserved slots
+    ///   ([`RemovalReason::Overflow`]), and [`shutdown_immediate()`],
+    ///   [`shutdown_timeout_floor()`] and [`reset()`]
+    ///   ([`RemovalReason::Cleared`]).
+    /// - Items handed back to the caller are not reported: the evicted item
+    ///   returned by [`enqueue_or_replace_own()`], and the items passed to
+    ///   [`shutdown_immediate_with()`] or returned by [`close_and_take()`].
+    /// - `f` runs on the thread that removed the items, once the internal
+    ///   lock has been released: it may call back into the queue, e.g. to
+    ///   requeue an item elsewhere. Items removed together are reported in
+    ///   removal order, after the operation's wakeups.
+    /// - Items removed before the hook is registered are not reported.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::{RemovalReason, SyncPriorityQueue};
+    /// use std::sync::mpsc;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// let (dead_letters, removed) = mpsc::channel();
+    /// pq.on_remove(move |reason, prio, entity, item| {
+    ///     dead_letters.send((reason, prio, entity, item)).unwrap();
+    /// })
+    /// .unwrap();
+    ///
+    /// pq.enqueue(1, "A".to_string(), "item1".to_string()).unwrap();
+    /// pq.remove_entity_at(1, &"A".to_string()).unwrap();
+    /// assert_eq!(
+    ///     removed.recv().unwrap(),
+    ///     (RemovalReason::EntityRemoved, 1, "A".to_string(), "item1".to_string()),
+    /// );
+    /// ```
+    ///
+    pub fn on_remove(
+        &self,
+        f: impl Fn(RemovalReason, usize, E, T) + Send + Sync + 'static,
+    ) -> Result<()> {
+        let mut st = self.lock("on_remove")?;
+        st.on_remove = Some(Arc::new(f));
+        Ok(())
+    }
+
+    /// Moves the head item of `from` at level `prio` to `to`, at the same level.
+    ///
+    /// Meant for reassigning queued work from one entity (e.g. a tenant) to
+    /// another, without a round-trip through a consumer. Returns `true` if an
+    /// item was moved, `false` if `from` had nothing queued at that level.
+    ///
+    /// # Behavior
+    ///
+    /// - The item goes to the **back** of `to`'s items at that level, as if
+    ///   `to` had just enqueued it: it waits behind `to`'s pending items, and
+    ///   counts against `to`'s fair share from then on. `to` joins the
+    ///   round-robin if it had no items at that level.
+    /// - `from` keeps its place in the round-robin if it has items left (the
+    ///   move is not its turn); otherwise it leaves the ring.
+    /// - The queue depth does not change, so no capacity is freed or taken
+    ///   and nobody is woken up. A [`CompletionHandle`] of the moved item
+    ///   stays attached to it.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::TooManyEntities`] — if `to` has no pending
+    ///   items, `from` keeps some, and the entity limit is reached (see
+    ///   [`with_max_entities()`]).
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(1, "A".to_string(), "job1".to_string()).unwrap();
+    /// pq.enqueue(1, "A".to_string(), "job2".to_string()).unwrap();
+    ///
+    /// // Hand A's next job over to B.
+    /// assert!(pq.reassign_head(1, &"A".to_string(), "B".to_string()).unwrap());
+    /// assert_eq!(pq.entities_at(1).unwrap(), vec!["A".to_string(), "B".to_string()]);
+    /// ```
+    ///
+    pub fn reassign_head(&self, prio: usize, from: &E, to: E) -> Result<bool> {
+        let mut st = self.lock("reassign_head")?;
+        let st = &mut *st;
+        // `to` is a newcomer unless it merely takes over `from`'s last item
+        if st.pq.entity_len(from) > 1 {
+            st.admit(&to)?;
+        }
+        let Some(index) = &mut st.index else {
+            return st.pq.reassign_head(prio, from, to);
+        };
+        let Some(seq) = st.pq.reassign_head_seq(prio, from, to.clone())? else {
+            return Ok(false);
+        };
+        if let Some(item) = st.pq.back_of_entity(prio, &to)? {
+            index.reassign(seq, &to, item);
+        }
+        Ok(true)
+    }
+
+    /// Reorders the round-robin ring of level `prio` so that entities whose head
+    /// item has waited the longest are served first.
+    ///
+    /// This is an explicit, occasional maintenance operation, **not** part of the
+    /// hot path: it sorts the whole ring of the level. It is meant to be called
+    /// periodically (e.g. from a maintenance tick) to counter the drift that heavy
+    /// entity churn can introduce in round-robin positions.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn rebalance(&self, prio: usize) -> Result<()> {
+        let mut st = self.lock("rebalance")?;
+        st.pq.rebalance(prio)
+    }
+
+    /// Subscribes to queue depth changes with hysteresis.
+    ///
+    /// The returned [`DepthWatcher`] blocks in [`DepthWatcher::wait()`] until the
+    /// depth rises above `high` ([`DepthEvent::AboveHigh`]) or falls below `low`
+    /// ([`DepthEvent::BelowLow`]). It is woken through a dedicated condition
+    /// variable, signaled on enqueue and dequeue only while at least one watcher
+    /// is alive, so consumers and producers are never woken by it.
+    ///
+    /// On shutdown, every watcher is unblocked with the terminal
+    /// [`DepthEvent::Closed`].
+    ///
+    /// # Arguments
+    ///
+    /// * `low` — Depth under which [`DepthEvent::BelowLow`] is reported.
+    /// * `high` — Depth above which [`DepthEvent::AboveHigh`] is reported.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `low > high`.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::{DepthEvent, SyncPriorityQueue};
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    /// let watcher = pq.watch_depth(10, 100).unwrap();
+    ///
+    /// loop {
+    ///     match watcher.wait().unwrap() {
+    ///         DepthEvent::AboveHigh => { /* pause producers */ }
+    ///         DepthEvent::BelowLow => { /* resume producers */ }
+    ///         DepthEvent::Closed => break,
+    ///     }
+    /// }
+    /// ```
+    ///
+    pub fn watch_depth(&self, low: usize, high: usize) -> Result<DepthWatcher<E, T>> {
+        DepthWatcher::new(Arc::clone(&self.inner), low, high)
+    }
+}
+
+/// ---
+/// ## Rounds
+///
+/// Operations available when entities have a total order.
+///
+impl<E, T> SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone + Ord,
+{
+    /// Starts a new scheduling round at level `prio`.
+    ///
+    /// The level's round-robin ring is re-sorted into canonical order
+    /// (ascending entity), instead of carrying over the rotation left by the
+    /// previous round. Scheduling algorithms that reason in discrete rounds get
+    /// the same, deterministic service order at the start of every round.
+    ///
+    /// Only the ring is reordered: each entity keeps its items, in their
+    /// enqueue order. This requires the entity type to implement [`Ord`].
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(1);
+    /// pq.enqueue(0, "B".to_string(), "B1".to_string()).unwrap();
+    /// pq.enqueue(0, "A".to_string(), "A1".to_string()).unwrap();
+    ///
+    /// pq.new_round(0).unwrap();
+    /// assert_eq!(pq.try_dequeue().unwrap(), Some("A1".to_string()));
+    /// ```
+    ///
+    pub fn new_round(&self, prio: usize) -> Result<()> {
+        let mut st = self.lock("new_round")?;
+        st.pq.new_round(prio)
+    }
+}
+
+/// ---
+/// ## Coalescing
+///
+/// Operations available when items can be compared.
+///
+impl<E, T> SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+    T: PartialEq,
+{
+    /// Enqueues an item, unless it equals the entity's last pending item at that level.
+    ///
+    /// Meant for idempotent signals (e.g. "refresh"): a burst of identical
+    /// items from one entity collapses into a single pending one, without a
+    /// separate dedup structure. Only the back of the entity's deque at level
+    /// `prio` is compared, so the check is O(1); an equal item further back,
+    /// at another level or from another entity does not prevent insertion.
+    ///
+    /// Returns `true` if the item was inserted, `false` if it was coalesced
+    /// with the tail (and dropped).
+    ///
+    /// # Behavior
+    ///
+    /// - Same as [`enqueue()`] when inserting, including blocking while the
+    ///   queue or the level is full.
+    /// - A coalesced item never blocks, even on a full queue.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed.
+    /// * [`PriorityQueueError::BadPriority`] — if the provided priority index is invalid.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// assert!(pq.enqueue_coalesce(1, "A".to_string(), "refresh".to_string()).unwrap());
+    /// assert!(!pq.enqueue_coalesce(1, "A".to_string(), "refresh".to_string()).unwrap());
+    /// assert_eq!(pq.len().unwrap(), 1);
+    /// ```
+    ///
+    pub fn enqueue_coalesce(&self, prio: impl IntoPriority, entity_id: E, item: T) -> Result<bool> {
+        let prio = prio.as_index();
+        self.push_blocking_unless(
+            "enqueue_coalesce",
+            prio,
+            entity_id,
+            item,
+            |st, e, item| {
+                st.pq
+                    .back_of_entity(prio, e)
+                    .is_ok_and(|back| back == Some(item))
+            },
+            Track::Nothing,
+        )
+        .map(|ahead| ahead.is_some())
+    }
+}
+
+/// ---
+/// ## Leases
+///
+/// Two-phase consumption for at-least-once processing: an item taken with
+/// [`dequeue_lease()`] stays in flight until it is acked, and goes back into
+/// the queue if it is nacked or its lease expires.
+///
+impl<E, T> SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+    T: Clone,
+{
+    /// Dequeues an item, blocking until one is available, and keeps a copy in flight.
+    ///
+    /// The item is removed from the queue exactly like [`dequeue()`], but a
+    /// copy is kept under the returned [`LeaseId`] until the caller settles it:
+    ///
+    /// - [`ack()`] once the item has been processed: the copy is dropped;
+    /// - [`nack()`] if processing failed: the item is requeued;
+    /// - otherwise, [`recover_expired_leases()`] eventually requeues it, which
+    ///   covers workers that crashed or hung.
+    ///
+    /// In-flight items are not counted in [`len()`] nor against capacity.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed and empty.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "task_1".to_string()).unwrap();
+    ///
+    /// let (lease, task) = pq.dequeue_lease().unwrap();
+    /// // ... process `task` ...
+    /// pq.ack(lease).unwrap();
+    /// ```
+    ///
+    pub fn dequeue_lease(&self) -> Result<(LeaseId, T)> {
+        self.pop_blocking("dequeue_lease", |st| {
+            let (prio, entity_id, item) = st.pop_tagged()?;
+            let lease = LeaseId(st.next_lease);
+            st.next_lease += 1;
+            st.leases.insert(
+                lease,
+                Lease {
+                    prio,
+                    entity_id,
+                    item: item.clone(),
+                    since: Instant::now(),
+                },
+            );
+            Some((lease, item))
+        })
+    }
+
+    /// Settles a lease as processed, dropping the in-flight copy of its item.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::UnknownLease`] — if the lease was already settled or recovered.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn ack(&self, lease: LeaseId) -> Result<()> {
+        let mut st = self.lock("ack")?;
+        match st.leases.remove(&lease) {
+            Some(_) => Ok(()),
+            None => Err(PriorityQueueError::UnknownLease(lease.as_u64())),
+        }
+    }
+
+    /// Settles a lease as failed, putting its item back into the queue.
+    ///
+    /// The item is requeued at its original priority level, for its original
+    /// entity, behind the entity's pending items. It was already admitted
+    /// once, so the capacity is not checked again.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::UnknownLease`] — if the lease was already settled or recovered.
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed; the lease is
+    ///   left in flight and can still be acked.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn nack(&self, lease: LeaseId) -> Result<()> {
+        let mut st = self.lock("nack")?;
+        if !st.leases.contains_key(&lease) {
+            return Err(PriorityQueueError::UnknownLease(lease.as_u64()));
+        }
+        if st.closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        let requeued = usize::from(st.requeue_leases([lease]) > 0);
+        self.unlock_enqueued(st, requeued);
+        Ok(())
+    }
+
+    /// Requeues every item whose lease is at least `older_than` old.
+    ///
+    /// Meant to be called periodically (e.g. from a maintenance tick): items
+    /// taken by a worker that crashed or hung become available again.
+    /// Expired leases are requeued oldest first, as with [`nack()`], and
+    /// settling them afterwards fails with [`PriorityQueueError::UnknownLease`].
+    ///
+    /// Returns the number of requeued items.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Closed`] — if the queue is closed; leases are left in flight.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn recover_expired_leases(&self, older_than: Duration) -> Result<usize> {
+        let mut st = self.lock("recover_expired_leases")?;
+        if st.closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        let mut expired: Vec<LeaseId> = st
+            .leases
+            .iter()
+            .filter(|(_, lease)| lease.since.elapsed() >= older_than)
+            .map(|(&id, _)| id)
+            .collect();
+        expired.sort_unstable();
+        let requeued = st.requeue_leases(expired);
+        self.unlock_enqueued(st, requeued);
+        Ok(requeued)
+    }
+}
+
+/// ---
+/// ## Snapshots
+///
+/// Copies of the queue content, leaving the queue untouched.
+///
+impl<E, T> SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+    T: Clone,
+{
+    /// Returns every queued item as `(priority, entity, item)`, in the exact
+    /// order successive [`dequeue()`] calls would return them.
+    ///
+    /// The order is computed from a copy of the queue taken in a single lock
+    /// acquisition, then drained with the lock released: the copy starts from
+    /// the same round-robin positions, level quotas, weights and policy, so
+    /// it walks the items exactly as the queue itself would if no other
+    /// operation came in between. Meant for offline verification of fairness
+    /// and debugging, not for the hot path: it clones every item and entity
+    /// while holding the lock.
+    ///
+    /// # Behavior
+    ///
+    /// - The queue itself is left unchanged.
+    /// - The order is that of an uninterrupted drain: any later enqueue or
+    ///   dequeue may change it.
+    /// - A fenced queue (see [`shutdown_graceful_then_fence()`]) gives
+    ///   nothing to [`dequeue()`]; its snapshot still lists the stray items.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(2);
+    /// pq.enqueue(1, "A".to_string(), 1).unwrap();
+    /// pq.enqueue(1, "A".to_string(), 2).unwrap();
+    /// pq.enqueue(1, "B".to_string(), 3).unwrap();
+    /// pq.enqueue(0, "C".to_string(), 4).unwrap();
+    ///
+    /// let order: Vec<u32> = pq.to_sorted_vec().unwrap().into_iter().map(|(_, _, v)| v).collect();
+    /// assert_eq!(order, vec![4, 1, 3, 2]);
+    /// ```
+    ///
+    pub fn to_sorted_vec(&self) -> Result<Vec<(usize, E, T)>> {
+        let st = self.lock("to_sorted_vec")?;
+        let mut copy = st.pq.clone();
+        drop(st);
+        let mut sorted = Vec::with_capacity(copy.len());
+        while let Some(entry) = copy.try_dequeue_tagged() {
+            sorted.push(entry);
+        }
+        Ok(sorted)
+    }
+}
+
+/// ---
+/// ## Shutdown Modes
+///
+/// Control how and when the queue stops processing items.
+///
+impl<E, T> SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    /// Immediately closes the queue and wakes all waiting threads.
+    ///
+    /// This method sets the internal `closed` flag to `true`, clears all pending
+    /// elements in the queue, and notifies all waiting producers and consumers.
+    ///
+    /// Unlike [`shutdown_graceful()`] or [`shutdown_timeout()`], this variant
+    /// does **not wait** for items to be consumed — it simply closes everything
+    /// at once.
+    ///
+    /// # Behavior
+    ///
+    /// - Any pending calls to [`dequeue()`] will return [`PriorityQueueError::Closed`].
+    /// - Any subsequent calls to [`enqueue()`] will also return [`PriorityQueueError::Closed`].
+    /// - The internal condition variable is signaled with `notify_all()`,
+    ///   ensuring that no thread remains blocked on the queue.
+    ///
+    /// This operation is **idempotent** — calling it multiple times has no effect
+    /// after the first invocation.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the internal state
+    ///   has been poisoned (for example, due to a panic in another thread).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    ///
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// pq.enqueue(1, "B".to_string(), "item2".to_string()).unwrap();
+    ///
+    /// pq.shutdown_immediate().unwrap();
+    ///
+    /// assert!(pq.enqueue(2, "C".to_string(), "item3".to_string()).is_err());
+    /// assert!(pq.dequeue().is_err());
+    /// ```
+    ///
+    /// # See also
+    /// * [`shutdown_graceful()`] — Waits for the queue to empty before closing.
+    /// * [`shutdown_timeout()`] — Like graceful shutdown, but with a maximum wait duration.
+    ///
+    pub fn shutdown_immediate(&self) -> Result<()> {
+        let mut st = self.lock("shutdown_immediate")?;
+        st.close();
+        st.discard_all();
+        let _removed = st.take_removed();
+        self.inner.gauges.publish(&st);
+        drop(st);
+        self.inner.cv.notify_all();
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+        Ok(())
+    }
+
+    /// Immediately closes the queue, passing each dropped item to `f`.
+    ///
+    /// Behaves like [`shutdown_immediate()`], except that pending items are
+    /// not silently discarded: each one is handed to `f(priority, entity, item)`,
+    /// in dequeue order, e.g. to log it or move it to a dead-letter store.
+    /// Items are streamed one by one, so even a huge backlog is never
+    /// collected into an intermediate `Vec` (see [`close_and_take()`] for that).
+    ///
+    /// `f` runs **while the lock is held**: the queue is closing anyway, so
+    /// nothing is held up but late callers, and it guarantees that no item can
+    /// be dequeued concurrently. As a consequence, `f` must not call back into
+    /// this queue (it would deadlock), and a panic in `f` poisons the lock.
+    /// Waiting threads are woken once every item has been handed out.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// pq.shutdown_immediate_with(|prio, entity, item| {
+    ///     eprintln!("dropped {item} (prio {prio}, from {entity})");
+    /// })
+    /// .unwrap();
+    /// ```
+    ///
+    /// # See also
+    /// * [`shutdown_immediate()`] — Same, discarding items.
+    /// * [`close_and_take()`] — Returns the pending items instead.
+    ///
+    pub fn shutdown_immediate_with(&self, mut f: impl FnMut(usize, E, T)) -> Result<()> {
+        let mut st = self.lock("shutdown_immediate_with")?;
+        st.close();
+        st.total_removed += st.pq.len() as u64;
+        st.notifiers.clear();
+        if let Some(bytes) = &mut st.bytes {
+            bytes.clear();
+        }
+        if let Some(index) = &mut st.index {
+            index.clear();
+        }
+        while let Some((prio, entity_id, item)) = st.pq.try_dequeue_tagged() {
+            f(prio, entity_id, item);
+        }
+        self.inner.gauges.publish(&st);
+        drop(st);
+        self.inner.cv.notify_all();
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+        Ok(())
+    }
+
+    /// Closes the queue and hands back every pending item, in dequeue order.
+    ///
+    /// This is the "flush then close" step of a rolling restart: in a single
+    /// lock acquisition, the queue stops accepting items, all pending items are
+    /// collected exactly in the order consumers would have received them, and
+    /// the queue is left empty. Nothing is dropped: whatever is not returned
+    /// here has already been handed to a consumer.
+    ///
+    /// Each entry is `(priority, entity, item)`, so the caller can re-enqueue
+    /// the work elsewhere (e.g. serialize it to another node) unchanged.
+    ///
+    /// # Behavior
+    ///
+    /// - Subsequent calls to [`enqueue()`] return [`PriorityQueueError::Closed`].
+    /// - Blocked consumers wake up and get [`PriorityQueueError::Closed`].
+    /// - A pending [`shutdown_graceful()`] completes, as the queue is now empty.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(1, "A".to_string(), "item1".to_string()).unwrap();
+    /// pq.enqueue(0, "B".to_string(), "item2".to_string()).unwrap();
+    ///
+    /// let pending = pq.close_and_take().unwrap();
+    /// assert_eq!(pending[0], (0, "B".to_string(), "item2".to_string()));
+    /// assert!(pq.enqueue(0, "C".to_string(), "item3".to_string()).is_err());
+    /// ```
+    ///
+    /// # See also
+    /// * [`shutdown_immediate()`] — Closes immediately, dropping pending items.
+    ///
+    pub fn close_and_take(&self) -> Result<Vec<(usize, E, T)>> {
+        let mut st = self.lock("close_and_take")?;
+        st.close();
+        let mut pending = Vec::with_capacity(st.pq.len());
+        while let Some(entry) = st.pq.try_dequeue_tagged() {
+            pending.push(entry);
+        }
+        st.total_removed += pending.len() as u64;
+        st.notifiers.clear();
+        if let Some(bytes) = &mut st.bytes {
+            bytes.clear();
+        }
+        if let Some(index) = &mut st.index {
+            index.clear();
+        }
+        self.inner.gauges.publish(&st);
+        drop(st);
+        self.inner.cv.notify_all();
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+        Ok(pending)
+    }
+
+    /// Consumes the last handle to the queue and returns the underlying fair queue.
+    ///
+    /// Once all producers and consumers are gone, this lets the owner drain or
+    /// inspect the leftover items single-threadedly, without paying for the
+    /// lock on each operation. The returned [`PriorityQueue`] keeps its pending
+    /// items, levels and round-robin state; the queue does not need to be
+    /// closed first.
+    ///
+    /// Like [`Arc::try_unwrap()`], this only succeeds if `self` is the only
+    /// handle: any other clone (including one held by a [`MpscCompat`] or a
+    /// [`DepthWatcher`]) makes it fail and hand `self` back unchanged.
+    ///
+    /// Items held by unsettled leases are not part of the fair queue and are
+    /// dropped. A poisoned mutex does not prevent the conversion.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Err(self)` if another handle to the queue still exists.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(1, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// let other = pq.clone();
+    /// let pq = pq.try_into_inner().err().unwrap();
+    /// drop(other);
+    ///
+    /// let mut inner = pq.try_into_inner().ok().unwrap();
+    /// assert_eq!(inner.try_dequeue(), Some("item1".to_string()));
+    /// ```
+    ///
+    pub fn try_into_inner(self) -> std::result::Result<PriorityQueue<E, T>, Self> {
+        match Arc::try_unwrap(self.inner) {
+            Ok(inner) => {
+                let st = inner
+                    .state
+                    .into_inner()
+                    .unwrap_or_else(PoisonError::into_inner);
+                Ok(st.pq)
+            }
+            Err(inner) => Err(Self { inner }),
+        }
+    }
+
+    /// Closes the queue and waits until all elements have been consumed.
+    ///
+    /// This method sets the internal `closed` flag to `true` and blocks
+    /// until the queue becomes empty. Once all items are processed,
+    /// it wakes all waiting threads with `notify_all()`.
+    ///
+    /// Unlike [`shutdown_immediate()`], this method gives active consumers
+    /// time to finish processing pending elements.
+    ///
+    /// # Behavior
+    ///
+    /// - The queue is marked as closed immediately.
+    /// - Producers can no longer enqueue new items.
+    /// - Consumers may continue dequeuing until the queue is empty.
+    /// - Once empty, all waiting threads are notified and the function returns.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the internal state
+    ///   has been poisoned (for example, due to a panic in another thread).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// // A separate thread will dequeue after a short delay.
+    /// let pq_clone = pq.clone();
+    /// thread::spawn(move || {
+    ///     thread::sleep(Duration::from_millis(50));
+    ///     pq_clone.dequeue().unwrap();
+    /// });
+    ///
+    /// pq.shutdown_graceful().unwrap();
+    /// ```
+    ///
+    /// # See also
+    /// * [`shutdown_immediate()`] — Closes the queue immediately without waiting.
+    /// * [`shutdown_timeout()`] — Like graceful shutdown, but with a maximum wait duration.
+    ///
+    pub fn shutdown_graceful(&self) -> Result<()> {
+        let mut st = self.lock("shutdown_graceful")?;
+        st.close();
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+        if st.pq.is_empty() {
+            drop(st);
+            self.inner.cv.notify_all();
+            return Ok(());
+        }
+        st = self
+            .inner
+            .cv
+            .wait_while(st, |s| !s.pq.is_empty())
+            .or_else(|e| self.inner.recover(e, "shutdown_graceful"))?;
+        drop(st);
+        self.inner.cv.notify_all();
+        Ok(())
+    }
+
+    /// Same as [`shutdown_graceful()`], but also fences the queue once it is
+    /// drained: from then on, no item can be dequeued anymore.
+    ///
+    /// With a plain graceful shutdown, a closed and empty queue already
+    /// answers [`PriorityQueueError::Closed`], but only as long as it stays
+    /// empty. The fence makes the terminal state unconditional, so that a
+    /// coordinator returning from this call knows that every consumer is done
+    /// pulling items, whatever happens to the queue afterwards.
+    ///
+    /// # Behavior
+    ///
+    /// - The queue is marked as closed immediately, and consumers keep
+    ///   dequeuing until it is empty, as with [`shutdown_graceful()`].
+    /// - The fence is set under the same lock acquisition that observes the
+    ///   queue empty: no dequeue can slip in between.
+    /// - Once fenced, every dequeue returns [`PriorityQueueError::Closed`]
+    ///   (blocking forms) or nothing (non-blocking forms), even if an item
+    ///   were to find its way back into the queue. Such an item stays in the
+    ///   queue, and can still be recovered with [`close_and_take()`].
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the internal state
+    ///   has been poisoned (for example, due to a panic in another thread).
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_core::PriorityQueueError;
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::thread;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// let pq_clone = pq.clone();
+    /// let worker = thread::spawn(move || while pq_clone.dequeue().is_ok() {});
+    ///
+    /// pq.shutdown_graceful_then_fence().unwrap();
+    /// assert!(matches!(pq.dequeue(), Err(PriorityQueueError::Closed)));
+    /// worker.join().unwrap();
+    /// ```
+    ///
+    /// # See also
+    /// * [`shutdown_graceful()`] — Same drain, without the fence.
+    ///
+    pub fn shutdown_graceful_then_fence(&self) -> Result<()> {
+        let mut st = self.lock("shutdown_graceful_then_fence")?;
+        st.close();
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+        let mut st = self
+            .inner
+            .cv
+            .wait_while(st, |s| !s.pq.is_empty())
+            .or_else(|e| self.inner.recover(e, "shutdown_graceful_then_fence"))?;
+        st.fenced = true;
+        // Parked consumers in line must all see the fence, not just the front one.
+        if let Some(waiters) = &st.waiters {
+            waiters.wake_all();
+        }
+        drop(st);
+        self.inner.cv.notify_all();
+        Ok(())
+    }
+
+    /// Closes the queue and waits for it to empty, up to a maximum duration.
+    ///
+    /// This method behaves like [`shutdown_graceful()`],
+    /// but limits the total waiting time.
+    /// If the queue is not empty after the specified duration,
+    /// it returns [`PriorityQueueError::Timeout`].
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` — Maximum duration to wait before aborting the shutdown.
+    ///
+    /// # Behavior
+    ///
+    /// - The queue is marked as closed immediately.
+    /// - Consumers may continue processing items until the timeout expires.
+    /// - If the timeout expires before the queue becomes empty,
+    ///   the function returns a `Timeout` error.
+    /// - Otherwise, the shutdown completes successfully.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the state is poisoned.
+    /// * [`PriorityQueueError::Timeout`] — if the queue did not empty within the timeout period.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::thread;
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// // Another thread consumes after a short delay
+    /// let pq_clone = pq.clone();
+    /// thread::spawn(move || {
+    ///     thread::sleep(Duration::from_millis(50));
+    ///     pq_clone.dequeue().unwrap();
+    /// });
+    ///
+    /// // This will succeed: queue empties before timeout
+    /// pq.shutdown_timeout(Duration::from_millis(100)).unwrap();
+    ///
+    /// // But if we reduce the timeout drastically:
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "B".to_string(), "item2".to_string()).unwrap();
+    /// let result = pq.shutdown_timeout(Duration::from_millis(5));
+    /// assert!(result.is_err()); // -> Timeout
+    /// ```
+    ///
+    /// # See also
+    /// * [`shutdown_graceful()`] — Waits indefinitely for the queue to empty.
+    /// * [`shutdown_immediate()`] — Closes immediately without waiting.
+    /// * [`shutdown_timeout_report()`] — Same, but tells how many items were left.
+    ///
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Result<()> {
+        match self.shutdown_timeout_report(timeout)? {
+            0 => Ok(()),
+            _ => Err(PriorityQueueError::Timeout),
+        }
+    }
+
+    /// Same as [`shutdown_timeout()`], but reports how many items were left
+    /// undrained instead of failing with [`PriorityQueueError::Timeout`].
+    ///
+    /// Returns `0` if the queue emptied within `timeout`, or the number of
+    /// items still queued when it expired. The caller can then decide, from
+    /// how much work is left, whether to force the shutdown (e.g. with
+    /// [`shutdown_immediate()`]) or to wait some more (the queue stays closed
+    /// and its consumers keep draining it).
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the state is poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    ///
+    /// // No consumer: nothing drains.
+    /// let left = pq.shutdown_timeout_report(Duration::from_millis(10)).unwrap();
+    /// if left > 100 {
+    ///     pq.shutdown_immediate().unwrap();
+    /// } else {
+    ///     pq.shutdown_graceful().unwrap();
+    /// }
+    /// ```
+    ///
+    pub fn shutdown_timeout_report(&self, timeout: Duration) -> Result<usize> {
+        let mut st = self.lock("shutdown_timeout_report")?;
+        st.close();
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+        if st.pq.is_empty() {
+            drop(st);
+            self.inner.cv.notify_all();
+            return Ok(0);
+        }
+        let (next_st, _) = self
+            .inner
+            .cv
+            .wait_timeout_while(st, timeout, |s| !s.pq.is_empty())
+            .or_else(|e| self.inner.recover(e, "shutdown_timeout_report"))?;
+
+        /*
+         * Edge case: race condition between the queue and the timer.
+         *
+         * If the timeout expires exactly when the queue becomes empty,
+         * `wait_timeout_while` may return `timed_out = true` even though
+         * the condition is no longer true (the queue is already empty).
+         *
+         * → Without this extra check, we could incorrectly report leftovers.
+         *   Always re-test the queue state before deciding.
+         */
+        let remaining = next_st.pq.len();
+        drop(next_st);
+        if remaining == 0 {
+            self.inner.cv.notify_all();
+        }
+        Ok(remaining)
+    }
+
+    /// Closes the queue and waits, up to `timeout`, for its urgent levels only to drain.
+    ///
+    /// This is a tiered variant of [`shutdown_timeout()`]: only the items with a
+    /// priority `< min_prio` (more urgent than the floor) must be consumed
+    /// within the deadline. Whatever remains at levels `min_prio` and below is
+    /// abandoned.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` — Maximum duration to wait for the urgent levels to drain.
+    /// * `min_prio` — The floor: levels `0..min_prio` must drain. Passing the
+    ///   number of levels makes this equivalent to [`shutdown_timeout()`].
+    ///
+    /// # Behavior
+    ///
+    /// - The queue is marked as closed immediately.
+    /// - Consumers keep dequeuing in priority order until the timeout expires.
+    /// - Once levels `0..min_prio` are empty, the remaining lower-priority items
+    ///   are dropped, blocked consumers get [`PriorityQueueError::Closed`], and
+    ///   the function returns `Ok(())`.
+    /// - If the timeout expires first, nothing is dropped and the function
+    ///   returns [`PriorityQueueError::Timeout`].
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::BadPriority`] — if `min_prio` exceeds the number of levels.
+    /// * [`PriorityQueueError::LockError`] — if the mutex guarding the state is poisoned.
+    /// * [`PriorityQueueError::Timeout`] — if the urgent levels did not drain in time.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::time::Duration;
+    ///
+    /// let pq = SyncPriorityQueue::new(3);
+    /// pq.enqueue(2, "A".to_string(), "background".to_string()).unwrap();
+    ///
+    /// // Nothing urgent is pending: returns at once and drops the prio-2 item.
+    /// pq.shutdown_timeout_floor(Duration::from_millis(100), 1).unwrap();
+    /// assert!(pq.is_empty().unwrap());
+    /// ```
+    ///
+    /// # See also
+    /// * [`shutdown_timeout()`] — Waits for every level to drain.
+    ///
+    pub fn shutdown_timeout_floor(&self, timeout: Duration, min_prio: usize) -> Result<()> {
+        let mut st = self.lock("shutdown_timeout_floor")?;
+        if min_prio > st.pq.n_prio() {
+            return Err(PriorityQueueError::BadPriority(min_prio));
+        }
+        st.close();
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+
+        // Consumers only signal `cv` when the whole queue empties: ask them to
+        // signal every dequeue while we wait on a subset of the levels.
+        st.floor_waiters += 1;
+        let res = self
+            .inner
+            .cv
+            .wait_timeout_while(st, timeout, |s| !s.is_drained_above(min_prio));
+        let (mut st, _) =
+            self.inner
+                .unpoison_leaving(res, "shutdown_timeout_floor", |(st, _)| {
+                    st.floor_waiters -= 1
+                })?;
+
+        // Same edge case as `shutdown_timeout_report`: trust the levels, not the timer.
+        if !st.is_drained_above(min_prio) {
+            return Err(PriorityQueueError::Timeout);
+        }
+        st.discard_all();
+        let _removed = st.take_removed();
+        self.inner.gauges.publish(&st);
+        drop(st);
+        self.inner.cv.notify_all();
+        self.inner.depth_cv.notify_all();
+        Ok(())
+    }
+
+    /// Resets the queue to its freshly built state: empty, open, with zeroed
+    /// counters, while keeping its configuration and allocated capacity.
+    ///
+    /// Meant for pools of queues reused across test cases or request cycles:
+    /// a pooled queue can be handed out again without the allocation cost of
+    /// a new one. Unlike a shutdown, this also reopens a closed queue.
+    ///
+    /// # Behavior
+    ///
+    /// - Pending items are dropped; completion handles of dropped items
+    ///   resolve as not dequeued.
+    /// - Leases in flight are forgotten: settling them afterwards fails with
+    ///   [`PriorityQueueError::UnknownLease`].
+    /// - Slots held by [`ItemGuard`]s are freed: dropping such a guard
+    ///   afterwards gives nothing back.
+    /// - [`total_enqueued()`], [`total_dequeued()`], [`total_removed()`],
+    ///   [`time_since_last_dequeue()`], the per-entity counts behind
+    ///   [`fairness_report()`] and the op log start over.
+    /// - The configuration is kept: capacities, quotas, policy, fair wakeup,
+    ///   spin and contention probe. The contention count is not reset. An
+    ///   adaptive capacity starts over from its minimum.
+    /// - The queue should not be in use by other threads during the reset:
+    ///   blocked producers are woken up (they find room again), but
+    ///   consumers that already returned [`PriorityQueueError::Closed`] do
+    ///   not come back.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    ///
+    /// let pq = SyncPriorityQueue::with_capacity(3, 1024);
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// pq.shutdown_immediate().unwrap();
+    ///
+    /// // Back to the pool, then handed out again.
+    /// pq.reset().unwrap();
+    /// pq.enqueue(0, "A".to_string(), "item2".to_string()).unwrap();
+    /// assert_eq!(pq.total_enqueued().unwrap(), 1);
+    /// ```
+    ///
+    pub fn reset(&self) -> Result<()> {
+        let mut st = self.lock("reset")?;
+        if st.on_remove.is_some() {
+            while let Some((prio, entity_id, item)) = st.pq.try_dequeue_tagged() {
+                st.discard(RemovalReason::Cleared, prio, entity_id, item);
+            }
+        }
+        let _removed = st.take_removed();
+        st.pq.reset();
+        st.notifiers.clear();
+        st.leases.clear();
+        st.next_lease = 0;
+        st.closed = false;
+        st.fenced = false;
+        st.held = 0;
+        st.held_epoch += 1;
+        st.total_enqueued = 0;
+        st.total_dequeued = 0;
+        st.total_removed = 0;
+        st.last_dequeue = None;
+        st.rate = DequeueRate::new();
+        if let Some(adaptive) = &mut st.adaptive {
+            adaptive.restart();
+            st.cap = Some(adaptive.min());
+        }
+        if let Some(served) = &mut st.served {
+            served.clear();
+        }
+        if let Some(bytes) = &mut st.bytes {
+            bytes.clear();
+        }
+        if let Some(index) = &mut st.index {
+            index.clear();
+        }
+        if let Some(log) = &mut st.op_log {
+            log.clear();
+        }
+        self.inner.gauges.publish(&st);
+        drop(st);
+        self.inner.wake_producers();
+        self.inner.depth_cv.notify_all();
+        Ok(())
+    }
+
+    /// Blocks until the queue is closed, whatever happens to its items.
+    ///
+    /// A "wait for the shutdown signal" primitive, e.g. for a coordinator
+    /// thread that tears other resources down once the queue stops, without
+    /// polling. Returns `true` once the queue is closed, or `false` if
+    /// `timeout` elapses first (`None` waits forever).
+    ///
+    /// # Behavior
+    ///
+    /// - Returns `true` right away if the queue is already closed.
+    /// - Every shutdown mode counts as a close, including
+    ///   [`close_and_take()`]; a [`reset()`] reopens the queue for later calls.
+    /// - The wait does not involve consumers: it never takes an item, nor a
+    ///   wakeup meant for a consumer.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::thread;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    /// let coordinator = {
+    ///     let pq = pq.clone();
+    ///     thread::spawn(move || {
+    ///         pq.wait_for_close(None).unwrap();
+    ///         println!("queue closed, releasing resources");
+    ///     })
+    /// };
+    ///
+    /// pq.shutdown_graceful().unwrap();
+    /// coordinator.join().unwrap();
+    /// ```
+    ///
+    pub fn wait_for_close(&self, timeout: Option<Duration>) -> Result<bool> {
+        let st = self.lock("wait_for_close")?;
+        // `depth_cv` rather than the consumers' `cv`: every close notifies
+        // it, and waiting there cannot swallow a single-consumer wakeup.
+        let not_closed = |s: &mut State<E, T>| !s.closed;
+        let st = match timeout {
+            None => self.inner.unpoison(
+                self.inner.depth_cv.wait_while(st, not_closed),
+                "wait_for_close",
+            )?,
+            Some(timeout) => {
+                self.inner
+                    .unpoison(
+                        self.inner
+                            .depth_cv
+                            .wait_timeout_while(st, timeout, not_closed),
+                        "wait_for_close",
+                    )?
+                    .0
+            }
+        };
+        Ok(st.closed)
+    }
+}
+
+/// ---
+/// ## Test Hooks
+///
+/// Control over wakeups, for deterministic concurrency tests. Only compiled
+/// in with the `test-hooks` feature (and in the crate's own tests).
+///
+#[cfg(any(test, feature = "test-hooks"))]
+impl<E, T> SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    /// Installs `f` to run right before threads are woken up after an
+    /// enqueue or a dequeue, replacing any previous hook.
+    ///
+    /// The hook runs on the thread that enqueued or dequeued, once the
+    /// internal lock has been released (so it may inspect the queue), and
+    /// the wakeups are only delivered when it returns. Blocking in the hook
+    /// thus holds the notifications back, which lets a test decide exactly
+    /// when waiting threads observe a change, and reproduce lost-wakeup or
+    /// ordering bugs instead of waiting for them to happen by chance.
+    ///
+    /// Shutdowns wake threads up directly, without calling the hook.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use pq_sync::SyncPriorityQueue;
+    /// use std::sync::mpsc;
+    ///
+    /// let pq = SyncPriorityQueue::<String, String>::new(3);
+    /// let (entered, on_enter) = mpsc::channel();
+    /// pq.set_notify_hook(move || entered.send(()).unwrap());
+    ///
+    /// pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    /// on_enter.recv().unwrap(); // the enqueue reached its wakeup phase
+    /// ```
+    ///
+    pub fn set_notify_hook(&self, f: impl Fn() + Send + Sync + 'static) {
+        *self
+            .inner
+            .notify_hook
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(f));
+    }
+}
+
+/// ---
+/// ## Internals
+///
+/// Locking and wake-up helpers shared by the operations above.
+/// The `unlock_*` helpers consume the guard so that threads are always woken
+/// after the lock has been released; they also republish the lock-free depth
+/// gauges read by [`metrics_relaxed()`], while the lock is still held.
+///
+impl<E, T> SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    // Locks the state, tagging a poisoned lock with the calling operation.
+    // With the contention probe, a first `try_lock` tells whether we had to wait.
+    fn lock(&self, op: &'static str) -> Result<MutexGuard<'_, State<E, T>>> {
+        let res = match &self.inner.contended {
+            None => self.inner.state.lock(),
+            Some(contended) => match self.inner.state.try_lock() {
+                Ok(st) => Ok(st),
+                Err(TryLockError::Poisoned(e)) => Err(e),
+                Err(TryLockError::WouldBlock) => {
+                    contended.fetch_add(1, Ordering::Relaxed);
+                    self.inner.state.lock()
+                }
+            },
+        };
+        self.inner.unpoison(res, op)
+    }
+
+    // Releases the lock, then wakes the threads interested in `added` items having been added.
+    fn unlock_enqueued(&self, mut st: MutexGuard<'_, State<E, T>>, added: usize) {
+        let _removed = st.take_removed();
+        if added == 0 {
+            drop(st);
+            return;
+        }
+        self.inner.gauges.publish(&st);
+        let watched = st.depth_watchers > 0;
+        let pinned = st.level_consumers > 0;
+        // Fair wakeup: the front consumer hands over to the next one itself.
+        let fair = st.waiters.as_ref().map(WaiterQueue::front);
+        drop(st);
+        self.inner.before_notify();
+        match fair {
+            Some(front) => {
+                if let Some(cv) = front {
+                    cv.notify_one();
+                }
+                // Consumers of a single level wait on `cv`, outside the line.
+                if pinned {
+                    self.inner.cv.notify_all();
+                }
+            }
+            None if added == 1 && !pinned => self.inner.cv.notify_one(),
+            None => self.inner.cv.notify_all(),
+        }
+        if watched {
+            self.inner.depth_cv.notify_all();
+        }
+    }
+
+    // Releases the lock, then wakes the threads interested in `freed` items having left.
+    fn unlock_dequeued(&self, mut st: MutexGuard<'_, State<E, T>>, freed: usize, from: Freed) {
+        let _removed = st.take_removed();
+        if freed == 0 {
+            drop(st);
+            return;
+        }
+        self.inner.gauges.publish(&st);
+        // Shutdowns wait on `cv` for the queue (or part of it) to drain.
+        let wake_drainers = st.pq.is_empty() || st.floor_waiters > 0;
+        // A guarded dequeue keeps its slot: producers only get it back from
+        // the guard, through here again.
+        let bounded = st.bytes.is_some() || (st.cap.is_some() && !st.is_full());
+        // Producers wait for different amounts of bytes: the one woken up
+        // might not fit while another would. An adaptive capacity may also
+        // have grown by more than the freed slot.
+        let wake_all_producers = st.bytes.is_some() || st.adaptive.is_some();
+        let watched = st.depth_watchers > 0;
+        // Only an item leaving a gated level can open it: wake its waiters only.
+        let opened = match from {
+            _ if st.level_waiting == 0 => Freed::Slot,
+            Freed::Level(prio) if st.level_waiters[prio] > 0 && !st.is_level_full(prio) => from,
+            Freed::Level(_) | Freed::Slot => Freed::Slot,
+            Freed::Levels => Freed::Levels,
+        };
+        // Items left: the next parked consumer in line takes its turn. Closed
+        // and drained: the whole line gives up.
+        let next_consumer = match &st.waiters {
+            Some(waiters) if !st.pq.is_empty() => waiters.front(),
+            Some(waiters) if st.closed => {
+                waiters.wake_all();
+                None
+            }
+            _ => None,
+        };
+        drop(st);
+        self.inner.before_notify();
+        if let Some(cv) = next_consumer {
+            cv.notify_one();
+        }
+        if wake_drainers {
+            self.inner.cv.notify_all();
+        }
+        if bounded {
+            if freed == 1 && !wake_all_producers {
+                self.inner.not_full.notify_one();
+            } else {
+                self.inner.not_full.notify_all();
+            }
+        }
+        match opened {
+            Freed::Level(prio) if freed == 1 => self.inner.level_not_full[prio].notify_one(),
+            Freed::Level(prio) => self.inner.level_not_full[prio].notify_all(),
+            // Gated producers recheck their own level.
+            Freed::Levels => {
+                for gate in &self.inner.level_not_full {
+                    gate.notify_all();
+                }
+            }
+            Freed::Slot => {}
+        }
+        if watched {
+            self.inner.depth_cv.notify_all();
+        }
+    }
+
+    // Enqueues without blocking; hands the entity and item back when full.
+    fn try_push(
+        &self,
+        op: &'static str,
+        prio: usize,
+        entity_id: E,
+        item: T,
+    ) -> Result<Option<(E, T)>> {
+        // Declared first, so that evicted items are reported after the lock
+        // is released, whatever the outcome.
+        let mut evicted = Removed::none();
+        let mut st = self.lock(op)?;
+        if st.closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        if prio >= st.pq.n_prio() {
+            return Err(PriorityQueueError::BadPriority(prio));
+        }
+        st.admit(&entity_id)?;
+        if st.is_level_full(prio) || !st.fits(&item) {
+            return Ok(Some((entity_id, item)));
+        }
+        // A full queue may be due to grow, or have a slot reserved for the
+        // entity: nothing else can fail past this point.
+        st.adapt();
+        st.evict_for(&entity_id);
+        evicted.append(st.take_removed());
+        if st.is_full() {
+            return Ok(Some((entity_id, item)));
+        }
+        st.push(prio, entity_id, item)?;
+        self.unlock_enqueued(st, 1);
+        Ok(None)
+    }
+
+    // Takes an item with `pop`, blocking until one is available or the queue is closed.
+    fn pop_blocking<R>(
+        &self,
+        op: &'static str,
+        mut pop: impl FnMut(&mut State<E, T>) -> Option<R>,
+    ) -> Result<R> {
+        // Optimistic phase: a few non-blocking attempts with a growing pause,
+        // since under load an item often shows up within microseconds. The
+        // spin watches the lock-free depth gauge, and only takes the lock
+        // once it shows an item: spinning consumers don't contend with
+        // producers for it. A stale gauge costs a wasted attempt at worst,
+        // the blocking path below has the final say.
+        for attempt in 0..self.inner.spin {
+            if self.inner.gauges.depth() > 0 {
+                let mut st = self.lock(op)?;
+                if let Some(v) = pop(&mut st) {
+                    let from = Freed::Level(st.popped_at);
+                    self.unlock_dequeued(st, 1, from);
+                    return Ok(v);
+                }
+                if st.closed {
+                    return Err(PriorityQueueError::Closed);
+                }
+            }
+            for _ in 0..(1u32 << attempt.min(6)) {
+                hint::spin_loop();
+            }
+        }
+
+        let mut st = self.lock(op)?;
+        loop {
+            st = if st.waiters.is_some() {
+                self.wait_in_line(op, st, None)?
+            } else {
+                self.inner
+                    .cv
+                    .wait_while(st, |s| s.pq.is_empty() && !s.closed)
+                    .or_else(|e| self.inner.recover(e, op))?
+            };
+            if let Some(v) = pop(&mut st) {
+                let from = Freed::Level(st.popped_at);
+                self.unlock_dequeued(st, 1, from);
+                return Ok(v);
+            }
+            // Only a closed queue ends the wait. The wait predicates are
+            // checked under the lock, so `pop` can only come back empty-handed
+            // here once closed (or fenced); looping keeps it that way should
+            // a `pop` ever decline an item on an open queue.
+            if st.closed {
+                return Err(PriorityQueueError::Closed);
+            }
+        }
+    }
+
+    // Same as `pop_blocking`, but gives up after `timeout` with `Ok(None)`.
+    fn pop_timeout<R>(
+        &self,
+        op: &'static str,
+        timeout: Duration,
+        mut pop: impl FnMut(&mut State<E, T>) -> Option<R>,
+    ) -> Result<Option<R>> {
+        let st = self.lock(op)?;
+        let mut st = if st.waiters.is_some() {
+            self.wait_in_line(op, st, Some(timeout))?
+        } else {
+            self.inner
+                .cv
+                .wait_timeout_while(st, timeout, |s| s.pq.is_empty() && !s.closed)
+                .or_else(|e| self.inner.recover(e, op))?
+                .0
+        };
+        // Trust the queue, not the timer: an item may have arrived right at the deadline.
+        if let Some(v) = pop(&mut st) {
+            let from = Freed::Level(st.popped_at);
+            self.unlock_dequeued(st, 1, from);
+            return Ok(Some(v));
+        }
+        if st.closed {
+            return Err(PriorityQueueError::Closed);
+        }
+        Ok(None)
+    }
+
+    // Fair wakeup: parks at the back of the line of consumers until it is in
+    // front and an item is available, the queue is closed, or `timeout`
+    // elapses. Leaves the line before returning the guard.
+    fn wait_in_line<'a>(
+        &'a self,
+        op: &'static str,
+        mut st: MutexGuard<'a, State<E, T>>,
+        timeout: Option<Duration>,
+    ) -> Result<MutexGuard<'a, State<E, T>>> {
+        let Some(waiters) = &mut st.waiters else {
+            unreachable!("wait_in_line without fair wakeup");
+        };
+        let me = waiters.join();
+        // The line is kept even once closed, while the leftovers are drained.
+        let blocked = |s: &mut State<E, T>| {
+            if s.pq.is_empty() || s.fenced {
+                !s.closed
+            } else {
+                !s.waiters.as_ref().is_some_and(|w| w.is_front(&me))
+            }
+        };
+        let mut st = match timeout {
+            None => self.inner.unpoison(me.wait_while(st, blocked), op)?,
+            Some(timeout) => {
+                self.inner
+                    .unpoison(me.wait_timeout_while(st, timeout, blocked), op)?
+                    .0
+            }
+        };
+        if let Some(waiters) = &mut st.waiters {
+            waiters.leave(&me);
+        }
+        Ok(st)
+    }
+
+    // Enqueues, blocking while the item's level or the whole queue is full.
+    fn push_blocking(&self, op: &'static str, prio: usize, entity_id: E, item: T) -> Result<()> {
+        self.push_blocking_unless(op, prio, entity_id, item, |_, _, _| false, Track::Nothing)
+            .map(|_| ())
+    }
+
+    // Same as `push_blocking`, but returns how many items of the same entity
+    // are ahead at that level.
+    fn push_blocking_pos(
+        &self,
+        op: &'static str,
+        prio: usize,
+        entity_id: E,
+        item: T,
+    ) -> Result<usize> {
+        self.push_blocking_unless(op, prio, entity_id, item, |_, _, _| false, Track::Ahead)
+            .map(|ahead| ahead.unwrap_or_default())
+    }
+
+    // Same as `push_blocking`, but gives up on the item, returning `None`,
+    // as soon as `skip` holds for it (checked before every wait). Otherwise
+    // returns the items of the entity ahead at that level with
+    // `Track::Ahead`, 0 with anything else (counting is not free).
+    fn push_blocking_unless(
+        &self,
+        op: &'static str,
+        prio: usize,
+        entity_id: E,
+        item: T,
+        skip: impl Fn(&State<E, T>, &E, &T) -> bool,
+        track: Track,
+    ) -> Result<Option<usize>> {
+        // Declared first, so that evicted items are reported after the lock
+        // is released, whatever the outcome.
+        let mut evicted = Removed::none();
+        let mut st = self.lock(op)?;
+        // Waiting would be forever.
+        if st.bytes.as_ref().is_some_and(|bytes| bytes.exceeds(&item)) && !st.closed {
+            return Err(PriorityQueueError::Full);
+        }
+        if prio >= st.pq.n_prio() {
+            return Err(PriorityQueueError::BadPriority(prio));
+        }
+        if !st.closed {
+            st.admit(&entity_id)?;
+        }
+        loop {
+            if !st.closed && skip(&st, &entity_id, &item) {
+                return Ok(None);
+            }
+            if st.is_level_full(prio) && !st.closed {
+                st.level_waiters[prio] += 1;
+                st.level_waiting += 1;
+                let res = self.inner.level_not_full[prio]
+                    .wait_while(st, |s| s.is_level_full(prio) && !s.closed);
+                st = self.inner.unpoison_leaving(res, op, |st| {
+                    st.level_waiters[prio] -= 1;
+                    st.level_waiting -= 1;
+                })?;
+            }
+            // Only evict for an item that then fits: room for it in bytes
+            // and at its level.
+            if !st.closed && !st.is_level_full(prio) && st.fits(&item) {
+                st.evict_for(&entity_id);
+                evicted.append(st.take_removed());
+            }
+            st = self
+                .inner
+                .not_full
+                .wait_while(st, |s| (s.is_full() || !s.fits(&item)) && !s.closed)
+                .or_else(|e| self.inner.recover(e, op))?;
+            if st.closed {
+                return Err(PriorityQueueError::Closed);
+            }
+            // The level may have filled up again while waiting for global room.
+            if !st.is_level_full(prio) && st.fits(&item) {
+                break;
+            }
+        }
+        if skip(&st, &entity_id, &item) {
+            return Ok(None);
+        }
+        // Checked once there is room: the entity may have left meanwhile.
+        st.admit(&entity_id)?;
+        let ahead = match track {
+            Track::Ahead => st.pq.entity_len_at(prio, &entity_id)?,
+            _ => 0,
+        };
+        let seq = st.push(prio, entity_id, item)?;
+        if let Track::Completion(completion) = track {
+            st.notifiers.insert(seq, completion);
+        }
+        self.unlock_enqueued(st, 1);
+        Ok(Some(ahead))
+    }
+}
+
+// Returns a pseudo-random value in `[0, 1)`, good enough to spread retries.
+fn jitter() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ops::Bound,
+        thread::{sleep, spawn, yield_now},
+        time::Duration,
+    };
+
+    use pq_core::PriorityQueueError;
+
+    use crate::{DepthEvent, DequeueOutcome, LeaseId, Op, QueueMetrics, SyncPriorityQueue};
+
+    #[test]
+    fn test_shutdown_timeout_empty() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        let res = pq.shutdown_timeout(Duration::from_millis(100));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_shutdown_timeout_with_items() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+
+        let pq_clone = pq.clone();
+        let handle = spawn(move || {
+            sleep(Duration::from_millis(50));
+            pq_clone.dequeue().unwrap();
+        });
+
+        let res = pq.shutdown_timeout(Duration::from_millis(100));
+        assert!(res.is_ok());
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_timeout_timeout_reached() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+
+        let res = pq.shutdown_timeout(Duration::from_millis(100));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_shutdown_graceful() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+
+        let pq_clone = pq.clone();
+        let handle = spawn(move || {
+            sleep(Duration::from_millis(50));
+            pq_clone.dequeue().unwrap();
+        });
+
+        let res = pq.shutdown_graceful();
+        assert!(res.is_ok());
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_watch_depth_hysteresis() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "A0".to_string()).unwrap();
+        let watcher = pq.watch_depth(1, 2).unwrap();
+
+        let pq_clone = pq.clone();
+        let handle = spawn(move || {
+            for i in 1..3 {
+                pq_clone
+                    .enqueue(0, "A".to_string(), format!("A{}", i))
+                    .unwrap();
+            }
+            sleep(Duration::from_millis(50));
+            for _ in 0..3 {
+                pq_clone.dequeue().unwrap();
+            }
+            sleep(Duration::from_millis(50));
+            pq_clone.shutdown_immediate().unwrap();
+        });
+
+        assert_eq!(watcher.wait().unwrap(), DepthEvent::AboveHigh);
+        assert_eq!(watcher.wait().unwrap(), DepthEvent::BelowLow);
+        assert_eq!(watcher.wait().unwrap(), DepthEvent::Closed);
+        assert_eq!(watcher.wait().unwrap(), DepthEvent::Closed);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "must not exceed high")]
+    fn test_watch_depth_rejects_inverted_thresholds() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(1);
+        let _ = pq.watch_depth(3, 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "n_prio must be > 0")]
+    fn test_flat_queue_needs_a_level() {
+        crate::SyncFlatPriorityQueue::<u32>::new(0);
+    }
+
+    #[test]
+    fn test_bounded_enqueue_blocks_until_room() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::with_capacity(3, 1);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+        assert!(matches!(
+            pq.try_enqueue(0, "A".to_string(), "item2".to_string()),
+            Err(PriorityQueueError::Full)
+        ));
+
+        let pq_clone = pq.clone();
+        let handle = spawn(move || {
+            sleep(Duration::from_millis(50));
+            pq_clone.dequeue().unwrap();
+        });
+
+        pq.enqueue(0, "A".to_string(), "item2".to_string()).unwrap();
+        assert_eq!(pq.len().unwrap(), 1);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_enqueue_backoff_schedule() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::with_capacity(3, 1);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+
+        let mut delays = Vec::new();
+        let res = pq.enqueue_backoff_with(
+            0,
+            "A".to_string(),
+            "item2".to_string(),
+            3,
+            Duration::from_millis(1),
+            |d| delays.push(d),
+        );
+        assert!(matches!(res, Err(PriorityQueueError::Full)));
+        assert_eq!(
+            delays,
+            vec![
+                Duration::from_millis(1),
+                Duration::from_millis(2),
+                Duration::from_millis(4)
+            ]
+        );
+
+        // Room is made while "sleeping": the retry succeeds.
+        let res = pq.enqueue_backoff_with(
+            0,
+            "A".to_string(),
+            "item2".to_string(),
+            3,
+            Duration::from_millis(1),
+            |_| {
+                pq.try_dequeue().unwrap();
+            },
+        );
+        assert!(res.is_ok());
+
+        // The queue closes while "sleeping": the retry reports it.
+        let res = pq.enqueue_backoff_with(
+            0,
+            "A".to_string(),
+            "item3".to_string(),
+            3,
+            Duration::from_millis(1),
+            |_| pq.shutdown_immediate().unwrap(),
+        );
+        assert!(matches!(res, Err(PriorityQueueError::Closed)));
+    }
+
+    #[test]
+    fn test_is_level_empty() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(1, "A".to_string(), "item1".to_string()).unwrap();
+
+        assert!(pq.is_level_empty(0).unwrap());
+        assert!(!pq.is_level_empty(1).unwrap());
+        assert!(pq.is_level_empty(2).unwrap());
+        assert!(matches!(
+            pq.is_level_empty(3),
+            Err(PriorityQueueError::BadPriority(3))
+        ));
+
+        pq.dequeue().unwrap();
+        assert!(pq.is_level_empty(1).unwrap());
+    }
+
+    #[test]
+    fn test_dequeue_map_runs_outside_lock() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+        pq.enqueue(0, "A".to_string(), "item2".to_string()).unwrap();
+
+        // The closure can re-enter the queue: it would deadlock under the lock.
+        let (first, second) = pq
+            .dequeue_map(|item| (item, pq.try_dequeue().unwrap()))
+            .unwrap();
+        assert_eq!(first, "item1");
+        assert_eq!(second, Some("item2".to_string()));
+    }
+
+    #[test]
+    fn test_lock_error_names_operation() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+
+        let pq_clone = pq.clone();
+        let res = spawn(move || {
+            let _st = pq_clone.inner.state.lock().unwrap();
+            panic!("poison the lock");
+        })
+        .join();
+        assert!(res.is_err());
+
+        let err = pq.try_dequeue().unwrap_err();
+        assert!(matches!(err, PriorityQueueError::LockError("try_dequeue")));
+        assert_eq!(err.to_string(), "lock failed in try_dequeue");
+    }
+
+    #[test]
+    fn test_is_full() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+        assert!(!pq.is_full().unwrap());
+        assert_eq!(pq.fill_ratio().unwrap(), None);
+
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::with_capacity(3, 2);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+        assert!(!pq.is_full().unwrap());
+        assert_eq!(pq.fill_ratio().unwrap(), Some(0.5));
+        pq.enqueue(1, "B".to_string(), "item2".to_string()).unwrap();
+        assert!(pq.is_full().unwrap());
+        pq.try_dequeue().unwrap();
+        assert!(!pq.is_full().unwrap());
+    }
+
+    #[test]
+    fn test_close_and_take() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(1, "A".to_string(), "A1".to_string()).unwrap();
+        pq.enqueue(1, "A".to_string(), "A2".to_string()).unwrap();
+        pq.enqueue(1, "B".to_string(), "B1".to_string()).unwrap();
+        pq.enqueue(0, "C".to_string(), "C1".to_string()).unwrap();
+
+        let pending = pq.close_and_take().unwrap();
+        let expected = [
+            (0, "C", "C1"),
+            (1, "A", "A1"),
+            (1, "B", "B1"),
+            (1, "A", "A2"),
+        ];
+        assert_eq!(pending.len(), expected.len());
+        for ((prio, entity, item), (e_prio, e_entity, e_item)) in pending.iter().zip(expected) {
+            assert_eq!(
+                (*prio, entity.as_str(), item.as_str()),
+                (e_prio, e_entity, e_item)
+            );
+        }
+
+        assert!(pq.is_empty().unwrap());
+        assert!(matches!(
+            pq.enqueue(0, "A".to_string(), "A3".to_string()),
+            Err(PriorityQueueError::Closed)
+        ));
+        assert!(matches!(pq.dequeue(), Err(PriorityQueueError::Closed)));
+    }
+
+    #[test]
+    fn test_metrics_relaxed_tracks_depth() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "A1".to_string()).unwrap();
+        pq.enqueue(2, "A".to_string(), "A2".to_string()).unwrap();
+        pq.enqueue(2, "B".to_string(), "B1".to_string()).unwrap();
+
+        let expected = QueueMetrics {
+            depth: 3,
+            depth_by_priority: vec![1, 0, 2],
+            total_enqueued: 3,
+            total_dequeued: 0,
+            total_removed: 0,
+        };
+        assert_eq!(pq.metrics_relaxed(), expected);
+        assert_eq!(pq.metrics().unwrap(), expected);
+
+        pq.dequeue().unwrap();
+        pq.remove_entity_at(2, &"B".to_string()).unwrap();
+        assert_eq!(
+            pq.metrics_relaxed(),
+            QueueMetrics {
+                depth: 1,
+                depth_by_priority: vec![0, 0, 1],
+                total_enqueued: 3,
+                total_dequeued: 1,
+                total_removed: 1,
+            }
+        );
+
+        pq.close_and_take().unwrap();
+        assert_eq!(pq.metrics_relaxed().depth, 0);
+        assert_eq!(pq.metrics_relaxed(), pq.metrics().unwrap());
+    }
+
+    #[test]
+    fn test_op_log_is_bounded_and_ordered() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::builder(3).op_log(3).build();
+        pq.enqueue(1, "A".to_string(), "A1".to_string()).unwrap();
+        pq.enqueue(0, "B".to_string(), "B1".to_string()).unwrap();
+        pq.try_dequeue().unwrap();
+        pq.shutdown_immediate().unwrap();
+
+        let ops: Vec<_> = pq.op_log().unwrap().into_iter().map(|r| r.op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Enqueue {
+                    prio: 0,
+                    entity: "B".to_string()
+                },
+                Op::Dequeue {
+                    prio: 0,
+                    entity: "B".to_string()
+                },
+                Op::Shutdown,
+            ]
+        );
+
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "A1".to_string()).unwrap();
+        assert!(pq.op_log().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_shutdown_timeout_floor() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "urgent".to_string())
+            .unwrap();
+        pq.enqueue(2, "B".to_string(), "background".to_string())
+            .unwrap();
+
+        // The urgent item is never consumed: the deadline is missed.
+        let res = pq.shutdown_timeout_floor(Duration::from_millis(50), 1);
+        assert!(matches!(res, Err(PriorityQueueError::Timeout)));
+        assert_eq!(pq.len().unwrap(), 2);
+
+        let pq_clone = pq.clone();
+        let handle = spawn(move || {
+            sleep(Duration::from_millis(50));
+            pq_clone.dequeue().unwrap()
+        });
+
+        // Only the prio-0 item must drain; the prio-2 leftover is dropped.
+        pq.shutdown_timeout_floor(Duration::from_secs(5), 1)
+            .unwrap();
+        assert_eq!(handle.join().unwrap(), "urgent");
+        assert!(pq.is_empty().unwrap());
+        assert!(matches!(pq.dequeue(), Err(PriorityQueueError::Closed)));
+
+        assert!(matches!(
+            pq.shutdown_timeout_floor(Duration::from_millis(1), 4),
+            Err(PriorityQueueError::BadPriority(4))
+        ));
+    }
+
+    #[test]
+    fn test_level_capacity_gates_are_independent() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::builder(3)
+            .level_capacity(0, 1)
+            .level_capacity(2, 1)
+            .build();
+        pq.enqueue_blocking(2, "A".to_string(), "bulk1".to_string())
+            .unwrap();
+        assert!(matches!(
+            pq.try_enqueue(2, "A".to_string(), "bulk2".to_string()),
+            Err(PriorityQueueError::Full)
+        ));
+
+        let pq_clone = pq.clone();
+        let parked = spawn(move || {
+            pq_clone
+                .enqueue_blocking(2, "A".to_string(), "bulk2".to_string())
+                .unwrap();
+        });
+        sleep(Duration::from_millis(50));
+        assert!(!parked.is_finished());
+
+        // The prio-0 band has room: its producer is not held back.
+        pq.enqueue_blocking(0, "B".to_string(), "urgent".to_string())
+            .unwrap();
+        // Freeing prio 0 does not open the prio-2 gate.
+        assert_eq!(pq.try_dequeue().unwrap(), Some("urgent".to_string()));
+        sleep(Duration::from_millis(50));
+        assert!(!parked.is_finished());
+
+        // A blocking dequeue reports its level too.
+        assert_eq!(pq.dequeue().unwrap(), "bulk1".to_string());
+        parked.join().unwrap();
+        assert_eq!(pq.try_dequeue().unwrap(), Some("bulk2".to_string()));
+    }
+
+    #[test]
+    fn test_with_levels_for() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::with_levels_for(2);
+        assert!(pq.enqueue(2, "A".to_string(), "item1".to_string()).is_ok());
+        assert!(matches!(
+            pq.enqueue(3, "A".to_string(), "item2".to_string()),
+            Err(PriorityQueueError::BadPriority(3))
+        ));
+    }
+
+    #[test]
+    #[should_panic(expected = "overflows")]
+    fn test_with_levels_for_rejects_max_index_overflow() {
+        let _: SyncPriorityQueue<String, String> = SyncPriorityQueue::with_levels_for(usize::MAX);
+    }
+
+    #[test]
+    fn test_lease_ack_nack_and_recovery() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "A1".to_string()).unwrap();
+        pq.enqueue(0, "A".to_string(), "A2".to_string()).unwrap();
+
+        // ack: the item is gone for good.
+        let (lease, item) = pq.dequeue_lease().unwrap();
+        assert_eq!(item, "A1");
+        pq.ack(lease).unwrap();
+        assert!(matches!(
+            pq.ack(lease),
+            Err(PriorityQueueError::UnknownLease(_))
+        ));
+
+        // nack: the item comes back.
+        let (lease, item) = pq.dequeue_lease().unwrap();
+        assert_eq!(item, "A2");
+        assert!(pq.is_empty().unwrap());
+        pq.nack(lease).unwrap();
+        assert_eq!(pq.len().unwrap(), 1);
+
+        // expiry: a forgotten lease is recovered, a fresh one is not.
+        let (stale, _) = pq.dequeue_lease().unwrap();
+        sleep(Duration::from_millis(50));
+        pq.enqueue(1, "B".to_string(), "B1".to_string()).unwrap();
+        let (fresh, _) = pq.dequeue_lease().unwrap();
+        assert_eq!(
+            pq.recover_expired_leases(Duration::from_millis(40))
+                .unwrap(),
+            1
+        );
+        assert_eq!(pq.try_dequeue().unwrap(), Some("A2".to_string()));
+        assert!(matches!(
+            pq.ack(stale),
+            Err(PriorityQueueError::UnknownLease(_))
+        ));
+        pq.ack(fresh).unwrap();
+        assert!(matches!(
+            pq.nack(LeaseId(u64::MAX)),
+            Err(PriorityQueueError::UnknownLease(u64::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_dequeue_with_depth() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(1, "A".to_string(), "A1".to_string()).unwrap();
+        pq.enqueue(0, "B".to_string(), "B1".to_string()).unwrap();
+        pq.enqueue(1, "A".to_string(), "A2".to_string()).unwrap();
+
+        assert_eq!(pq.dequeue_with_depth().unwrap(), ("B1".to_string(), 2));
+        assert_eq!(pq.dequeue_with_depth().unwrap(), ("A1".to_string(), 1));
+        assert_eq!(pq.dequeue_with_depth().unwrap(), ("A2".to_string(), 0));
+
+        pq.shutdown_immediate().unwrap();
+        assert!(matches!(
+            pq.dequeue_with_depth(),
+            Err(PriorityQueueError::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_dequeue_timeout_outcomes() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        assert_eq!(
+            pq.dequeue_timeout(Duration::from_millis(20)).unwrap(),
+            DequeueOutcome::Timeout
+        );
+
+        let pq_clone = pq.clone();
+        let handle = spawn(move || {
+            sleep(Duration::from_millis(20));
+            pq_clone
+                .enqueue(0, "A".to_string(), "item1".to_string())
+                .unwrap();
+        });
+        assert_eq!(
+            pq.dequeue_timeout(Duration::from_secs(5)).unwrap(),
+            DequeueOutcome::Item("item1".to_string())
+        );
+        handle.join().unwrap();
+
+        pq.enqueue(0, "A".to_string(), "item2".to_string()).unwrap();
+        let pq_clone = pq.clone();
+        let handle = spawn(move || pq_clone.shutdown_graceful().unwrap());
+        // Closed, but not drained yet: the pending item still comes out.
+        sleep(Duration::from_millis(20));
+        assert_eq!(
+            pq.dequeue_timeout(Duration::from_millis(20)).unwrap(),
+            DequeueOutcome::Item("item2".to_string())
+        );
+        handle.join().unwrap();
+        assert_eq!(
+            pq.dequeue_timeout(Duration::from_millis(20)).unwrap(),
+            DequeueOutcome::Closed
+        );
+    }
+
+    #[test]
+    fn test_drain_for_respects_budget() {
+        let pq: SyncPriorityQueue<String, usize> = SyncPriorityQueue::new(3);
+        for i in 0..10 {
+            pq.enqueue(0, "A".to_string(), i).unwrap();
+        }
+
+        // Each item takes at least 20ms: a 50ms budget stops after the third
+        // one at the latest (sooner if the sleeps overshoot).
+        let mut seen = Vec::new();
+        let n = pq
+            .drain_for(Duration::from_millis(50), |i| {
+                sleep(Duration::from_millis(20));
+                seen.push(i);
+            })
+            .unwrap();
+        assert!((1..=3).contains(&n));
+        assert_eq!(seen, (0..n).collect::<Vec<_>>());
+        assert_eq!(pq.len().unwrap(), 10 - n);
+
+        // Plenty of budget: stops once the queue is empty.
+        let rest = pq.drain_for(Duration::from_secs(5), |_| {}).unwrap();
+        assert_eq!(rest, 10 - n);
+        assert!(pq.is_empty().unwrap());
+    }
+
+    #[test]
+    fn test_time_since_last_dequeue() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+        pq.enqueue(0, "A".to_string(), "item2".to_string()).unwrap();
+        assert_eq!(pq.time_since_last_dequeue().unwrap(), None);
+
+        pq.dequeue().unwrap();
+        sleep(Duration::from_millis(30));
+        let idle = pq.time_since_last_dequeue().unwrap().unwrap();
+        assert!(idle >= Duration::from_millis(30));
+
+        pq.try_dequeue().unwrap();
+        let since_last = pq.time_since_last_dequeue().unwrap().unwrap();
+        assert!(since_last < idle);
+    }
+
+    #[test]
+    fn test_enqueue_rejected_while_graceful_drains() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
+        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+
+        let pq_clone = pq.clone();
+        let shutdown = spawn(move || pq_clone.shutdown_graceful());
+        // Let the shutdown close the queue and start waiting for the drain.
+        sleep(Duration::from_millis(50));
+        assert!(!shutdown.is_finished());
+
+        let pq_clone = pq.clone();
+        let producer = spawn(move || pq_clone.enqueue(0, "B".to_string(), "late".to_string()));
+        assert!(matches!(
+            producer.join().unwrap(),
+            Err(PriorityQueueError::Closed)
+        ));
+        assert!(matches!(
+            pq.try_enqueue(1, "B".to_string(), "late".to_string()),
+            Err(PriorityQueueError::Closed)
+        ));
+
+        // Only the item queued before the close is left to drain.
+        assert_eq!(pq.len().unwrap(), 1);
+        assert_eq!(pq.dequeue().unwrap(), "item1");
+        shutdown.join().unwrap().unwrap();
+        assert!(matches!(pq.dequeue(), Err(PriorityQueueError::Closed)));
+    }
+
+    #[test]
+    fn test_shutdown_immediate_with_observes_drops() {
+        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::with_capacity(3, 2);
+        pq.enqueue(2, "A".to_string(), "A1".to_string()).unwrap();
+        pq.enqueue(0, "B".to_string(), "B1".to_string()).unwrap();
+
+        // A producer blocked on the full queue is woken by the shutdown.
+        let pq_clone = pq.clone();
+        let producer = spawn(move || pq_clone.enqueue(1, "C".to_string(), "C1".to_string()));
+        sleep(Duration::from_millis(50));
+
+        let mut dropped = Vec::new();
+        pq.shutdown_immediate_with(|prio, entity, item| dropped.push((prio, entity, item)))
+            .unwrap();
+        assert_eq!(
+            dropped,
+            vec![
+                (0, "B".to_string(), "B1".to_string()),
+                (2, "A".to_string(), "A1".to_string()),
+            ]
+        );
+        assert!(matches!(
+            producer.join().unwrap(),
+            Err(PriorityQueueError::Closed)
+        ));
+        assert!(pq.is_empty().unwrap());
+        assert!(matches!(pq.dequeue(), Err(PriorityQueueError::Closed)));
+    }
+
+    #[test]
+    fn test_enqueue_or_replace_own() {
+        let pq: SyncPriorityQueue<String, (u32, &str)> = SyncPriorityQueue::with_capacity(3, 3);
+        let importance = |item: &(u32, &str)| item.0;
+        pq.enqueue(1, "A".to_string(), (5, "a5")).unwrap();
+        pq.enqueue(2, "A".to_string(), (1, "a1")).unwrap();
+        assert_eq!(
+            pq.enqueue_or_replace_own(0, "B".to_string(), (0, "b0"), importance)
+                .unwrap(),
+            None
+        );
+
+        // Full: A's least important item makes room.
+        assert_eq!(
+            pq.enqueue_or_replace_own(0, "A".to_string(), (3, "a3"), importance)
+                .unwrap(),
+            Some((1, "a1"))
+        );
+        // A has nothing below 2 any more, and B's items are off limits.
+        assert!(matches!(
+            pq.enqueue_or_replace_own(0, "A".to_string(), (2, "a2"), importance),
+            Err(PriorityQueueError::Full)
+        ));
+        assert_eq!(pq.len().unwrap(), 3);
+        let order: Vec<_> = std::iter::from_fn(|| pq.try_dequeue().unwrap()).collect();
+        assert_eq!(order, vec![(0, "b0"), (3, "a3"), (5, "a5")]);
+    }
+
+    #[test]
+    fn test_enqueue_or_replace_own_frees_the_full_level() {
+        let pq: SyncPriorityQueue<String, (u32, &str)> = SyncPriorityQueue::builder(2)
+            .capacity(2)
+            .level_capacity(0, 1)
+            .op_log(8)
+            .build();
+        let importance = |item: &(u32, &str)| item.0;
+        pq.enqueue(0, "A".to_string(), (2, "a2")).unwrap();
+        pq.enqueue(1, "A".to_string(), (1, "a1")).unwrap();
+
+        // Both the queue and level 0 are full: evicting a1 would not free
+        // level 0, so a2 goes even though it is more important.
+        assert_eq!(
+            pq.enqueue_or_replace_own(0, "A".to_string(), (3, "a3"), importance)
+                .unwrap(),
+            Some((2, "a2"))
+        );
+        let ops: Vec<_> = pq.op_log().unwrap().into_iter().map(|r| r.op).collect();
+        assert_eq!(
+            ops[2..],
+            [
+                Op::Dequeue {
+                    prio: 0,
+                    entity: "A".to_string()
+                },
+                Op::Enqueue {
+                    prio: 0,
+                    entity: "A".to_string()
+                },
+            ]
+        );
+        let order: Vec<_> = std::iter::from_fn(|| pq.try_dequeue().unwrap()).collect();
+        assert_eq!(order, vec![(3, "a3"), (1, "a1")]);
+    }
+
+    #[test]
+    fn test_lifetime_counters_match_depth() {
+        let pq: SyncPriorityQueue<String, u32> = SyncPriorityQueue::with_capacity(2, 3);
+        let check = |pq: &SyncPriorityQueue<String, u32>| {
+            let m = pq.metrics().unwrap();
+            assert_eq!(
+                (m.total_enqueued - m.total_dequeued - m.total_removed) as usize,
+                m.depth
+            );
+        };
+        for i in 0..3 {
+            pq.enqueue(i % 2, format!("E{i}"), i as u32).unwrap();
         }
-        let (next_st, wait_res) = self
-            .inner
-            .cv
-            .wait_timeout_while(st, timeout, |s| !s.pq.is_empty())
-            .map_err(|_| PriorityQueueError::LockError)?;
+        pq.dequeue().unwrap();
+        check(&pq);
+        pq.dequeue_one_per_entity(1).unwrap();
+        check(&pq);
+        pq.enqueue(0, "E0".to_string(), 3).unwrap();
+        let (lease, _) = pq.dequeue_lease().unwrap();
+        pq.nack(lease).unwrap();
+        check(&pq);
+        pq.shutdown_immediate().unwrap();
+        check(&pq);
 
-        /*
-         * Edge case: race condition between the queue and the timer.
-         *
-         * If the timeout expires exactly when the queue becomes empty,
-         * `wait_timeout_while` may return `timed_out = true` even though
-         * the condition is no longer true (the queue is already empty).
-         *
-         * → Without this extra check, we could incorrectly return `Timeout`.
-         *   Always re-test the queue state before deciding.
-         */
-        let became_empty = next_st.pq.is_empty();
-        drop(next_st);
-        if wait_res.timed_out() && !became_empty {
-            return Err(PriorityQueueError::Timeout);
+        assert_eq!(pq.total_enqueued().unwrap(), 5);
+        // Two items were still queued at shutdown, and dropped.
+        assert_eq!(pq.total_dequeued().unwrap(), 3);
+        assert_eq!(pq.total_removed().unwrap(), 2);
+        assert_eq!(pq.metrics_relaxed(), pq.metrics().unwrap());
+    }
+
+    #[test]
+    fn test_builder_global_entity_fair_policy() {
+        let pq: SyncPriorityQueue<&str, &str> = SyncPriorityQueue::builder(2)
+            .policy(crate::Policy::GlobalEntityFair)
+            .build();
+        pq.enqueue(0, "A", "A0a").unwrap();
+        pq.enqueue(0, "A", "A0b").unwrap();
+        pq.enqueue(1, "B", "B1").unwrap();
+        let order: Vec<_> = std::iter::from_fn(|| pq.try_dequeue().unwrap()).collect();
+        assert_eq!(order, vec!["A0a", "B1", "A0b"]);
+    }
+
+    #[test]
+    fn test_try_into_inner_requires_last_handle() {
+        let pq: SyncPriorityQueue<&str, u32> = SyncPriorityQueue::new(2);
+        pq.enqueue(1, "A", 1).unwrap();
+        pq.enqueue(0, "B", 2).unwrap();
+
+        let other = pq.clone();
+        let pq = pq.try_into_inner().err().unwrap();
+        drop(other);
+
+        let mut inner = pq.try_into_inner().ok().unwrap();
+        assert_eq!(inner.len(), 2);
+        assert_eq!(inner.try_dequeue_tagged(), Some((0, "B", 2)));
+        assert_eq!(inner.try_dequeue_tagged(), Some((1, "A", 1)));
+    }
+
+    #[test]
+    fn test_enqueue_coalesce_checks_the_tail_only() {
+        let pq: SyncPriorityQueue<&str, &str> = SyncPriorityQueue::with_capacity(2, 3);
+        assert!(matches!(
+            pq.enqueue_coalesce(5, "A", "refresh"),
+            Err(PriorityQueueError::BadPriority(5))
+        ));
+        assert!(pq.enqueue_coalesce(0, "A", "refresh").unwrap());
+        assert!(!pq.enqueue_coalesce(0, "A", "refresh").unwrap());
+        // Another entity, another level.
+        assert!(pq.enqueue_coalesce(0, "B", "refresh").unwrap());
+        assert!(pq.enqueue_coalesce(1, "A", "refresh").unwrap());
+        // Full, but coalescing does not need room.
+        assert!(!pq.enqueue_coalesce(1, "A", "refresh").unwrap());
+        assert_eq!(pq.len().unwrap(), 3);
+
+        pq.dequeue().unwrap();
+        pq.dequeue().unwrap();
+        assert!(pq.enqueue_coalesce(0, "A", "other").unwrap());
+        assert!(pq.enqueue_coalesce(0, "A", "refresh").unwrap());
+    }
+
+    #[test]
+    fn test_with_heads_serves_the_chosen_entity() {
+        let pq: SyncPriorityQueue<&str, u32> = SyncPriorityQueue::new(2);
+        pq.enqueue(1, "A", 30).unwrap();
+        pq.enqueue(1, "B", 10).unwrap();
+        pq.enqueue(1, "B", 20).unwrap();
+
+        fn smallest(heads: &[(&'static str, &u32)]) -> Option<&'static str> {
+            heads.iter().min_by_key(|h| *h.1).map(|h| h.0)
         }
-        self.inner.cv.notify_all();
-        Ok(())
+        assert_eq!(pq.with_heads(1, smallest).unwrap(), Some(10));
+        assert_eq!(pq.with_heads(1, smallest).unwrap(), Some(20));
+        assert_eq!(pq.with_heads(1, |_| None).unwrap(), None);
+        assert_eq!(pq.with_heads(1, |_| Some("Z")).unwrap(), None);
+        assert_eq!(pq.with_heads(0, |_| unreachable!()).unwrap(), None);
+        assert_eq!(pq.len().unwrap(), 1);
+        assert_eq!(pq.total_dequeued().unwrap(), 2);
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::{
-        thread::{sleep, spawn},
-        time::Duration,
-    };
+    #[test]
+    fn test_fair_wakeup_spreads_items_across_consumers() {
+        const CONSUMERS: usize = 4;
+        const ITEMS: usize = 400;
+        let pq: SyncPriorityQueue<usize, usize> =
+            SyncPriorityQueue::builder(1).spin(0).fair_wakeup().build();
 
-    use crate::SyncPriorityQueue;
+        let consumers: Vec<_> = (0..CONSUMERS)
+            .map(|_| {
+                let pq = pq.clone();
+                spawn(move || {
+                    let mut served = 0;
+                    while pq.dequeue().is_ok() {
+                        served += 1;
+                    }
+                    served
+                })
+            })
+            .collect();
+
+        sleep(Duration::from_millis(50));
+        // One item at a time, each handed to a parked consumer.
+        for i in 0..ITEMS {
+            pq.enqueue(0, i % 7, i).unwrap();
+            while !pq.is_empty().unwrap() {
+                yield_now();
+            }
+        }
+        pq.shutdown_graceful().unwrap();
+
+        let served: Vec<usize> = consumers.into_iter().map(|c| c.join().unwrap()).collect();
+        assert_eq!(served.iter().sum::<usize>(), ITEMS);
+        for &count in &served {
+            assert!(count >= ITEMS / CONSUMERS / 4, "uneven: {served:?}");
+        }
+    }
 
     #[test]
-    fn test_shutdown_timeout_empty() {
-        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
-        let res = pq.shutdown_timeout(Duration::from_millis(100));
-        assert!(res.is_ok());
+    fn test_enqueue_notified_resolves_on_dequeue() {
+        let pq: SyncPriorityQueue<&str, u32> = SyncPriorityQueue::new(2);
+        let first = pq.enqueue_notified(1, "A", 1).unwrap();
+        let removed = pq.enqueue_notified(1, "B", 2).unwrap();
+        pq.enqueue(0, "A", 0).unwrap();
+        let leased = pq.enqueue_notified(0, "C", 3).unwrap();
+
+        assert!(matches!(
+            first.wait_timeout(Duration::from_millis(10)),
+            Err(PriorityQueueError::Timeout)
+        ));
+        assert_eq!(pq.dequeue().unwrap(), 0);
+        assert!(!first.is_dequeued());
+
+        let (lease, _) = pq.dequeue_lease().unwrap();
+        assert!(leased.is_dequeued());
+        pq.ack(lease).unwrap();
+
+        pq.remove_entity_at(1, &"B").unwrap();
+        assert!(matches!(removed.wait(), Err(PriorityQueueError::Closed)));
+
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || pq.dequeue().unwrap())
+        };
+        first.wait().unwrap();
+        assert_eq!(consumer.join().unwrap(), 1);
+
+        // Per-level and per-entity dequeues settle exactly the items taken.
+        let a = pq.enqueue_notified(1, "A", 5).unwrap();
+        let b = pq.enqueue_notified(1, "B", 6).unwrap();
+        let c = pq.enqueue_notified(1, "C", 7).unwrap();
+        assert_eq!(pq.try_dequeue_from(1).unwrap(), Some(5));
+        assert!(a.is_dequeued() && !b.is_dequeued());
+        assert_eq!(pq.with_heads(1, |_| Some("C")).unwrap(), Some(7));
+        assert!(c.is_dequeued() && !b.is_dequeued());
+        assert_eq!(pq.dequeue_one_per_entity(1).unwrap(), vec![("B", 6)]);
+        assert!(b.is_dequeued());
+
+        let dropped = pq.enqueue_notified(0, "A", 4).unwrap();
+        pq.shutdown_immediate().unwrap();
+        assert!(matches!(dropped.wait(), Err(PriorityQueueError::Closed)));
     }
 
     #[test]
-    fn test_shutdown_timeout_with_items() {
-        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
-        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    fn test_dequeue_from_waits_for_its_level_only() {
+        let pq = SyncPriorityQueue::new(3);
+        assert!(matches!(
+            pq.try_dequeue_from(3),
+            Err(PriorityQueueError::BadPriority(3))
+        ));
+        assert!(matches!(
+            pq.dequeue_from(3),
+            Err(PriorityQueueError::BadPriority(3))
+        ));
 
-        let pq_clone = pq.clone();
-        let handle = spawn(move || {
-            sleep(Duration::from_millis(50));
-            pq_clone.dequeue().unwrap();
-        });
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.enqueue(2, "A", 2).unwrap();
+        pq.enqueue(2, "B", 3).unwrap();
+        pq.enqueue(2, "A", 4).unwrap();
+        assert_eq!(pq.try_dequeue_from(2).unwrap(), Some(2));
+        assert_eq!(pq.try_dequeue_from(2).unwrap(), Some(3));
+        assert_eq!(pq.try_dequeue_from(2).unwrap(), Some(4));
+        assert_eq!(pq.try_dequeue_from(2).unwrap(), None);
+        assert_eq!(pq.try_dequeue_from(1).unwrap(), None);
 
-        let res = pq.shutdown_timeout(Duration::from_millis(100));
-        assert!(res.is_ok());
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || {
+                let mut got = vec![];
+                while let Ok(v) = pq.dequeue_from(1) {
+                    got.push(v);
+                }
+                got
+            })
+        };
+        // Items of other levels do not satisfy the pinned consumer.
+        pq.enqueue(2, "B", 5).unwrap();
+        pq.enqueue(1, "B", 6).unwrap();
+        pq.enqueue(1, "A", 7).unwrap();
+        while !pq.is_level_empty(1).unwrap() {
+            yield_now();
+        }
+        assert_eq!(pq.len().unwrap(), 2);
+        pq.shutdown_immediate().unwrap();
+        assert_eq!(consumer.join().unwrap(), vec![6, 7]);
+    }
 
-        handle.join().unwrap();
+    #[test]
+    fn test_contention_count() {
+        let pq = SyncPriorityQueue::<&str, u32>::new(1);
+        pq.enqueue(0, "A", 1).unwrap();
+        assert_eq!(pq.contention_count(), 0);
+
+        let pq = SyncPriorityQueue::<&str, u32>::builder(1)
+            .contention_probe()
+            .build();
+        pq.enqueue(0, "A", 1).unwrap();
+        assert_eq!(pq.contention_count(), 0);
+
+        let st = pq.inner.state.lock().unwrap();
+        let reader = {
+            let pq = pq.clone();
+            spawn(move || pq.len().unwrap())
+        };
+        while pq.contention_count() == 0 {
+            yield_now();
+        }
+        drop(st);
+        assert_eq!(reader.join().unwrap(), 1);
+        assert_eq!(pq.contention_count(), 1);
     }
 
     #[test]
-    fn test_shutdown_timeout_timeout_reached() {
-        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
-        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    fn test_shutdown_graceful_then_fence() {
+        let pq = SyncPriorityQueue::new(2);
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.enqueue(1, "B", 2).unwrap();
 
-        let res = pq.shutdown_timeout(Duration::from_millis(100));
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || {
+                let mut got = vec![];
+                while let Ok(v) = pq.dequeue() {
+                    got.push(v);
+                }
+                got
+            })
+        };
+        pq.shutdown_graceful_then_fence().unwrap();
+        assert_eq!(consumer.join().unwrap(), vec![1, 2]);
+
+        // Even an item put back behind the queue's back is never served.
+        pq.inner.state.lock().unwrap().push(1, "B", 3).unwrap();
+        assert_eq!(pq.try_dequeue().unwrap(), None);
+        assert!(matches!(pq.dequeue(), Err(PriorityQueueError::Closed)));
+        assert!(matches!(
+            pq.dequeue_timeout(Duration::from_millis(1)).unwrap(),
+            DequeueOutcome::Closed
+        ));
+        assert_eq!(pq.try_dequeue_from(1).unwrap(), None);
+        assert!(matches!(
+            pq.try_dequeue_from(2),
+            Err(PriorityQueueError::BadPriority(2))
+        ));
+        assert!(pq.dequeue_one_per_entity(1).unwrap().is_empty());
+        assert_eq!(pq.with_heads(1, |heads| Some(heads[0].0)).unwrap(), None);
+        assert_eq!(pq.close_and_take().unwrap(), vec![(1, "B", 3)]);
+    }
+
+    #[test]
+    fn test_reset_matches_a_fresh_queue() {
+        fn run(pq: &SyncPriorityQueue<&'static str, u32>) -> Vec<u32> {
+            pq.enqueue(1, "A", 1).unwrap();
+            pq.enqueue(0, "B", 2).unwrap();
+            pq.enqueue(1, "C", 3).unwrap();
+            pq.enqueue(1, "A", 4).unwrap();
+            assert!(matches!(
+                pq.try_enqueue(1, "A", 5),
+                Err(PriorityQueueError::Full)
+            ));
+            let mut got = vec![];
+            while let Some(v) = pq.try_dequeue().unwrap() {
+                got.push(v);
+            }
+            got
+        }
+
+        let pq = SyncPriorityQueue::builder(2).capacity(4).op_log(8).build();
+        pq.enqueue(0, "A", 9).unwrap();
+        pq.enqueue(0, "A", 8).unwrap();
+        let (lease, _) = pq.dequeue_lease().unwrap();
+        pq.shutdown_immediate().unwrap();
+
+        pq.reset().unwrap();
+        assert!(pq.is_empty().unwrap());
+        assert_eq!(pq.total_enqueued().unwrap(), 0);
+        assert_eq!(pq.total_dequeued().unwrap(), 0);
+        assert_eq!(pq.time_since_last_dequeue().unwrap(), None);
+        assert!(pq.op_log().unwrap().is_empty());
+        assert!(matches!(
+            pq.ack(lease),
+            Err(PriorityQueueError::UnknownLease(_))
+        ));
+
+        let fresh = SyncPriorityQueue::builder(2).capacity(4).op_log(8).build();
+        assert_eq!(run(&pq), run(&fresh));
+        assert_eq!(pq.metrics().unwrap(), fresh.metrics().unwrap());
+        assert_eq!(pq.op_log().unwrap().len(), fresh.op_log().unwrap().len());
+    }
+
+    #[test]
+    fn test_fairness_report() {
+        let pq = SyncPriorityQueue::new(2);
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.dequeue().unwrap();
+        assert!(pq.fairness_report(0.1).unwrap().is_empty());
+
+        let pq = SyncPriorityQueue::builder(2).entity_stats().build();
+        for i in 0..4 {
+            pq.enqueue(1, "A", i).unwrap();
+            pq.enqueue(1, "B", i).unwrap();
+        }
+        while pq.try_dequeue().unwrap().is_some() {}
+        let report = pq.fairness_report(0.01).unwrap();
+        assert_eq!(report.len(), 2);
+        assert!(report.iter().all(|&(_, share, fair)| share == 0.5 && fair));
+
+        // Deliberately unfair: "C" only submits urgent work and strict
+        // priority serves it first; then a custom policy keeps picking "A".
+        for i in 0..8 {
+            pq.enqueue(0, "C", i).unwrap();
+        }
+        pq.enqueue(1, "A", 8).unwrap();
+        pq.enqueue(1, "B", 8).unwrap();
+        while pq.try_dequeue_from(0).unwrap().is_some() {}
+        pq.with_heads(1, |_| Some("A")).unwrap();
+        pq.dequeue_one_per_entity(1).unwrap();
+
+        let mut report = pq.fairness_report(0.1).unwrap();
+        assert_eq!(report[0], ("C", 8.0 / 18.0, false));
+        // "A" and "B" tie: their order is unspecified.
+        report[1..].sort_by_key(|&(entity_id, _, _)| entity_id);
+        assert_eq!(
+            report[1..],
+            [("A", 5.0 / 18.0, true), ("B", 5.0 / 18.0, true)]
+        );
+        assert_eq!(pq.total_dequeued().unwrap(), 18);
+    }
+
+    #[cfg(feature = "bytes")]
+    #[test]
+    fn test_bytes_payloads_are_not_copied() {
+        use crate::BytesPriorityQueue;
+        use bytes::Bytes;
+
+        let pq = BytesPriorityQueue::new(2);
+        let buf = Bytes::from(vec![7u8; 4096]);
+        let ptr = buf.as_ptr();
+        pq.enqueue_bytes(1, "A".to_string(), buf.clone()).unwrap();
+        pq.try_enqueue_bytes(0, "B".to_string(), &b"static"[..])
+            .unwrap();
+
+        assert_eq!(pq.dequeue().unwrap(), Bytes::from_static(b"static"));
+        let out = pq.dequeue().unwrap();
+        // Same buffer: only the refcount moved through the queue.
+        assert_eq!(out.as_ptr(), ptr);
+        assert_eq!(out, buf);
+    }
+
+    #[test]
+    fn test_guard_frees_slot_of_panicking_consumer() {
+        let pq = SyncPriorityQueue::with_capacity(2, 1);
+        pq.enqueue(0, "A", 1).unwrap();
+
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || {
+                let item = pq.dequeue_guarded().unwrap();
+                assert!(matches!(
+                    pq.try_enqueue(0, "A", 2),
+                    Err(PriorityQueueError::Full)
+                ));
+                assert_eq!(pq.fill_ratio().unwrap(), Some(1.0));
+                panic!("failed to process item {}", *item);
+            })
+        };
+        assert!(consumer.join().is_err());
+
+        assert!(!pq.is_full().unwrap());
+        pq.try_enqueue(0, "A", 3).unwrap();
+        let mut item = pq.dequeue_guarded().unwrap();
+        *item += 1;
+        assert_eq!(*item, 4);
+        drop(item);
+        pq.try_enqueue(0, "A", 5).unwrap();
+
+        // Unbounded: nothing is held.
+        let pq = SyncPriorityQueue::new(1);
+        pq.enqueue(0, "A", 1).unwrap();
+        let _item = pq.dequeue_guarded().unwrap();
+        assert_eq!(pq.inner.state.lock().unwrap().held, 0);
+    }
+
+    #[test]
+    fn test_guard_slot_goes_back_to_producers_on_drop_only() {
+        let pq = SyncPriorityQueue::with_capacity(1, 1);
+        pq.enqueue(0, "A", 1).unwrap();
+        let item = pq.dequeue_guarded().unwrap();
+        let producer = {
+            let pq = pq.clone();
+            spawn(move || pq.enqueue_blocking(0, "A", 2))
+        };
+        sleep(Duration::from_millis(20));
+        assert!(!producer.is_finished());
+        assert!(pq.is_empty().unwrap());
+
+        drop(item);
+        producer.join().unwrap().unwrap();
+        assert_eq!(pq.metrics_relaxed().depth, 1);
+
+        // A reset forgets the held slot: a guard from before it must not
+        // free the slot of one taken after it.
+        let stale = pq.dequeue_guarded().unwrap();
+        pq.reset().unwrap();
+        pq.enqueue(0, "A", 3).unwrap();
+        let fresh = pq.dequeue_guarded().unwrap();
+        drop(stale);
+        assert!(pq.is_full().unwrap());
+        drop(fresh);
+        assert!(!pq.is_full().unwrap());
+    }
+
+    #[test]
+    fn test_spin_polls_the_depth_hint() {
+        let pq = SyncPriorityQueue::builder(1).spin(64).build();
+        assert_eq!(pq.inner.gauges.depth(), 0);
+        pq.enqueue(0, "A", 1).unwrap();
+        assert_eq!(pq.inner.gauges.depth(), 1);
+        assert_eq!(pq.dequeue().unwrap(), 1);
+        assert_eq!(pq.inner.gauges.depth(), 0);
+
+        // An empty queue: the consumer spins on the hint, then parks, and
+        // still gets the item once the lock is free again.
+        let st = pq.inner.state.lock().unwrap();
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || pq.dequeue())
+        };
+        sleep(Duration::from_millis(20));
+        drop(st);
+        pq.enqueue(0, "A", 2).unwrap();
+        assert_eq!(consumer.join().unwrap().unwrap(), 2);
+
+        // Closed and empty: the spin ends on the blocking path's verdict.
+        pq.shutdown_immediate().unwrap();
+        assert!(matches!(pq.dequeue(), Err(PriorityQueueError::Closed)));
+    }
+
+    #[test]
+    fn test_reassign_head_keeps_completion() {
+        let pq = SyncPriorityQueue::new(2);
+        let handle = pq.enqueue_notified(1, "A", 1).unwrap();
+        pq.enqueue(1, "B", 2).unwrap();
+        assert!(pq.reassign_head(1, &"A", "B").unwrap());
+        assert!(!pq.reassign_head(1, &"A", "B").unwrap());
+        assert_eq!(pq.entities_at(1).unwrap(), vec!["B"]);
+        assert_eq!(pq.len().unwrap(), 2);
+
+        assert_eq!(pq.try_dequeue().unwrap(), Some(2));
+        assert!(!handle.is_dequeued());
+        assert_eq!(pq.try_dequeue().unwrap(), Some(1));
+        assert!(handle.is_dequeued());
+    }
+
+    #[test]
+    fn test_shutdown_timeout_report() {
+        let pq = SyncPriorityQueue::new(2);
+        for i in 0..3 {
+            pq.enqueue(i % 2, "A", i).unwrap();
+        }
+        let left = pq
+            .shutdown_timeout_report(Duration::from_millis(10))
+            .unwrap();
+        assert_eq!(left, 3);
+        assert!(matches!(
+            pq.shutdown_timeout(Duration::from_millis(1)),
+            Err(PriorityQueueError::Timeout)
+        ));
+
+        // Still closed, and draining: the next report can be clean.
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || while pq.dequeue().is_ok() {})
+        };
+        let left = pq.shutdown_timeout_report(Duration::from_secs(10)).unwrap();
+        assert_eq!(left, 0);
+        consumer.join().unwrap();
+        assert!(pq.shutdown_timeout(Duration::from_millis(1)).is_ok());
+    }
+
+    #[test]
+    fn test_has_work_in_range() {
+        let pq = SyncPriorityQueue::new(4);
+        assert!(!pq.has_work_in_range(..).unwrap());
+        pq.enqueue(2, "A", 1).unwrap();
+
+        assert!(pq.has_work_in_range(..).unwrap());
+        assert!(!pq.has_work_in_range(..2).unwrap());
+        assert!(pq.has_work_in_range(..=2).unwrap());
+        assert!(pq.has_work_in_range(2..3).unwrap());
+        assert!(!pq.has_work_in_range(2..2).unwrap());
+        assert!(!pq.has_work_in_range(3..).unwrap());
+        assert!(!pq.has_work_in_range(4..).unwrap());
+        assert!(
+            pq.has_work_in_range((Bound::Excluded(1), Bound::Unbounded))
+                .unwrap()
+        );
+        assert!(
+            !pq.has_work_in_range((Bound::Excluded(2), Bound::Unbounded))
+                .unwrap()
+        );
+
+        assert!(matches!(
+            pq.has_work_in_range(..=4),
+            Err(PriorityQueueError::BadPriority(4))
+        ));
+        assert!(matches!(
+            pq.has_work_in_range(1..6),
+            Err(PriorityQueueError::BadPriority(5))
+        ));
+        assert!(matches!(
+            pq.has_work_in_range(5..),
+            Err(PriorityQueueError::BadPriority(5))
+        ));
+    }
+
+    #[test]
+    fn test_notify_hook_holds_wakeups_back() {
+        use std::sync::{
+            Arc, Barrier,
+            atomic::{AtomicUsize, Ordering},
+        };
+
+        let pq = SyncPriorityQueue::new(1);
+        let calls = Arc::new(AtomicUsize::new(0));
+        {
+            let calls = Arc::clone(&calls);
+            pq.set_notify_hook(move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        pq.enqueue(0, "A", 1).unwrap();
+        assert_eq!(pq.try_dequeue().unwrap(), Some(1));
+        // Nothing changed: nobody to wake.
+        assert_eq!(pq.try_dequeue().unwrap(), None);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // A blocking hook: the item is queued, but the producer is held in
+        // its wakeup phase until the test lets it go.
+        let entered = Arc::new(Barrier::new(2));
+        let release = Arc::new(Barrier::new(2));
+        {
+            let (entered, release) = (Arc::clone(&entered), Arc::clone(&release));
+            pq.set_notify_hook(move || {
+                entered.wait();
+                release.wait();
+            });
+        }
+        let producer = {
+            let pq = pq.clone();
+            spawn(move || pq.enqueue(0, "A", 2))
+        };
+        entered.wait();
+        assert_eq!(pq.inner.state.lock().unwrap().pq.len(), 1);
+        assert!(!producer.is_finished());
+        release.wait();
+        producer.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_enqueue_pos() {
+        let pq = SyncPriorityQueue::new(2);
+        assert_eq!(pq.enqueue_pos(1, "A", 1).unwrap(), 0);
+        assert_eq!(pq.enqueue_pos(1, "A", 2).unwrap(), 1);
+        assert_eq!(pq.enqueue_pos(1, "B", 3).unwrap(), 0);
+        assert_eq!(pq.enqueue_pos(0, "A", 4).unwrap(), 0);
+        assert!(matches!(
+            pq.enqueue_pos(2, "A", 5),
+            Err(PriorityQueueError::BadPriority(2))
+        ));
+        assert_eq!(pq.dequeue().unwrap(), 4);
+        assert_eq!(pq.dequeue().unwrap(), 1);
+        assert_eq!(pq.enqueue_pos(1, "A", 6).unwrap(), 1);
+    }
+
+    #[test]
+    fn test_byte_capacity_boundary() {
+        let pq = SyncPriorityQueue::with_byte_capacity(2, 10, |s: &&str| s.len());
+        pq.enqueue(1, "A", "aaaa").unwrap();
+        pq.enqueue(1, "B", "bbbbbb").unwrap();
+        // Exactly at `max_bytes`: one more byte does not fit.
+        assert_eq!(pq.byte_len().unwrap(), 10);
+        assert!(matches!(
+            pq.try_enqueue(0, "A", "c"),
+            Err(PriorityQueueError::Full)
+        ));
+        assert!(matches!(
+            pq.enqueue_or_replace_own(1, "A", "c", |_| 9),
+            Err(PriorityQueueError::Full)
+        ));
+        // Larger than the whole budget: rejected even by a blocking enqueue.
+        assert!(matches!(
+            pq.enqueue(0, "A", "ccccccccccc"),
+            Err(PriorityQueueError::Full)
+        ));
+
+        assert_eq!(pq.dequeue().unwrap(), "aaaa");
+        assert_eq!(pq.byte_len().unwrap(), 6);
+        pq.try_enqueue(0, "A", "dddd").unwrap();
+        assert_eq!(pq.byte_len().unwrap(), 10);
+
+        // A blocked producer goes through once enough bytes are freed.
+        let producer = {
+            let pq = pq.clone();
+            spawn(move || pq.enqueue(1, "C", "eeeeeee"))
+        };
+        assert_eq!(pq.dequeue().unwrap(), "dddd");
+        // 6 + 7 bytes would not fit yet.
+        assert_eq!(pq.byte_len().unwrap(), 6);
+        assert_eq!(pq.dequeue().unwrap(), "bbbbbb");
+        producer.join().unwrap().unwrap();
+        assert_eq!(pq.byte_len().unwrap(), 7);
+
+        assert_eq!(pq.remove_entity_at(1, &"C").unwrap(), 1);
+        assert_eq!(pq.byte_len().unwrap(), 0);
+        pq.enqueue(0, "A", "ffffffffff").unwrap();
+        pq.shutdown_immediate().unwrap();
+        assert_eq!(pq.byte_len().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_adaptive_capacity_follows_consumers() {
+        let pq = SyncPriorityQueue::builder(1)
+            .adaptive_capacity(2, 34, 0.5)
+            .adapt_every(Duration::from_millis(5))
+            .build();
+        assert_eq!(pq.capacity().unwrap(), 2);
+
+        // Fast consumer: the queue never fills up, the capacity grows by
+        // (34 - 2) / 16 = 2 per period up to the max.
+        let mut caps = vec![2];
+        for i in 0..10_000 {
+            pq.enqueue(0, "A", i).unwrap();
+            pq.dequeue().unwrap();
+            let cap = pq.capacity().unwrap();
+            if cap != *caps.last().unwrap() {
+                caps.push(cap);
+            }
+            if cap == 34 {
+                break;
+            }
+            sleep(Duration::from_millis(1));
+        }
+        assert_eq!(caps, (1..=17).map(|i| i * 2).collect::<Vec<_>>());
+
+        // Slow consumer: the queue stays full and the rate does not pick up,
+        // the capacity is halved down to the min.
+        for i in 0..10_000 {
+            while pq.try_enqueue(0, "A", i).is_ok() {}
+            if pq.capacity().unwrap() == 2 {
+                break;
+            }
+            if i % 4 == 0 {
+                pq.dequeue().unwrap();
+            }
+            sleep(Duration::from_millis(1));
+        }
+        assert_eq!(pq.capacity().unwrap(), 2);
+        assert!(pq.len().unwrap() >= 2);
+        assert!(matches!(
+            pq.try_enqueue(0, "A", 0),
+            Err(PriorityQueueError::Full)
+        ));
+    }
+
+    #[test]
+    fn test_adaptive_capacity_survives_reset() {
+        let pq = SyncPriorityQueue::builder(1)
+            .adaptive_capacity(2, 34, 0.5)
+            .adapt_every(Duration::from_millis(1))
+            .build();
+        for i in 0..20 {
+            pq.enqueue(0, "A", i).unwrap();
+            pq.dequeue().unwrap();
+            sleep(Duration::from_millis(1));
+        }
+        assert!(pq.capacity().unwrap() > 2);
+
+        // The dequeue count goes back to 0: the next adjustment must not
+        // measure its rate against the count from before the reset.
+        pq.reset().unwrap();
+        assert_eq!(pq.capacity().unwrap(), 2);
+        sleep(Duration::from_millis(2));
+        pq.enqueue(0, "A", 0).unwrap();
+        assert_eq!(pq.dequeue().unwrap(), 0);
+        assert!(pq.len().is_ok());
+    }
+
+    #[test]
+    fn test_fair_capacity_reserves_slots() {
+        let pq = SyncPriorityQueue::builder(2)
+            .capacity(4)
+            .reserved_per_entity(2)
+            .build();
+        pq.try_enqueue(0, "greedy", 1).unwrap();
+        pq.try_enqueue(1, "greedy", 2).unwrap();
+        pq.try_enqueue(1, "greedy", 3).unwrap();
+        pq.try_enqueue(0, "polite", 10).unwrap();
+        // Full: greedy is above its reservation and cannot take more.
+        assert!(matches!(
+            pq.try_enqueue(0, "greedy", 4),
+            Err(PriorityQueueError::Full)
+        ));
+
+        // polite is below its reservation: greedy's newest background item goes.
+        let done = pq.enqueue_notified(0, "polite", 11).unwrap();
+        assert_eq!(pq.len().unwrap(), 4);
+        assert!(!done.is_dequeued());
+
+        // Both exactly at the reservation: nobody can be evicted anymore.
+        assert!(matches!(
+            pq.try_enqueue(0, "polite", 12),
+            Err(PriorityQueueError::Full)
+        ));
+        assert!(matches!(
+            pq.try_enqueue(0, "newcomer", 20),
+            Err(PriorityQueueError::Full)
+        ));
+
+        let mut drained = Vec::new();
+        while let Some(item) = pq.try_dequeue().unwrap() {
+            drained.push(item);
+        }
+        drained.sort();
+        assert_eq!(drained, vec![1, 2, 10, 11]);
+        assert!(done.is_dequeued());
+        let m = pq.metrics().unwrap();
+        assert_eq!((m.total_dequeued, m.total_removed), (4, 1));
+    }
+
+    #[test]
+    fn test_fair_capacity_evicts_only_for_a_sure_enqueue() {
+        let pq = SyncPriorityQueue::builder(2)
+            .capacity(3)
+            .level_capacity(0, 1)
+            .reserved_per_entity(1)
+            .build();
+        pq.try_enqueue(0, "greedy", 1).unwrap();
+        pq.try_enqueue(1, "greedy", 2).unwrap();
+        pq.try_enqueue(1, "greedy", 3).unwrap();
+
+        // polite could take one of greedy's slots, but its enqueue fails
+        // anyway: nothing is evicted.
+        assert!(matches!(
+            pq.try_enqueue(0, "polite", 10),
+            Err(PriorityQueueError::Full)
+        ));
+        assert!(matches!(
+            pq.try_enqueue(2, "polite", 10),
+            Err(PriorityQueueError::BadPriority(2))
+        ));
+        assert_eq!(pq.len().unwrap(), 3);
+
+        pq.try_enqueue(1, "polite", 10).unwrap();
+        assert_eq!(pq.len().unwrap(), 3);
+        assert_eq!(pq.entities_at(1).unwrap(), ["greedy", "polite"]);
+    }
+
+    #[test]
+    fn test_dequeue_with_lends_the_entity() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        // An entity that counts its clones.
+        static CLONES: AtomicUsize = AtomicUsize::new(0);
+        #[derive(PartialEq, Eq, Hash)]
+        struct Tenant(&'static str);
+        impl Clone for Tenant {
+            fn clone(&self) -> Self {
+                CLONES.fetch_add(1, Ordering::SeqCst);
+                Tenant(self.0)
+            }
+        }
+
+        let pq = SyncPriorityQueue::builder(2).entity_stats().build();
+        pq.enqueue(1, Tenant("A"), 1).unwrap();
+        pq.enqueue(1, Tenant("A"), 2).unwrap();
+        pq.enqueue(0, Tenant("B"), 3).unwrap();
+        let baseline = CLONES.load(Ordering::SeqCst);
+
+        assert_eq!(pq.dequeue_with(|t, item| (t.0, item)).unwrap(), ("B", 3));
+        assert_eq!(pq.dequeue_with(|t, item| (t.0, item)).unwrap(), ("A", 1));
+        // Stats mode clones an entity the first time it is served only.
+        assert_eq!(CLONES.load(Ordering::SeqCst), baseline + 2);
+        assert_eq!(pq.dequeue_with(|t, item| (t.0, item)).unwrap(), ("A", 2));
+        assert_eq!(CLONES.load(Ordering::SeqCst), baseline + 2);
+        assert_eq!(pq.metrics().unwrap().total_dequeued, 3);
+
+        pq.shutdown_immediate().unwrap();
+        assert!(matches!(
+            pq.dequeue_with(|t, item| (t.0, item)),
+            Err(PriorityQueueError::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_weight_bump_shifts_the_served_ratio() {
+        let pq = SyncPriorityQueue::builder(2).entity_stats().build();
+        for i in 0..100 {
+            pq.enqueue(1, "tenant", i).unwrap();
+            pq.enqueue(1, "other", i).unwrap();
+        }
+        assert_eq!(pq.weight(1, &"tenant").unwrap(), None);
+        for _ in 0..20 {
+            pq.dequeue().unwrap();
+        }
+        let report = pq.fairness_report(0.0).unwrap();
+        assert!(report.iter().all(|&(_, share, fair)| share == 0.5 && fair));
+
+        // Promoted: from its next turn, 3 items for 1.
+        pq.set_weight(1, &"tenant", 3).unwrap();
+        assert_eq!(pq.weight(1, &"tenant").unwrap(), Some(3));
+        for _ in 0..80 {
+            pq.dequeue().unwrap();
+        }
+        // 10 + 60 vs 10 + 20.
+        let report = pq.fairness_report(0.01).unwrap();
+        assert_eq!(report[0].0, "tenant");
+        assert!((report[0].1 - 0.7).abs() < 0.02);
+        // The expected share follows the weight: 3/4.
+        assert!(!report[0].2);
+        assert!(pq.fairness_report(0.1).unwrap()[0].2);
+
+        assert!(matches!(
+            pq.set_weight(2, &"tenant", 2),
+            Err(PriorityQueueError::BadPriority(2))
+        ));
+    }
+
+    #[test]
+    fn test_max_entities_rejects_newcomers_only() {
+        let pq = SyncPriorityQueue::with_max_entities(2, 2);
+        pq.try_enqueue(0, "A", 1).unwrap();
+        pq.enqueue(1, "B", 2).unwrap();
+        assert!(matches!(
+            pq.try_enqueue(0, "C", 3),
+            Err(PriorityQueueError::TooManyEntities)
+        ));
+        assert!(matches!(
+            pq.enqueue(1, "C", 3),
+            Err(PriorityQueueError::TooManyEntities)
+        ));
+        // Existing entities still enqueue, at any level.
+        pq.try_enqueue(1, "A", 4).unwrap();
+        pq.enqueue(0, "B", 5).unwrap();
+        assert_eq!(pq.len().unwrap(), 4);
+
+        // B leaves once its last item is gone, making room for C.
+        pq.remove_entity_at(0, &"B").unwrap();
+        assert!(pq.try_enqueue(0, "C", 3).is_err());
+        pq.remove_entity_at(1, &"B").unwrap();
+        pq.try_enqueue(0, "C", 3).unwrap();
+
+        // Handing an item over counts too, unless the giver leaves.
+        assert!(matches!(
+            pq.reassign_head(1, &"A", "D"),
+            Err(PriorityQueueError::TooManyEntities)
+        ));
+        assert!(pq.reassign_head(0, &"C", "D").unwrap());
+        assert_eq!(pq.entities_at(0).unwrap(), ["A", "D"]);
+    }
+
+    #[test]
+    fn test_dequeue_at_least() {
+        let pq = SyncPriorityQueue::new(2);
+        assert!(
+            pq.dequeue_at_least(0, Duration::from_secs(5))
+                .unwrap()
+                .is_empty()
+        );
+        // Times out with whatever is there.
+        pq.enqueue(1, "A", 1).unwrap();
+        assert_eq!(
+            pq.dequeue_at_least(3, Duration::from_millis(20)).unwrap(),
+            vec![1]
+        );
+        assert!(
+            pq.dequeue_at_least(3, Duration::from_millis(20))
+                .unwrap()
+                .is_empty()
+        );
+
+        // Returns as soon as the backlog is large enough, in fair order.
+        let producer = {
+            let pq = pq.clone();
+            spawn(move || {
+                for (entity, i) in [("A", 2), ("A", 3), ("B", 4), ("A", 5)] {
+                    sleep(Duration::from_millis(5));
+                    pq.enqueue(1, entity, i).unwrap();
+                }
+            })
+        };
+        assert_eq!(
+            pq.dequeue_at_least(3, Duration::from_secs(30)).unwrap(),
+            vec![2, 4, 3]
+        );
+        producer.join().unwrap();
+
+        // Closing ends the wait with what is left, then reports Closed.
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || pq.dequeue_at_least(10, Duration::from_secs(30)))
+        };
+        sleep(Duration::from_millis(20));
+        pq.shutdown_graceful().unwrap();
+        assert_eq!(consumer.join().unwrap().unwrap(), vec![5]);
+        assert!(matches!(
+            pq.dequeue_at_least(10, Duration::from_secs(30)),
+            Err(PriorityQueueError::Closed)
+        ));
+    }
+
+    #[test]
+    fn test_shutdown_immediate_wakes_every_parked_consumer() {
+        use std::sync::mpsc;
+
+        const CONSUMERS: usize = 8;
+        for iteration in 0..100 {
+            let pq = if iteration % 2 == 0 {
+                SyncPriorityQueue::<&str, u32>::new(3)
+            } else {
+                SyncPriorityQueue::builder(3).fair_wakeup().build()
+            };
+            let (tx, rx) = mpsc::channel();
+            for _ in 0..CONSUMERS {
+                let (pq, tx) = (pq.clone(), tx.clone());
+                spawn(move || tx.send(pq.dequeue()).unwrap());
+            }
+            // Most consumers are parked by now; any straggler must see the
+            // closed queue as well.
+            sleep(Duration::from_millis(2));
+            pq.shutdown_immediate().unwrap();
+            for _ in 0..CONSUMERS {
+                let res = rx
+                    .recv_timeout(Duration::from_secs(10))
+                    .unwrap_or_else(|_| panic!("consumer hung in iteration {iteration}"));
+                assert!(matches!(res, Err(PriorityQueueError::Closed)));
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_sorted_vec_matches_dequeue_order() {
+        let pq = SyncPriorityQueue::builder(3)
+            .level_quotas(vec![3, 2, 1])
+            .build();
+        pq.set_weight(1, &"B", 2).unwrap();
+        for i in 0..30 {
+            pq.enqueue(i % 3, ["A", "B", "C", "D"][i % 4], i).unwrap();
+        }
+        // Start from the middle of a round.
+        pq.dequeue().unwrap();
+        pq.dequeue().unwrap();
+
+        let sorted = pq.to_sorted_vec().unwrap();
+        assert_eq!(sorted.len(), 28);
+        assert_eq!(pq.len().unwrap(), 28);
+        let mut drained = Vec::new();
+        while let Some(item) = pq.try_dequeue().unwrap() {
+            drained.push(item);
+        }
+        let items: Vec<usize> = sorted.iter().map(|&(_, _, v)| v).collect();
+        assert_eq!(items, drained);
+        assert!(sorted.iter().all(|&(prio, _, v)| prio == v % 3));
+    }
+
+    #[test]
+    fn test_poison_policies() {
+        use std::panic::{AssertUnwindSafe, catch_unwind};
+
+        use crate::PoisonPolicy;
+
+        fn poison(pq: &SyncPriorityQueue<&'static str, u32>) {
+            pq.enqueue(0, "A", 1).unwrap();
+            pq.enqueue(0, "A", 2).unwrap();
+            let pq = pq.clone();
+            let res = spawn(move || {
+                let _st = pq.inner.state.lock().unwrap();
+                panic!("poison the lock");
+            })
+            .join();
+            assert!(res.is_err());
+        }
+
+        let pq = SyncPriorityQueue::builder(1)
+            .poison_policy(PoisonPolicy::Error)
+            .build();
+        poison(&pq);
+        assert!(matches!(
+            pq.enqueue(0, "A", 3),
+            Err(PriorityQueueError::LockError("enqueue"))
+        ));
+        assert!(matches!(
+            pq.len(),
+            Err(PriorityQueueError::LockError("len"))
+        ));
+
+        // Poisoned while waiting: the waiter count is still given back.
+        let pq = SyncPriorityQueue::builder(1)
+            .poison_policy(PoisonPolicy::Error)
+            .build();
+        let waiter = {
+            let pq = pq.clone();
+            spawn(move || pq.dequeue_at_least(3, Duration::from_millis(200)))
+        };
+        sleep(Duration::from_millis(20));
+        poison(&pq);
+        assert!(matches!(
+            waiter.join().unwrap(),
+            Err(PriorityQueueError::LockError("dequeue_at_least"))
+        ));
+        let st = pq.inner.state.lock().unwrap_or_else(|e| e.into_inner());
+        assert_eq!(st.depth_watchers, 0);
+        drop(st);
+
+        let pq = SyncPriorityQueue::builder(1)
+            .poison_policy(PoisonPolicy::Recover)
+            .build();
+        let watcher = pq.watch_depth(1, 2).unwrap();
+        poison(&pq);
+        drop(watcher);
+        let st = pq.inner.state.lock().unwrap_or_else(|e| e.into_inner());
+        assert_eq!(st.depth_watchers, 0);
+        drop(st);
+        pq.enqueue(0, "A", 3).unwrap();
+        assert_eq!(pq.len().unwrap(), 3);
+        assert_eq!(pq.dequeue().unwrap(), 1);
+        assert_eq!(pq.try_dequeue().unwrap(), Some(2));
+        assert_eq!(pq.try_dequeue().unwrap(), Some(3));
+
+        let pq = SyncPriorityQueue::builder(1)
+            .poison_policy(PoisonPolicy::Panic)
+            .build();
+        poison(&pq);
+        let res = catch_unwind(AssertUnwindSafe(|| pq.enqueue(0, "A", 3)));
         assert!(res.is_err());
     }
 
     #[test]
-    fn test_shutdown_graceful() {
-        let pq: SyncPriorityQueue<String, String> = SyncPriorityQueue::new(3);
-        pq.enqueue(0, "A".to_string(), "item1".to_string()).unwrap();
+    fn test_wait_for_close() {
+        let pq = SyncPriorityQueue::<&str, u32>::new(1);
+        assert!(!pq.wait_for_close(Some(Duration::from_millis(20))).unwrap());
 
-        let pq_clone = pq.clone();
-        let handle = spawn(move || {
-            sleep(Duration::from_millis(50));
-            pq_clone.dequeue().unwrap();
-        });
+        let waiter = {
+            let pq = pq.clone();
+            spawn(move || pq.wait_for_close(None).unwrap())
+        };
+        // Items come and go without waking the waiter up for good.
+        pq.enqueue(0, "A", 1).unwrap();
+        assert_eq!(pq.dequeue().unwrap(), 1);
+        pq.shutdown_graceful().unwrap();
+        assert!(waiter.join().unwrap());
+        assert!(pq.wait_for_close(Some(Duration::ZERO)).unwrap());
+    }
 
-        let res = pq.shutdown_graceful();
-        assert!(res.is_ok());
+    #[test]
+    fn test_index_stays_consistent() {
+        let pq = SyncPriorityQueue::with_index(3, |item: &(u32, u32)| item.0);
+        for i in 0..6 {
+            pq.enqueue(
+                i as usize % 3,
+                if i % 2 == 0 { "A" } else { "B" },
+                (i % 2, i),
+            )
+            .unwrap();
+        }
+        // Job 0: items 0, 2, 4; job 1: items 1, 3, 5.
+        assert_eq!(pq.dequeue().unwrap(), (0, 0));
+        assert_eq!(pq.remove_entity_at(1, &"B").unwrap(), 1); // (1, 1)
+        assert!(pq.reassign_head(2, &"A", "C").unwrap()); // (0, 2)
+        assert_eq!(pq.cancel_by_key(&0u32).unwrap(), 2);
+        assert_eq!(pq.cancel_by_key(&0u32).unwrap(), 0);
+        assert_eq!(pq.len().unwrap(), 2);
 
-        handle.join().unwrap();
+        // Keys come back once their items are gone, without stale leftovers.
+        pq.enqueue(0, "A", (0, 6)).unwrap();
+        assert_eq!(pq.cancel_by_key(&0u32).unwrap(), 1);
+        assert_eq!(pq.cancel_by_key(&1u32).unwrap(), 2);
+        assert!(pq.is_empty().unwrap());
+
+        // A key that stays live through many dequeues gets compacted.
+        pq.enqueue(0, "A", (2, 0)).unwrap();
+        for i in 1..500 {
+            pq.enqueue(0, "A", (2, i)).unwrap();
+            assert_eq!(pq.dequeue().unwrap(), (2, i - 1));
+        }
+        assert_eq!(pq.cancel_by_key(&2u32).unwrap(), 1);
+        assert_eq!(
+            pq.total_dequeued().unwrap() + pq.total_removed().unwrap(),
+            pq.total_enqueued().unwrap()
+        );
+
+        // Wrong key type, or no index at all: an error, not a panic.
+        assert!(matches!(
+            pq.cancel_by_key(&2i32),
+            Err(PriorityQueueError::NoIndex)
+        ));
+        let plain: SyncPriorityQueue<&str, u32> = SyncPriorityQueue::new(1);
+        assert!(matches!(
+            plain.cancel_by_key(&2u32),
+            Err(PriorityQueueError::NoIndex)
+        ));
+    }
+
+    #[test]
+    fn test_on_remove_reports_removals() {
+        use crate::RemovalReason::{Cancelled, Cleared, EntityRemoved, Overflow};
+        use std::sync::{Arc, Mutex};
+
+        let pq = SyncPriorityQueue::builder(2)
+            .capacity(3)
+            .reserved_per_entity(1)
+            .index(|item: &u32| item / 10)
+            .build();
+        let removed = Arc::new(Mutex::new(Vec::new()));
+        let (sink, inner) = (Arc::clone(&removed), Arc::downgrade(&pq.inner));
+        pq.on_remove(move |reason, prio, entity, item| {
+            // Runs outside the lock.
+            let inner = inner.upgrade().unwrap();
+            assert!(inner.state.try_lock().is_ok());
+            sink.lock().unwrap().push((reason, prio, entity, item));
+        })
+        .unwrap();
+
+        pq.enqueue(0, "A", 1).unwrap();
+        pq.enqueue(1, "A", 2).unwrap();
+        pq.enqueue(1, "A", 21).unwrap();
+        // Full: B has a reserved slot, A's newest background item goes.
+        pq.enqueue(0, "B", 3).unwrap();
+        assert_eq!(pq.remove_entity_at(0, &"A").unwrap(), 1);
+        assert_eq!(pq.cancel_by_key(&0u32).unwrap(), 2);
+        pq.enqueue(0, "C", 4).unwrap();
+        pq.shutdown_immediate().unwrap();
+        // Dequeued items are not reported.
+        pq.reset().unwrap();
+        pq.enqueue(0, "A", 5).unwrap();
+        assert_eq!(pq.dequeue().unwrap(), 5);
+
+        assert_eq!(
+            *removed.lock().unwrap(),
+            [
+                (Overflow, 1, "A", 21),
+                (EntityRemoved, 0, "A", 1),
+                (Cancelled, 1, "A", 2),
+                (Cancelled, 0, "B", 3),
+                (Cleared, 0, "C", 4),
+            ]
+        );
     }
 }
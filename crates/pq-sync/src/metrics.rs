@@ -0,0 +1,89 @@
+use std::{
+    hash::Hash,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use crate::State;
+
+/// Point-in-time view of the queue depth and lifetime counters.
+///
+/// Returned by [`SyncPriorityQueue::metrics()`](crate::SyncPriorityQueue::metrics)
+/// (exact, taken under the lock) and by
+/// [`SyncPriorityQueue::metrics_relaxed()`](crate::SyncPriorityQueue::metrics_relaxed)
+/// (lock-free, possibly stale).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueMetrics {
+    /// Number of queued items, across all priority levels.
+    pub depth: usize,
+    /// Number of queued items at each priority level, indexed by priority.
+    pub depth_by_priority: Vec<usize>,
+    /// Number of items enqueued since the queue was created.
+    pub total_enqueued: u64,
+    /// Number of items dequeued since the queue was created.
+    pub total_dequeued: u64,
+    /// Number of items that left the queue without being dequeued (removed,
+    /// evicted or dropped at shutdown) since it was created.
+    pub total_removed: u64,
+}
+
+// Lock-free mirror of the queue depth, republished under the state lock
+// after every mutation and readable without taking it.
+pub(crate) struct Gauges {
+    depth: AtomicUsize,
+    depth_by_priority: Box<[AtomicUsize]>,
+    total_enqueued: AtomicU64,
+    total_dequeued: AtomicU64,
+    total_removed: AtomicU64,
+}
+
+impl Gauges {
+    pub(crate) fn new(n_prio: usize) -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+            depth_by_priority: (0..n_prio).map(|_| AtomicUsize::new(0)).collect(),
+            total_enqueued: AtomicU64::new(0),
+            total_dequeued: AtomicU64::new(0),
+            total_removed: AtomicU64::new(0),
+        }
+    }
+
+    // Must be called with the state lock held, so that publications are
+    // totally ordered and the last one always matches the current state.
+    pub(crate) fn publish<E, T>(&self, st: &State<E, T>)
+    where
+        E: Eq + Hash + Clone,
+    {
+        let pq = &st.pq;
+        self.depth.store(pq.len(), Ordering::Relaxed);
+        for (prio, gauge) in self.depth_by_priority.iter().enumerate() {
+            if let Ok(depth) = pq.len_at_priority(prio) {
+                gauge.store(depth, Ordering::Relaxed);
+            }
+        }
+        self.total_enqueued
+            .store(st.total_enqueued, Ordering::Relaxed);
+        self.total_dequeued
+            .store(st.total_dequeued, Ordering::Relaxed);
+        self.total_removed
+            .store(st.total_removed, Ordering::Relaxed);
+    }
+
+    // Last published depth, without the lock: a hint, possibly stale.
+    pub(crate) fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn snapshot(&self) -> QueueMetrics {
+        QueueMetrics {
+            depth: self.depth.load(Ordering::Relaxed),
+            depth_by_priority: self
+                .depth_by_priority
+                .iter()
+                .map(|gauge| gauge.load(Ordering::Relaxed))
+                .collect(),
+            total_enqueued: self.total_enqueued.load(Ordering::Relaxed),
+            total_dequeued: self.total_dequeued.load(Ordering::Relaxed),
+            total_removed: self.total_removed.load(Ordering::Relaxed),
+        }
+    }
+}
@@ -0,0 +1,140 @@
+use std::{
+    sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError},
+    time::Duration,
+};
+
+use crate::{State, SyncPriorityQueue};
+
+/// A [`std::sync::mpsc::Receiver`] look-alike backed by a [`SyncPriorityQueue`].
+///
+/// `recv()`, `try_recv()` and `recv_timeout()` have the same signatures as
+/// their `std` counterparts, so code consuming a channel can switch to the
+/// priority queue with minimal changes, then adopt priorities gradually.
+/// Producers push through [`queue()`](MpscCompat::queue) with a unit entity.
+///
+/// A closed queue plays the part of a disconnected channel: once it is
+/// closed and drained, receiving fails with [`RecvError`] (or the
+/// `Disconnected` variant of the other error types).
+///
+/// # Examples
+///
+/// ```no_run
+/// use pq_sync::MpscCompat;
+///
+/// let rx = MpscCompat::new();
+/// let tx = rx.queue().clone();
+///
+/// std::thread::spawn(move || {
+///     tx.enqueue(0, (), "hello".to_string()).unwrap();
+///     tx.shutdown_graceful().unwrap();
+/// });
+///
+/// while let Ok(msg) = rx.recv() {
+///     println!("{msg}");
+/// }
+/// ```
+#[derive(Clone)]
+pub struct MpscCompat<T> {
+    pq: SyncPriorityQueue<(), T>,
+}
+
+impl<T> MpscCompat<T> {
+    /// Creates a receiver over a new, single-priority, unbounded queue.
+    pub fn new() -> Self {
+        Self::from_queue(SyncPriorityQueue::new(1))
+    }
+
+    /// Creates a receiver over an existing queue, e.g. one with several levels.
+    pub fn from_queue(pq: SyncPriorityQueue<(), T>) -> Self {
+        Self { pq }
+    }
+
+    /// Returns the underlying queue, to produce items or shut it down.
+    pub fn queue(&self) -> &SyncPriorityQueue<(), T> {
+        &self.pq
+    }
+
+    /// Blocks until an item is available, like [`std::sync::mpsc::Receiver::recv()`].
+    ///
+    /// Fails once the queue is closed and empty, or if its mutex was poisoned.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.pq.dequeue().map_err(|_| RecvError)
+    }
+
+    /// Takes an item if one is available, like [`std::sync::mpsc::Receiver::try_recv()`].
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        match self.pq.try_dequeue() {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) if !self.is_closed() => Err(TryRecvError::Empty),
+            _ => Err(TryRecvError::Disconnected),
+        }
+    }
+
+    /// Blocks at most `timeout` for an item, like [`std::sync::mpsc::Receiver::recv_timeout()`].
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        match self.pq.pop_timeout("recv_timeout", timeout, State::pop) {
+            Ok(Some(v)) => Ok(v),
+            Ok(None) => Err(RecvTimeoutError::Timeout),
+            Err(_) => Err(RecvTimeoutError::Disconnected),
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        self.pq.lock("try_recv").map_or(true, |st| st.closed)
+    }
+}
+
+impl<T> Default for MpscCompat<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> From<SyncPriorityQueue<(), T>> for MpscCompat<T> {
+    fn from(pq: SyncPriorityQueue<(), T>) -> Self {
+        Self::from_queue(pq)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc::{RecvError, RecvTimeoutError, TryRecvError},
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use super::MpscCompat;
+
+    #[test]
+    fn test_errors_match_std() {
+        let rx: MpscCompat<String> = MpscCompat::new();
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Empty));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Timeout)
+        );
+
+        let tx = rx.queue().clone();
+        let handle = spawn(move || {
+            sleep(Duration::from_millis(20));
+            tx.enqueue(0, (), "item1".to_string()).unwrap();
+            tx.enqueue(0, (), "item2".to_string()).unwrap();
+            tx.shutdown_graceful().unwrap();
+        });
+
+        assert_eq!(rx.recv(), Ok("item1".to_string()));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_secs(5)),
+            Ok("item2".to_string())
+        );
+        handle.join().unwrap();
+
+        assert_eq!(rx.recv(), Err(RecvError));
+        assert_eq!(rx.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(20)),
+            Err(RecvTimeoutError::Disconnected)
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use std::{collections::VecDeque, time::Instant};
+
+/// Number of records kept by [`SyncPriorityQueue::with_op_log()`](crate::SyncPriorityQueue::with_op_log).
+pub const DEFAULT_OP_LOG_LEN: usize = 1024;
+
+/// An operation recorded in the queue's op log.
+///
+/// Only scheduling metadata is kept (priority and entity), never the payload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op<E> {
+    /// An item of `entity` was enqueued at level `prio`.
+    Enqueue { prio: usize, entity: E },
+    /// An item of `entity` was dequeued from level `prio`.
+    Dequeue { prio: usize, entity: E },
+    /// The queue was closed, by any of the shutdown modes.
+    Shutdown,
+}
+
+/// One entry of the op log, as returned by
+/// [`SyncPriorityQueue::op_log()`](crate::SyncPriorityQueue::op_log).
+#[derive(Debug, Clone)]
+pub struct OpRecord<E> {
+    /// When the operation took effect.
+    pub at: Instant,
+    /// What happened.
+    pub op: Op<E>,
+}
+
+// Bounded ring of the most recent operations; the oldest record is dropped
+// when a new one does not fit.
+pub(crate) struct OpLog<E> {
+    records: VecDeque<OpRecord<E>>,
+    max_len: usize,
+}
+
+impl<E> OpLog<E>
+where
+    E: Clone,
+{
+    pub(crate) fn new(max_len: usize) -> Self {
+        Self {
+            records: VecDeque::with_capacity(max_len),
+            max_len,
+        }
+    }
+
+    pub(crate) fn push(&mut self, op: Op<E>) {
+        if self.records.len() == self.max_len {
+            self.records.pop_front();
+        }
+        self.records.push_back(OpRecord {
+            at: Instant::now(),
+            op,
+        });
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.records.clear();
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<OpRecord<E>> {
+        self.records.iter().cloned().collect()
+    }
+}
@@ -0,0 +1,70 @@
+use std::hash::Hash;
+
+use bytes::Bytes;
+use pq_core::{IntoPriority, Result};
+
+use crate::SyncPriorityQueue;
+
+/// A [`SyncPriorityQueue`] of network buffers, keyed by `String` entities.
+///
+/// [`Bytes`] is a reference-counted view over a shared buffer: moving one
+/// through the queue (enqueue, dequeue, or requeue of a lease) only moves the
+/// handle and never copies the payload. Cloning a `Bytes` before enqueueing
+/// it, e.g. to keep it for a retry, bumps the refcount as well. Requires the
+/// `bytes` feature.
+///
+/// # Examples
+///
+/// ```no_run
+/// use pq_sync::BytesPriorityQueue;
+///
+/// let pq = BytesPriorityQueue::new(3);
+/// pq.enqueue_bytes(0, "conn-1".to_string(), vec![1, 2, 3]).unwrap();
+/// assert_eq!(&pq.dequeue().unwrap()[..], &[1, 2, 3]);
+/// ```
+///
+pub type BytesPriorityQueue = SyncPriorityQueue<String, Bytes>;
+
+/// ---
+/// ## Bytes Payloads
+///
+/// Conveniences for queues of [`Bytes`] (requires the `bytes` feature).
+///
+impl<E> SyncPriorityQueue<E, Bytes>
+where
+    E: Eq + Hash + Clone,
+{
+    /// Same as [`enqueue()`](Self::enqueue), for any payload convertible into [`Bytes`].
+    ///
+    /// The conversion itself is free for a `Bytes`, a `Vec<u8>`, a
+    /// `Box<[u8]>` or a `String` (the buffer is taken over, not copied); a
+    /// `&'static [u8]` is borrowed as is.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`enqueue()`](Self::enqueue).
+    ///
+    pub fn enqueue_bytes(
+        &self,
+        prio: impl IntoPriority,
+        entity_id: E,
+        b: impl Into<Bytes>,
+    ) -> Result<()> {
+        self.enqueue(prio, entity_id, b.into())
+    }
+
+    /// Same as [`try_enqueue()`](Self::try_enqueue), for any payload convertible into [`Bytes`].
+    ///
+    /// # Errors
+    ///
+    /// Same as [`try_enqueue()`](Self::try_enqueue).
+    ///
+    pub fn try_enqueue_bytes(
+        &self,
+        prio: impl IntoPriority,
+        entity_id: E,
+        b: impl Into<Bytes>,
+    ) -> Result<()> {
+        self.try_enqueue(prio, entity_id, b.into())
+    }
+}
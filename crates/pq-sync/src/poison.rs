@@ -0,0 +1,30 @@
+/// What a queue does when its internal lock turns out to be poisoned, i.e. a
+/// thread panicked while holding it (see
+/// [`SyncPriorityQueueBuilder::poison_policy()`](crate::SyncPriorityQueueBuilder::poison_policy)).
+///
+/// # Data consistency
+///
+/// A panic can only come from user code run under the lock (a `size_fn`, an
+/// `importance` closure, a `dequeue_with` callback, ...) or from a bug in the
+/// queue itself. With [`Recover`](Self::Recover), the queue carries on with
+/// the state that thread left behind, and the operation it was running may
+/// be half done: the item in flight may be lost, a counter (depth gauges,
+/// lifetime totals, byte usage) may be off by one, or an entity may have
+/// dropped out of its round-robin with items still queued, so that `len()`
+/// counts them but no dequeue ever returns them. The queue stays memory-safe,
+/// but nothing more is guaranteed: only recover when a panic under the lock
+/// is known to happen before the queue's state is touched, or when a
+/// degraded queue beats a failing one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PoisonPolicy {
+    /// Every operation fails with
+    /// [`PriorityQueueError::LockError`](pq_core::PriorityQueueError::LockError)
+    /// from then on (the default).
+    #[default]
+    Error,
+    /// Operations ignore the poisoning and go on with the state as is.
+    Recover,
+    /// Operations panic in turn, propagating the failure to every user of
+    /// the queue.
+    Panic,
+}
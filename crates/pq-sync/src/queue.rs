@@ -0,0 +1,256 @@
+// Implementations of the generic queue traits of `pq_core`, for code that is
+// written once against `Queue`/`BlockingQueue` and run on either this queue,
+// its fairness-free `SyncFlatPriorityQueue` variant or a single-threaded
+// `pq_fair::PriorityQueue`.
+//
+// The traits take `&mut self`, as the single-threaded queue needs. A shared
+// `&SyncPriorityQueue` implements them too, so that generic code can run on
+// a queue borrowed from other threads, without cloning the handle.
+
+use std::hash::Hash;
+
+use pq_core::{BlockingQueue, Queue, Result};
+
+use crate::{SyncFlatPriorityQueue, SyncPriorityQueue};
+
+impl<E, T> Queue<E, T> for &SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    fn enqueue(&mut self, prio: usize, entity_id: E, item: T) -> Result<()> {
+        SyncPriorityQueue::enqueue(self, prio, entity_id, item)
+    }
+
+    fn try_dequeue(&mut self) -> Result<Option<T>> {
+        SyncPriorityQueue::try_dequeue(self)
+    }
+
+    fn len(&self) -> Result<usize> {
+        SyncPriorityQueue::len(self)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        SyncPriorityQueue::is_empty(self)
+    }
+}
+
+impl<E, T> BlockingQueue<E, T> for &SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    fn dequeue(&mut self) -> Result<T> {
+        SyncPriorityQueue::dequeue(self)
+    }
+
+    fn shutdown_immediate(&mut self) -> Result<()> {
+        SyncPriorityQueue::shutdown_immediate(self)
+    }
+
+    fn shutdown_graceful(&mut self) -> Result<()> {
+        SyncPriorityQueue::shutdown_graceful(self)
+    }
+}
+
+impl<E, T> Queue<E, T> for SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    fn enqueue(&mut self, prio: usize, entity_id: E, item: T) -> Result<()> {
+        Queue::enqueue(&mut &*self, prio, entity_id, item)
+    }
+
+    fn try_dequeue(&mut self) -> Result<Option<T>> {
+        Queue::try_dequeue(&mut &*self)
+    }
+
+    fn len(&self) -> Result<usize> {
+        Queue::len(&self)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Queue::is_empty(&self)
+    }
+}
+
+impl<E, T> BlockingQueue<E, T> for SyncPriorityQueue<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    fn dequeue(&mut self) -> Result<T> {
+        BlockingQueue::dequeue(&mut &*self)
+    }
+
+    fn shutdown_immediate(&mut self) -> Result<()> {
+        BlockingQueue::shutdown_immediate(&mut &*self)
+    }
+
+    fn shutdown_graceful(&mut self) -> Result<()> {
+        BlockingQueue::shutdown_graceful(&mut &*self)
+    }
+}
+
+// The flat queue has no entities: the entity argument is ignored.
+impl<E, T> Queue<E, T> for &SyncFlatPriorityQueue<T> {
+    fn enqueue(&mut self, prio: usize, _entity_id: E, item: T) -> Result<()> {
+        SyncFlatPriorityQueue::enqueue(self, prio, item)
+    }
+
+    fn try_dequeue(&mut self) -> Result<Option<T>> {
+        SyncFlatPriorityQueue::try_dequeue(self)
+    }
+
+    fn len(&self) -> Result<usize> {
+        SyncFlatPriorityQueue::len(self)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        SyncFlatPriorityQueue::is_empty(self)
+    }
+}
+
+impl<E, T> BlockingQueue<E, T> for &SyncFlatPriorityQueue<T> {
+    fn dequeue(&mut self) -> Result<T> {
+        SyncFlatPriorityQueue::dequeue(self)
+    }
+
+    fn shutdown_immediate(&mut self) -> Result<()> {
+        SyncFlatPriorityQueue::shutdown_immediate(self)
+    }
+
+    fn shutdown_graceful(&mut self) -> Result<()> {
+        SyncFlatPriorityQueue::shutdown_graceful(self)
+    }
+}
+
+impl<E, T> Queue<E, T> for SyncFlatPriorityQueue<T> {
+    fn enqueue(&mut self, prio: usize, entity_id: E, item: T) -> Result<()> {
+        Queue::enqueue(&mut &*self, prio, entity_id, item)
+    }
+
+    fn try_dequeue(&mut self) -> Result<Option<T>> {
+        Queue::<E, T>::try_dequeue(&mut &*self)
+    }
+
+    fn len(&self) -> Result<usize> {
+        Queue::<E, T>::len(&self)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        Queue::<E, T>::is_empty(&self)
+    }
+}
+
+impl<E, T> BlockingQueue<E, T> for SyncFlatPriorityQueue<T> {
+    fn dequeue(&mut self) -> Result<T> {
+        BlockingQueue::<E, T>::dequeue(&mut &*self)
+    }
+
+    fn shutdown_immediate(&mut self) -> Result<()> {
+        BlockingQueue::<E, T>::shutdown_immediate(&mut &*self)
+    }
+
+    fn shutdown_graceful(&mut self) -> Result<()> {
+        BlockingQueue::<E, T>::shutdown_graceful(&mut &*self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::spawn;
+
+    use pq_core::{BlockingQueue, PriorityQueueError, Queue, Result};
+    use pq_fair::{FlatPriorityQueue, PriorityQueue};
+
+    use crate::{SyncFlatPriorityQueue, SyncPriorityQueue};
+
+    // Generic over the implementation: urgent items first, fair within a level.
+    fn fill<Q: Queue<&'static str, u32>>(q: &mut Q) -> Result<()> {
+        q.enqueue(1, "A", 1)?;
+        q.enqueue(1, "A", 2)?;
+        q.enqueue(1, "B", 3)?;
+        q.enqueue(0, "B", 4)
+    }
+
+    fn take_all<Q: Queue<&'static str, u32>>(q: &mut Q) -> Result<Vec<u32>> {
+        let mut items = Vec::new();
+        while let Some(item) = q.try_dequeue()? {
+            items.push(item);
+        }
+        Ok(items)
+    }
+
+    #[test]
+    fn test_implementations_are_interchangeable() {
+        let mut local = PriorityQueue::new(2);
+        fill(&mut local).unwrap();
+        assert_eq!(Queue::len(&local).unwrap(), 4);
+
+        let mut shared = SyncPriorityQueue::new(2);
+        fill(&mut shared).unwrap();
+        assert_eq!(Queue::len(&shared).unwrap(), 4);
+
+        assert_eq!(take_all(&mut local).unwrap(), vec![4, 1, 3, 2]);
+        assert_eq!(take_all(&mut shared).unwrap(), vec![4, 1, 3, 2]);
+        assert!(Queue::is_empty(&local).unwrap());
+    }
+
+    #[test]
+    fn test_blocking_through_a_shared_borrow() {
+        fn consume<Q: BlockingQueue<&'static str, u32>>(mut q: Q) -> Vec<u32> {
+            let mut items = Vec::new();
+            loop {
+                match q.dequeue() {
+                    Ok(item) => items.push(item),
+                    Err(PriorityQueueError::Closed) => return items,
+                    Err(e) => panic!("{e}"),
+                }
+            }
+        }
+
+        let pq = SyncPriorityQueue::new(2);
+        let consumer = {
+            let pq = pq.clone();
+            spawn(move || consume(&pq))
+        };
+        let mut producer = &pq;
+        fill(&mut producer).unwrap();
+        producer.shutdown_graceful().unwrap();
+        let mut items = consumer.join().unwrap();
+        items.sort();
+        assert_eq!(items, vec![1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn test_flat_implementations_ignore_entities() {
+        let mut local = FlatPriorityQueue::new(2);
+        fill(&mut local).unwrap();
+        let mut shared = SyncFlatPriorityQueue::new(2);
+        fill(&mut shared).unwrap();
+        assert_eq!(Queue::<&str, u32>::len(&shared).unwrap(), 4);
+
+        // Arrival order within a level, whatever the entity.
+        assert_eq!(take_all(&mut local).unwrap(), vec![4, 1, 2, 3]);
+        assert_eq!(take_all(&mut shared).unwrap(), vec![4, 1, 2, 3]);
+
+        let consumer = {
+            let pq = shared.clone();
+            spawn(move || {
+                let mut items = Vec::new();
+                while let Ok(item) = pq.dequeue() {
+                    items.push(item);
+                }
+                items
+            })
+        };
+        fill(&mut &shared).unwrap();
+        shared.shutdown_graceful().unwrap();
+        assert!(shared.is_empty().unwrap());
+        assert!(matches!(
+            shared.enqueue(0, 5),
+            Err(PriorityQueueError::Closed)
+        ));
+        let mut items = consumer.join().unwrap();
+        items.sort();
+        assert_eq!(items, vec![1, 2, 3, 4]);
+    }
+}
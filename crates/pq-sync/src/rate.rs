@@ -0,0 +1,136 @@
+use std::time::{Duration, Instant};
+
+// Weight of the newest sample in the moving average: about the last ten
+// dequeues dominate the estimate.
+const ALPHA: f64 = 0.2;
+
+// Exponentially weighted moving average of the time between two dequeues,
+// backing `SyncPriorityQueue::estimated_wait`.
+//
+// Only busy time is measured: when an item arrives in an empty queue, the
+// clock restarts, so that idle periods (no item to dequeue) do not read as
+// slow consumers. A batch of `n` items dequeued at once counts as `n`
+// dequeues evenly spread over the elapsed time.
+pub(crate) struct DequeueRate {
+    // start of the current interval: the last dequeue, or the arrival of an
+    // item in an empty queue
+    since: Option<Instant>,
+    // average seconds per dequeued item
+    interval: Option<f64>,
+}
+
+impl DequeueRate {
+    pub(crate) fn new() -> Self {
+        Self {
+            since: None,
+            interval: None,
+        }
+    }
+
+    // An item arrived in an empty queue at `now`.
+    pub(crate) fn restart(&mut self, now: Instant) {
+        self.since = Some(now);
+    }
+
+    // `n` items were dequeued at `now`.
+    pub(crate) fn record(&mut self, now: Instant, n: usize) {
+        if let Some(since) = self.since
+            && n > 0
+        {
+            let sample = now.saturating_duration_since(since).as_secs_f64() / n as f64;
+            self.interval = Some(match self.interval {
+                None => sample,
+                Some(avg) => ALPHA * sample + (1.0 - ALPHA) * avg,
+            });
+        }
+        self.since = Some(now);
+    }
+
+    // Expected time to dequeue `n` items at the current rate, if measured yet.
+    pub(crate) fn time_for(&self, n: usize) -> Option<Duration> {
+        self.interval
+            .map(|interval| Duration::from_secs_f64(interval * n as f64))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::DequeueRate;
+
+    // Dequeues per second, as implied by the estimate for one item.
+    fn per_sec(rate: &DequeueRate) -> Option<f64> {
+        rate.time_for(1).map(|d| 1.0 / d.as_secs_f64())
+    }
+
+    #[test]
+    fn test_no_rate_before_two_events() {
+        let mut rate = DequeueRate::new();
+        assert_eq!(per_sec(&rate), None);
+        assert_eq!(rate.time_for(3), None);
+
+        // The first dequeue only starts the clock.
+        rate.record(Instant::now(), 1);
+        assert_eq!(per_sec(&rate), None);
+    }
+
+    #[test]
+    fn test_steady_rate() {
+        let t0 = Instant::now();
+        let mut rate = DequeueRate::new();
+        rate.restart(t0);
+        for i in 1..=20 {
+            rate.record(t0 + Duration::from_millis(100 * i), 1);
+        }
+        assert!((per_sec(&rate).unwrap() - 10.0).abs() < 1e-6);
+        let eta = rate.time_for(5).unwrap();
+        assert!(eta.abs_diff(Duration::from_millis(500)) < Duration::from_micros(1));
+    }
+
+    #[test]
+    fn test_batches_spread_over_the_interval() {
+        let t0 = Instant::now();
+        let mut rate = DequeueRate::new();
+        rate.restart(t0);
+        rate.record(t0 + Duration::from_secs(1), 4);
+        assert!((per_sec(&rate).unwrap() - 4.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_converges_to_a_new_rate() {
+        let t0 = Instant::now();
+        let mut rate = DequeueRate::new();
+        rate.restart(t0);
+        let mut t = t0;
+        for _ in 0..10 {
+            t += Duration::from_millis(100);
+            rate.record(t, 1);
+        }
+        // Consumers slow down to 2/s: the average follows within a few dozen samples.
+        for _ in 0..10 {
+            t += Duration::from_millis(500);
+            rate.record(t, 1);
+        }
+        let mid = per_sec(&rate).unwrap();
+        assert!(mid > 2.0 && mid < 10.0);
+        for _ in 0..40 {
+            t += Duration::from_millis(500);
+            rate.record(t, 1);
+        }
+        assert!((per_sec(&rate).unwrap() - 2.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_idle_time_is_not_counted() {
+        let t0 = Instant::now();
+        let mut rate = DequeueRate::new();
+        rate.restart(t0);
+        rate.record(t0 + Duration::from_millis(100), 1);
+        // The queue stays empty for a minute, then an item arrives.
+        let t1 = t0 + Duration::from_secs(60);
+        rate.restart(t1);
+        rate.record(t1 + Duration::from_millis(100), 1);
+        assert!((per_sec(&rate).unwrap() - 10.0).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+/// Why an item left the queue without being dequeued, as reported to the
+/// hook registered with
+/// [`SyncPriorityQueue::on_remove()`](crate::SyncPriorityQueue::on_remove).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum RemovalReason {
+    /// Removed with its entity, by
+    /// [`remove_entity_at()`](crate::SyncPriorityQueue::remove_entity_at).
+    EntityRemoved,
+    /// Removed by key, by
+    /// [`cancel_by_key()`](crate::SyncPriorityQueue::cancel_by_key).
+    Cancelled,
+    /// Dropped when the queue was emptied at once: by an immediate shutdown,
+    /// the end of [`shutdown_timeout_floor()`](crate::SyncPriorityQueue::shutdown_timeout_floor),
+    /// or [`reset()`](crate::SyncPriorityQueue::reset).
+    Cleared,
+    /// Evicted from a full queue to honor another entity's reserved slots
+    /// (see [`SyncPriorityQueueBuilder::reserved_per_entity()`](crate::SyncPriorityQueueBuilder::reserved_per_entity)).
+    Overflow,
+}
+
+pub(crate) type RemoveHook<E, T> = Arc<dyn Fn(RemovalReason, usize, E, T) + Send + Sync>;
+
+// Items removed under the lock, on their way to the removal hook. They are
+// handed to it when this is dropped, which callers arrange to happen after
+// the lock is released, on every return path.
+pub(crate) struct Removed<E, T> {
+    hook: Option<RemoveHook<E, T>>,
+    items: Vec<(RemovalReason, usize, E, T)>,
+}
+
+impl<E, T> Removed<E, T> {
+    pub(crate) fn new(
+        hook: Option<RemoveHook<E, T>>,
+        items: Vec<(RemovalReason, usize, E, T)>,
+    ) -> Self {
+        Self { hook, items }
+    }
+
+    pub(crate) fn none() -> Self {
+        Self::new(None, Vec::new())
+    }
+
+    pub(crate) fn append(&mut self, mut other: Self) {
+        if self.hook.is_none() {
+            self.hook = other.hook.take();
+        }
+        self.items.append(&mut other.items);
+    }
+}
+
+impl<E, T> Drop for Removed<E, T> {
+    fn drop(&mut self) {
+        if let Some(hook) = &self.hook {
+            for (reason, prio, entity_id, item) in self.items.drain(..) {
+                hook(reason, prio, entity_id, item);
+            }
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    sync::{Condvar, Mutex},
+    time::Duration,
+};
+
+use pq_core::{PriorityQueueError, Result};
+
+/// A counting semaphore backed by a [`Mutex`] and a [`Condvar`].
+///
+/// Each call to [`acquire()`](Semaphore::acquire) takes one permit, blocking
+/// while none is available; each call to [`release()`](Semaphore::release)
+/// gives one back and wakes a single waiter.
+///
+/// # Examples
+///
+/// ```no_run
+/// use pq_sync::Semaphore;
+///
+/// let sem = Semaphore::new(2);
+/// sem.acquire().unwrap();
+/// sem.acquire().unwrap();
+/// assert!(!sem.try_acquire().unwrap());
+/// sem.release().unwrap();
+/// assert!(sem.try_acquire().unwrap());
+/// ```
+pub struct Semaphore {
+    permits: Mutex<usize>,
+    cv: Condvar,
+}
+
+impl Semaphore {
+    /// Creates a semaphore holding `permits` available permits.
+    pub fn new(permits: usize) -> Self {
+        Self {
+            permits: Mutex::new(permits),
+            cv: Condvar::new(),
+        }
+    }
+
+    /// Takes one permit, blocking until one becomes available.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn acquire(&self) -> Result<()> {
+        let n = self
+            .permits
+            .lock()
+            .map_err(|_| PriorityQueueError::LockError("Semaphore::acquire"))?;
+        let mut n = self
+            .cv
+            .wait_while(n, |n| *n == 0)
+            .map_err(|_| PriorityQueueError::LockError("Semaphore::acquire"))?;
+        *n -= 1;
+        Ok(())
+    }
+
+    /// Takes one permit if one is available, without blocking.
+    ///
+    /// Returns `Ok(true)` if a permit was taken, `Ok(false)` otherwise.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn try_acquire(&self) -> Result<bool> {
+        let mut n = self
+            .permits
+            .lock()
+            .map_err(|_| PriorityQueueError::LockError("Semaphore::try_acquire"))?;
+        if *n == 0 {
+            return Ok(false);
+        }
+        *n -= 1;
+        Ok(true)
+    }
+
+    /// Takes one permit, blocking at most `timeout`.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::Timeout`] — if no permit became available in time.
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn acquire_timeout(&self, timeout: Duration) -> Result<()> {
+        let n = self
+            .permits
+            .lock()
+            .map_err(|_| PriorityQueueError::LockError("Semaphore::acquire_timeout"))?;
+        let (mut n, _) = self
+            .cv
+            .wait_timeout_while(n, timeout, |n| *n == 0)
+            .map_err(|_| PriorityQueueError::LockError("Semaphore::acquire_timeout"))?;
+        // The wait may report a timeout although a permit came back right at
+        // the deadline: trust the counter, not the timer.
+        if *n == 0 {
+            return Err(PriorityQueueError::Timeout);
+        }
+        *n -= 1;
+        Ok(())
+    }
+
+    /// Gives back one permit and wakes one waiting thread, if any.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn release(&self) -> Result<()> {
+        let mut n = self
+            .permits
+            .lock()
+            .map_err(|_| PriorityQueueError::LockError("Semaphore::release"))?;
+        *n += 1;
+        drop(n);
+        self.cv.notify_one();
+        Ok(())
+    }
+
+    /// Returns the number of permits currently available.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`] — if the internal mutex was poisoned.
+    ///
+    pub fn available(&self) -> Result<usize> {
+        let n = self
+            .permits
+            .lock()
+            .map_err(|_| PriorityQueueError::LockError("Semaphore::available"))?;
+        Ok(*n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            Arc,
+            atomic::{AtomicUsize, Ordering},
+        },
+        thread::{sleep, spawn},
+        time::Duration,
+    };
+
+    use super::Semaphore;
+
+    #[test]
+    fn test_full_empty_boundary() {
+        let sem = Semaphore::new(2);
+        assert!(sem.try_acquire().unwrap());
+        assert!(sem.try_acquire().unwrap());
+        assert!(!sem.try_acquire().unwrap());
+        assert!(sem.acquire_timeout(Duration::from_millis(20)).is_err());
+
+        sem.release().unwrap();
+        assert_eq!(sem.available().unwrap(), 1);
+        assert!(sem.acquire_timeout(Duration::from_millis(20)).is_ok());
+        assert_eq!(sem.available().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_multiple_waiters_released() {
+        let sem = Arc::new(Semaphore::new(0));
+        let acquired = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let sem = Arc::clone(&sem);
+                let acquired = Arc::clone(&acquired);
+                spawn(move || {
+                    sem.acquire().unwrap();
+                    acquired.fetch_add(1, Ordering::SeqCst);
+                })
+            })
+            .collect();
+
+        sleep(Duration::from_millis(50));
+        assert_eq!(acquired.load(Ordering::SeqCst), 0);
+
+        // One permit per waiter: every waiter gets exactly one, none is left behind.
+        for _ in 0..4 {
+            sem.release().unwrap();
+        }
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(acquired.load(Ordering::SeqCst), 4);
+        assert_eq!(sem.available().unwrap(), 0);
+    }
+}
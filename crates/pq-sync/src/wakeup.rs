@@ -0,0 +1,52 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Condvar},
+};
+
+// FIFO of parked consumers, for fair wakeup (opt-in, see
+// `SyncPriorityQueueBuilder::fair_wakeup()`).
+//
+// Each parked consumer waits on its own condvar, so that waking "the next
+// one" targets the longest-waiting consumer rather than whichever thread the
+// OS picks on a shared condvar. Only the consumer in front may take an item;
+// it hands over to the next one when it leaves.
+pub(crate) struct WaiterQueue {
+    waiters: VecDeque<Arc<Condvar>>,
+}
+
+impl WaiterQueue {
+    pub(crate) fn new() -> Self {
+        Self {
+            waiters: VecDeque::new(),
+        }
+    }
+
+    // Parks a new consumer at the back; it waits on the returned condvar.
+    pub(crate) fn join(&mut self) -> Arc<Condvar> {
+        let cv = Arc::new(Condvar::new());
+        self.waiters.push_back(Arc::clone(&cv));
+        cv
+    }
+
+    pub(crate) fn is_front(&self, cv: &Arc<Condvar>) -> bool {
+        self.waiters
+            .front()
+            .is_some_and(|front| Arc::ptr_eq(front, cv))
+    }
+
+    pub(crate) fn leave(&mut self, cv: &Arc<Condvar>) {
+        self.waiters.retain(|w| !Arc::ptr_eq(w, cv));
+    }
+
+    // The condvar of the longest-waiting consumer, to notify once the lock
+    // is released.
+    pub(crate) fn front(&self) -> Option<Arc<Condvar>> {
+        self.waiters.front().cloned()
+    }
+
+    pub(crate) fn wake_all(&self) {
+        for cv in &self.waiters {
+            cv.notify_one();
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    cell::Cell,
+    hash::Hash,
+    sync::{Arc, PoisonError},
+};
+
+use pq_core::Result;
+
+use crate::Inner;
+
+/// Event reported by a [`DepthWatcher`] when the queue depth crosses one of
+/// its thresholds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DepthEvent {
+    /// The depth rose strictly above the `high` threshold.
+    AboveHigh,
+    /// The depth fell strictly below the `low` threshold.
+    BelowLow,
+    /// The queue has been closed; this event is terminal.
+    Closed,
+}
+
+/// Handle returned by [`SyncPriorityQueue::watch_depth()`](crate::SyncPriorityQueue::watch_depth).
+///
+/// The watcher implements hysteresis: after reporting [`DepthEvent::AboveHigh`],
+/// the next event can only be [`DepthEvent::BelowLow`] (or [`DepthEvent::Closed`]),
+/// and vice versa. A controller can therefore loop on [`wait()`](DepthWatcher::wait)
+/// to pause and resume producers without ever polling the queue length.
+pub struct DepthWatcher<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    inner: Arc<Inner<E, T>>,
+    low: usize,
+    high: usize,
+    last: Cell<Option<DepthEvent>>,
+}
+
+impl<E, T> DepthWatcher<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    pub(crate) fn new(inner: Arc<Inner<E, T>>, low: usize, high: usize) -> Result<Self> {
+        // Inverted thresholds would report both events at once, back and forth.
+        assert!(
+            low <= high,
+            "watch_depth: low ({low}) must not exceed high ({high})"
+        );
+        inner
+            .state
+            .lock()
+            .or_else(|e| inner.recover(e, "watch_depth"))?
+            .depth_watchers += 1;
+        Ok(Self {
+            inner,
+            low,
+            high,
+            last: Cell::new(None),
+        })
+    }
+
+    /// Blocks until the queue depth crosses a threshold, or the queue is closed.
+    ///
+    /// # Behavior
+    ///
+    /// - Returns [`DepthEvent::AboveHigh`] once `len > high`, unless that was
+    ///   the last event reported.
+    /// - Returns [`DepthEvent::BelowLow`] once `len < low`, unless that was
+    ///   the last event reported.
+    /// - Returns [`DepthEvent::Closed`] as soon as the queue is closed, and on
+    ///   every subsequent call.
+    ///
+    /// # Errors
+    ///
+    /// Returns:
+    /// * [`PriorityQueueError::LockError`](pq_core::PriorityQueueError::LockError) — if the
+    ///   internal mutex was poisoned (with [`PoisonPolicy::Error`](crate::PoisonPolicy::Error)).
+    ///
+    pub fn wait(&self) -> Result<DepthEvent> {
+        let st = self
+            .inner
+            .state
+            .lock()
+            .or_else(|e| self.inner.recover(e, "DepthWatcher::wait"))?;
+        let last = self.last.get();
+        let st = self
+            .inner
+            .depth_cv
+            .wait_while(st, |s| {
+                let depth = s.pq.len();
+                let crossed = (depth > self.high && last != Some(DepthEvent::AboveHigh))
+                    || (depth < self.low && last != Some(DepthEvent::BelowLow));
+                !s.closed && !crossed
+            })
+            .or_else(|e| self.inner.recover(e, "DepthWatcher::wait"))?;
+        let event = if st.closed {
+            DepthEvent::Closed
+        } else if st.pq.len() > self.high {
+            DepthEvent::AboveHigh
+        } else {
+            DepthEvent::BelowLow
+        };
+        self.last.set(Some(event));
+        Ok(event)
+    }
+}
+
+impl<E, T> Drop for DepthWatcher<E, T>
+where
+    E: Eq + Hash + Clone,
+{
+    fn drop(&mut self) {
+        // Even on a poisoned lock: under `PoisonPolicy::Recover`, a count
+        // left behind would keep every enqueue notifying `depth_cv`.
+        let mut st = self
+            .inner
+            .state
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        st.depth_watchers -= 1;
+    }
+}